@@ -0,0 +1,183 @@
+//! Access log in the Common or Combined Log Format, separate from the tracing output, optionally
+//! followed by the timings of the request.
+//!
+//! The lines of the successful requests can be sampled, the ones of the errors and denials, with
+//! statuses from `400`, being always written. The values of the query parameters are redacted.
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    io::Write,
+    net::SocketAddr,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use axum::{
+    extract::{ConnectInfo, Query, Request, State},
+    http::header,
+    middleware::Next,
+    response::Response,
+};
+use chrono::{DateTime, Local};
+use clap::ValueEnum;
+
+use crate::{
+    anonymize,
+    body::Counted,
+    log_file::{RotatingFile, Rotation},
+    quota::{bearer_token, credential_id},
+    redact,
+    timing::Timings,
+};
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum Format {
+    Common,
+    /// Common Log Format followed by the referer and user agent
+    Combined,
+    /// Combined Log Format followed by the duration of the request and the name resolution, TCP and
+    /// TLS handshakes, time to first byte and body phases of the upstream request, in microseconds or
+    /// `-`
+    CombinedTiming,
+}
+
+pub struct AccessLog {
+    file: Mutex<RotatingFile>,
+    format: Format,
+    /// One in this many successful requests is logged.
+    sample: u64,
+    successes: AtomicU64,
+}
+
+impl AccessLog {
+    pub fn open(path: &Path, format: Format, rotation: Rotation, sample: u64) -> Result<Self> {
+        let file = RotatingFile::open(path, rotation).context("could not open the access log")?;
+        Ok(Self {
+            file: Mutex::new(file),
+            format,
+            sample,
+            successes: AtomicU64::new(0),
+        })
+    }
+
+    /// Whether the request whose response has `status` is logged.
+    fn sampled(&self, status: u16) -> bool {
+        status >= 400
+            || self
+                .successes
+                .fetch_add(1, Ordering::Relaxed)
+                .is_multiple_of(self.sample)
+    }
+
+    fn write(&self, entry: &Entry, bytes: u64) {
+        let mut line = format!(
+            "{} - {} [{}] \"{}\" {} {}",
+            entry.client,
+            entry.credential.as_deref().unwrap_or("-"),
+            entry.time.format("%d/%b/%Y:%H:%M:%S %z"),
+            escape(&entry.request_line),
+            entry.status,
+            bytes,
+        );
+        if let Format::Combined | Format::CombinedTiming = self.format {
+            let _ = write!(
+                line,
+                " \"{}\" \"{}\"",
+                escape(entry.referer.as_deref().unwrap_or("-")),
+                escape(entry.user_agent.as_deref().unwrap_or("-")),
+            );
+        }
+        if let Format::CombinedTiming = self.format {
+            // request duration in microseconds, like Apache's `%D`
+            let _ = write!(line, " {}", entry.start.elapsed().as_micros());
+            // followed by the phases of the upstream request, if any
+            let micros = |duration: Option<Duration>| {
+                duration.map_or("-".to_string(), |duration| duration.as_micros().to_string())
+            };
+            let phases = match &entry.timings {
+                Some(timings) => [
+                    timings.dns,
+                    timings.tcp,
+                    timings.tls,
+                    Some(timings.ttfb),
+                    timings.body.get().copied(),
+                ],
+                None => [None; 5],
+            };
+            for duration in phases {
+                let _ = write!(line, " {}", micros(duration));
+            }
+        }
+        line.push('\n');
+        if let Err(err) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+            tracing::error!(error = %err, "Could not write access log");
+        }
+    }
+}
+
+/// What is known about a request before its response body is sent.
+struct Entry {
+    client: String,
+    /// Stable id of the credential, see [`credential_id`].
+    credential: Option<String>,
+    time: DateTime<Local>,
+    start: Instant,
+    request_line: String,
+    status: u16,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    timings: Option<Timings>,
+}
+
+/// Middleware writing an access log line once the response body has been sent.
+pub async fn log_access(
+    State(log): State<Arc<AccessLog>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let headers = request.headers();
+    let header = |name| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+    };
+    // log the proxied URL rather than the request to the proxy itself
+    let target = Query::<HashMap<String, String>>::try_from_uri(request.uri())
+        .ok()
+        .and_then(|Query(mut params)| params.remove("url"))
+        .unwrap_or_else(|| request.uri().to_string());
+    let mut entry = Entry {
+        client: anonymize::client_ip(addr.ip()),
+        credential: bearer_token(headers).map(credential_id),
+        time: Local::now(),
+        start: Instant::now(),
+        request_line: format!(
+            "{} {} {:?}",
+            request.method(),
+            redact::query(&target),
+            request.version()
+        ),
+        status: 0,
+        referer: header(header::REFERER),
+        user_agent: header(header::USER_AGENT),
+        timings: None,
+    };
+    let response = next.run(request).await;
+    entry.status = response.status().as_u16();
+    if !log.sampled(entry.status) {
+        return response;
+    }
+    entry.timings = response.extensions().get::<Timings>().cloned();
+    response.map(|body| Counted::wrap(body, move |bytes| log.write(&entry, bytes)))
+}
+
+fn escape(field: &str) -> String {
+    field.replace('\\', "\\\\").replace('"', "\\\"")
+}
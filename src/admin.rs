@@ -0,0 +1,461 @@
+//! Admin API, served on a separate address so that it can stay private.
+//!
+//! Unless it only listens on a loopback address, it requires the `ADMIN_TOKEN` as a bearer token,
+//! except for the health probes. The gRPC control plane is served on the same address, see
+//! [`crate::grpc`].
+use std::{
+    collections::HashMap,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+
+use anyhow::Result;
+use axum::{
+    body::Body,
+    extract::{FromRef, Query, Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::{self, Next},
+    response::{Html, IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use hyper::{body::Incoming, server::conn::http1, service::service_fn};
+use hyper_util::rt::TokioIo;
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpListener;
+use tower::ServiceExt;
+
+use crate::{
+    cache::Purge,
+    grpc::{self, Control},
+    metrics::{self, METRICS},
+    presign::constant_time_eq,
+    profile::{self, Profile},
+    server, tail, telemetry, AppState,
+};
+
+/// Page showing the stats and the denied requests, polling the API.
+const DASHBOARD: &str = include_str!("dashboard.html");
+
+/// Timeout of the canary request made by the readiness probe.
+const CANARY_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Serialize)]
+struct Purged {
+    purged: usize,
+}
+
+/// The default persona.
+#[derive(Serialize)]
+struct DefaultPersona {
+    name: String,
+    user_agent: String,
+}
+
+#[derive(Clone)]
+struct AdminState {
+    app: AppState,
+    /// Effective settings, from the command line and the config file.
+    settings: Arc<str>,
+}
+
+impl FromRef<AdminState> for AppState {
+    fn from_ref(state: &AdminState) -> Self {
+        state.app.clone()
+    }
+}
+
+pub async fn serve(
+    listener: TcpListener,
+    state: AppState,
+    token: Option<String>,
+    settings: String,
+) -> Result<()> {
+    let token = token.map(Arc::<str>::from);
+    let settings = Arc::<str>::from(settings);
+    let control = Arc::new(Control::new(state.clone(), token.clone(), settings.clone()));
+    let mut app = Router::new()
+        .route("/cache", get(list_cache).delete(purge_cache))
+        .route("/cache/stats", get(cache_stats))
+        .route("/metrics", get(metrics))
+        .route("/stats", get(stats))
+        .route("/config", get(config))
+        .route("/credentials/reload", post(reload_credentials))
+        .route(
+            "/log-level",
+            get(log_level).put(set_log_level).delete(reset_log_level),
+        )
+        .route("/tail", get(tail))
+        .route("/denied", get(denied))
+        .route("/har", get(har))
+        .route("/usage", get(usage))
+        .route("/drain", get(drain))
+        .route(
+            "/maintenance",
+            get(maintenance)
+                .put(enable_maintenance)
+                .delete(disable_maintenance),
+        )
+        .route("/persona", get(persona).put(switch_persona))
+        .route("/profile", get(cpu_profile))
+        .route("/heap", get(heap));
+    if let Some(token) = token {
+        app = app.route_layer(middleware::from_fn_with_state(token, require_token));
+    }
+    let app = app
+        // the page asks for the token to call the API, if needed
+        .route("/dashboard", get(dashboard))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .with_state(AdminState {
+            app: state,
+            settings,
+        });
+    tracing::info!(addr = %listener.local_addr()?, "Admin API listening");
+    loop {
+        let Some((stream, _)) = server::accept(&listener).await else {
+            continue;
+        };
+        let (app, control) = (app.clone(), control.clone());
+        tokio::spawn(async move {
+            // the gRPC clients speak HTTP/2 from the start
+            if grpc::is_http2(&stream).await {
+                return control.serve(stream).await;
+            }
+            let service = service_fn(move |request: hyper::Request<Incoming>| {
+                app.clone().oneshot(request.map(Body::new))
+            });
+            let connection = http1::Builder::new().serve_connection(TokioIo::new(stream), service);
+            if let Err(err) = connection.await {
+                tracing::debug!(error = %err, "Admin API connection failed");
+            }
+        });
+    }
+}
+
+async fn drain(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.drain.progress())
+}
+
+async fn maintenance(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.maintenance.status())
+}
+
+/// Enter maintenance mode, with the `retry_after` query param and the message in the body, if set,
+/// replacing the configured ones.
+async fn enable_maintenance(
+    Query(params): Query<HashMap<String, String>>,
+    State(state): State<AppState>,
+    message: String,
+) -> impl IntoResponse {
+    let retry_after = match params
+        .get("retry_after")
+        .map(|value| humantime::parse_duration(value))
+    {
+        Some(Ok(retry_after)) => Some(retry_after),
+        Some(Err(err)) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                format!("invalid `retry_after`: {err}"),
+            )
+                .into_response()
+        }
+        None => None,
+    };
+    let message = Some(message.trim().to_string()).filter(|message| !message.is_empty());
+    state.maintenance.enable(retry_after, message);
+    Json(state.maintenance.status()).into_response()
+}
+
+async fn disable_maintenance(State(state): State<AppState>) -> impl IntoResponse {
+    state.maintenance.disable();
+    Json(state.maintenance.status())
+}
+
+fn default_persona(state: &AppState) -> Json<DefaultPersona> {
+    let personas = state.personas();
+    let persona = personas.default();
+    Json(DefaultPersona {
+        name: persona.name.clone(),
+        user_agent: persona.user_agent().to_string(),
+    })
+}
+
+async fn persona(State(state): State<AppState>) -> impl IntoResponse {
+    default_persona(&state)
+}
+
+/// Switch the default persona to the one of the `name` query param if set, and its `User-Agent` to
+/// the body if not empty, without dropping the connections.
+async fn switch_persona(
+    Query(params): Query<HashMap<String, String>>,
+    State(state): State<AppState>,
+    user_agent: String,
+) -> impl IntoResponse {
+    let user_agent = Some(user_agent.trim()).filter(|user_agent| !user_agent.is_empty());
+    let switched = {
+        let mut personas = state.personas.write().unwrap();
+        personas
+            .switched(params.get("name").map(String::as_str), user_agent)
+            .map(|switched| *personas = Arc::new(switched))
+    };
+    if let Err(err) = switched {
+        return (StatusCode::BAD_REQUEST, format!("{err:#}")).into_response();
+    }
+    let persona = default_persona(&state);
+    tracing::warn!(
+        persona = persona.name,
+        user_agent = persona.user_agent,
+        "Switched the default persona"
+    );
+    persona.into_response()
+}
+
+async fn require_token(State(token): State<Arc<str>>, request: Request, next: Next) -> Response {
+    let authorized = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|bearer| constant_time_eq(bearer, &token));
+    if !authorized {
+        tracing::error!("Unauthorized admin API access attempt");
+        return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
+    }
+    next.run(request).await
+}
+
+/// Liveness probe, succeeding as long as the process is able to answer.
+async fn healthz() -> impl IntoResponse {
+    "ok"
+}
+
+/// Readiness probe, succeeding once the proxy listener is bound, until the proxy shuts down, and
+/// while the canary URL, if any, can be fetched.
+async fn readyz(State(state): State<AppState>) -> impl IntoResponse {
+    if state.drain.stopping() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "shutting down".to_string());
+    }
+    if !state.ready.load(Ordering::Relaxed) {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "listener is not bound".to_string(),
+        );
+    }
+    if let Some(canary) = &state.readiness_canary {
+        let response = state
+            .upstream
+            .client(canary, None)
+            .get(canary.clone())
+            .timeout(CANARY_TIMEOUT)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        if let Err(err) = response {
+            tracing::warn!(error = %err, "Readiness canary request failed");
+            return (
+                StatusCode::SERVICE_UNAVAILABLE,
+                format!("canary request failed: {err}"),
+            );
+        }
+    }
+    (StatusCode::OK, "ready".to_string())
+}
+
+async fn metrics(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    let openmetrics = headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .any(|accept| accept.contains("application/openmetrics-text"));
+    let content_type = if openmetrics {
+        "application/openmetrics-text; version=1.0.0; charset=utf-8"
+    } else {
+        "text/plain; version=0.0.4"
+    };
+    (
+        [(header::CONTENT_TYPE, content_type)],
+        METRICS.render(&state, openmetrics),
+    )
+}
+
+async fn stats(State(state): State<AppState>) -> impl IntoResponse {
+    Json(metrics::snapshot(&state))
+}
+
+async fn config(State(state): State<AdminState>) -> impl IntoResponse {
+    state.settings.to_string()
+}
+
+/// Read the proxy credential again, see [`crate::reload_auth_token`].
+async fn reload_credentials(State(state): State<AppState>) -> impl IntoResponse {
+    match crate::reload_auth_token(&state) {
+        Ok(()) => {
+            tracing::info!("Reloaded credentials");
+            (StatusCode::OK, "reloaded".to_string())
+        }
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, format!("{err:#}")),
+    }
+}
+
+async fn log_level() -> impl IntoResponse {
+    telemetry::log_filter().unwrap_or_default()
+}
+
+/// Replace the log filter with the request body, in the `RUST_LOG` syntax.
+async fn set_log_level(directives: String) -> impl IntoResponse {
+    match telemetry::set_log_filter(directives.trim()) {
+        Ok(()) => {
+            tracing::info!(filter = directives.trim(), "Changed log filter");
+            (StatusCode::OK, "updated".to_string())
+        }
+        Err(err) => (StatusCode::BAD_REQUEST, format!("{err:#}")),
+    }
+}
+
+/// Restore the log filter the proxy started with.
+async fn reset_log_level() -> impl IntoResponse {
+    match telemetry::reset_log_filter() {
+        Ok(()) => {
+            tracing::info!(filter = telemetry::log_filter(), "Reset log filter");
+            (StatusCode::OK, "reset".to_string())
+        }
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, format!("{err:#}")),
+    }
+}
+
+/// Stream a summary of the requests as server-sent events, optionally filtered by `host` or
+/// `credential`.
+async fn tail(
+    Query(filter): Query<tail::Filter>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    tail::subscribe(&state.tail, filter)
+}
+
+/// The requests recently denied, from the most recent.
+async fn denied(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.tail.denied())
+}
+
+async fn dashboard() -> impl IntoResponse {
+    Html(DASHBOARD)
+}
+
+#[derive(Deserialize)]
+struct UsageParams {
+    #[serde(default)]
+    format: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ProfileParams {
+    /// Duration of the profile in seconds.
+    #[serde(default = "ProfileParams::default_seconds")]
+    seconds: u64,
+    #[serde(default)]
+    format: Option<String>,
+}
+
+impl ProfileParams {
+    fn default_seconds() -> u64 {
+        10
+    }
+}
+
+/// CPU profile of the proxy over the `seconds` query param, in the pprof format or as folded
+/// stacks for the flamegraph tools with `?format=folded`.
+async fn cpu_profile(Query(params): Query<ProfileParams>) -> impl IntoResponse {
+    let duration = Duration::from_secs(params.seconds);
+    if duration.is_zero() || duration > profile::MAX_DURATION {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!(
+                "`seconds` must be between 1 and {}",
+                profile::MAX_DURATION.as_secs()
+            ),
+        )
+            .into_response();
+    }
+    tracing::info!(seconds = params.seconds, "Taking CPU profile");
+    let profile = match Profile::take(duration).await {
+        Ok(profile) => profile,
+        Err(err) => return (StatusCode::CONFLICT, err.to_string()).into_response(),
+    };
+    if profile.dropped > 0 {
+        tracing::warn!(dropped = profile.dropped, "Dropped CPU profile samples");
+    }
+    match params.format.as_deref() {
+        Some("folded") => profile.folded().into_response(),
+        _ => (
+            [(header::CONTENT_TYPE, "application/octet-stream")],
+            profile.pprof(),
+        )
+            .into_response(),
+    }
+}
+
+async fn heap() -> impl IntoResponse {
+    Json(profile::heap())
+}
+
+/// Usage of each credential, as JSON or as CSV with `?format=csv`.
+async fn usage(
+    Query(params): Query<UsageParams>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    match params.format.as_deref() {
+        Some("csv") => ([(header::CONTENT_TYPE, "text/csv")], state.usage.to_csv()),
+        _ => (
+            [(header::CONTENT_TYPE, "application/json")],
+            state.usage.to_json(),
+        ),
+    }
+}
+
+async fn har(State(state): State<AppState>) -> impl IntoResponse {
+    match &state.har {
+        Some(har) => ([(header::CONTENT_TYPE, "application/json")], har.to_json()).into_response(),
+        None => (StatusCode::NOT_FOUND, "HAR recording is disabled").into_response(),
+    }
+}
+
+async fn list_cache(State(state): State<AppState>) -> impl IntoResponse {
+    match &state.cache {
+        Some(cache) => Json(cache.entries()).into_response(),
+        None => (StatusCode::NOT_FOUND, "cache is disabled").into_response(),
+    }
+}
+
+async fn cache_stats(State(state): State<AppState>) -> impl IntoResponse {
+    match &state.cache {
+        Some(cache) => Json(cache.stats()).into_response(),
+        None => (StatusCode::NOT_FOUND, "cache is disabled").into_response(),
+    }
+}
+
+/// Purge cache entries matching the `url`, `prefix` or `host` query param.
+async fn purge_cache(
+    Query(params): Query<HashMap<String, String>>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let Some(cache) = &state.cache else {
+        return (StatusCode::NOT_FOUND, "cache is disabled").into_response();
+    };
+    let purge = if let Some(url) = params.get("url") {
+        Purge::Url(url)
+    } else if let Some(prefix) = params.get("prefix") {
+        Purge::Prefix(prefix)
+    } else if let Some(host) = params.get("host") {
+        Purge::Host(host)
+    } else {
+        return (
+            StatusCode::BAD_REQUEST,
+            "Missing `url`, `prefix` or `host` param",
+        )
+            .into_response();
+    };
+    let purged = cache.purge(purge);
+    tracing::info!(purged, "Purged cache entries");
+    Json(Purged { purged }).into_response()
+}
@@ -0,0 +1,217 @@
+//! Webhook notifications when the error rates cross thresholds.
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use anyhow::{ensure, Context, Result};
+use reqwest::{header, Client, Url};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::metrics::METRICS;
+
+/// Timeout of the webhook requests.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WebhookFormat {
+    /// Slack incoming webhook message
+    Slack,
+    /// JSON object with the condition, its value and its threshold
+    #[default]
+    Json,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AlertConfig {
+    pub webhook: String,
+    #[serde(default)]
+    pub format: WebhookFormat,
+    /// Period over which the conditions are evaluated.
+    #[serde(default = "AlertConfig::default_window", with = "humantime_serde")]
+    pub window: Duration,
+    /// Minimum time between two notifications for the same condition.
+    #[serde(default = "AlertConfig::default_cooldown", with = "humantime_serde")]
+    pub cooldown: Duration,
+    /// Rates are not evaluated over windows with fewer requests than this.
+    #[serde(default = "AlertConfig::default_min_requests")]
+    pub min_requests: u64,
+    /// Fraction of the responses to clients that are server errors.
+    pub error_rate: Option<f64>,
+    /// Number of failed authentications per window.
+    pub auth_failures: Option<u64>,
+    /// Fraction of the upstream requests that fail or get a server error.
+    pub upstream_error_rate: Option<f64>,
+}
+
+impl AlertConfig {
+    fn default_window() -> Duration {
+        Duration::from_secs(60)
+    }
+
+    fn default_cooldown() -> Duration {
+        Duration::from_secs(15 * 60)
+    }
+
+    fn default_min_requests() -> u64 {
+        20
+    }
+}
+
+/// Alert raised by the evaluation of a window.
+struct Alert {
+    condition: &'static str,
+    value: f64,
+    threshold: f64,
+    message: String,
+}
+
+pub struct Alerter {
+    config: AlertConfig,
+    client: Client,
+    /// Counter values at the start of the current window.
+    last: HashMap<&'static str, u64>,
+    /// When each condition was last notified, and how many alerts were held back since.
+    sent: HashMap<&'static str, (Instant, u64)>,
+}
+
+impl Alerter {
+    pub fn new(config: AlertConfig) -> Result<Self> {
+        for rate in [config.error_rate, config.upstream_error_rate]
+            .into_iter()
+            .flatten()
+        {
+            ensure!(
+                (0.0..=1.0).contains(&rate),
+                "Alert error rates must be between 0 and 1"
+            );
+        }
+        Url::parse(&config.webhook).context("invalid alert webhook URL")?;
+        ensure!(
+            !config.window.is_zero(),
+            "The alert window must not be zero"
+        );
+        let client = Client::builder().timeout(WEBHOOK_TIMEOUT).build()?;
+        Ok(Self {
+            config,
+            client,
+            last: HashMap::new(),
+            sent: HashMap::new(),
+        })
+    }
+
+    /// Increase of a counter since the last evaluation.
+    fn delta(&mut self, name: &'static str, counter: u64) -> u64 {
+        counter - self.last.insert(name, counter).unwrap_or_default()
+    }
+
+    fn evaluate(&mut self) -> Vec<Alert> {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let responses = self.delta("responses", METRICS.responses.iter().map(load).sum());
+        let server_errors = self.delta("server_errors", load(&METRICS.responses[4]));
+        let auth_failures = self.delta("auth_failures", load(&METRICS.auth_failures));
+        let upstream_requests = self.delta("upstream_requests", load(&METRICS.upstream_requests));
+        let upstream_errors = self.delta("upstream_errors", load(&METRICS.upstream_errors));
+        let window = humantime::format_duration(self.config.window);
+
+        let mut alerts = Vec::new();
+        let mut rate = |condition, what, errors: u64, total: u64, threshold: Option<f64>| {
+            let Some(threshold) = threshold else {
+                return;
+            };
+            let value = errors as f64 / total as f64;
+            if total >= self.config.min_requests && value > threshold {
+                alerts.push(Alert {
+                    condition,
+                    value,
+                    threshold,
+                    message: format!(
+                        "{what} was {:.1}% over the last {window} ({errors} of {total}), above \
+                         the {:.1}% threshold",
+                        value * 1e2,
+                        threshold * 1e2
+                    ),
+                });
+            }
+        };
+        rate(
+            "error_rate",
+            "Server error rate",
+            server_errors,
+            responses,
+            self.config.error_rate,
+        );
+        rate(
+            "upstream_error_rate",
+            "Upstream error rate",
+            upstream_errors,
+            upstream_requests,
+            self.config.upstream_error_rate,
+        );
+        if let Some(threshold) = self.config.auth_failures {
+            if auth_failures > threshold {
+                alerts.push(Alert {
+                    condition: "auth_failures",
+                    value: auth_failures as f64,
+                    threshold: threshold as f64,
+                    message: format!(
+                        "{auth_failures} failed authentications over the last {window}, above \
+                         the threshold of {threshold}"
+                    ),
+                });
+            }
+        }
+        alerts
+    }
+
+    async fn notify(&mut self, mut alert: Alert) {
+        let now = Instant::now();
+        if let Some((sent, held_back)) = self.sent.get_mut(alert.condition) {
+            if now.duration_since(*sent) < self.config.cooldown {
+                *held_back += 1;
+                return;
+            }
+            if *held_back > 0 {
+                alert.message += &format!(" ({held_back} similar alerts held back)");
+            }
+        }
+        self.sent.insert(alert.condition, (now, 0));
+        tracing::warn!(condition = alert.condition, "{}", alert.message);
+        let payload = match self.config.format {
+            WebhookFormat::Slack => json!({ "text": alert.message }),
+            WebhookFormat::Json => json!({
+                "condition": alert.condition,
+                "value": alert.value,
+                "threshold": alert.threshold,
+                "window_secs": self.config.window.as_secs(),
+                "message": alert.message,
+            }),
+        };
+        let sent = self
+            .client
+            .post(&self.config.webhook)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(payload.to_string())
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        if let Err(err) = sent {
+            tracing::error!(error = %err, "Could not deliver alert");
+        }
+    }
+
+    /// Evaluate the conditions at the end of each window, notifying the webhook as needed.
+    pub async fn watch(mut self) {
+        let mut interval = tokio::time::interval(self.config.window);
+        loop {
+            interval.tick().await;
+            for alert in self.evaluate() {
+                self.notify(alert).await;
+            }
+        }
+    }
+}
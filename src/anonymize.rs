@@ -0,0 +1,61 @@
+//! Anonymization of the client IPs written to the logs, for privacy.
+//!
+//! Only the output is affected, rate limits and bandwidth limits still apply to the full IPs.
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::OnceLock,
+};
+
+use clap::ValueEnum;
+use sha2::{Digest, Sha256};
+
+#[derive(Clone, Copy, Debug, Default, ValueEnum)]
+pub enum Mode {
+    /// Log the full IPs
+    #[default]
+    Off,
+    /// Zero the last octet of IPv4 addresses and all but the first 48 bits of IPv6 addresses
+    Truncate,
+    /// Replace the IPs with a salted hash, which stays the same until the proxy restarts
+    Hash,
+}
+
+static MODE: OnceLock<(Mode, [u8; 16])> = OnceLock::new();
+
+pub fn init(mode: Mode) {
+    let _ = MODE.set((mode, rand::random()));
+}
+
+/// The client IP as it should be logged.
+pub fn client_ip(ip: IpAddr) -> String {
+    let (mode, salt) = MODE.get().copied().unwrap_or_default();
+    match (mode, ip) {
+        (Mode::Off, _) => ip.to_string(),
+        (Mode::Truncate, IpAddr::V4(ip)) => {
+            let [a, b, c, _] = ip.octets();
+            Ipv4Addr::new(a, b, c, 0).to_string()
+        }
+        (Mode::Truncate, IpAddr::V6(ip)) => {
+            let [a, b, c, ..] = ip.segments();
+            Ipv6Addr::new(a, b, c, 0, 0, 0, 0, 0).to_string()
+        }
+        (Mode::Hash, _) => {
+            let digest = Sha256::new()
+                .chain_update(salt)
+                .chain_update(ip.to_string())
+                .finalize();
+            digest[..8]
+                .iter()
+                .map(|byte| format!("{byte:02x}"))
+                .collect()
+        }
+    }
+}
+
+/// The client address as it should be logged, with its port unless anonymized.
+pub fn peer(addr: SocketAddr) -> String {
+    match MODE.get() {
+        Some((Mode::Truncate | Mode::Hash, _)) => client_ip(addr.ip()),
+        _ => addr.to_string(),
+    }
+}
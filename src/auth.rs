@@ -0,0 +1,41 @@
+//! Authentication of the clients, with the static `AUTH_TOKEN` by default, or with an
+//! [`Authenticator`] registered with
+//! [`ProxyBuilder::authenticator`](crate::ProxyBuilder::authenticator).
+use std::{
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+};
+
+use async_trait::async_trait;
+
+use crate::presign::constant_time_eq;
+
+/// Whether a client may use the proxy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Allow,
+    Deny,
+}
+
+// the futures boxed by `async_trait` are `must_use` already
+#[allow(clippy::double_must_use)]
+#[async_trait]
+pub trait Authenticator: Send + Sync + 'static {
+    /// Decide whether the client at `client_addr` may use the proxy with the bearer token
+    /// `credentials`.
+    async fn authenticate(&self, credentials: &str, client_addr: SocketAddr) -> Decision;
+}
+
+/// A single token, which can be replaced at runtime.
+pub struct StaticToken(pub Arc<RwLock<String>>);
+
+#[async_trait]
+impl Authenticator for StaticToken {
+    async fn authenticate(&self, credentials: &str, _client_addr: SocketAddr) -> Decision {
+        if constant_time_eq(credentials, &self.0.read().unwrap()) {
+            Decision::Allow
+        } else {
+            Decision::Deny
+        }
+    }
+}
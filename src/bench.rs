@@ -0,0 +1,172 @@
+//! Load testing of the proxy, reporting throughput and latency percentiles.
+use std::{
+    env,
+    future::Future,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, ensure, Result};
+use clap::Args;
+use reqwest::{Client, Url};
+use tokio::net::TcpStream;
+
+/// How long to wait for the in-process proxy to listen.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Args)]
+pub struct BenchArgs {
+    /// URL requested through the proxy
+    #[arg(long)]
+    url: Url,
+    /// Address of a running proxy (e.g. `http://127.0.0.1:7788/`), the proxy is started in the
+    /// process with the other options if not set
+    #[arg(long)]
+    proxy: Option<Url>,
+    /// Number of requests in flight
+    #[arg(short, long, default_value_t = 16)]
+    concurrency: usize,
+    /// Total number of requests
+    #[arg(short = 'n', long, default_value_t = 1000)]
+    requests: u64,
+    /// Send requests for this long instead of a fixed number
+    #[arg(short, long, value_parser = humantime::parse_duration)]
+    duration: Option<Duration>,
+    /// Token sent to the proxy, `AUTH_TOKEN` by default
+    #[arg(long)]
+    token: Option<String>,
+}
+
+/// Outcome of the requests of one worker.
+#[derive(Default)]
+struct Samples {
+    latencies: Vec<Duration>,
+    errors: u64,
+    bytes: u64,
+}
+
+/// Run the benchmark, against `server` if no proxy address is given.
+pub async fn run(args: BenchArgs, server: impl Future<Output = Result<()>>) -> Result<()> {
+    ensure!(args.concurrency > 0, "The concurrency must be positive");
+    if let Some(proxy) = &args.proxy {
+        return drive(&args, proxy.clone()).await;
+    }
+    let port: u16 = env::var("PORT")
+        .unwrap_or("7788".to_string())
+        .parse()
+        .map_err(|_| anyhow::anyhow!("invalid PORT"))?;
+    let proxy = Url::parse(&format!("http://127.0.0.1:{port}/"))?;
+    tokio::select! {
+        result = server => {
+            result?;
+            bail!("The proxy stopped before the end of the benchmark")
+        }
+        result = async {
+            wait_listening(port).await?;
+            drive(&args, proxy).await
+        } => result,
+    }
+}
+
+async fn wait_listening(port: u16) -> Result<()> {
+    let deadline = Instant::now() + STARTUP_TIMEOUT;
+    while TcpStream::connect(("127.0.0.1", port)).await.is_err() {
+        ensure!(
+            Instant::now() < deadline,
+            "The proxy didn't listen on port {port} in time"
+        );
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    Ok(())
+}
+
+async fn drive(args: &BenchArgs, mut proxy: Url) -> Result<()> {
+    let token = match &args.token {
+        Some(token) => token.clone(),
+        None => env::var("AUTH_TOKEN")?,
+    };
+    proxy
+        .query_pairs_mut()
+        .append_pair("url", args.url.as_str());
+    let client = Client::builder()
+        .pool_max_idle_per_host(args.concurrency)
+        .build()?;
+    let deadline = args.duration.map(|duration| Instant::now() + duration);
+    let sent = AtomicU64::new(0);
+    let worker = || async {
+        let mut samples = Samples::default();
+        loop {
+            let more = match deadline {
+                Some(deadline) => Instant::now() < deadline,
+                None => sent.fetch_add(1, Ordering::Relaxed) < args.requests,
+            };
+            if !more {
+                return samples;
+            }
+            let start = Instant::now();
+            let response = client
+                .get(proxy.clone())
+                .bearer_auth(&token)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+            let body = match response {
+                Ok(response) => response.bytes().await,
+                Err(err) => Err(err),
+            };
+            match body {
+                Ok(body) => {
+                    samples.latencies.push(start.elapsed());
+                    samples.bytes += body.len() as u64;
+                }
+                Err(err) => {
+                    tracing::debug!(error = %err, "Benchmark request failed");
+                    samples.errors += 1;
+                }
+            }
+        }
+    };
+    let start = Instant::now();
+    let workers = futures_util::future::join_all((0..args.concurrency).map(|_| worker())).await;
+    let elapsed = start.elapsed();
+
+    let mut total = Samples::default();
+    for samples in workers {
+        total.latencies.extend(samples.latencies);
+        total.errors += samples.errors;
+        total.bytes += samples.bytes;
+    }
+    report(&mut total, elapsed);
+    Ok(())
+}
+
+fn report(samples: &mut Samples, elapsed: Duration) {
+    let successes = samples.latencies.len();
+    let seconds = elapsed.as_secs_f64();
+    println!(
+        "{} requests in {:.2}s, {} errors",
+        successes as u64 + samples.errors,
+        seconds,
+        samples.errors
+    );
+    println!(
+        "Throughput: {:.1} requests/s, {:.2} MB/s",
+        successes as f64 / seconds,
+        samples.bytes as f64 / seconds / 1e6
+    );
+    if successes == 0 {
+        return;
+    }
+    samples.latencies.sort_unstable();
+    let percentile = |p: f64| {
+        let index = ((successes - 1) as f64 * p).round() as usize;
+        samples.latencies[index].as_secs_f64() * 1e3
+    };
+    println!(
+        "Latency: p50 {:.2}ms, p90 {:.2}ms, p99 {:.2}ms, max {:.2}ms",
+        percentile(0.5),
+        percentile(0.9),
+        percentile(0.99),
+        percentile(1.0)
+    );
+}
@@ -0,0 +1,61 @@
+//! Response body wrappers.
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use axum::body::{Body, Bytes};
+use http_body::{Frame, SizeHint};
+
+/// Body counting the bytes sent, calling `on_done` with their number once it is dropped, after the
+/// `on_done` of the bodies it wraps.
+pub struct Counted<F: FnOnce(u64)> {
+    inner: Body,
+    bytes: u64,
+    on_done: Option<F>,
+}
+
+impl<F: FnOnce(u64) + Send + Unpin + 'static> Counted<F> {
+    pub fn wrap(inner: Body, on_done: F) -> Body {
+        Body::new(Self {
+            inner,
+            bytes: 0,
+            on_done: Some(on_done),
+        })
+    }
+}
+
+impl<F: FnOnce(u64) + Unpin> http_body::Body for Counted<F> {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let frame = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &frame {
+            if let Some(data) = frame.data_ref() {
+                self.bytes += data.len() as u64;
+            }
+        }
+        frame
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl<F: FnOnce(u64)> Drop for Counted<F> {
+    fn drop(&mut self) {
+        if let Some(on_done) = self.on_done.take() {
+            self.inner = Body::empty();
+            on_done(self.bytes);
+        }
+    }
+}
@@ -0,0 +1,700 @@
+//! In-memory cache for successful upstream responses.
+use std::{
+    collections::HashMap,
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::Bytes,
+    http::{header, HeaderMap, StatusCode},
+};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+
+use crate::config::HostPattern;
+
+/// An upstream response as it is stored in the cache.
+#[derive(Clone)]
+pub struct CachedResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+impl CachedResponse {
+    /// Conditional request headers that allow the origin to answer with `304 Not Modified`.
+    pub fn validators(&self) -> HeaderMap {
+        let mut validators = HeaderMap::new();
+        if let Some(etag) = self.headers.get(header::ETAG) {
+            validators.insert(header::IF_NONE_MATCH, etag.clone());
+        }
+        if let Some(last_modified) = self.headers.get(header::LAST_MODIFIED) {
+            validators.insert(header::IF_MODIFIED_SINCE, last_modified.clone());
+        }
+        validators
+    }
+}
+
+pub enum Lookup {
+    /// The entry can be served as is.
+    Fresh(CachedResponse),
+    /// The entry has expired but can be served while it is revalidated in the background.
+    ///
+    /// `refresh` is `false` when another request already started the revalidation.
+    Revalidating {
+        cached: CachedResponse,
+        refresh: bool,
+    },
+    /// The entry has expired and must be revalidated with the origin before being served.
+    ///
+    /// `usable_on_error` tells whether it may still be served if the origin fails.
+    Stale {
+        cached: CachedResponse,
+        usable_on_error: bool,
+    },
+    Miss,
+}
+
+/// How the cache was involved in a response.
+#[derive(Clone, Copy)]
+pub enum CacheStatus {
+    /// Served from the cache, possibly after revalidation.
+    Hit,
+    /// Fetched from the origin.
+    Miss,
+    /// Served from the cache after expiry.
+    Stale,
+    /// The client asked to skip the cache.
+    Bypass,
+}
+
+impl CacheStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Hit => "HIT",
+            Self::Miss => "MISS",
+            Self::Stale => "STALE",
+            Self::Bypass => "BYPASS",
+        }
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    stale: AtomicU64,
+    bypasses: AtomicU64,
+    evictions: AtomicU64,
+    /// Bytes of the stored bodies, kept up to date so that reading it doesn't lock the entries.
+    stored_bytes: AtomicU64,
+}
+
+/// Cache counters, as shown by the admin API.
+#[derive(Serialize)]
+pub struct Stats {
+    pub hits: u64,
+    pub misses: u64,
+    pub stale: u64,
+    pub bypasses: u64,
+    pub evictions: u64,
+    /// Share of the requests served from the cache, fresh or stale.
+    pub hit_ratio: f64,
+    pub entries: usize,
+    pub stored_bytes: usize,
+}
+
+/// Default lifetimes used when the origin doesn't provide its own `Cache-Control` directives.
+#[derive(Clone, Copy)]
+pub struct Lifetimes {
+    pub ttl: Duration,
+    pub stale_while_revalidate: Duration,
+    pub stale_if_error: Duration,
+}
+
+/// Customizes the cache key for the matching hosts.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KeyRule {
+    pub host: HostPattern,
+    /// Query params that don't influence the response, like tracking params.
+    #[serde(default)]
+    pub ignore_params: Vec<String>,
+    /// Request headers that the response varies on.
+    #[serde(default)]
+    pub headers: Vec<String>,
+    /// Keep a separate entry for each authenticated user.
+    #[serde(default)]
+    pub per_user: bool,
+}
+
+/// Which entries to remove from the cache.
+pub enum Purge<'a> {
+    Url(&'a str),
+    Prefix(&'a str),
+    Host(&'a str),
+}
+
+/// Summary of a cache entry, as shown by the admin API.
+#[derive(Serialize)]
+pub struct EntryInfo {
+    pub key: String,
+    pub size: usize,
+    pub age_secs: u64,
+    pub hits: u64,
+    pub fresh: bool,
+}
+
+struct Entry {
+    url: String,
+    response: CachedResponse,
+    stored_at: Instant,
+    hits: u64,
+    /// Last time the entry was served, to evict the least recently used ones first.
+    used_at: Instant,
+    expires_at: Instant,
+    stale_while_revalidate: Duration,
+    stale_if_error: Duration,
+    refreshing: bool,
+}
+
+impl Entry {
+    fn new(url: String, response: CachedResponse, lifetimes: Lifetimes) -> Self {
+        let now = Instant::now();
+        Self {
+            url,
+            response,
+            stored_at: now,
+            hits: 0,
+            used_at: now,
+            expires_at: now + lifetimes.ttl,
+            stale_while_revalidate: lifetimes.stale_while_revalidate,
+            stale_if_error: lifetimes.stale_if_error,
+            refreshing: false,
+        }
+    }
+
+    fn size(&self) -> u64 {
+        self.response.body.len() as u64
+    }
+
+    /// Whether the entry can't be served anymore, not even stale.
+    fn is_expired(&self, now: Instant) -> bool {
+        let stale = self.stale_while_revalidate.max(self.stale_if_error);
+        self.expires_at + stale <= now
+    }
+}
+
+pub struct Cache {
+    entries: Mutex<HashMap<String, Entry>>,
+    defaults: Lifetimes,
+    max_entries: usize,
+    key_rules: Vec<KeyRule>,
+    counters: Counters,
+}
+
+impl Cache {
+    pub fn new(defaults: Lifetimes, max_entries: usize, key_rules: Vec<KeyRule>) -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+            defaults,
+            max_entries,
+            key_rules,
+            counters: Counters::default(),
+        }
+    }
+
+    /// Compute the cache key of a request, which is its URL unless a key rule matches its host.
+    pub fn key(&self, url: &Url, headers: &HeaderMap, user: &str) -> String {
+        let Some(rule) = self
+            .key_rules
+            .iter()
+            .find(|rule| url.host_str().is_some_and(|host| rule.host.matches(host)))
+        else {
+            return url.to_string();
+        };
+        let mut url = url.clone();
+        if !rule.ignore_params.is_empty() {
+            let params: Vec<_> = url
+                .query_pairs()
+                .filter(|(name, _)| !rule.ignore_params.iter().any(|ignored| ignored == name))
+                .map(|(name, value)| (name.into_owned(), value.into_owned()))
+                .collect();
+            if params.is_empty() {
+                url.set_query(None);
+            } else {
+                url.query_pairs_mut().clear().extend_pairs(params);
+            }
+        }
+        let mut key = url.to_string();
+        for name in &rule.headers {
+            let value = headers
+                .get(name.as_str())
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default();
+            key.push_str(&format!(" {}={value}", name.to_ascii_lowercase()));
+        }
+        if rule.per_user {
+            // don't expose credentials in the admin API
+            let mut hasher = DefaultHasher::new();
+            user.hash(&mut hasher);
+            key.push_str(&format!(" user={:016x}", hasher.finish()));
+        }
+        key
+    }
+
+    pub fn lookup(&self, key: &str) -> Lookup {
+        self.lookup_at(key, Instant::now())
+    }
+
+    fn lookup_at(&self, key: &str, now: Instant) -> Lookup {
+        let mut entries = self.entries.lock().unwrap();
+        let Some(entry) = entries.get_mut(key) else {
+            return Lookup::Miss;
+        };
+        entry.used_at = now;
+        if entry.expires_at > now {
+            entry.hits += 1;
+            return Lookup::Fresh(entry.response.clone());
+        }
+        let age = now - entry.expires_at;
+        if age < entry.stale_while_revalidate {
+            entry.hits += 1;
+            let refresh = !entry.refreshing;
+            entry.refreshing = true;
+            return Lookup::Revalidating {
+                cached: entry.response.clone(),
+                refresh,
+            };
+        }
+        Lookup::Stale {
+            cached: entry.response.clone(),
+            usable_on_error: age < entry.stale_if_error,
+        }
+    }
+
+    /// Store a response for `url` under `key`, unless the origin forbids it.
+    pub fn store(&self, key: String, url: &str, response: CachedResponse) {
+        let mut entries = self.entries.lock().unwrap();
+        let Some(lifetimes) = freshness(&response.headers, self.defaults) else {
+            if let Some(entry) = entries.remove(&key) {
+                self.forget(&entry);
+            }
+            return;
+        };
+        if !entries.contains_key(&key) && entries.len() >= self.max_entries {
+            // evict the entry closest to expiry
+            if let Some(oldest) = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.expires_at)
+                .map(|(key, _)| key.clone())
+            {
+                let entry = entries.remove(&oldest).expect("the entry is stored");
+                self.forget(&entry);
+                self.counters.evictions.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        let entry = Entry::new(url.to_string(), response, lifetimes);
+        self.counters
+            .stored_bytes
+            .fetch_add(entry.size(), Ordering::Relaxed);
+        if let Some(replaced) = entries.insert(key, entry) {
+            self.forget(&replaced);
+        }
+    }
+
+    /// Evict entries until `bytes` are freed, the ones that can't be served anymore first and then
+    /// the least recently used ones, returning the bytes freed.
+    pub fn shrink(&self, bytes: u64) -> u64 {
+        let mut entries = self.entries.lock().unwrap();
+        let now = Instant::now();
+        let mut candidates: Vec<_> = entries
+            .iter()
+            .map(|(key, entry)| ((!entry.is_expired(now), entry.used_at), key.clone()))
+            .collect();
+        candidates.sort_unstable();
+        let mut freed = 0;
+        for (_, key) in candidates {
+            if freed >= bytes {
+                break;
+            }
+            let entry = entries.remove(&key).expect("the entry is stored");
+            freed += entry.size();
+            self.forget(&entry);
+            self.counters.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+        freed
+    }
+
+    /// Bytes of the stored bodies.
+    pub fn stored_bytes(&self) -> u64 {
+        self.counters.stored_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Account for an entry removed from the cache.
+    fn forget(&self, entry: &Entry) {
+        self.counters
+            .stored_bytes
+            .fetch_sub(entry.size(), Ordering::Relaxed);
+    }
+
+    /// Mark a stale entry as fresh again after the origin answered `304 Not Modified`.
+    ///
+    /// The headers of the `304` response replace the stored ones, as they may carry updated
+    /// validators and freshness information.
+    pub fn revalidate(&self, key: &str, headers: &HeaderMap) -> Option<CachedResponse> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get_mut(key)?;
+        for (name, value) in headers {
+            entry.response.headers.insert(name, value.clone());
+        }
+        let lifetimes = freshness(&entry.response.headers, self.defaults).unwrap_or(Lifetimes {
+            ttl: Duration::ZERO,
+            ..self.defaults
+        });
+        let hits = entry.hits + 1;
+        *entry = Entry::new(entry.url.clone(), entry.response.clone(), lifetimes);
+        entry.hits = hits;
+        Some(entry.response.clone())
+    }
+
+    /// Remove matching entries, returning how many were removed.
+    pub fn purge(&self, purge: Purge) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let before = entries.len();
+        entries.retain(|_, entry| {
+            let kept = match purge {
+                Purge::Url(url) => entry.url != url,
+                Purge::Prefix(prefix) => !entry.url.starts_with(prefix),
+                Purge::Host(host) => Url::parse(&entry.url)
+                    .ok()
+                    .and_then(|url| url.host_str().map(|h| !h.eq_ignore_ascii_case(host)))
+                    .unwrap_or(true),
+            };
+            if !kept {
+                self.forget(entry);
+            }
+            kept
+        });
+        before - entries.len()
+    }
+
+    pub fn record(&self, status: CacheStatus) {
+        let counter = match status {
+            CacheStatus::Hit => &self.counters.hits,
+            CacheStatus::Miss => &self.counters.misses,
+            CacheStatus::Stale => &self.counters.stale,
+            CacheStatus::Bypass => &self.counters.bypasses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> Stats {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let (hits, misses, stale, bypasses) = (
+            load(&self.counters.hits),
+            load(&self.counters.misses),
+            load(&self.counters.stale),
+            load(&self.counters.bypasses),
+        );
+        let total = hits + misses + stale + bypasses;
+        let entries = self.entries.lock().unwrap();
+        Stats {
+            hits,
+            misses,
+            stale,
+            bypasses,
+            evictions: load(&self.counters.evictions),
+            hit_ratio: if total == 0 {
+                0.0
+            } else {
+                (hits + stale) as f64 / total as f64
+            },
+            entries: entries.len(),
+            stored_bytes: self.stored_bytes() as usize,
+        }
+    }
+
+    pub fn entries(&self) -> Vec<EntryInfo> {
+        let now = Instant::now();
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, entry)| EntryInfo {
+                key: key.clone(),
+                size: entry.response.body.len(),
+                age_secs: (now - entry.stored_at).as_secs(),
+                hits: entry.hits,
+                fresh: entry.expires_at > now,
+            })
+            .collect()
+    }
+
+    /// Allow another background revalidation once one is over, in case its response wasn't stored.
+    pub fn abort_refresh(&self, key: &str) {
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(key) {
+            entry.refreshing = false;
+        }
+    }
+}
+
+/// How long a response can be served from the cache, or `None` if it must not be stored.
+fn freshness(headers: &HeaderMap, defaults: Lifetimes) -> Option<Lifetimes> {
+    let Some(cache_control) = headers
+        .get(header::CACHE_CONTROL)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return Some(defaults);
+    };
+    let mut lifetimes = defaults;
+    let mut shared_ttl = None;
+    for directive in cache_control.split(',').map(str::trim) {
+        match directive.split_once('=') {
+            Some((name, value)) => {
+                let Ok(seconds) = value.trim_matches('"').parse().map(Duration::from_secs) else {
+                    continue;
+                };
+                match name.to_ascii_lowercase().as_str() {
+                    "max-age" => lifetimes.ttl = seconds,
+                    // we are a shared cache, so `s-maxage` takes precedence
+                    "s-maxage" => shared_ttl = Some(seconds),
+                    "stale-while-revalidate" => lifetimes.stale_while_revalidate = seconds,
+                    "stale-if-error" => lifetimes.stale_if_error = seconds,
+                    _ => {}
+                }
+            }
+            None if directive.eq_ignore_ascii_case("no-store")
+                || directive.eq_ignore_ascii_case("private") =>
+            {
+                return None
+            }
+            None if directive.eq_ignore_ascii_case("no-cache") => lifetimes.ttl = Duration::ZERO,
+            None if directive.eq_ignore_ascii_case("must-revalidate")
+                || directive.eq_ignore_ascii_case("proxy-revalidate") =>
+            {
+                lifetimes.stale_while_revalidate = Duration::ZERO;
+                lifetimes.stale_if_error = Duration::ZERO;
+            }
+            None => {}
+        }
+    }
+    if let Some(ttl) = shared_ttl {
+        lifetimes.ttl = ttl;
+    }
+    Some(lifetimes)
+}
+
+/// A recent upstream failure, replayed instead of contacting the origin again.
+#[derive(Clone)]
+pub enum Failure {
+    /// Server error response for a URL.
+    Response(CachedResponse),
+    /// Connection failure (including DNS resolution) for a host.
+    Error(String),
+}
+
+/// Short-lived cache of upstream failures, so that a dead origin isn't hammered by every client.
+pub struct FailureCache {
+    failures: Mutex<HashMap<String, (Failure, Instant)>>,
+    ttl: Duration,
+}
+
+impl FailureCache {
+    /// Past this number of failures, expired ones are pruned.
+    const PRUNE_THRESHOLD: usize = 1024;
+
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            failures: Mutex::new(HashMap::new()),
+            ttl,
+        }
+    }
+
+    pub fn lookup(&self, url: &Url) -> Option<Failure> {
+        let failures = self.failures.lock().unwrap();
+        let now = Instant::now();
+        [Some(url.as_str()), url.host_str()]
+            .into_iter()
+            .flatten()
+            .find_map(|key| match failures.get(key) {
+                Some((failure, expires_at)) if *expires_at > now => Some(failure.clone()),
+                _ => None,
+            })
+    }
+
+    /// Record a failure, keyed by URL for responses and by host for connection errors.
+    pub fn record(&self, url: &Url, failure: Failure) {
+        let key = match (&failure, url.host_str()) {
+            (Failure::Error(_), Some(host)) => host.to_string(),
+            _ => url.to_string(),
+        };
+        let mut failures = self.failures.lock().unwrap();
+        let now = Instant::now();
+        if failures.len() >= Self::PRUNE_THRESHOLD {
+            failures.retain(|_, (_, expires_at)| *expires_at > now);
+        }
+        failures.insert(key, (failure, now + self.ttl));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEFAULTS: Lifetimes = Lifetimes {
+        ttl: Duration::from_secs(60),
+        stale_while_revalidate: Duration::from_secs(30),
+        stale_if_error: Duration::from_secs(300),
+    };
+
+    fn lifetimes(cache_control: &str) -> Option<Lifetimes> {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CACHE_CONTROL, cache_control.parse().unwrap());
+        freshness(&headers, DEFAULTS)
+    }
+
+    fn response(cache_control: &str) -> CachedResponse {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CACHE_CONTROL, cache_control.parse().unwrap());
+        CachedResponse {
+            status: StatusCode::OK,
+            headers,
+            body: Bytes::from_static(b"body"),
+        }
+    }
+
+    #[test]
+    fn default_freshness() {
+        let lifetimes = freshness(&HeaderMap::new(), DEFAULTS).unwrap();
+        assert_eq!(lifetimes.ttl, DEFAULTS.ttl);
+        assert_eq!(
+            lifetimes.stale_while_revalidate,
+            DEFAULTS.stale_while_revalidate
+        );
+        assert_eq!(lifetimes.stale_if_error, DEFAULTS.stale_if_error);
+    }
+
+    #[test]
+    fn directives() {
+        let parsed =
+            lifetimes("Max-Age=10, stale-while-revalidate=\"5\", stale-if-error=20").unwrap();
+        assert_eq!(parsed.ttl, Duration::from_secs(10));
+        assert_eq!(parsed.stale_while_revalidate, Duration::from_secs(5));
+        assert_eq!(parsed.stale_if_error, Duration::from_secs(20));
+        // invalid values are ignored
+        assert_eq!(lifetimes("max-age=soon").unwrap().ttl, DEFAULTS.ttl);
+        assert!(lifetimes("public, max-age=0").unwrap().ttl.is_zero());
+    }
+
+    #[test]
+    fn shared_max_age_wins() {
+        for cache_control in ["s-maxage=100, max-age=10", "max-age=10, s-maxage=100"] {
+            assert_eq!(
+                lifetimes(cache_control).unwrap().ttl,
+                Duration::from_secs(100)
+            );
+        }
+    }
+
+    #[test]
+    fn not_stored() {
+        assert!(lifetimes("no-store").is_none());
+        assert!(lifetimes("max-age=60, Private").is_none());
+    }
+
+    #[test]
+    fn revalidated() {
+        let no_cache = lifetimes("no-cache, stale-if-error=20").unwrap();
+        assert!(no_cache.ttl.is_zero());
+        assert_eq!(no_cache.stale_if_error, Duration::from_secs(20));
+        let must_revalidate = lifetimes("max-age=10, must-revalidate").unwrap();
+        assert_eq!(must_revalidate.ttl, Duration::from_secs(10));
+        assert!(must_revalidate.stale_while_revalidate.is_zero());
+        assert!(must_revalidate.stale_if_error.is_zero());
+        assert!(lifetimes("proxy-revalidate")
+            .unwrap()
+            .stale_if_error
+            .is_zero());
+    }
+
+    #[test]
+    fn stale_windows() {
+        let cache = Cache::new(DEFAULTS, 10, Vec::new());
+        let response = response("max-age=10, stale-while-revalidate=5, stale-if-error=20");
+        cache.store("key".to_string(), "http://example.com/", response);
+        let expires_at = cache.entries.lock().unwrap()["key"].expires_at;
+        let at = |offset: Duration, before: bool| {
+            let now = expires_at + offset;
+            cache.lookup_at(
+                "key",
+                if before {
+                    now - Duration::from_nanos(1)
+                } else {
+                    now
+                },
+            )
+        };
+
+        assert!(matches!(at(Duration::ZERO, true), Lookup::Fresh(_)));
+        // the first stale request refreshes the entry, the others are served while it does
+        assert!(matches!(
+            at(Duration::ZERO, false),
+            Lookup::Revalidating { refresh: true, .. }
+        ));
+        assert!(matches!(
+            at(Duration::from_secs(5), true),
+            Lookup::Revalidating { refresh: false, .. }
+        ));
+        cache.abort_refresh("key");
+        assert!(matches!(
+            at(Duration::from_secs(1), false),
+            Lookup::Revalidating { refresh: true, .. }
+        ));
+        assert!(matches!(
+            at(Duration::from_secs(5), false),
+            Lookup::Stale {
+                usable_on_error: true,
+                ..
+            }
+        ));
+        assert!(matches!(
+            at(Duration::from_secs(20), true),
+            Lookup::Stale {
+                usable_on_error: true,
+                ..
+            }
+        ));
+        assert!(matches!(
+            at(Duration::from_secs(20), false),
+            Lookup::Stale {
+                usable_on_error: false,
+                ..
+            }
+        ));
+        assert!(matches!(cache.lookup("other"), Lookup::Miss));
+    }
+
+    #[test]
+    fn no_store_removes_entry() {
+        let cache = Cache::new(DEFAULTS, 10, Vec::new());
+        cache.store(
+            "key".to_string(),
+            "http://example.com/",
+            response("max-age=10"),
+        );
+        assert_eq!(cache.stored_bytes(), 4);
+        cache.store(
+            "key".to_string(),
+            "http://example.com/",
+            response("no-store"),
+        );
+        assert!(matches!(cache.lookup("key"), Lookup::Miss));
+        assert_eq!(cache.stored_bytes(), 0);
+    }
+}
@@ -0,0 +1,74 @@
+//! Opt-in logging of request and response bodies, for debugging specific hosts or requests.
+use axum::http::HeaderMap;
+use serde::Deserialize;
+
+use crate::{cache::CachedResponse, config::HostPattern, redact};
+
+/// Request header asking for the bodies of the request to be logged, when trusted.
+pub const DEBUG_HEADER: &str = "x-proxy-debug";
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CaptureConfig {
+    /// Hosts for which the bodies of all requests are logged.
+    pub hosts: Vec<HostPattern>,
+}
+
+pub struct Capture {
+    hosts: Vec<HostPattern>,
+    /// Whether clients can enable the capture with the [`DEBUG_HEADER`].
+    trust_header: bool,
+    /// Bodies are truncated to this many bytes.
+    limit: usize,
+}
+
+impl Capture {
+    /// Returns `None` if the capture can't be enabled for any request.
+    pub fn new(config: CaptureConfig, trust_header: bool, limit: usize) -> Option<Self> {
+        (trust_header || !config.hosts.is_empty()).then_some(Self {
+            hosts: config.hosts,
+            trust_header,
+            limit,
+        })
+    }
+
+    pub fn enabled(&self, host: &str, headers: &HeaderMap) -> bool {
+        (self.trust_header && headers.contains_key(DEBUG_HEADER))
+            || self.hosts.iter().any(|pattern| pattern.matches(host))
+    }
+
+    /// Log the bodies of a proxied request and of its response.
+    pub fn log(&self, url: &str, request_body: &[u8], response: &CachedResponse) {
+        tracing::info!(
+            url,
+            status_code = response.status.as_u16(),
+            headers = ?redact::headers(&response.headers),
+            request_body = self.describe(request_body),
+            response_body = self.describe(&response.body),
+            "Captured bodies"
+        );
+    }
+
+    /// Text bodies are logged up to the limit, binary bodies are only described.
+    fn describe(&self, body: &[u8]) -> String {
+        let prefix = &body[..body.len().min(self.limit)];
+        // a multi-byte character may have been cut at the end of the prefix
+        let text = match std::str::from_utf8(prefix) {
+            Ok(text) => Some(text),
+            Err(err) if err.error_len().is_none() => {
+                std::str::from_utf8(&prefix[..err.valid_up_to()]).ok()
+            }
+            Err(_) => None,
+        };
+        match text {
+            Some(text) if !text.contains('\0') => {
+                if text.len() < body.len() {
+                    format!("{text}... ({} bytes in total)", body.len())
+                } else {
+                    text.to_string()
+                }
+            }
+            _ => format!("<{} bytes of binary data>", body.len()),
+        }
+    }
+}
@@ -0,0 +1,176 @@
+//! Detection of the block pages and challenges of anti-bot services in upstream responses.
+//!
+//! The responses with a status used by the signatures have the start of their body inspected. The
+//! requests answered with a challenge can be sent again with other personas, and the response of
+//! the last attempt is passed to the client with the [`CHALLENGE_HEADER`]. Origins are only reached
+//! directly or through the configured connector, so retrying through another exit is left to a
+//! [`Connector`](crate::Connector).
+use anyhow::{ensure, Result};
+use axum::{
+    body::Bytes,
+    http::{HeaderName, HeaderValue, StatusCode},
+};
+use futures_util::StreamExt;
+use serde::Deserialize;
+
+use crate::{metrics::METRICS, persona::Personas};
+
+/// Response header naming the challenge detected in the upstream response.
+pub const CHALLENGE_HEADER: &str = "x-proxy-challenge";
+
+/// Bytes of the body inspected, block pages being small.
+const MAX_INSPECTED: usize = 64 * 1024;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChallengeConfig {
+    /// Signatures detected in addition to the built-in ones.
+    pub signatures: Vec<Signature>,
+    /// Personas the request is sent with again when it is answered with a challenge, in order.
+    pub retry_personas: Vec<String>,
+    /// Only detect the signatures configured here.
+    pub no_builtin: bool,
+}
+
+/// A kind of block page, recognized by its status and either a header or some text of its body.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Signature {
+    pub name: String,
+    #[serde(default = "Signature::default_statuses")]
+    pub statuses: Vec<u16>,
+    /// Response headers, any of which marks the challenge.
+    #[serde(default)]
+    pub headers: Vec<String>,
+    /// Text of the body, any of which marks the challenge.
+    #[serde(default)]
+    pub body: Vec<String>,
+}
+
+impl Signature {
+    fn default_statuses() -> Vec<u16> {
+        vec![403, 429, 503]
+    }
+
+    fn builtin() -> Vec<Self> {
+        let signature = |name: &str, headers: &[&str], body: &[&str]| Self {
+            name: name.to_string(),
+            statuses: Self::default_statuses(),
+            headers: headers.iter().map(ToString::to_string).collect(),
+            body: body.iter().map(ToString::to_string).collect(),
+        };
+        vec![
+            signature(
+                "cloudflare",
+                &["cf-mitigated"],
+                &[
+                    "Just a moment...",
+                    "cf-chl-",
+                    "Attention Required! | Cloudflare",
+                ],
+            ),
+            signature("datadome", &["x-datadome-cid"], &["captcha-delivery.com"]),
+            signature("perimeterx", &[], &["px-captcha", "_pxAppId"]),
+            signature("akamai", &[], &["errors.edgesuite.net"]),
+            signature("captcha", &[], &["g-recaptcha", "h-captcha"]),
+        ]
+    }
+
+    fn matches(&self, headers: &axum::http::HeaderMap, body: &[u8]) -> bool {
+        self.headers.iter().any(|name| headers.contains_key(name))
+            || self.body.iter().any(|text| {
+                body.windows(text.len())
+                    .any(|window| window == text.as_bytes())
+            })
+    }
+}
+
+pub struct Challenges {
+    signatures: Vec<Signature>,
+    retry_personas: Vec<String>,
+}
+
+impl Challenges {
+    pub fn new(config: ChallengeConfig, personas: &Personas) -> Result<Self> {
+        for signature in &config.signatures {
+            ensure!(
+                !signature.headers.is_empty() || !signature.body.is_empty(),
+                "The challenge signature {} needs `headers` or a `body`",
+                signature.name
+            );
+            for status in &signature.statuses {
+                StatusCode::from_u16(*status)?;
+            }
+        }
+        for name in &config.retry_personas {
+            ensure!(
+                personas.get(name).is_some(),
+                "unknown challenge retry persona {name}"
+            );
+        }
+        let mut signatures = config.signatures;
+        if !config.no_builtin {
+            signatures.extend(Signature::builtin());
+        }
+        Ok(Self {
+            signatures,
+            retry_personas: config.retry_personas,
+        })
+    }
+
+    /// Personas to try again with after a challenge, in order.
+    pub fn retry_personas(&self) -> &[String] {
+        &self.retry_personas
+    }
+
+    /// The response, with the [`CHALLENGE_HEADER`] if it is a challenge, and the name of the
+    /// challenge.
+    pub async fn inspect(
+        &self,
+        response: reqwest::Response,
+    ) -> reqwest::Result<(reqwest::Response, Option<String>)> {
+        let status = response.status();
+        if !self
+            .signatures
+            .iter()
+            .any(|signature| signature.statuses.contains(&status.as_u16()))
+        {
+            return Ok((response, None));
+        }
+        let (version, mut headers) = (response.version(), response.headers().clone());
+        let mut stream = response.bytes_stream();
+        let mut chunks: Vec<Bytes> = Vec::new();
+        let mut inspected = 0;
+        while inspected < MAX_INSPECTED {
+            match stream.next().await.transpose()? {
+                Some(chunk) => {
+                    inspected += chunk.len();
+                    chunks.push(chunk);
+                }
+                None => break,
+            }
+        }
+        let body = chunks.concat();
+        let challenge = self
+            .signatures
+            .iter()
+            .find(|signature| {
+                signature.statuses.contains(&status.as_u16()) && signature.matches(&headers, &body)
+            })
+            .map(|signature| signature.name.clone());
+        if let Some(name) = &challenge {
+            METRICS.record_challenge(name);
+            if let Ok(value) = HeaderValue::try_from(name) {
+                headers.insert(HeaderName::from_static(CHALLENGE_HEADER), value);
+            }
+        }
+        // the consumed start of the body is put back in front of the rest
+        let prefix = futures_util::stream::iter(chunks.into_iter().map(Ok::<_, reqwest::Error>));
+        let mut rebuilt =
+            axum::http::Response::new(reqwest::Body::wrap_stream(prefix.chain(stream)));
+        *rebuilt.status_mut() = status;
+        *rebuilt.version_mut() = version;
+        *rebuilt.headers_mut() = headers;
+        Ok((reqwest::Response::from(rebuilt), challenge))
+    }
+}
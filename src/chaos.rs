@@ -0,0 +1,184 @@
+//! Fault injection, making the proxy behave like a flaky network so that the apps behind it can be
+//! tested against one.
+//!
+//! Each request matching a rule gets one of its faults at the rule's probability, drawn at random:
+//! added latency, an error response instead of reaching the origin, a connection reset before the
+//! response, or a body cut short.
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use anyhow::{ensure, Result};
+use axum::{
+    body::{Body, Bytes},
+    http::{HeaderMap, StatusCode},
+};
+use http_body::{Frame, SizeHint};
+use rand::{seq::SliceRandom, Rng};
+use serde::Deserialize;
+
+use crate::{config::HostPattern, metrics::METRICS};
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Fault {
+    /// The request is delayed by the `latency` of the rule.
+    Latency,
+    /// The request is answered with the `status` of the rule.
+    Error,
+    /// The connection is closed without a response.
+    Reset,
+    /// The connection is closed after `truncate_after` bytes of the body.
+    Truncate,
+}
+
+impl Fault {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Latency => "latency",
+            Self::Error => "error",
+            Self::Reset => "reset",
+            Self::Truncate => "truncate",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChaosRule {
+    /// Target hosts, all of them if not set.
+    pub host: Option<HostPattern>,
+    /// Request header the requests must have, e.g. to only inject faults when the client opts in.
+    pub header: Option<String>,
+    /// Probability of a fault for each matching request.
+    pub probability: f64,
+    /// Faults one of which is drawn at random.
+    pub faults: Vec<Fault>,
+    #[serde(default = "ChaosRule::default_latency", with = "humantime_serde")]
+    pub latency: Duration,
+    #[serde(default = "ChaosRule::default_status")]
+    pub status: u16,
+    #[serde(default)]
+    pub truncate_after: usize,
+}
+
+impl ChaosRule {
+    fn default_latency() -> Duration {
+        Duration::from_secs(2)
+    }
+
+    fn default_status() -> u16 {
+        503
+    }
+}
+
+pub struct Chaos {
+    rules: Vec<ChaosRule>,
+}
+
+impl Chaos {
+    pub fn new(rules: Vec<ChaosRule>) -> Result<Self> {
+        for rule in &rules {
+            ensure!(
+                (0.0..=1.0).contains(&rule.probability),
+                "the chaos probability must be between 0 and 1"
+            );
+            ensure!(!rule.faults.is_empty(), "chaos rules need `faults`");
+            StatusCode::from_u16(rule.status)?;
+        }
+        Ok(Self { rules })
+    }
+
+    /// The fault injected in a request to `host`, if any.
+    pub fn draw(&self, host: &str, headers: &HeaderMap) -> Option<Injected> {
+        let rule = self.rules.iter().find(|rule| {
+            rule.host
+                .as_ref()
+                .is_none_or(|pattern| pattern.matches(host))
+                && rule
+                    .header
+                    .as_ref()
+                    .is_none_or(|name| headers.contains_key(name))
+        })?;
+        let mut rng = rand::thread_rng();
+        if !rng.gen_bool(rule.probability) {
+            return None;
+        }
+        let fault = *rule.faults.choose(&mut rng)?;
+        tracing::info!(host, fault = fault.name(), "Injecting fault");
+        METRICS.record_fault(fault.name());
+        Some(match fault {
+            Fault::Latency => Injected::Latency(rule.latency),
+            Fault::Error => Injected::Error(StatusCode::from_u16(rule.status).unwrap_or_default()),
+            Fault::Reset => Injected::Reset,
+            Fault::Truncate => Injected::Truncate(rule.truncate_after),
+        })
+    }
+}
+
+/// A fault drawn for a request.
+pub enum Injected {
+    Latency(Duration),
+    Error(StatusCode),
+    Reset,
+    /// Number of bytes of the body sent.
+    Truncate(usize),
+}
+
+/// Body failing after `limit` bytes of `inner`, which closes the connection to the client.
+pub struct Truncated {
+    inner: Body,
+    remaining: usize,
+    /// Whether the sent bytes were given a chance to be flushed before the failure.
+    flushed: bool,
+}
+
+impl Truncated {
+    pub fn wrap(inner: Body, limit: usize) -> Body {
+        Body::new(Self {
+            inner,
+            remaining: limit,
+            flushed: limit == 0,
+        })
+    }
+}
+
+impl http_body::Body for Truncated {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        if self.remaining == 0 {
+            if !self.flushed {
+                // the connection writes its buffer while the body is pending
+                self.flushed = true;
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            return Poll::Ready(Some(Err(axum::Error::new("injected truncation"))));
+        }
+        let frame = match Pin::new(&mut self.inner).poll_frame(cx) {
+            Poll::Ready(Some(Ok(frame))) => frame,
+            other => return other,
+        };
+        let Ok(mut data) = frame.into_data() else {
+            return Poll::Ready(Some(Err(axum::Error::new("injected truncation"))));
+        };
+        let len = data.len().min(self.remaining);
+        self.remaining -= len;
+        Poll::Ready(Some(Ok(Frame::data(data.split_to(len)))))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        false
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        SizeHint::default()
+    }
+}
@@ -0,0 +1,132 @@
+//! Per-host circuit breaker, failing fast for origins that are down.
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+
+use crate::{errors, metrics::METRICS};
+
+enum Circuit {
+    /// Requests go through, counting the consecutive failures.
+    Closed { failures: u32 },
+    /// Requests fail fast until the deadline, after which a single request probes the host.
+    Open { until: Instant },
+    /// A probe is in flight, the other requests still fail fast until the deadline, in case the
+    /// probe never completes.
+    HalfOpen { until: Instant },
+}
+
+/// Error of the requests to a host whose circuit is open.
+#[derive(Clone, Debug)]
+pub struct CircuitOpen {
+    host: String,
+    retry_after: Duration,
+}
+
+impl fmt::Display for CircuitOpen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "circuit open for {}", self.host)
+    }
+}
+
+impl std::error::Error for CircuitOpen {}
+
+impl IntoResponse for CircuitOpen {
+    fn into_response(self) -> Response {
+        METRICS.record_rate_limited("circuit_breaker");
+        let retry_after =
+            self.retry_after.as_secs() + u64::from(self.retry_after.subsec_nanos() > 0);
+        (
+            [(header::RETRY_AFTER, retry_after.max(1).to_string())],
+            errors::response(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "circuit_open",
+                format!("{} is unavailable", self.host),
+            ),
+        )
+            .into_response()
+    }
+}
+
+pub struct CircuitBreaker {
+    hosts: Mutex<HashMap<String, Circuit>>,
+    /// Consecutive failures opening the circuit of a host.
+    threshold: u32,
+    /// How long a circuit stays open before the host is probed.
+    open_for: Duration,
+}
+
+impl CircuitBreaker {
+    /// Past this number of hosts, the ones with a closed circuit are forgotten.
+    const PRUNE_THRESHOLD: usize = 10_000;
+
+    pub fn new(threshold: u32, open_for: Duration) -> Self {
+        Self {
+            hosts: Mutex::new(HashMap::new()),
+            threshold: threshold.max(1),
+            open_for,
+        }
+    }
+
+    /// Whether a request to `host` may be sent.
+    pub fn check(&self, host: &str) -> Result<(), CircuitOpen> {
+        let mut hosts = self.hosts.lock().unwrap();
+        let retry_after = match hosts.get(host) {
+            None | Some(Circuit::Closed { .. }) => return Ok(()),
+            Some(Circuit::Open { until } | Circuit::HalfOpen { until }) => {
+                let now = Instant::now();
+                if *until <= now {
+                    tracing::info!(host, "Probing host with an open circuit");
+                    let until = now + self.open_for;
+                    hosts.insert(host.to_string(), Circuit::HalfOpen { until });
+                    return Ok(());
+                }
+                *until - now
+            }
+        };
+        Err(CircuitOpen {
+            host: host.to_string(),
+            retry_after,
+        })
+    }
+
+    /// Record the outcome of a request to `host`.
+    pub fn record(&self, host: &str, success: bool) {
+        let mut hosts = self.hosts.lock().unwrap();
+        if success {
+            // closed circuits without failures aren't kept around
+            if let Some(Circuit::HalfOpen { .. } | Circuit::Open { .. }) = hosts.remove(host) {
+                tracing::info!(host, "Closed circuit");
+            }
+            return;
+        }
+        let failures = match hosts.get(host) {
+            Some(Circuit::Closed { failures }) => failures + 1,
+            Some(Circuit::HalfOpen { .. }) => self.threshold,
+            // a request sent before the circuit opened
+            Some(Circuit::Open { .. }) => return,
+            None => {
+                if hosts.len() >= Self::PRUNE_THRESHOLD {
+                    hosts.retain(|_, circuit| !matches!(circuit, Circuit::Closed { .. }));
+                }
+                1
+            }
+        };
+        let circuit = if failures >= self.threshold {
+            tracing::warn!(host, open_for = ?self.open_for, "Opened circuit");
+            Circuit::Open {
+                until: Instant::now() + self.open_for,
+            }
+        } else {
+            Circuit::Closed { failures }
+        };
+        hosts.insert(host.to_string(), circuit);
+    }
+}
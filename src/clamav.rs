@@ -0,0 +1,141 @@
+//! Scanning of the response bodies by ClamAV before they are delivered, with the `INSTREAM`
+//! command of `clamd`.
+//!
+//! Only the bodies buffered by the proxy and up to `max_size` are scanned, larger ones being
+//! streamed to the client unscanned. Cached bodies are scanned again on each delivery, so that
+//! updated signatures apply to them.
+use std::{path::PathBuf, sync::atomic::Ordering, time::Duration};
+
+use anyhow::{anyhow, bail, Result};
+use axum::{http::StatusCode, response::Response};
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpStream, UnixStream},
+};
+
+use crate::{config::ByteSize, errors, metrics::METRICS};
+
+/// Size of the chunks the bodies are sent to `clamd` in.
+const CHUNK: usize = 64 * 1024;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClamAvConfig {
+    /// Address of `clamd`, `unix:/run/clamav/clamd.ctl` or `host:port`.
+    pub address: String,
+    /// Largest body scanned, which must not exceed the `StreamMaxLength` of `clamd`.
+    #[serde(default = "ClamAvConfig::default_max_size")]
+    pub max_size: ByteSize,
+    #[serde(default = "ClamAvConfig::default_timeout", with = "humantime_serde")]
+    pub timeout: Duration,
+    /// Deliver the responses that couldn't be scanned, instead of failing them with `503`.
+    #[serde(default)]
+    pub fail_open: bool,
+}
+
+impl ClamAvConfig {
+    fn default_max_size() -> ByteSize {
+        ByteSize(10_000_000)
+    }
+
+    fn default_timeout() -> Duration {
+        Duration::from_secs(10)
+    }
+}
+
+enum Address {
+    Unix(PathBuf),
+    Tcp(String),
+}
+
+pub struct ClamAv {
+    address: Address,
+    max_size: u64,
+    timeout: Duration,
+    fail_open: bool,
+}
+
+impl ClamAv {
+    pub fn new(config: ClamAvConfig) -> Self {
+        let address = match config.address.strip_prefix("unix:") {
+            Some(path) => Address::Unix(path.into()),
+            None => Address::Tcp(config.address),
+        };
+        Self {
+            address,
+            max_size: config.max_size.0,
+            timeout: config.timeout,
+            fail_open: config.fail_open,
+        }
+    }
+
+    /// Whether a body of `len` bytes is scanned.
+    pub fn scans(&self, len: usize) -> bool {
+        len as u64 <= self.max_size
+    }
+
+    /// The response replacing the one of `url` with `body`, if it isn't delivered.
+    pub async fn check(&self, url: &str, body: &[u8]) -> Option<Response> {
+        let result = tokio::time::timeout(self.timeout, self.scan(body))
+            .await
+            .unwrap_or_else(|_| Err(anyhow!("clamd timed out")));
+        match result {
+            Ok(None) => None,
+            Ok(Some(signature)) => {
+                METRICS.infected_responses.fetch_add(1, Ordering::Relaxed);
+                tracing::warn!(url, signature, "Blocked infected response");
+                Some(errors::response(
+                    StatusCode::FORBIDDEN,
+                    "infected",
+                    "Blocked by the antivirus",
+                ))
+            }
+            Err(err) if self.fail_open => {
+                tracing::error!(url, error = %err, "Could not scan response, delivering it");
+                None
+            }
+            Err(err) => {
+                tracing::error!(url, error = %err, "Could not scan response");
+                Some(errors::response(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "antivirus_unavailable",
+                    "Could not scan the response",
+                ))
+            }
+        }
+    }
+
+    /// The signature `body` matches, if any.
+    async fn scan(&self, body: &[u8]) -> Result<Option<String>> {
+        match &self.address {
+            Address::Unix(path) => instream(UnixStream::connect(path).await?, body).await,
+            Address::Tcp(addr) => instream(TcpStream::connect(addr).await?, body).await,
+        }
+    }
+}
+
+async fn instream(
+    mut stream: impl AsyncRead + AsyncWrite + Unpin,
+    body: &[u8],
+) -> Result<Option<String>> {
+    stream.write_all(b"zINSTREAM\0").await?;
+    for chunk in body.chunks(CHUNK) {
+        stream
+            .write_all(&(chunk.len() as u32).to_be_bytes())
+            .await?;
+        stream.write_all(chunk).await?;
+    }
+    stream.write_all(&0_u32.to_be_bytes()).await?;
+    let mut reply = Vec::new();
+    stream.read_to_end(&mut reply).await?;
+    let reply = String::from_utf8_lossy(&reply);
+    // e.g. `stream: OK` or `stream: Eicar-Test-Signature FOUND`
+    let reply = reply.trim_end_matches(['\0', '\n']);
+    let verdict = reply.strip_prefix("stream: ").unwrap_or(reply);
+    match verdict.strip_suffix(" FOUND") {
+        Some(signature) => Ok(Some(signature.to_string())),
+        None if verdict == "OK" => Ok(None),
+        None => bail!("clamd failed: {reply}"),
+    }
+}
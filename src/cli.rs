@@ -0,0 +1,401 @@
+//! Options of the command line, and the proxy run with them by the binary.
+use std::{env, net::SocketAddr, path::PathBuf, time::Duration};
+
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand};
+use reqwest::Url;
+
+use crate::{
+    access_log, anonymize, bench, config, errors,
+    log_file::{self, RotatingFile, Rotation},
+    persona, presign,
+    redact::Secret,
+    syslog::{self, Syslog},
+    telemetry, usage, ProxyBuilder,
+};
+
+/// Options of the proxy, from the command line.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// `User-Agent` of the default persona
+    #[arg(short, long)]
+    pub user_agent: Option<String>,
+    /// Persona of the requests that don't ask for one, and whose host has none
+    #[arg(long, default_value = persona::BUILT_IN)]
+    pub persona: String,
+    /// Number of threads handling requests, everything runs on the main thread if not set
+    #[arg(long)]
+    pub worker_threads: Option<usize>,
+    /// Number of listener sockets accepting connections, sharing the port with `SO_REUSEPORT`
+    #[arg(long, default_value_t = 1)]
+    pub acceptors: usize,
+    /// How long connections may take to complete after `SIGTERM` or `SIGINT`, before they are
+    /// closed
+    #[arg(long, default_value = "30s", value_parser = humantime::parse_duration)]
+    pub drain_timeout: Duration,
+    /// How long connections are still accepted after `SIGTERM`, while the readiness probe fails,
+    /// so that the load balancers stop sending new ones first (e.g. instead of a `preStop` sleep
+    /// in Kubernetes)
+    #[arg(long, default_value = "0s", value_parser = humantime::parse_duration)]
+    pub shutdown_delay: Duration,
+    /// Close the client connections without a request in flight for this long
+    #[arg(long, default_value = "60s", value_parser = humantime::parse_duration)]
+    pub client_idle_timeout: Duration,
+    /// Path to a TOML configuration file
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+    /// Cache successful upstream responses in memory
+    #[arg(long)]
+    pub cache: bool,
+    /// Freshness lifetime of cached responses that don't specify a `max-age`
+    #[arg(long, default_value = "60s", value_parser = humantime::parse_duration)]
+    pub cache_ttl: Duration,
+    /// How long an expired response may be served while it is revalidated in the background
+    #[arg(long, default_value = "0s", value_parser = humantime::parse_duration)]
+    pub cache_stale_while_revalidate: Duration,
+    /// How long an expired response may be served when the origin fails
+    #[arg(long, default_value = "0s", value_parser = humantime::parse_duration)]
+    pub cache_stale_if_error: Duration,
+    /// Maximum number of responses kept in the cache
+    #[arg(long, default_value_t = 1024)]
+    pub cache_max_entries: usize,
+    /// Experimental: warm the cache with up to this many same-origin scripts, stylesheets and
+    /// images of each HTML page fetched, for browsing through the proxy
+    #[arg(long, requires = "cache")]
+    pub prefetch_subresources: Option<usize>,
+    /// Remember upstream connection failures and server errors for this long, disabled if not set
+    #[arg(long, value_parser = humantime::parse_duration)]
+    pub negative_cache_ttl: Option<Duration>,
+    /// Maximum sustained number of requests per second from a single client IP
+    #[arg(long)]
+    pub rate_limit: Option<f64>,
+    /// Number of requests a client IP can make in a burst above the rate limit
+    #[arg(long, default_value_t = 10)]
+    pub rate_limit_burst: u32,
+    /// Maximum number of proxied requests in flight, unlimited if not set
+    #[arg(long)]
+    pub max_concurrent_requests: Option<usize>,
+    /// How long a request may wait for a slot when the concurrency limit is reached
+    #[arg(long, default_value = "0s", value_parser = humantime::parse_duration)]
+    pub concurrency_wait: Duration,
+    /// Maximum number of requests waiting for a slot, beyond which they are rejected right away
+    #[arg(long, default_value_t = 100)]
+    pub concurrency_queue_size: usize,
+    /// Slow down to this many requests per second to hosts answering 429 or 503, halving the rate
+    /// on each further such response and recovering gradually
+    #[arg(long)]
+    pub adaptive_backoff: Option<f64>,
+    /// Also pause requests to throttling hosts for the time specified by their `Retry-After`
+    #[arg(long)]
+    pub honor_retry_after: bool,
+    /// Maximum total bandwidth of proxied responses (e.g. `50Mbit` or `5MB`)
+    #[arg(long, value_parser = config::parse_bytes)]
+    pub bandwidth_limit: Option<u64>,
+    /// Maximum bandwidth of proxied responses per client connection
+    #[arg(long, value_parser = config::parse_bytes)]
+    pub connection_bandwidth_limit: Option<u64>,
+    /// Maximum bandwidth of proxied responses per credential
+    #[arg(long, value_parser = config::parse_bytes)]
+    pub credential_bandwidth_limit: Option<u64>,
+    /// File where per-credential usage is persisted, required to enforce quotas
+    #[arg(long)]
+    pub quota_file: Option<PathBuf>,
+    /// Maximum number of requests per credential and day (UTC)
+    #[arg(long)]
+    pub daily_request_quota: Option<u64>,
+    /// Maximum amount of data transferred per credential and day (e.g. `10GB`)
+    #[arg(long, value_parser = config::parse_bytes)]
+    pub daily_byte_quota: Option<u64>,
+    /// Maximum number of requests per credential and month (UTC)
+    #[arg(long)]
+    pub monthly_request_quota: Option<u64>,
+    /// Maximum amount of data transferred per credential and month
+    #[arg(long, value_parser = config::parse_bytes)]
+    pub monthly_byte_quota: Option<u64>,
+    /// Share the client rate limits and the quotas with the other instances through this Redis
+    /// server, 7.0 or later (e.g. `redis://:password@10.0.0.5:6379/0`)
+    #[arg(long)]
+    pub redis_url: Option<Secret<Url>>,
+    /// Prefix of the Redis keys, to use the same server for several fleets
+    #[arg(long, default_value = "simple-proxy:")]
+    pub redis_prefix: String,
+    /// Address of the admin API and of the gRPC control plane, disabled if not set (e.g.
+    /// `127.0.0.1:7789`). `ADMIN_TOKEN` is required unless it is a loopback address
+    #[arg(long)]
+    pub admin_addr: Option<SocketAddr>,
+    /// Record the upstream requests and responses to this HAR file
+    #[arg(long)]
+    pub har_file: Option<PathBuf>,
+    /// Number of most recent exchanges kept in the HAR file
+    #[arg(long, default_value_t = 1000)]
+    pub har_max_entries: usize,
+    /// Response bodies larger than this are left out of the HAR file
+    #[arg(long, default_value = "64KiB", value_parser = config::parse_bytes)]
+    pub har_body_limit: u64,
+    /// Save the upstream exchanges to this directory, to answer from them with `--replay`
+    #[arg(long, conflicts_with = "replay")]
+    pub record: Option<PathBuf>,
+    /// Answer the requests from the exchanges saved in this directory by `--record`, without
+    /// reaching the origins
+    #[arg(long)]
+    pub replay: Option<PathBuf>,
+    /// Log the bodies of the requests with an `x-proxy-debug` header, in addition to the ones to
+    /// the hosts listed in the config file
+    #[arg(long)]
+    pub trust_debug_header: bool,
+    /// Logged bodies are truncated to this size
+    #[arg(long, default_value = "4KiB", value_parser = config::parse_bytes)]
+    pub debug_body_limit: u64,
+    /// Append the TLS secrets of the upstream connections to this file in the `SSLKEYLOGFILE`
+    /// format, so that captures of their traffic can be decrypted. Only for debugging, as anyone
+    /// reading the file can decrypt the traffic
+    #[arg(long)]
+    pub tls_key_log: Option<PathBuf>,
+    /// File where the usage of each credential is periodically exported
+    #[arg(long)]
+    pub usage_export: Option<PathBuf>,
+    #[arg(long, value_enum, default_value = "csv")]
+    pub usage_export_format: usage::ExportFormat,
+    /// How often the usage is exported
+    #[arg(long, default_value = "1h", value_parser = humantime::parse_duration)]
+    pub usage_export_interval: Duration,
+    /// SQLite database where the usage per credential, destination and day is persisted, requires
+    /// the `sqlite` feature
+    #[arg(long)]
+    pub stats_db: Option<PathBuf>,
+    /// How long the usage is kept in the statistics database
+    #[arg(long, default_value = "90days", value_parser = humantime::parse_duration)]
+    pub stats_db_retention: Duration,
+    /// Push metrics to this StatsD agent (e.g. `127.0.0.1:8125`)
+    #[arg(long)]
+    pub statsd: Option<String>,
+    /// Prefix of the StatsD metric names
+    #[arg(long, default_value = "simple_proxy")]
+    pub statsd_prefix: String,
+    /// Send tags with the DogStatsD extension, instead of including them in the metric names
+    #[arg(long)]
+    pub statsd_tags: bool,
+    /// How often metrics are pushed to StatsD
+    #[arg(long, default_value = "10s", value_parser = humantime::parse_duration)]
+    pub statsd_interval: Duration,
+    /// URL requested by the readiness probe of the admin API, which fails if the request does
+    #[arg(long)]
+    pub readiness_canary: Option<Url>,
+    /// Timeout for upstream requests
+    #[arg(long, value_parser = humantime::parse_duration)]
+    pub upstream_timeout: Option<Duration>,
+    /// Abort upstream responses that stall for this long between two reads, waiting for the
+    /// headers included, so that long downloads keep flowing unlike with `--upstream-timeout`
+    #[arg(long, value_parser = humantime::parse_duration)]
+    pub upstream_read_timeout: Option<Duration>,
+    /// Let clients set the timeout of their upstream requests with an `x-proxy-timeout` header
+    /// (e.g. `30s`), up to this value
+    #[arg(long, value_parser = humantime::parse_duration)]
+    pub max_upstream_timeout: Option<Duration>,
+    /// Number of times upstream requests are retried on connection errors, timeouts and the
+    /// `--retry-on-status` statuses
+    #[arg(long, default_value_t = 0)]
+    pub retries: u32,
+    /// Delay before the first retry, doubled for each further one and randomized
+    #[arg(long, default_value = "100ms", value_parser = humantime::parse_duration)]
+    pub retry_backoff: Duration,
+    /// Upstream response statuses that are retried
+    #[arg(long, value_delimiter = ',', default_value = "502,503,504")]
+    pub retry_on_status: Vec<u16>,
+    /// Longest delay before a retry given by the `Retry-After` of a 429 or 503 response
+    #[arg(long, default_value = "30s", value_parser = humantime::parse_duration)]
+    pub max_retry_after: Duration,
+    /// Number of times an upstream download streamed to the client is resumed with a ranged
+    /// request when it is interrupted, so that the client receives the whole body
+    #[arg(long, default_value_t = 0)]
+    pub resume_downloads: u32,
+    /// Fail fast with `503 Service Unavailable` for hosts after this many consecutive connection
+    /// errors, timeouts or server errors, disabled if not set
+    #[arg(long)]
+    pub circuit_breaker_failures: Option<u32>,
+    /// How long requests to a failing host fail fast before it is probed again
+    #[arg(long, default_value = "30s", value_parser = humantime::parse_duration)]
+    pub circuit_breaker_open: Duration,
+    /// Cache the resolved addresses of upstream hosts, instead of resolving them for each new
+    /// connection
+    #[arg(long)]
+    pub dns_cache: bool,
+    /// Maximum number of host names in the DNS cache
+    #[arg(long, default_value_t = 1024)]
+    pub dns_cache_size: usize,
+    /// Minimum time addresses are cached, even if their TTL is lower
+    #[arg(long, default_value = "1s", value_parser = humantime::parse_duration)]
+    pub dns_min_ttl: Duration,
+    /// Maximum time addresses are cached, even if their TTL is higher
+    #[arg(long, default_value = "1h", value_parser = humantime::parse_duration)]
+    pub dns_max_ttl: Duration,
+    /// Number of the most requested host names resolved again shortly before they expire from the
+    /// DNS cache, so that their requests don't wait for the resolution
+    #[arg(long, default_value_t = 0, requires = "dns_cache")]
+    pub dns_prefetch: usize,
+    /// Largest response body that is buffered, larger ones are streamed to the client without
+    /// being cached, recorded or captured
+    #[arg(long, default_value = "10MiB", value_parser = config::parse_bytes)]
+    pub max_buffered_body: u64,
+    /// Shed new requests while the response bodies held in memory, cached or not, exceed this size
+    /// (e.g. `512MiB`)
+    #[arg(long, value_parser = config::parse_bytes)]
+    pub memory_budget: Option<u64>,
+    /// Maximum number of idle upstream connections kept open per host
+    #[arg(long, default_value_t = 32)]
+    pub pool_max_idle_per_host: usize,
+    /// Close idle upstream connections after this long
+    #[arg(long, default_value = "90s", value_parser = humantime::parse_duration)]
+    pub pool_idle_timeout: Duration,
+    /// Maximum time to connect to an origin, shared by its addresses, so that the next one is
+    /// tried when an address doesn't answer
+    #[arg(long, default_value = "10s", value_parser = humantime::parse_duration)]
+    pub connect_timeout: Duration,
+    /// Stop reusing upstream connections after about this long, so that they don't live forever
+    #[arg(long, value_parser = humantime::parse_duration)]
+    pub pool_max_lifetime: Option<Duration>,
+    /// Format of the log output
+    #[arg(long, value_enum, default_value = "text")]
+    pub log_format: telemetry::LogFormat,
+    /// File logs are written to instead of stdout
+    #[arg(long)]
+    pub log_file: Option<PathBuf>,
+    /// Start new log files periodically, this also applies to the access log
+    #[arg(long, value_enum, default_value = "never")]
+    pub log_rotation: log_file::Period,
+    /// Start a new log file once it would exceed this size (e.g. `100MB`)
+    #[arg(long, value_parser = config::parse_bytes)]
+    pub log_max_size: Option<u64>,
+    /// Number of rotated log files kept
+    #[arg(long, default_value_t = 5)]
+    pub log_max_files: usize,
+    /// Log filter switched to on `SIGUSR2`, the next signal restoring the startup filter
+    #[arg(long, default_value = "simple_proxy=trace,tower_http=trace")]
+    pub verbose_log_filter: String,
+    /// Also send logs to this syslog server (e.g. `udp://localhost`, `tls://logs.example.com` or
+    /// `unix:///dev/log`)
+    #[arg(long)]
+    pub syslog: Option<String>,
+    /// Facility of the messages sent to syslog
+    #[arg(long, value_enum, default_value = "daemon")]
+    pub syslog_facility: syslog::Facility,
+    /// OTLP/HTTP endpoint traces are exported to, disabled if not set
+    /// (e.g. `http://localhost:4318/v1/traces`)
+    #[arg(long)]
+    pub otlp_endpoint: Option<String>,
+    /// Fraction of the traces started by the proxy that are exported
+    #[arg(long, default_value_t = 1.0)]
+    pub otlp_sample_ratio: f64,
+    /// File where a line is appended for each request, disabled if not set
+    #[arg(long)]
+    pub access_log: Option<PathBuf>,
+    /// Format of the access log lines
+    #[arg(long, value_enum, default_value = "combined")]
+    pub access_log_format: access_log::Format,
+    /// Write the access log lines of 1 in N successful requests, the ones of the errors and denials
+    /// being all written
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    pub access_log_sample: u64,
+    /// Decompress the upstream responses whose encoding isn't accepted by the client
+    #[arg(long)]
+    pub decompress: bool,
+    /// Tell in the `x-proxy-bytes-received`, `x-proxy-bytes-sent` and `x-proxy-duration` (in
+    /// milliseconds) response headers what each request cost, when known before the body is sent
+    #[arg(long)]
+    pub accounting_headers: bool,
+    /// Verify the response bodies against the digests sent by the origins in `Content-Digest`,
+    /// `Digest` or `Content-MD5`, failing the responses that don't match
+    #[arg(long)]
+    pub verify_digests: bool,
+    /// Format of the error responses of the proxy
+    #[arg(long, value_enum, default_value = "json")]
+    pub error_format: errors::ErrorFormat,
+    /// HTML template of the error responses with the `html` format
+    #[arg(long)]
+    pub error_template: Option<PathBuf>,
+    /// Add a `Server-Timing` header with the phases of the upstream request to the responses, without
+    /// the transfer of the streamed bodies, which isn't over when the header is sent
+    #[arg(long)]
+    pub server_timing: bool,
+    /// Anonymize the client IPs in the logs, access log and live tail
+    #[arg(long, value_enum, default_value = "off")]
+    pub anonymize_ips: anonymize::Mode,
+    /// Reject the malformed requests: invalid header characters, absolute-form targets not
+    /// matching the `Host`, oversized headers and unsupported transfer codings
+    #[arg(long)]
+    pub strict: bool,
+    /// Maximum size of the request headers in strict mode
+    #[arg(long, default_value = "16KiB", value_parser = config::parse_bytes)]
+    pub max_header_size: u64,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Send concurrent requests through the proxy and report the throughput and latencies
+    Bench(bench::BenchArgs),
+    /// Print a pre-signed URL of the proxy, signed with the `URL_SIGNING_KEY`
+    Sign(presign::SignArgs),
+}
+
+/// Run the proxy with the options of the command line, as the binary does.
+///
+/// The proxy listens on the `PORT` environment variable and the clients authenticate with the
+/// `AUTH_TOKEN` one. It logs as configured, drains its connections on `SIGTERM` and hands its
+/// listeners over to a new process on `SIGHUP`.
+pub async fn run(mut cli: Cli) -> Result<()> {
+    match cli.command.take() {
+        Some(Command::Bench(args)) => bench::run(args, serve(cli)).await,
+        Some(Command::Sign(args)) => presign::run(args),
+        None => serve(cli).await,
+    }
+}
+
+async fn serve(cli: Cli) -> Result<()> {
+    let log_file = cli
+        .log_file
+        .as_deref()
+        .map(|path| RotatingFile::open(path, cli.rotation()))
+        .transpose()?;
+    let tracer_provider = telemetry::init(
+        cli.log_format,
+        log_file,
+        cli.syslog
+            .as_deref()
+            .map(|url| Syslog::connect(url, cli.syslog_facility))
+            .transpose()?,
+        cli.otlp_endpoint.as_deref(),
+        cli.otlp_sample_ratio,
+    )?;
+    let verbose_log_filter = cli.verbose_log_filter.clone();
+    tokio::spawn(async move {
+        if let Err(err) = telemetry::toggle_on_sigusr2(verbose_log_filter).await {
+            tracing::error!(error = %err, "Could not listen for SIGUSR2");
+        }
+    });
+
+    let port = env::var("PORT").unwrap_or("7788".to_string());
+    let addr = SocketAddr::from(([0, 0, 0, 0], port.parse().context("invalid PORT")?));
+    let proxy = ProxyBuilder::from_cli(cli, env::var("AUTH_TOKEN")?)
+        .addr(addr)
+        .handle_signals()
+        .build()
+        .await?;
+    proxy.serve().await?;
+    if let Some(provider) = tracer_provider {
+        provider.shutdown()?;
+    }
+    Ok(())
+}
+
+impl Cli {
+    pub fn rotation(&self) -> Rotation {
+        Rotation {
+            period: self.log_rotation,
+            max_size: self.log_max_size,
+            max_files: self.log_max_files,
+        }
+    }
+}
@@ -0,0 +1,89 @@
+//! Collapsing of concurrent identical requests into a single upstream fetch.
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use futures_util::{
+    future::{BoxFuture, Shared},
+    FutureExt,
+};
+
+type Fetch<T> = Shared<BoxFuture<'static, T>>;
+
+pub struct Coalescer<T> {
+    /// The in-flight fetches by key, with the id telling them apart from the later ones.
+    inflight: Mutex<HashMap<String, (u64, Fetch<T>)>>,
+    next_id: AtomicU64,
+}
+
+impl<T: Clone + Send + Sync + 'static> Coalescer<T> {
+    pub fn new() -> Self {
+        Self {
+            inflight: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    /// Run the future built by `make` unless one is already running for `key`, in which case its
+    /// output is awaited instead.
+    ///
+    /// The shared future keeps running as long as one waiter is interested in it, so a disconnecting
+    /// client doesn't cancel the fetch for the others. It is forgotten once done or once all of its
+    /// waiters are gone, so that the later requests don't wait for a fetch no one polls anymore.
+    pub async fn run<F>(&self, key: &str, make: impl FnOnce() -> F) -> T
+    where
+        F: Future<Output = T> + Send + 'static,
+    {
+        let (id, shared) = {
+            let mut inflight = self.inflight.lock().unwrap();
+            match inflight.get(key) {
+                Some((id, shared)) => {
+                    tracing::debug!(key, "Coalesced with in-flight request");
+                    (*id, shared.clone())
+                }
+                None => {
+                    let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+                    let shared = make().boxed().shared();
+                    inflight.insert(key.to_string(), (id, shared.clone()));
+                    (id, shared)
+                }
+            }
+        };
+        let mut waiter = Waiter {
+            coalescer: self,
+            key,
+            id,
+            shared: Some(shared),
+        };
+        waiter.shared.as_mut().expect("the waiter is polled").await
+    }
+}
+
+/// A waiter of an in-flight fetch, removing it when dropped if it was the last one or if the fetch
+/// is done, whether the waiter completed or was cancelled.
+struct Waiter<'a, T: Clone> {
+    coalescer: &'a Coalescer<T>,
+    key: &'a str,
+    id: u64,
+    shared: Option<Fetch<T>>,
+}
+
+impl<T: Clone> Drop for Waiter<'_, T> {
+    fn drop(&mut self) {
+        // the handle of the waiter doesn't count anymore
+        self.shared = None;
+        let mut inflight = self.coalescer.inflight.lock().unwrap();
+        let Some((id, shared)) = inflight.get(self.key) else {
+            return;
+        };
+        // the one left is the handle of the map
+        if *id == self.id && (shared.peek().is_some() || shared.strong_count() == Some(1)) {
+            inflight.remove(self.key);
+        }
+    }
+}
@@ -0,0 +1,88 @@
+//! Compression of the responses to the clients accepting it, when the origins didn't compress
+//! them, to save the bandwidth of the last mile.
+//!
+//! The bodies already compressed by the origins are passed through instead, and the partial ones
+//! are left alone, their ranges being of the sent bytes.
+use std::sync::Arc;
+
+use axum::http::{header, Response, StatusCode};
+use serde::Deserialize;
+use tower_http::compression::{
+    predicate::{NotForContentType, Predicate},
+    CompressionLayer,
+};
+
+use crate::config::ByteSize;
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CompressionConfig {
+    /// Compress the responses for the clients accepting it.
+    pub enabled: bool,
+    /// Smallest body compressed, the streamed ones whose size isn't known being compressed.
+    pub min_size: ByteSize,
+    /// Types of the compressed bodies, e.g. `text/*` or `application/json`, all of them but the
+    /// images and the event streams if empty.
+    pub content_types: Vec<String>,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            min_size: ByteSize(32),
+            content_types: Vec::new(),
+        }
+    }
+}
+
+impl CompressionConfig {
+    pub fn layer(self) -> CompressionLayer<impl Predicate> {
+        let compressible = Compressible(Arc::new(self))
+            .and(NotForContentType::GRPC)
+            .and(NotForContentType::IMAGES)
+            .and(NotForContentType::SSE);
+        CompressionLayer::new().compress_when(compressible)
+    }
+}
+
+/// Predicate of the responses compressed according to the settings.
+#[derive(Clone)]
+struct Compressible(Arc<CompressionConfig>);
+
+impl Predicate for Compressible {
+    fn should_compress<B>(&self, response: &Response<B>) -> bool
+    where
+        B: http_body::Body,
+    {
+        let config = &self.0;
+        if !config.enabled || response.status() == StatusCode::PARTIAL_CONTENT {
+            return false;
+        }
+        let size = response.body().size_hint().exact().or_else(|| {
+            let length = response.headers().get(header::CONTENT_LENGTH)?;
+            length.to_str().ok()?.parse().ok()
+        });
+        if size.is_some_and(|size| size < config.min_size.0) {
+            return false;
+        }
+        if config.content_types.is_empty() {
+            return true;
+        }
+        let content_type = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        let essence = content_type.split(';').next().unwrap_or_default().trim();
+        config
+            .content_types
+            .iter()
+            .any(|pattern| match pattern.strip_suffix("/*") {
+                Some(kind) => essence
+                    .split_once('/')
+                    .is_some_and(|(prefix, _)| prefix.eq_ignore_ascii_case(kind)),
+                None => essence.eq_ignore_ascii_case(pattern),
+            })
+    }
+}
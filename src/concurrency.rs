@@ -0,0 +1,102 @@
+//! Global cap on in-flight proxied requests.
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tokio::sync::Semaphore;
+
+use crate::{errors, metrics::METRICS};
+
+pub struct ConcurrencyLimiter {
+    permits: Arc<Semaphore>,
+    max_concurrent: usize,
+    /// How long a request may wait in the queue for a permit before being shed.
+    wait: Duration,
+    /// Maximum number of requests waiting for a permit, beyond which they are shed right away.
+    queue_size: usize,
+    queued: AtomicUsize,
+}
+
+impl ConcurrencyLimiter {
+    pub fn new(max_concurrent: usize, wait: Duration, queue_size: usize) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(max_concurrent)),
+            max_concurrent,
+            wait,
+            queue_size,
+            queued: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.max_concurrent - self.permits.available_permits()
+    }
+
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+}
+
+/// Middleware queueing requests when too many are in flight, and shedding them with
+/// `503 Service Unavailable` when the queue is full or they waited too long.
+pub async fn limit_concurrency(
+    State(limiter): State<Arc<ConcurrencyLimiter>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let start = Instant::now();
+    let permit = match limiter.permits.clone().try_acquire_owned() {
+        Ok(permit) => Some(permit),
+        Err(_) if limiter.wait.is_zero() => None,
+        Err(_) => {
+            // the semaphore hands out permits in FIFO order
+            if limiter.queued.fetch_add(1, Ordering::Relaxed) < limiter.queue_size {
+                let permit =
+                    tokio::time::timeout(limiter.wait, limiter.permits.clone().acquire_owned())
+                        .await
+                        .ok()
+                        .and_then(Result::ok);
+                limiter.queued.fetch_sub(1, Ordering::Relaxed);
+                permit
+            } else {
+                limiter.queued.fetch_sub(1, Ordering::Relaxed);
+                None
+            }
+        }
+    };
+    let Some(_permit) = permit else {
+        METRICS.shed.fetch_add(1, Ordering::Relaxed);
+        tracing::warn!(
+            in_flight = limiter.in_flight(),
+            queued = limiter.queued(),
+            "Too many requests in flight, shedding load"
+        );
+        return (
+            [
+                ("x-proxy-in-flight", limiter.in_flight().to_string()),
+                ("x-proxy-queue-depth", limiter.queued().to_string()),
+                (
+                    "x-proxy-queue-wait-ms",
+                    start.elapsed().as_millis().to_string(),
+                ),
+            ],
+            errors::response(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "overloaded",
+                "Server overloaded",
+            ),
+        )
+            .into_response();
+    };
+    next.run(request).await
+}
@@ -0,0 +1,152 @@
+//! Custom transports of the upstream connections, registered with
+//! [`ProxyBuilder::connector`](crate::ProxyBuilder::connector), e.g. to reach the origins through
+//! an SSH tunnel or a VPN library, or to answer from a test double.
+//!
+//! The HTTP client doesn't accept a connector of its own, so the upstream connections go through a
+//! SOCKS5 proxy served on a loopback port, which opens them with the [`Connector`]. The connector
+//! gets the host names of the origins, and so resolves them itself: the `[dns]` settings don't
+//! apply. TLS to the origins runs on top of the streams of the connector, like for direct
+//! connections.
+use std::{
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use rand::distributions::{Alphanumeric, DistString};
+use reqwest::Url;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+/// A bidirectional stream to an origin.
+pub trait Io: AsyncRead + AsyncWrite + Send + Unpin + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin + 'static> Io for T {}
+
+// the futures boxed by `async_trait` are `must_use` already
+#[allow(clippy::double_must_use)]
+#[async_trait]
+pub trait Connector: Send + Sync + 'static {
+    /// Open a connection to `port` of `host`, a domain name or an IP address.
+    async fn connect(&self, host: &str, port: u16) -> io::Result<Box<dyn Io>>;
+}
+
+/// SOCKS5 proxy opening the connections with a [`Connector`].
+///
+/// It requires a random password, so that other programs on the host can't use the connector.
+pub struct Bridge {
+    addr: SocketAddr,
+    password: String,
+}
+
+impl Bridge {
+    pub async fn start(connector: Arc<dyn Connector>) -> Result<Self> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let bridge = Self {
+            addr: listener.local_addr()?,
+            password: Alphanumeric.sample_string(&mut rand::thread_rng(), 32),
+        };
+        let password = bridge.password.clone();
+        tokio::spawn(async move {
+            loop {
+                let stream = match listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(err) => {
+                        tracing::error!(error = %err, "Could not accept connector connection");
+                        continue;
+                    }
+                };
+                let (connector, password) = (connector.clone(), password.clone());
+                tokio::spawn(async move {
+                    if let Err(err) = relay(stream, &*connector, &password).await {
+                        tracing::warn!(error = %err, "Connector connection failed");
+                    }
+                });
+            }
+        });
+        Ok(bridge)
+    }
+
+    /// Proxy URL of the bridge for the HTTP client, `socks5h` so that it passes the host names.
+    pub fn proxy_url(&self) -> Url {
+        format!("socks5h://proxy:{}@{}", self.password, self.addr)
+            .parse()
+            .expect("the proxy URL is valid")
+    }
+}
+
+const VERSION: u8 = 5;
+const USER_PASSWORD: u8 = 2;
+const NO_METHOD: u8 = 0xff;
+const CONNECT: u8 = 1;
+
+/// Serve a SOCKS5 connection (RFC 1928) authenticated with a password (RFC 1929), and relay it to
+/// the connection of the connector.
+async fn relay(mut stream: TcpStream, connector: &dyn Connector, password: &str) -> Result<()> {
+    let [version, methods] = read_array(&mut stream).await?;
+    anyhow::ensure!(version == VERSION, "unsupported SOCKS version {version}");
+    let methods = read_vec(&mut stream, methods).await?;
+    if !methods.contains(&USER_PASSWORD) {
+        stream.write_all(&[VERSION, NO_METHOD]).await?;
+        anyhow::bail!("SOCKS client without password");
+    }
+    stream.write_all(&[VERSION, USER_PASSWORD]).await?;
+
+    let [_, len] = read_array(&mut stream).await?;
+    let _user = read_vec(&mut stream, len).await?;
+    let [len] = read_array(&mut stream).await?;
+    let authenticated = read_vec(&mut stream, len).await? == password.as_bytes();
+    stream.write_all(&[1, u8::from(!authenticated)]).await?;
+    anyhow::ensure!(authenticated, "SOCKS client with a wrong password");
+
+    let [_, command, _, address_type] = read_array(&mut stream).await?;
+    let host = match address_type {
+        1 => Ipv4Addr::from(read_array::<4>(&mut stream).await?).to_string(),
+        3 => {
+            let [len] = read_array(&mut stream).await?;
+            String::from_utf8(read_vec(&mut stream, len).await?)?
+        }
+        4 => Ipv6Addr::from(read_array::<16>(&mut stream).await?).to_string(),
+        _ => anyhow::bail!("unsupported SOCKS address type {address_type}"),
+    };
+    let port = u16::from_be_bytes(read_array(&mut stream).await?);
+    if command != CONNECT {
+        // command not supported
+        reply(&mut stream, 7).await?;
+        anyhow::bail!("unsupported SOCKS command {command}");
+    }
+    let mut upstream = match connector.connect(&host, port).await {
+        Ok(upstream) => upstream,
+        Err(err) => {
+            // general failure, the client reports it as a connection error
+            reply(&mut stream, 1).await?;
+            return Err(anyhow::anyhow!("could not connect to {host}:{port}: {err}"));
+        }
+    };
+    reply(&mut stream, 0).await?;
+    tokio::io::copy_bidirectional(&mut stream, &mut upstream).await?;
+    Ok(())
+}
+
+async fn reply(stream: &mut TcpStream, status: u8) -> io::Result<()> {
+    // the bound address is unspecified, the client doesn't use it
+    stream
+        .write_all(&[VERSION, status, 0, 1, 0, 0, 0, 0, 0, 0])
+        .await
+}
+
+async fn read_array<const N: usize>(stream: &mut TcpStream) -> io::Result<[u8; N]> {
+    let mut buf = [0; N];
+    stream.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+async fn read_vec(stream: &mut TcpStream, len: u8) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0; len.into()];
+    stream.read_exact(&mut buf).await?;
+    Ok(buf)
+}
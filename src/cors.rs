@@ -0,0 +1,96 @@
+//! CORS preflights answered by the proxy and CORS headers added to its responses, so that browser
+//! apps can use the proxy for cross-origin requests without the origins cooperating.
+use std::time::Duration;
+
+use anyhow::{ensure, Context, Result};
+use axum::http::{HeaderName, HeaderValue, Method};
+use serde::Deserialize;
+use tower_http::cors::{AllowHeaders, AllowOrigin, CorsLayer, ExposeHeaders};
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CorsConfig {
+    /// Origins of the apps allowed to use the proxy, e.g. `https://app.example.com`, or `*` for
+    /// all of them.
+    pub allowed_origins: Vec<String>,
+    #[serde(default = "CorsConfig::default_allowed_methods")]
+    pub allowed_methods: Vec<String>,
+    /// Request headers the apps may send, or `*` for all of them.
+    #[serde(default = "CorsConfig::default_allowed_headers")]
+    pub allowed_headers: Vec<String>,
+    /// Response headers the apps may read, in addition to the CORS-safelisted ones.
+    #[serde(default)]
+    pub exposed_headers: Vec<String>,
+    /// Allow the requests with credentials, which requires listing the allowed origins and
+    /// headers.
+    #[serde(default)]
+    pub allow_credentials: bool,
+    /// How long the browsers may cache the answers to preflights.
+    #[serde(default = "CorsConfig::default_max_age", with = "humantime_serde")]
+    pub max_age: Duration,
+}
+
+impl CorsConfig {
+    fn default_allowed_methods() -> Vec<String> {
+        vec!["GET".to_string()]
+    }
+
+    fn default_allowed_headers() -> Vec<String> {
+        vec!["authorization".to_string()]
+    }
+
+    fn default_max_age() -> Duration {
+        Duration::from_secs(60 * 60)
+    }
+
+    pub fn layer(&self) -> Result<CorsLayer> {
+        let any_origin = self.allowed_origins.iter().any(|origin| origin == "*");
+        let any_header = self.allowed_headers.iter().any(|name| name == "*");
+        ensure!(
+            !self.allow_credentials || !(any_origin || any_header),
+            "CORS requests with credentials require listing the allowed origins and headers"
+        );
+        let origins = if any_origin {
+            AllowOrigin::any()
+        } else {
+            AllowOrigin::list(
+                self.allowed_origins
+                    .iter()
+                    .map(|origin| {
+                        HeaderValue::try_from(origin.as_str())
+                            .with_context(|| format!("invalid CORS origin {origin}"))
+                    })
+                    .collect::<Result<Vec<_>>>()?,
+            )
+        };
+        let methods = self
+            .allowed_methods
+            .iter()
+            .map(|method| {
+                Method::try_from(method.as_str())
+                    .with_context(|| format!("invalid CORS method {method}"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let headers = if any_header {
+            AllowHeaders::any()
+        } else {
+            AllowHeaders::list(header_names(&self.allowed_headers)?)
+        };
+        Ok(CorsLayer::new()
+            .allow_origin(origins)
+            .allow_methods(methods)
+            .allow_headers(headers)
+            .expose_headers(ExposeHeaders::list(header_names(&self.exposed_headers)?))
+            .allow_credentials(self.allow_credentials)
+            .max_age(self.max_age))
+    }
+}
+
+fn header_names(names: &[String]) -> Result<Vec<HeaderName>> {
+    names
+        .iter()
+        .map(|name| {
+            HeaderName::try_from(name.as_str()).with_context(|| format!("invalid header {name}"))
+        })
+        .collect()
+}
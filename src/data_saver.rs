@@ -0,0 +1,288 @@
+//! Recompression of the large images for the clients saving data, which opt in with the
+//! `Save-Data: on` client hint or the `X-Proxy-Save-Data: on` header of the proxy.
+//!
+//! JPEGs are requantized at a lower quality and PNGs deflated again, see [`crate::jpeg`] and
+//! [`crate::png`], and both lose their metadata along with WebPs, which aren't re-encoded. Only
+//! the bodies buffered by the proxy are recompressed, the cached ones again on each delivery, and
+//! the images are delivered as they are when recompressing them doesn't make them smaller.
+use std::sync::atomic::Ordering;
+
+use anyhow::{ensure, Result};
+use axum::{
+    body::Bytes,
+    http::{HeaderMap, HeaderValue},
+};
+use serde::Deserialize;
+
+use crate::{config::ByteSize, jpeg, metrics::METRICS, png};
+
+pub const SAVE_DATA_HEADER: &str = "x-proxy-save-data";
+
+/// `Vary` of the images, whose bodies depend on the opt-in of the clients.
+pub const VARY: &str = "save-data, x-proxy-save-data";
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DataSaverConfig {
+    /// Smallest image recompressed.
+    #[serde(default = "DataSaverConfig::default_min_size")]
+    pub min_size: ByteSize,
+    /// Quality of the JPEGs, from 1 to 100, the ones of a lower quality keeping theirs.
+    #[serde(default = "DataSaverConfig::default_quality")]
+    pub quality: u8,
+}
+
+impl DataSaverConfig {
+    fn default_min_size() -> ByteSize {
+        ByteSize(100_000)
+    }
+
+    fn default_quality() -> u8 {
+        60
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Format {
+    Jpeg,
+    Png,
+    WebP,
+}
+
+impl Format {
+    fn of(content_type: Option<&HeaderValue>) -> Option<Self> {
+        let content_type = content_type?.to_str().ok()?;
+        let mime = content_type.split(';').next()?.trim();
+        match mime.to_ascii_lowercase().as_str() {
+            "image/jpeg" | "image/jpg" | "image/pjpeg" => Some(Self::Jpeg),
+            "image/png" => Some(Self::Png),
+            "image/webp" => Some(Self::WebP),
+            _ => None,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Jpeg => "jpeg",
+            Self::Png => "png",
+            Self::WebP => "webp",
+        }
+    }
+}
+
+pub struct DataSaver {
+    min_size: u64,
+    quality: u8,
+}
+
+impl DataSaver {
+    pub fn new(config: DataSaverConfig) -> Result<Self> {
+        ensure!(
+            (1..=100).contains(&config.quality),
+            "the quality of the data saver must be from 1 to 100"
+        );
+        Ok(Self {
+            min_size: config.min_size.0,
+            quality: config.quality,
+        })
+    }
+
+    /// Whether the client of a request with `headers` opted in.
+    pub fn requested(headers: &HeaderMap) -> bool {
+        ["save-data", SAVE_DATA_HEADER].into_iter().any(|name| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .is_some_and(|value| value.trim().eq_ignore_ascii_case("on"))
+        })
+    }
+
+    /// Whether the responses of `content_type` may be recompressed, and vary with the opt-in.
+    pub fn applies(content_type: Option<&HeaderValue>) -> bool {
+        Format::of(content_type).is_some()
+    }
+
+    /// The image `body` of `url`, recompressed if it is large enough and gets smaller.
+    pub async fn recompress(
+        &self,
+        url: &str,
+        content_type: Option<&HeaderValue>,
+        body: &Bytes,
+    ) -> Option<Bytes> {
+        let format = Format::of(content_type)?;
+        if (body.len() as u64) < self.min_size {
+            return None;
+        }
+        let (image, quality) = (body.clone(), self.quality);
+        let recompressed = tokio::task::spawn_blocking(move || match format {
+            Format::Jpeg => jpeg::recompress(&image, quality),
+            Format::Png => png::recompress(&image),
+            Format::WebP => webp(&image),
+        })
+        .await
+        .ok()
+        .flatten();
+        let Some(recompressed) = recompressed.filter(|image| image.len() < body.len()) else {
+            tracing::debug!(url, format = format.as_str(), "Image not recompressed");
+            return None;
+        };
+        let saved = (body.len() - recompressed.len()) as u64;
+        METRICS.data_saver_images.fetch_add(1, Ordering::Relaxed);
+        METRICS
+            .data_saver_bytes_saved
+            .fetch_add(saved, Ordering::Relaxed);
+        tracing::debug!(
+            url,
+            format = format.as_str(),
+            original_len = body.len(),
+            data_len = recompressed.len(),
+            "Recompressed image"
+        );
+        Some(recompressed.into())
+    }
+}
+
+/// The WebP `data` without its Exif and XMP metadata, if it has any.
+fn webp(data: &[u8]) -> Option<Vec<u8>> {
+    let (riff, rest) = data.split_first_chunk::<12>()?;
+    if riff[..4] != *b"RIFF" || riff[8..] != *b"WEBP" {
+        return None;
+    }
+    let mut chunks = Vec::new();
+    let mut rest = rest;
+    while let Some((header, after)) = rest.split_first_chunk::<8>() {
+        let len = u32::from_le_bytes(header[4..].try_into().ok()?) as usize;
+        // chunks are padded to an even size
+        let padded = len + len % 2;
+        let body = after.get(..len)?;
+        chunks.push((&header[..4], body));
+        rest = after.get(padded..).unwrap_or_default();
+    }
+    let mut out = Vec::with_capacity(data.len());
+    out.extend_from_slice(&riff[..]);
+    for (kind, body) in chunks {
+        let mut body = body.to_vec();
+        match kind {
+            b"EXIF" | b"XMP " => continue,
+            // the flags of the metadata, no longer there
+            b"VP8X" => *body.first_mut()? &= !0x0c,
+            _ => {}
+        }
+        out.extend_from_slice(kind);
+        out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        out.extend_from_slice(&body);
+        if body.len() % 2 == 1 {
+            out.push(0);
+        }
+    }
+    let size = (out.len() - 8) as u32;
+    out[4..8].copy_from_slice(&size.to_le_bytes());
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn saver(min_size: &str) -> DataSaver {
+        DataSaver::new(toml::from_str(&format!("min_size = \"{min_size}\"")).unwrap()).unwrap()
+    }
+
+    fn webp_chunk(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut chunk = kind.to_vec();
+        chunk.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        chunk.extend_from_slice(body);
+        if body.len() % 2 == 1 {
+            chunk.push(0);
+        }
+        chunk
+    }
+
+    fn riff(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let chunks = chunks.concat();
+        let mut data = b"RIFF".to_vec();
+        data.extend_from_slice(&(chunks.len() as u32 + 4).to_le_bytes());
+        data.extend_from_slice(b"WEBP");
+        data.extend_from_slice(&chunks);
+        data
+    }
+
+    #[test]
+    fn opts_in_with_either_header() {
+        let mut headers = HeaderMap::new();
+        assert!(!DataSaver::requested(&headers));
+        headers.insert("save-data", HeaderValue::from_static("off"));
+        assert!(!DataSaver::requested(&headers));
+        headers.insert("save-data", HeaderValue::from_static(" On"));
+        assert!(DataSaver::requested(&headers));
+        headers.clear();
+        headers.insert(SAVE_DATA_HEADER, HeaderValue::from_static("on"));
+        assert!(DataSaver::requested(&headers));
+    }
+
+    #[test]
+    fn applies_to_images() {
+        for content_type in ["image/jpeg", "IMAGE/PNG", "image/webp; q=1", "image/pjpeg"] {
+            assert!(DataSaver::applies(Some(&HeaderValue::from_static(
+                content_type
+            ))));
+        }
+        assert!(!DataSaver::applies(Some(&HeaderValue::from_static(
+            "image/gif"
+        ))));
+        assert!(!DataSaver::applies(None));
+    }
+
+    #[test]
+    fn rejects_invalid_qualities() {
+        assert!(DataSaver::new(toml::from_str("quality = 0").unwrap()).is_err());
+        assert!(DataSaver::new(toml::from_str("quality = 101").unwrap()).is_err());
+    }
+
+    #[test]
+    fn strips_webp_metadata() {
+        let image = riff(&[
+            webp_chunk(b"VP8X", &[0x1c, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
+            webp_chunk(b"VP8L", b"\x2f\0\0\0\0"),
+            webp_chunk(b"EXIF", b"Exi"),
+            webp_chunk(b"XMP ", b"<x/>"),
+        ]);
+        let expected = riff(&[
+            webp_chunk(b"VP8X", &[0x10, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
+            webp_chunk(b"VP8L", b"\x2f\0\0\0\0"),
+        ]);
+        assert_eq!(webp(&image), Some(expected));
+        assert_eq!(webp(b"RIFF\0\0\0\0WAVE"), None);
+    }
+
+    #[tokio::test]
+    async fn recompresses_large_enough_images() {
+        let png = Some(HeaderValue::from_static("image/png"));
+        let image = Bytes::from_static(include_bytes!("../testdata/png/rgba16.png"));
+        assert!(saver("2KB")
+            .recompress("", png.as_ref(), &image)
+            .await
+            .is_none());
+        let images = METRICS.data_saver_images.load(Ordering::Relaxed);
+        let saved = METRICS.data_saver_bytes_saved.load(Ordering::Relaxed);
+        let recompressed = saver("1KB")
+            .recompress("", png.as_ref(), &image)
+            .await
+            .unwrap();
+        assert!(recompressed.len() < image.len());
+        assert!(METRICS.data_saver_images.load(Ordering::Relaxed) > images);
+        let bytes_saved = (image.len() - recompressed.len()) as u64;
+        assert!(METRICS.data_saver_bytes_saved.load(Ordering::Relaxed) >= saved + bytes_saved);
+        // not images, or not smaller
+        let gif = Some(HeaderValue::from_static("image/gif"));
+        assert!(saver("1B")
+            .recompress("", gif.as_ref(), &image)
+            .await
+            .is_none());
+        let png = Some(HeaderValue::from_static("image/png"));
+        assert!(saver("1B")
+            .recompress("", png.as_ref(), &recompressed)
+            .await
+            .is_none());
+    }
+}
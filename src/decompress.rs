@@ -0,0 +1,74 @@
+//! Decompression of the upstream responses whose encoding isn't accepted by the client.
+use async_compression::tokio::bufread::{BrotliDecoder, GzipDecoder, ZlibDecoder, ZstdDecoder};
+use axum::{
+    body::Body,
+    http::{header, HeaderMap},
+};
+use futures_util::TryStreamExt;
+use tokio_util::io::{ReaderStream, StreamReader};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Deflate,
+    Gzip,
+    Zstd,
+}
+
+impl Encoding {
+    /// The encoding of a `Content-Encoding` value, if it is supported.
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "br" => Some(Self::Brotli),
+            "deflate" => Some(Self::Deflate),
+            "gzip" | "x-gzip" => Some(Self::Gzip),
+            "zstd" => Some(Self::Zstd),
+            _ => None,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Brotli => "br",
+            Self::Deflate => "deflate",
+            Self::Gzip => "gzip",
+            Self::Zstd => "zstd",
+        }
+    }
+
+    /// Whether the `Accept-Encoding` of a request allows this encoding.
+    pub fn accepted(self, headers: &HeaderMap) -> bool {
+        headers
+            .get_all(header::ACCEPT_ENCODING)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|item| {
+                let mut params = item.split(';');
+                let coding = params.next().unwrap_or_default().trim();
+                let refused = params.any(|param| {
+                    param
+                        .trim()
+                        .strip_prefix("q=")
+                        .and_then(|q| q.parse::<f32>().ok())
+                        .is_some_and(|q| q == 0.0)
+                });
+                !refused
+                    && (coding == "*"
+                        || coding.eq_ignore_ascii_case(self.as_str())
+                        || (self == Self::Gzip && coding.eq_ignore_ascii_case("x-gzip")))
+            })
+    }
+
+    /// Decode a body as it is streamed.
+    pub fn decode(self, body: Body) -> Body {
+        let reader = StreamReader::new(body.into_data_stream().map_err(std::io::Error::other));
+        match self {
+            Self::Brotli => Body::from_stream(ReaderStream::new(BrotliDecoder::new(reader))),
+            // the `deflate` content coding is actually the zlib format
+            Self::Deflate => Body::from_stream(ReaderStream::new(ZlibDecoder::new(reader))),
+            Self::Gzip => Body::from_stream(ReaderStream::new(GzipDecoder::new(reader))),
+            Self::Zstd => Body::from_stream(ReaderStream::new(ZstdDecoder::new(reader))),
+        }
+    }
+}
@@ -0,0 +1,608 @@
+//! Name resolution of the upstream hosts, optionally cached in-process.
+//!
+//! When a host has both IPv6 and IPv4 addresses, the client connects to the family of the first
+//! one and races a connection to the other family if that takes more than 300ms (Happy Eyeballs,
+//! RFC 8305), so both families must be resolved.
+//!
+//! The client tries the addresses of a host in turn until it connects to one, and the addresses
+//! that it couldn't connect to are tried last for a while.
+//!
+//! The most requested names of the cache can be resolved again shortly before they expire, so that
+//! their requests don't wait for the name servers.
+//!
+//! With DNS-over-TLS or DNS-over-HTTPS, the names of the upstream hosts aren't visible to the local
+//! network, and the system resolver is never used.
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use hickory_resolver::{
+    config::{
+        LookupIpStrategy, NameServerConfig, Protocol, ResolverConfig, ResolverOpts,
+        ServerOrderingStrategy,
+    },
+    system_conf, TokioAsyncResolver,
+};
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use serde::{Deserialize, Serialize};
+
+use crate::{config::HostPattern, timing};
+
+/// Name servers used instead of the ones of the system configuration, e.g. internal resolvers in
+/// split-horizon environments.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DnsConfig {
+    /// Addresses of the name servers, as `ip` or `ip:port`, tried in order.
+    pub servers: Vec<String>,
+    pub protocol: DnsProtocol,
+    /// Public resolver used when no servers are configured.
+    pub provider: Option<DnsProvider>,
+    /// Name in the certificate of the servers, required with the encrypted protocols.
+    pub tls_name: Option<String>,
+    /// How long to wait for an answer from a server before trying the next one.
+    #[serde(with = "humantime_serde")]
+    pub timeout: Duration,
+    /// Number of times the servers are tried for a lookup.
+    pub attempts: usize,
+    /// Spread the lookups over the servers in turn, instead of always trying the first one first.
+    pub rotate: bool,
+    /// Addresses of hosts, used instead of resolving them, like `/etc/hosts`.
+    pub hosts: HashMap<String, IpAddr>,
+    /// Address family of the hosts not matching any of the `families` rules.
+    pub family: IpFamily,
+    /// Address families of the matching hosts, the first matching rule applies.
+    pub families: Vec<FamilyRule>,
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        Self {
+            servers: Vec::new(),
+            protocol: DnsProtocol::default(),
+            provider: None,
+            tls_name: None,
+            timeout: Duration::from_secs(5),
+            attempts: 2,
+            rotate: false,
+            hosts: HashMap::new(),
+            family: IpFamily::default(),
+            families: Vec::new(),
+        }
+    }
+}
+
+impl DnsConfig {
+    /// Configurations of each name server, by protocol, `None` to use the system ones.
+    fn name_servers(&self) -> Result<Option<Vec<Vec<NameServerConfig>>>> {
+        if self.servers.is_empty() {
+            let Some(provider) = self.provider else {
+                anyhow::ensure!(
+                    self.protocol == DnsProtocol::Udp,
+                    "encrypted DNS requires servers or a provider"
+                );
+                return Ok(None);
+            };
+            let mut servers: Vec<Vec<NameServerConfig>> = Vec::new();
+            for server in provider.config(self.protocol).name_servers() {
+                match servers
+                    .iter_mut()
+                    .find(|configs| configs[0].socket_addr == server.socket_addr)
+                {
+                    Some(configs) => configs.push(server.clone()),
+                    None => servers.push(vec![server.clone()]),
+                }
+            }
+            return Ok(Some(servers));
+        }
+        let tls_name = match self.protocol {
+            DnsProtocol::Udp => None,
+            DnsProtocol::Tls | DnsProtocol::Https => Some(
+                self.tls_name
+                    .clone()
+                    .context("encrypted DNS servers require a `tls_name`")?,
+            ),
+        };
+        self.servers
+            .iter()
+            .map(|server| {
+                let addr = parse_server(server, self.protocol.default_port())?;
+                Ok(match self.protocol {
+                    // TCP is used for the truncated answers
+                    DnsProtocol::Udp => vec![
+                        NameServerConfig::new(addr, Protocol::Udp),
+                        NameServerConfig::new(addr, Protocol::Tcp),
+                    ],
+                    DnsProtocol::Tls | DnsProtocol::Https => {
+                        let mut config = NameServerConfig::new(addr, self.protocol.into());
+                        config.tls_dns_name = tls_name.clone();
+                        vec![config]
+                    }
+                })
+            })
+            .collect::<Result<_>>()
+            .map(Some)
+    }
+}
+
+/// How the queries are sent to the name servers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DnsProtocol {
+    /// Plain text, over UDP and TCP for the truncated answers
+    #[default]
+    Udp,
+    /// DNS-over-TLS (RFC 7858)
+    Tls,
+    /// DNS-over-HTTPS (RFC 8484)
+    Https,
+}
+
+impl DnsProtocol {
+    fn default_port(self) -> u16 {
+        match self {
+            Self::Udp => 53,
+            Self::Tls => 853,
+            Self::Https => 443,
+        }
+    }
+}
+
+impl From<DnsProtocol> for Protocol {
+    fn from(protocol: DnsProtocol) -> Self {
+        match protocol {
+            DnsProtocol::Udp => Self::Udp,
+            DnsProtocol::Tls => Self::Tls,
+            DnsProtocol::Https => Self::Https,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DnsProvider {
+    Cloudflare,
+    Google,
+    Quad9,
+}
+
+impl DnsProvider {
+    fn config(self, protocol: DnsProtocol) -> ResolverConfig {
+        match (self, protocol) {
+            (Self::Cloudflare, DnsProtocol::Udp) => ResolverConfig::cloudflare(),
+            (Self::Cloudflare, DnsProtocol::Tls) => ResolverConfig::cloudflare_tls(),
+            (Self::Cloudflare, DnsProtocol::Https) => ResolverConfig::cloudflare_https(),
+            (Self::Google, DnsProtocol::Udp) => ResolverConfig::google(),
+            (Self::Google, DnsProtocol::Tls) => ResolverConfig::google_tls(),
+            (Self::Google, DnsProtocol::Https) => ResolverConfig::google_https(),
+            (Self::Quad9, DnsProtocol::Udp) => ResolverConfig::quad9(),
+            (Self::Quad9, DnsProtocol::Tls) => ResolverConfig::quad9_tls(),
+            (Self::Quad9, DnsProtocol::Https) => ResolverConfig::quad9_https(),
+        }
+    }
+}
+
+/// Address families connected to, e.g. for egress paths with broken IPv6 or without IPv4.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum IpFamily {
+    /// In the order of the resolver, usually IPv6 first
+    #[default]
+    Auto,
+    /// IPv4 first, the other family being only tried when it is slow or fails
+    Ipv4,
+    /// IPv6 first, the other family being only tried when it is slow or fails
+    Ipv6,
+    Ipv4Only,
+    Ipv6Only,
+}
+
+impl IpFamily {
+    fn apply(self, addrs: &mut Vec<SocketAddr>) {
+        match self {
+            Self::Auto => {}
+            Self::Ipv4 => addrs.sort_by_key(SocketAddr::is_ipv6),
+            Self::Ipv6 => addrs.sort_by_key(SocketAddr::is_ipv4),
+            Self::Ipv4Only => addrs.retain(SocketAddr::is_ipv4),
+            Self::Ipv6Only => addrs.retain(SocketAddr::is_ipv6),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FamilyRule {
+    pub host: HostPattern,
+    pub family: IpFamily,
+}
+
+fn parse_server(server: &str, default_port: u16) -> Result<SocketAddr> {
+    server
+        .parse()
+        .or_else(|_| server.parse().map(|ip| SocketAddr::new(ip, default_port)))
+        .with_context(|| format!("invalid DNS server address {server}"))
+}
+
+/// How often the names about to expire are prefetched.
+const PREFETCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Bounds of the in-process cache of the lookups.
+pub struct CacheLimits {
+    pub max_entries: usize,
+    pub min_ttl: Duration,
+    pub max_ttl: Duration,
+    /// Number of the most requested names resolved again before they expire.
+    pub prefetch: usize,
+}
+
+#[derive(Clone, Copy, Serialize)]
+pub struct DnsStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    /// Lookups made ahead of the expiry of a name.
+    pub prefetches: u64,
+}
+
+struct Entry {
+    addrs: Vec<IpAddr>,
+    expires: Instant,
+    ttl: Duration,
+    /// Requests of the name since it was resolved.
+    requests: u64,
+}
+
+/// Cache of the lookups, honoring the record TTLs within bounds.
+struct DnsCache {
+    entries: Mutex<HashMap<String, Entry>>,
+    max_entries: usize,
+    min_ttl: Duration,
+    max_ttl: Duration,
+    prefetch: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    prefetches: AtomicU64,
+}
+
+impl DnsCache {
+    fn new(limits: CacheLimits) -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+            max_entries: limits.max_entries.max(1),
+            min_ttl: limits.min_ttl,
+            max_ttl: limits.max_ttl.max(limits.min_ttl),
+            prefetch: limits.prefetch,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            prefetches: AtomicU64::new(0),
+        }
+    }
+
+    fn get(&self, name: &str) -> Option<Vec<IpAddr>> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries
+            .get_mut(name)
+            .filter(|entry| entry.expires > Instant::now());
+        match entry {
+            Some(entry) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                entry.requests += 1;
+                Some(entry.addrs.clone())
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// The most requested names expiring before the next prefetch, or shortly after.
+    fn expiring(&self) -> Vec<String> {
+        let now = Instant::now();
+        let entries = self.entries.lock().unwrap();
+        let mut expiring: Vec<_> = entries
+            .iter()
+            .filter(|(_, entry)| entry.requests > 0 && entry.expires > now)
+            .filter(|(_, entry)| entry.expires - now <= entry.ttl / 10 + PREFETCH_INTERVAL)
+            .collect();
+        expiring.sort_unstable_by_key(|(_, entry)| std::cmp::Reverse(entry.requests));
+        expiring
+            .into_iter()
+            .take(self.prefetch)
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    /// Cache the addresses of `name`, `requested` if it is for a request rather than a prefetch.
+    fn insert(&self, name: &str, addrs: Vec<IpAddr>, valid_until: Instant, requested: bool) {
+        let now = Instant::now();
+        let ttl = valid_until
+            .saturating_duration_since(now)
+            .clamp(self.min_ttl, self.max_ttl);
+
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.max_entries && !entries.contains_key(name) {
+            entries.retain(|_, entry| entry.expires > now);
+            if entries.len() >= self.max_entries {
+                let soonest = entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.expires)
+                    .map(|(name, _)| name.clone());
+                if let Some(soonest) = soonest {
+                    entries.remove(&soonest);
+                }
+            }
+        }
+        entries.insert(
+            name.to_string(),
+            Entry {
+                addrs,
+                expires: now + ttl,
+                ttl,
+                requests: u64::from(requested),
+            },
+        );
+    }
+}
+
+/// Resolvers using the same name servers, each trying them from a different one, so that the
+/// lookups rotate over the servers.
+struct NameServers {
+    resolvers: Vec<TokioAsyncResolver>,
+    next: AtomicUsize,
+}
+
+impl NameServers {
+    fn resolver(&self) -> TokioAsyncResolver {
+        let next = self.next.fetch_add(1, Ordering::Relaxed);
+        self.resolvers[next % self.resolvers.len()].clone()
+    }
+
+    /// Addresses of `name`, with the time until which they are valid.
+    async fn lookup(&self, name: &str) -> Result<(Vec<IpAddr>, Instant)> {
+        let lookup = self.resolver().lookup_ip(name).await?;
+        let mut addrs: Vec<_> = lookup.iter().collect();
+        // prefer IPv6, like the system resolver usually does
+        addrs.sort_by_key(IpAddr::is_ipv4);
+        Ok((addrs, lookup.valid_until()))
+    }
+}
+
+/// Addresses that the client couldn't connect to, inferred from the one it connected to.
+#[derive(Default)]
+struct Failover {
+    /// Order of the addresses last resolved for each host, until a connection to it is made.
+    resolved: HashMap<String, Vec<IpAddr>>,
+    /// Until when each address that couldn't be connected to is tried last.
+    failed: HashMap<IpAddr, Instant>,
+}
+
+impl Failover {
+    /// Past this number of hosts or addresses, the oldest ones are forgotten.
+    const PRUNE_THRESHOLD: usize = 10_000;
+    /// How long an address that couldn't be connected to is tried last.
+    const FAILED_FOR: Duration = Duration::from_secs(30);
+
+    /// Move the addresses that recently failed after the other ones.
+    fn order(&mut self, name: &str, addrs: &mut [SocketAddr]) {
+        let now = Instant::now();
+        addrs.sort_by_key(|addr| {
+            self.failed
+                .get(&addr.ip())
+                .is_some_and(|until| *until > now)
+        });
+        if self.resolved.len() >= Self::PRUNE_THRESHOLD {
+            self.resolved.clear();
+        }
+        self.resolved
+            .insert(name.to_string(), addrs.iter().map(SocketAddr::ip).collect());
+    }
+
+    /// The client connected to `addr` after resolving `name`, so the addresses of the same family
+    /// before it failed.
+    fn connected(&mut self, name: &str, addr: SocketAddr) {
+        let Some(resolved) = self.resolved.remove(name) else {
+            return;
+        };
+        let now = Instant::now();
+        if self.failed.len() >= Self::PRUNE_THRESHOLD {
+            self.failed.retain(|_, until| *until > now);
+        }
+        self.failed.remove(&addr.ip());
+        for ip in resolved.into_iter().take_while(|ip| *ip != addr.ip()) {
+            // the other family is only raced after a delay, and might just have been slower
+            if ip.is_ipv4() == addr.is_ipv4() {
+                tracing::debug!(host = name, address = %ip, "Could not connect to address");
+                self.failed.insert(ip, now + Self::FAILED_FOR);
+            }
+        }
+    }
+}
+
+/// Resolver of the upstream client, timing the lookups for [`timing`].
+pub struct Resolver {
+    /// `None` to resolve with the system resolver, like the default client.
+    servers: Option<Arc<NameServers>>,
+    cache: Option<Arc<DnsCache>>,
+    /// Overrides of the resolution, by lowercase host name.
+    hosts: HashMap<String, IpAddr>,
+    family: IpFamily,
+    families: Vec<(HostPattern, IpFamily)>,
+    failover: Arc<Mutex<Failover>>,
+}
+
+impl Resolver {
+    /// Resolve with the configured name servers or provider, or the ones of the system
+    /// configuration when caching, optionally caching the names for their TTL within the `cache`
+    /// limits.
+    ///
+    /// Without name servers nor cache, names are resolved with the system resolver on each new
+    /// connection.
+    pub fn new(config: &DnsConfig, cache: Option<CacheLimits>) -> Result<Self> {
+        let hosts = config
+            .hosts
+            .iter()
+            .map(|(name, ip)| (name.to_ascii_lowercase(), *ip))
+            .collect();
+        let families = config
+            .families
+            .iter()
+            .map(|rule| (rule.host.clone(), rule.family))
+            .collect();
+        let servers = config.name_servers()?;
+        if servers.is_none() && cache.is_none() {
+            return Ok(Self {
+                servers: None,
+                cache: None,
+                hosts,
+                family: config.family,
+                families,
+                failover: Arc::default(),
+            });
+        }
+        let (system, mut options) = match servers {
+            None => system_conf::read_system_conf()?,
+            Some(_) => {
+                let mut options = ResolverOpts::default();
+                options.timeout = config.timeout;
+                options.attempts = config.attempts;
+                // query the servers one at a time, in order
+                options.num_concurrent_reqs = 1;
+                options.server_ordering_strategy = ServerOrderingStrategy::UserProvidedOrder;
+                // keep the search domains of the system, if any
+                let system = system_conf::read_system_conf()
+                    .map(|(system, _)| system)
+                    .unwrap_or_default();
+                (system, options)
+            }
+        };
+        // our cache replaces the one of the resolver, which ignores the TTL bounds
+        options.cache_size = 0;
+        options.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+
+        let resolvers = match servers {
+            None => vec![TokioAsyncResolver::tokio(system, options)],
+            Some(servers) => {
+                let rotations = if config.rotate { servers.len() } else { 1 };
+                (0..rotations)
+                    .map(|first| {
+                        let mut config = ResolverConfig::from_parts(
+                            system.domain().cloned(),
+                            system.search().to_vec(),
+                            vec![],
+                        );
+                        for server in servers.iter().cycle().skip(first).take(servers.len()) {
+                            for protocol in server {
+                                config.add_name_server(protocol.clone());
+                            }
+                        }
+                        TokioAsyncResolver::tokio(config, options.clone())
+                    })
+                    .collect()
+            }
+        };
+        Ok(Self {
+            servers: Some(Arc::new(NameServers {
+                resolvers,
+                next: AtomicUsize::new(0),
+            })),
+            cache: cache.map(|limits| Arc::new(DnsCache::new(limits))),
+            hosts,
+            family: config.family,
+            families,
+            failover: Arc::default(),
+        })
+    }
+
+    /// Record that the client connected to `addr` for `host`, to try the addresses it couldn't
+    /// connect to last.
+    pub fn connected(&self, host: &str, addr: SocketAddr) {
+        self.failover.lock().unwrap().connected(host, addr);
+    }
+
+    pub fn stats(&self) -> Option<DnsStats> {
+        let cache = self.cache.as_ref()?;
+        Some(DnsStats {
+            hits: cache.hits.load(Ordering::Relaxed),
+            misses: cache.misses.load(Ordering::Relaxed),
+            entries: cache.entries.lock().unwrap().len(),
+            prefetches: cache.prefetches.load(Ordering::Relaxed),
+        })
+    }
+
+    /// Resolve the most requested names again shortly before they expire.
+    pub async fn prefetch_loop(self: Arc<Self>) {
+        let (Some(servers), Some(cache)) = (&self.servers, &self.cache) else {
+            return;
+        };
+        if cache.prefetch == 0 {
+            return;
+        }
+        let mut interval = tokio::time::interval(PREFETCH_INTERVAL);
+        loop {
+            interval.tick().await;
+            for name in cache.expiring() {
+                match servers.lookup(&name).await {
+                    Ok((ips, valid_until)) => {
+                        cache.prefetches.fetch_add(1, Ordering::Relaxed);
+                        cache.insert(&name, ips, valid_until, false);
+                    }
+                    // the name is resolved again by its next request
+                    Err(err) => tracing::debug!(host = name, error = %err, "Could not prefetch"),
+                }
+            }
+        }
+    }
+}
+
+impl Resolve for Resolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        if let Some(&ip) = self.hosts.get(&name.as_str().to_ascii_lowercase()) {
+            let addrs: Addrs = Box::new(std::iter::once(SocketAddr::new(ip, 0)));
+            return Box::pin(std::future::ready(Ok(addrs)));
+        }
+        let servers = self.servers.clone();
+        let cache = self.cache.clone();
+        let failover = self.failover.clone();
+        let family = self
+            .families
+            .iter()
+            .find(|(host, _)| host.matches(name.as_str()))
+            .map_or(self.family, |(_, family)| *family);
+        Box::pin(async move {
+            let start = Instant::now();
+            let name = name.as_str();
+            let mut addrs: Vec<SocketAddr> = match servers {
+                Some(servers) => {
+                    let cached = cache.as_ref().and_then(|cache| cache.get(name));
+                    let ips = match cached {
+                        Some(ips) => ips,
+                        None => {
+                            let (ips, valid_until) = servers.lookup(name).await?;
+                            if let Some(cache) = &cache {
+                                cache.insert(name, ips.clone(), valid_until, true);
+                            }
+                            ips
+                        }
+                    };
+                    ips.into_iter().map(|ip| SocketAddr::new(ip, 0)).collect()
+                }
+                None => tokio::net::lookup_host((name, 0)).await?.collect(),
+            };
+            timing::record_dns(start.elapsed());
+            family.apply(&mut addrs);
+            if addrs.is_empty() {
+                return Err(anyhow::anyhow!("no address of {name} in its family").into());
+            }
+            failover.lock().unwrap().order(name, &mut addrs);
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
@@ -0,0 +1,158 @@
+//! Graceful shutdown, letting the requests in flight complete before exiting.
+//!
+//! On `SIGTERM`, the proxy reports unready right away but keeps accepting connections for the
+//! shutdown delay, so that the load balancers (e.g. the endpoints of a Kubernetes service) stop
+//! sending it traffic before it stops accepting connections.
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use axum::{
+    http::{header, HeaderValue, Version},
+    response::Response,
+};
+use serde::Serialize;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+};
+
+use crate::{
+    hooks::{Hook, ProxyError, RequestHead},
+    metrics::METRICS,
+};
+
+/// Drain progress, served by the admin API.
+#[derive(Serialize)]
+pub struct Progress {
+    stopping: bool,
+    draining: bool,
+    elapsed_secs: Option<f64>,
+    timeout_secs: f64,
+    active_connections: u64,
+}
+
+pub struct Drain {
+    /// Whether the proxy is shutting down, draining or waiting for the shutdown delay.
+    stopping: AtomicBool,
+    started: Mutex<Option<Instant>>,
+    /// Tells the listeners that the drain started.
+    sender: watch::Sender<bool>,
+    /// How long connections may take to complete once the drain started.
+    timeout: Duration,
+    /// How long connections are still accepted once shutting down.
+    delay: Duration,
+}
+
+impl Drain {
+    pub fn new(timeout: Duration, delay: Duration) -> Self {
+        Self {
+            stopping: AtomicBool::new(false),
+            started: Mutex::new(None),
+            sender: watch::channel(false).0,
+            timeout,
+            delay,
+        }
+    }
+
+    /// Start draining once the shutdown delay is over.
+    pub async fn stop(&self) {
+        self.stopping.store(true, Ordering::Relaxed);
+        if !self.delay.is_zero() {
+            tracing::info!(delay = ?self.delay, "Shutting down, draining after the delay");
+            tokio::time::sleep(self.delay).await;
+        }
+        self.start();
+    }
+
+    pub fn stopping(&self) -> bool {
+        self.stopping.load(Ordering::Relaxed)
+    }
+
+    /// Stop accepting connections, and close the idle ones.
+    pub fn start(&self) {
+        self.stopping.store(true, Ordering::Relaxed);
+        let mut started = self.started.lock().unwrap();
+        if started.is_none() {
+            *started = Some(Instant::now());
+            self.sender.send_replace(true);
+        }
+    }
+
+    fn draining(&self) -> bool {
+        *self.sender.borrow()
+    }
+
+    /// Wait until the drain starts.
+    pub async fn started(&self) {
+        let mut receiver = self.sender.subscribe();
+        // the sender lives as long as the drain
+        let _ = receiver.wait_for(|started| *started).await;
+    }
+
+    /// Wait until the connections are out of time to complete.
+    pub async fn expired(&self) {
+        self.started().await;
+        tokio::time::sleep(self.timeout).await;
+    }
+
+    pub fn progress(&self) -> Progress {
+        let started = *self.started.lock().unwrap();
+        Progress {
+            stopping: self.stopping(),
+            draining: started.is_some(),
+            elapsed_secs: started.map(|started| started.elapsed().as_secs_f64()),
+            timeout_secs: self.timeout.as_secs_f64(),
+            active_connections: METRICS.active_connections.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Hook asking HTTP/1 clients to close their connection once the drain started, so that they
+/// don't send further requests on it.
+pub struct CloseConnections(pub Arc<Drain>);
+
+impl CloseConnections {
+    fn close(&self, request: &RequestHead, mut response: Response) -> Response {
+        // HTTP/2 connections are closed with a `GOAWAY` frame instead
+        if request.version <= Version::HTTP_11 && self.0.draining() {
+            response
+                .headers_mut()
+                .insert(header::CONNECTION, HeaderValue::from_static("close"));
+        }
+        response
+    }
+}
+
+#[async_trait]
+impl Hook for CloseConnections {
+    async fn on_response(&self, request: &RequestHead, response: Response) -> Response {
+        self.close(request, response)
+    }
+
+    async fn on_error(
+        &self,
+        request: &RequestHead,
+        _error: &ProxyError,
+        response: Response,
+    ) -> Response {
+        self.close(request, response)
+    }
+}
+
+/// Wait for `SIGTERM` or `SIGINT`.
+pub async fn signal_received() -> Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    tokio::select! {
+        _ = terminate.recv() => tracing::info!("Received SIGTERM, shutting down"),
+        _ = interrupt.recv() => tracing::info!("Received SIGINT, shutting down"),
+    }
+    Ok(())
+}
@@ -0,0 +1,215 @@
+//! Error responses of the proxy, rendered in the configured format.
+use std::{
+    any::Any,
+    fmt, fs,
+    path::Path,
+    sync::{atomic::Ordering, Arc},
+};
+
+use anyhow::{Context, Result};
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{header, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use clap::ValueEnum;
+use serde_json::json;
+
+use crate::{metrics::METRICS, telemetry::REQUEST_ID_HEADER};
+
+/// Response header with the error code, for clients that don't read the body.
+const ERROR_HEADER: &str = "x-proxy-error";
+
+/// Page used for the HTML format when no template is configured.
+const DEFAULT_TEMPLATE: &str = "<!DOCTYPE html>
+<html>
+<head><title>{status}</title></head>
+<body>
+<h1>{status}</h1>
+<p>{message}</p>
+<p><small>Error code: {code}, request id: {request_id}</small></p>
+</body>
+</html>
+";
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum ErrorFormat {
+    /// Object with the status, error code, message and request id
+    Json,
+    /// Page from the template, with these fields as `{status}`, `{code}`, `{message}` and
+    /// `{request_id}` placeholders
+    Html,
+    /// The message only
+    Text,
+}
+
+/// Failure of an upstream request, attached as context to its error.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Gateway {
+    /// The connection to the origin couldn't be established.
+    Connect,
+    Timeout,
+    /// The origin closed the connection or sent an invalid response.
+    Protocol,
+    /// The body sent by the origin didn't match its digest.
+    Integrity,
+}
+
+impl Gateway {
+    pub fn of(err: &reqwest::Error) -> Self {
+        if err.is_timeout() {
+            Self::Timeout
+        } else if err.is_connect() {
+            Self::Connect
+        } else {
+            Self::Protocol
+        }
+    }
+
+    pub fn status(self) -> StatusCode {
+        match self {
+            Self::Connect | Self::Protocol | Self::Integrity => StatusCode::BAD_GATEWAY,
+            Self::Timeout => StatusCode::GATEWAY_TIMEOUT,
+        }
+    }
+
+    pub fn code(self) -> &'static str {
+        match self {
+            Self::Connect => "upstream_connect",
+            Self::Timeout => "upstream_timeout",
+            Self::Protocol => "upstream_protocol",
+            Self::Integrity => "upstream_integrity",
+        }
+    }
+}
+
+impl fmt::Display for Gateway {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Connect => "could not connect to the origin",
+            Self::Timeout => "the origin did not answer in time",
+            Self::Protocol => "invalid response from the origin",
+            Self::Integrity => "corrupted response from the origin",
+        })
+    }
+}
+
+/// Attached to the error responses of the proxy, telling how to render them.
+#[derive(Clone, Debug)]
+pub struct ProxyError {
+    code: &'static str,
+    message: String,
+}
+
+impl ProxyError {
+    /// Machine-readable code of the error, also sent in the `x-proxy-error` header.
+    pub fn code(&self) -> &'static str {
+        self.code
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+/// Error response of the proxy, whose body is the `message` until it is rendered.
+pub fn response(status: StatusCode, code: &'static str, message: impl Into<String>) -> Response {
+    let message = message.into();
+    let mut response = (status, [(ERROR_HEADER, code)], message.clone()).into_response();
+    response
+        .extensions_mut()
+        .insert(ProxyError { code, message });
+    response
+}
+
+/// Response to a request whose handling panicked, the other requests being unaffected.
+pub fn panicked(panic: Box<dyn Any + Send>) -> Response {
+    let message = panic
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_default();
+    METRICS.panics.fetch_add(1, Ordering::Relaxed);
+    tracing::error!(panic = message, "Request handling panicked");
+    response(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "internal_error",
+        "Something went wrong",
+    )
+}
+
+pub struct ErrorPages {
+    format: ErrorFormat,
+    template: String,
+}
+
+impl ErrorPages {
+    pub fn new(format: ErrorFormat, template: Option<&Path>) -> Result<Self> {
+        let template = match template {
+            Some(path) => fs::read_to_string(path)
+                .with_context(|| format!("could not read error template {}", path.display()))?,
+            None => DEFAULT_TEMPLATE.to_string(),
+        };
+        Ok(Self { format, template })
+    }
+
+    fn html(&self, status: StatusCode, error: &ProxyError, request_id: &str) -> String {
+        self.template
+            .replace("{status}", &escape(&status.to_string()))
+            .replace("{code}", error.code)
+            .replace("{message}", &escape(&error.message))
+            .replace("{request_id}", &escape(request_id))
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// Middleware rendering the error responses of the proxy in the configured format.
+pub async fn render(
+    State(pages): State<Arc<ErrorPages>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let request_id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    let response = next.run(request).await;
+    let Some(error) = response.extensions().get::<ProxyError>().cloned() else {
+        return response;
+    };
+    let status = response.status();
+    let (content_type, body) = match pages.format {
+        ErrorFormat::Json => (
+            "application/json",
+            json!({
+                "status": status.as_u16(),
+                "code": error.code,
+                "message": error.message,
+                "request_id": request_id,
+            })
+            .to_string(),
+        ),
+        ErrorFormat::Html => (
+            "text/html; charset=utf-8",
+            pages.html(status, &error, &request_id),
+        ),
+        ErrorFormat::Text => return response,
+    };
+    let (mut parts, _) = response.into_parts();
+    parts
+        .headers
+        .insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(parts, Body::from(body))
+}
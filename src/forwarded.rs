@@ -0,0 +1,139 @@
+//! `X-Forwarded-Proto`, `X-Forwarded-Host` and `X-Forwarded-Port` headers of the upstream requests,
+//! telling the origins how the clients reached the proxy, e.g. for the absolute URLs of their links.
+//!
+//! The values sent by the clients are only believed from the trusted addresses, like the ones of a
+//! TLS-terminating front, and replaced by the ones of the client request otherwise. With chains of
+//! fronts, the first value of each header is the one of the original client.
+use std::net::IpAddr;
+
+use axum::http::{header, uri::Authority, HeaderMap, HeaderValue};
+use serde::Deserialize;
+
+pub const PROTO_HEADER: &str = "x-forwarded-proto";
+pub const HOST_HEADER: &str = "x-forwarded-host";
+pub const PORT_HEADER: &str = "x-forwarded-port";
+pub const HEADERS: [&str; 3] = [PROTO_HEADER, HOST_HEADER, PORT_HEADER];
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ForwardedConfig {
+    /// Addresses whose `X-Forwarded-*` headers are believed, e.g. `10.0.0.0/8` or `192.168.1.5`.
+    pub trusted: Vec<Cidr>,
+}
+
+/// A range of IP addresses, a single one if the prefix length is omitted.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let (network, ip, bits) = match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                (u32::from(network).into(), u32::from(ip).into(), 32)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => (u128::from(network), u128::from(ip), 128),
+            _ => return false,
+        };
+        self.prefix == 0 || (network ^ ip) >> (bits - self.prefix) == 0
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(range: String) -> Result<Self, String> {
+        let (network, prefix) = match range.split_once('/') {
+            Some((network, prefix)) => (network, Some(prefix)),
+            None => (range.as_str(), None),
+        };
+        let network: IpAddr = network
+            .parse()
+            .map_err(|_| format!("invalid address in `{range}`"))?;
+        let bits = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|prefix| *prefix <= bits)
+                .ok_or_else(|| format!("invalid prefix length in `{range}`"))?,
+            None => bits,
+        };
+        Ok(Self { network, prefix })
+    }
+}
+
+pub struct Forwarded {
+    trusted: Vec<Cidr>,
+}
+
+impl Forwarded {
+    pub fn new(config: ForwardedConfig) -> Self {
+        Self {
+            trusted: config.trusted,
+        }
+    }
+
+    /// Replace the `X-Forwarded-*` headers of a request from `peer` with the ones sent to the
+    /// origin.
+    pub fn apply(&self, peer: IpAddr, headers: &mut HeaderMap) {
+        let trusted = self.trusted.iter().any(|range| range.contains(peer));
+        let (proto, host, port) = if trusted {
+            (
+                inbound(headers, PROTO_HEADER, parse_proto),
+                inbound(headers, HOST_HEADER, parse_host),
+                inbound(headers, PORT_HEADER, |port| port.parse::<u16>().ok()),
+            )
+        } else {
+            if HEADERS.iter().any(|name| headers.contains_key(*name)) {
+                tracing::debug!("Replacing forwarded headers of untrusted client");
+            }
+            (None, None, None)
+        };
+        // the listeners only speak plain HTTP
+        let proto = proto.unwrap_or("http");
+        let host = host.or_else(|| {
+            let host = headers.get(header::HOST)?.to_str().ok()?;
+            parse_host(host)
+        });
+        let port = port
+            .or_else(|| host.as_ref().and_then(Authority::port_u16))
+            .unwrap_or(if proto == "https" { 443 } else { 80 });
+        for name in HEADERS {
+            headers.remove(name);
+        }
+        headers.insert(PROTO_HEADER, HeaderValue::from_static(proto));
+        if let Some(Ok(host)) = host.map(|host| HeaderValue::from_str(host.as_str())) {
+            headers.insert(HOST_HEADER, host);
+        }
+        headers.insert(PORT_HEADER, HeaderValue::from(port));
+    }
+}
+
+/// The value of the header `name` set by a trusted front, the first one if it's a list.
+fn inbound<T>(headers: &HeaderMap, name: &str, parse: impl FnOnce(&str) -> Option<T>) -> Option<T> {
+    let value = headers.get(name)?;
+    let parsed = value
+        .to_str()
+        .ok()
+        .and_then(|value| parse(value.split(',').next().unwrap_or_default().trim()));
+    if parsed.is_none() {
+        tracing::warn!(header = name, ?value, "Ignoring invalid forwarded header");
+    }
+    parsed
+}
+
+fn parse_proto(proto: &str) -> Option<&'static str> {
+    ["http", "https"]
+        .into_iter()
+        .find(|known| proto.eq_ignore_ascii_case(known))
+}
+
+fn parse_host(host: &str) -> Option<Authority> {
+    host.parse::<Authority>()
+        .ok()
+        .filter(|host| !host.as_str().contains('@'))
+}
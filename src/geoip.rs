@@ -0,0 +1,391 @@
+//! Access control of the clients by the country of their IP address, from a MaxMind DB like
+//! GeoLite2 Country, as a defense in depth for the deployments exposed to the internet.
+//!
+//! The connections from the denied countries are closed as soon as they are accepted, before any
+//! request. The database is read whole at startup, and only the parts of the format leading to the
+//! country of an address are decoded.
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use serde::Deserialize;
+
+use crate::{anonymize, metrics::METRICS};
+
+/// Start of the metadata section, at the end of the file.
+const METADATA_MARKER: &[u8] = b"\xab\xcd\xefMaxMind.com";
+
+/// Nesting of the decoded values, beyond which the database is considered corrupted.
+const MAX_DEPTH: usize = 16;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GeoIpConfig {
+    /// Path of the MaxMind DB, e.g. GeoLite2 Country or City.
+    pub database: PathBuf,
+    /// ISO codes of the countries the clients may connect from, all of them if empty.
+    #[serde(default)]
+    pub allowed_countries: Vec<String>,
+    /// ISO codes of the countries the clients may not connect from, even if allowed.
+    #[serde(default)]
+    pub denied_countries: Vec<String>,
+    /// Deny the addresses of no known country, like the private ones.
+    #[serde(default)]
+    pub deny_unknown: bool,
+}
+
+pub struct GeoIp {
+    database: Database,
+    allowed: Vec<String>,
+    denied: Vec<String>,
+    deny_unknown: bool,
+}
+
+impl GeoIp {
+    pub fn open(config: GeoIpConfig) -> Result<Self> {
+        let path = &config.database;
+        let data = std::fs::read(path)
+            .with_context(|| format!("could not read GeoIP database {}", path.display()))?;
+        let database = Database::parse(data)
+            .with_context(|| format!("invalid GeoIP database {}", path.display()))?;
+        let upper = |countries: Vec<String>| {
+            countries
+                .into_iter()
+                .map(|country| country.to_ascii_uppercase())
+                .collect()
+        };
+        Ok(Self {
+            database,
+            allowed: upper(config.allowed_countries),
+            denied: upper(config.denied_countries),
+            deny_unknown: config.deny_unknown,
+        })
+    }
+
+    /// Whether a client connecting from `addr` is allowed, counted in the metrics by country.
+    pub fn allows(&self, addr: SocketAddr) -> bool {
+        let country = self.database.country(addr.ip());
+        let allowed = match &country {
+            Some(country) => {
+                !self.denied.contains(country)
+                    && (self.allowed.is_empty() || self.allowed.contains(country))
+            }
+            None => !self.deny_unknown,
+        };
+        let country = country.as_deref().unwrap_or("unknown");
+        METRICS.record_geoip(country, allowed);
+        if !allowed {
+            tracing::warn!(
+                peer = anonymize::peer(addr),
+                country,
+                "Rejected connection from denied country"
+            );
+        }
+        allowed
+    }
+}
+
+/// Decoded value of the data section, keeping only what is needed.
+enum Value {
+    String(String),
+    Uint(u128),
+    Map(Vec<(String, Value)>),
+    Array,
+    Other,
+}
+
+impl Value {
+    fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Self::Map(entries) => entries
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::String(string) => Some(string),
+            _ => None,
+        }
+    }
+
+    fn as_uint(&self) -> Option<u128> {
+        match self {
+            Self::Uint(uint) => Some(*uint),
+            _ => None,
+        }
+    }
+}
+
+/// A MaxMind DB: a binary search tree of the address bits, whose leaves point to the records of
+/// the data section.
+struct Database {
+    data: Vec<u8>,
+    node_count: usize,
+    /// Bits of each of the two records of a node.
+    record_size: usize,
+    /// 4 for the IPv4 only databases, 6 for the others.
+    ip_version: u128,
+    /// Offset of the data section.
+    data_start: usize,
+    /// Node of the `::/96` subtree, where the IPv4 addresses are in the IPv6 databases.
+    ipv4_start: usize,
+}
+
+impl Database {
+    fn parse(data: Vec<u8>) -> Result<Self> {
+        let marker = data
+            .windows(METADATA_MARKER.len())
+            .rposition(|window| window == METADATA_MARKER)
+            .context("no metadata")?;
+        let metadata_start = marker + METADATA_MARKER.len();
+        let Some((metadata, _)) = decode(&data[metadata_start..], 0, 0) else {
+            bail!("invalid metadata");
+        };
+        let field = |name| {
+            metadata
+                .get(name)
+                .and_then(Value::as_uint)
+                .with_context(|| format!("no {name} in the metadata"))
+        };
+        let node_count = field("node_count")? as usize;
+        let record_size = field("record_size")? as usize;
+        let ip_version = field("ip_version")?;
+        ensure!(
+            matches!(record_size, 24 | 28 | 32),
+            "unsupported record size {record_size}"
+        );
+        // the search tree is followed by 16 zero bytes
+        let data_start = node_count * record_size / 4 + 16;
+        ensure!(data_start <= marker, "truncated search tree");
+        let mut database = Self {
+            data,
+            node_count,
+            record_size,
+            ip_version,
+            data_start,
+            ipv4_start: 0,
+        };
+        if ip_version == 6 {
+            let mut node = 0;
+            for _ in 0..96 {
+                if node >= node_count {
+                    break;
+                }
+                node = database.record(node, 0).context("truncated search tree")?;
+            }
+            database.ipv4_start = node;
+        }
+        Ok(database)
+    }
+
+    /// Record `bit` of `node`, the left one for 0.
+    fn record(&self, node: usize, bit: u8) -> Option<usize> {
+        let size = self.record_size / 4;
+        let bytes = self.data.get(node * size..(node + 1) * size)?;
+        let be = |bytes: &[u8]| {
+            bytes
+                .iter()
+                .fold(0, |value, byte| value << 8 | usize::from(*byte))
+        };
+        Some(match (self.record_size, bit) {
+            (24, 0) => be(&bytes[..3]),
+            (24, _) => be(&bytes[3..]),
+            // the middle byte holds the high bits of both records
+            (28, 0) => usize::from(bytes[3] >> 4) << 24 | be(&bytes[..3]),
+            (28, _) => usize::from(bytes[3] & 0x0f) << 24 | be(&bytes[4..]),
+            (_, 0) => be(&bytes[..4]),
+            (_, _) => be(&bytes[4..]),
+        })
+    }
+
+    /// ISO code of the country of `ip`, or else of the country it is registered in.
+    fn country(&self, ip: IpAddr) -> Option<String> {
+        let (address, bits, mut node) = match ip.to_canonical() {
+            IpAddr::V4(ip) => (u128::from(u32::from(ip)), 32, self.ipv4_start),
+            IpAddr::V6(_) if self.ip_version == 4 => return None,
+            IpAddr::V6(ip) => (u128::from(ip), 128, 0),
+        };
+        for i in (0..bits).rev() {
+            if node >= self.node_count {
+                break;
+            }
+            node = self.record(node, ((address >> i) & 1) as u8)?;
+        }
+        // the node count itself means no data
+        if node <= self.node_count {
+            return None;
+        }
+        let offset = node - self.node_count - 16;
+        let (record, _) = decode(self.data.get(self.data_start..)?, offset, 0)?;
+        ["country", "registered_country"].iter().find_map(|name| {
+            let code = record.get(name)?.get("iso_code")?.as_str()?;
+            Some(code.to_string())
+        })
+    }
+}
+
+/// Decode the value at `offset` of `section`, which pointers are relative to, along with the
+/// offset following it.
+fn decode(section: &[u8], offset: usize, depth: usize) -> Option<(Value, usize)> {
+    if depth > MAX_DEPTH {
+        return None;
+    }
+    let be = |from: usize, len: usize| {
+        let bytes = section.get(from..from.checked_add(len)?)?;
+        Some(
+            bytes
+                .iter()
+                .fold(0_u128, |value, byte| value << 8 | u128::from(*byte)),
+        )
+    };
+    let control = *section.get(offset)?;
+    let mut offset = offset + 1;
+    let mut kind = control >> 5;
+    if kind == 1 {
+        // pointers have 3 size bits, and up to 3 more bits of their value
+        let len = usize::from(control >> 3 & 0x3) + 1;
+        let value = be(offset, len)? as usize;
+        let pointer = match len {
+            1 => usize::from(control & 0x7) << 8 | value,
+            2 => (usize::from(control & 0x7) << 16 | value) + 2048,
+            3 => (usize::from(control & 0x7) << 24 | value) + 526_336,
+            _ => value,
+        };
+        let (value, _) = decode(section, pointer, depth + 1)?;
+        return Some((value, offset + len));
+    }
+    if kind == 0 {
+        kind = 7 + *section.get(offset)?;
+        offset += 1;
+    }
+    let mut size = usize::from(control & 0x1f);
+    if size >= 29 {
+        let len = size - 28;
+        size = [29, 285, 65_821][len - 1] + be(offset, len)? as usize;
+        offset += len;
+    }
+    match kind {
+        // UTF-8 string
+        2 => {
+            let bytes = section.get(offset..offset.checked_add(size)?)?;
+            let string = String::from_utf8_lossy(bytes).into_owned();
+            Some((Value::String(string), offset + size))
+        }
+        // unsigned integers, of up to 128 bits
+        5 | 6 | 9 | 10 if size <= 16 => Some((Value::Uint(be(offset, size)?), offset + size)),
+        7 => {
+            let mut entries = Vec::new();
+            for _ in 0..size {
+                let (key, next) = decode(section, offset, depth + 1)?;
+                let Value::String(key) = key else {
+                    return None;
+                };
+                let (value, next) = decode(section, next, depth + 1)?;
+                entries.push((key, value));
+                offset = next;
+            }
+            Some((Value::Map(entries), offset))
+        }
+        11 => {
+            for _ in 0..size {
+                (_, offset) = decode(section, offset, depth + 1)?;
+            }
+            Some((Value::Array, offset))
+        }
+        // booleans, whose size is the value
+        14 => Some((Value::Other, offset)),
+        // doubles, bytes, signed integers and floats
+        3 | 4 | 8 | 15 => {
+            section.get(offset..offset.checked_add(size)?)?;
+            Some((Value::Other, offset + size))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // databases of `1.0.0.0/8` in AU, `2.0.0.0/9` registered in FR, `3.0.0.0/8` pointing to the
+    // country of the first record and, in the IPv6 ones, `2001:db8::/32` in DE
+    const IPV4: &[u8] = include_bytes!("../testdata/geoip/country-24-ipv4.mmdb");
+    const IPV6: [&[u8]; 3] = [
+        include_bytes!("../testdata/geoip/country-24-ipv6.mmdb"),
+        include_bytes!("../testdata/geoip/country-28-ipv6.mmdb"),
+        include_bytes!("../testdata/geoip/country-32-ipv6.mmdb"),
+    ];
+
+    fn country(database: &Database, ip: &str) -> Option<String> {
+        database.country(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn search_tree() {
+        for data in IPV6 {
+            let database = Database::parse(data.to_vec()).unwrap();
+            assert_eq!(database.ip_version, 6);
+            assert_eq!(country(&database, "1.2.3.4").as_deref(), Some("AU"));
+            assert_eq!(country(&database, "::ffff:1.2.3.4").as_deref(), Some("AU"));
+            assert_eq!(country(&database, "2.127.0.1").as_deref(), Some("FR"));
+            assert_eq!(country(&database, "2.128.0.1"), None);
+            assert_eq!(country(&database, "3.0.0.1").as_deref(), Some("AU"));
+            assert_eq!(country(&database, "2001:db8::1").as_deref(), Some("DE"));
+            assert_eq!(country(&database, "2001:db9::1"), None);
+            assert_eq!(country(&database, "127.0.0.1"), None);
+        }
+
+        let database = Database::parse(IPV4.to_vec()).unwrap();
+        assert_eq!(database.ip_version, 4);
+        assert_eq!(database.record_size, 24);
+        assert_eq!(country(&database, "1.2.3.4").as_deref(), Some("AU"));
+        assert_eq!(country(&database, "3.0.0.1").as_deref(), Some("AU"));
+        assert_eq!(country(&database, "2001:db8::1"), None);
+    }
+
+    #[test]
+    fn corrupted() {
+        assert!(Database::parse(b"not a database".to_vec()).is_err());
+        // the search tree cut off before the metadata
+        let data = IPV6[0];
+        let marker = data
+            .windows(METADATA_MARKER.len())
+            .rposition(|window| window == METADATA_MARKER)
+            .unwrap();
+        let truncated = [&data[..64], &data[marker..]].concat();
+        assert!(Database::parse(truncated).is_err());
+        // pointers to themselves
+        assert!(decode(&[0x20, 0x00], 0, 0).is_none());
+    }
+
+    #[test]
+    fn allows() {
+        let geoip = |toml: &str| {
+            let mut config: GeoIpConfig = toml::from_str(toml).unwrap();
+            config.database = [
+                env!("CARGO_MANIFEST_DIR"),
+                "testdata/geoip/country-24-ipv6.mmdb",
+            ]
+            .iter()
+            .collect();
+            GeoIp::open(config).unwrap()
+        };
+        let addr = |ip: &str| SocketAddr::new(ip.parse().unwrap(), 443);
+
+        let geoip_denied = geoip("database = ''\ndenied_countries = ['de']");
+        assert!(geoip_denied.allows(addr("1.2.3.4")));
+        assert!(!geoip_denied.allows(addr("2001:db8::1")));
+        assert!(geoip_denied.allows(addr("127.0.0.1")));
+
+        let geoip_allowed = geoip("database = ''\nallowed_countries = ['AU']\ndeny_unknown = true");
+        assert!(geoip_allowed.allows(addr("1.2.3.4")));
+        assert!(!geoip_allowed.allows(addr("2.0.0.1")));
+        assert!(!geoip_allowed.allows(addr("127.0.0.1")));
+    }
+}
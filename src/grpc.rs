@@ -0,0 +1,555 @@
+//! gRPC control plane, served on the admin address next to the admin API, for the controllers of a
+//! fleet of proxies.
+//!
+//! The clients connect over HTTP/2 without TLS, with prior knowledge, and the admin listener tells
+//! them apart from the HTTP/1 clients of the admin API by the preface of their connection. The
+//! service is described by `proto/control.proto`. Like the admin API, it requires the
+//! `ADMIN_TOKEN` as a bearer token in the `authorization` metadata, if set. The messages can't be
+//! compressed.
+use std::{future::poll_fn, sync::Arc, time::Duration};
+
+use axum::body::Bytes;
+use h2::{server::SendResponse, RecvStream};
+use http02::{HeaderMap, HeaderValue, Request, Response};
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use prost::Message;
+use tokio::net::TcpStream;
+
+use crate::{
+    config::Config,
+    metrics::{self, Snapshot},
+    presign::constant_time_eq,
+    AppState,
+};
+
+/// First bytes sent by the HTTP/2 clients.
+const PREFACE: &[u8; 24] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// Time the clients have to send the first bytes of their connection.
+const PREFACE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Prefix of the paths of the methods.
+const SERVICE: &str = "/simple_proxy.control.v1.Control/";
+
+/// Largest request message, the default limit of the gRPC implementations.
+const MAX_MESSAGE_SIZE: usize = 4 << 20;
+
+/// Interval of the stats when the client doesn't ask for one, and the shortest one.
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(1);
+const MIN_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Bytes percent-encoded in the `grpc-message` trailer.
+const MESSAGE_ENCODED: &AsciiSet = &CONTROLS.add(b'%');
+
+#[derive(Clone, PartialEq, Message)]
+struct GetConfigRequest {}
+
+#[derive(Clone, PartialEq, Message)]
+struct GetConfigResponse {
+    #[prost(string, tag = "1")]
+    settings: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct PushConfigRequest {
+    #[prost(string, tag = "1")]
+    config: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct PushConfigResponse {}
+
+#[derive(Clone, PartialEq, Message)]
+struct SetAuthTokenRequest {
+    #[prost(string, tag = "1")]
+    auth_token: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct SetAuthTokenResponse {}
+
+#[derive(Clone, PartialEq, Message)]
+struct ReloadCredentialsRequest {}
+
+#[derive(Clone, PartialEq, Message)]
+struct ReloadCredentialsResponse {}
+
+#[derive(Clone, PartialEq, Message)]
+struct StreamStatsRequest {
+    #[prost(uint32, tag = "1")]
+    interval_ms: u32,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Stats {
+    #[prost(uint64, tag = "1")]
+    uptime_secs: u64,
+    #[prost(uint64, tag = "2")]
+    active_connections: u64,
+    #[prost(uint64, tag = "3")]
+    idle_connections: u64,
+    #[prost(btree_map = "string, uint64", tag = "4")]
+    responses: std::collections::BTreeMap<String, u64>,
+    #[prost(uint64, tag = "5")]
+    auth_failures: u64,
+    #[prost(uint64, tag = "6")]
+    shed_requests: u64,
+    #[prost(uint64, tag = "7")]
+    upstream_bytes_received: u64,
+    #[prost(uint64, tag = "8")]
+    client_bytes_sent: u64,
+    #[prost(message, repeated, tag = "9")]
+    top_hosts: Vec<HostRequests>,
+    #[prost(uint64, optional, tag = "10")]
+    memory_bytes: Option<u64>,
+    #[prost(uint64, optional, tag = "11")]
+    active_sessions: Option<u64>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct HostRequests {
+    #[prost(string, tag = "1")]
+    host: String,
+    #[prost(uint64, tag = "2")]
+    requests: u64,
+}
+
+impl From<Snapshot> for Stats {
+    fn from(snapshot: Snapshot) -> Self {
+        Self {
+            uptime_secs: snapshot.uptime_secs,
+            active_connections: snapshot.active_connections,
+            idle_connections: snapshot.idle_connections,
+            responses: snapshot.responses,
+            auth_failures: snapshot.auth_failures,
+            shed_requests: snapshot.shed_requests,
+            upstream_bytes_received: snapshot.upstream_bytes_received,
+            client_bytes_sent: snapshot.client_bytes_sent,
+            top_hosts: snapshot
+                .top_hosts
+                .into_iter()
+                .map(|(host, requests)| HostRequests { host, requests })
+                .collect(),
+            memory_bytes: snapshot.memory_bytes,
+            active_sessions: snapshot.active_sessions.map(|sessions| sessions as u64),
+        }
+    }
+}
+
+/// Status codes of gRPC.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Code {
+    Ok = 0,
+    InvalidArgument = 3,
+    ResourceExhausted = 8,
+    FailedPrecondition = 9,
+    Unimplemented = 12,
+    Internal = 13,
+    Unauthenticated = 16,
+}
+
+#[derive(Debug)]
+struct Status {
+    code: Code,
+    message: String,
+}
+
+impl Status {
+    fn new(code: Code, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+
+    /// The `grpc-status` and `grpc-message` headers of the status.
+    fn headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("grpc-status", HeaderValue::from(self.code as u16));
+        if !self.message.is_empty() {
+            let message = utf8_percent_encode(&self.message, MESSAGE_ENCODED).to_string();
+            if let Ok(message) = HeaderValue::try_from(message) {
+                headers.insert("grpc-message", message);
+            }
+        }
+        headers
+    }
+}
+
+/// A reply to a call, before it is sent.
+enum Reply {
+    Message(Vec<u8>),
+    /// Stats streamed at an interval.
+    Stats(Duration),
+}
+
+/// Whether the client of `stream` speaks HTTP/2, from the first bytes it sent.
+pub async fn is_http2(stream: &TcpStream) -> bool {
+    let mut buf = [0; PREFACE.len()];
+    let peek = async {
+        loop {
+            let len = stream.peek(&mut buf).await.ok()?;
+            if len == 0 || buf[..len] != PREFACE[..len] {
+                return Some(false);
+            }
+            if len == PREFACE.len() {
+                return Some(true);
+            }
+            // the rest of the preface is yet to come
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+    matches!(
+        tokio::time::timeout(PREFACE_TIMEOUT, peek).await,
+        Ok(Some(true))
+    )
+}
+
+pub struct Control {
+    state: AppState,
+    token: Option<Arc<str>>,
+    /// Effective settings, from the command line and the config file.
+    settings: Arc<str>,
+}
+
+impl Control {
+    pub fn new(state: AppState, token: Option<Arc<str>>, settings: Arc<str>) -> Self {
+        Self {
+            state,
+            token,
+            settings,
+        }
+    }
+
+    /// Serve the calls of the HTTP/2 connection `stream`.
+    pub async fn serve(self: Arc<Self>, stream: TcpStream) {
+        let mut connection = match h2::server::handshake(stream).await {
+            Ok(connection) => connection,
+            Err(err) => {
+                tracing::debug!(error = %err, "gRPC handshake failed");
+                return;
+            }
+        };
+        while let Some(accepted) = connection.accept().await {
+            match accepted {
+                Ok((request, respond)) => {
+                    tokio::spawn(self.clone().call(request, respond));
+                }
+                Err(err) => {
+                    tracing::debug!(error = %err, "gRPC connection failed");
+                    return;
+                }
+            }
+        }
+    }
+
+    async fn call(self: Arc<Self>, request: Request<RecvStream>, mut respond: SendResponse<Bytes>) {
+        let path = request.uri().path().to_string();
+        let method = path.strip_prefix(SERVICE).unwrap_or_default();
+        let sent = match self.reply(method, request).await {
+            Ok(Reply::Message(message)) => send_message(&mut respond, &message),
+            Ok(Reply::Stats(interval)) => self.stream_stats(&mut respond, interval).await,
+            Err(status) => {
+                if status.code == Code::Internal {
+                    tracing::error!(method, error = status.message, "gRPC call failed");
+                }
+                respond
+                    .send_response(response(status.headers()), true)
+                    .map(drop)
+            }
+        };
+        if let Err(err) = sent {
+            tracing::debug!(method, error = %err, "Could not send gRPC response");
+        }
+    }
+
+    async fn reply(&self, method: &str, request: Request<RecvStream>) -> Result<Reply, Status> {
+        if !self.authorized(request.headers()) {
+            tracing::error!("Unauthorized gRPC control plane access attempt");
+            return Err(Status::new(Code::Unauthenticated, "Unauthorized"));
+        }
+        let message = read_message(request.into_body()).await?;
+        let reply = match method {
+            "GetConfig" => {
+                decode::<GetConfigRequest>(&message)?;
+                GetConfigResponse {
+                    settings: self.settings.to_string(),
+                }
+                .encode_to_vec()
+            }
+            "PushConfig" => {
+                let request = decode::<PushConfigRequest>(&message)?;
+                let config: Config = toml::from_str(&request.config).map_err(|err| {
+                    Status::new(Code::InvalidArgument, format!("invalid config: {err}"))
+                })?;
+                self.state
+                    .apply_config(config)
+                    .map_err(|err| Status::new(Code::InvalidArgument, format!("{err:#}")))?;
+                tracing::info!("Applied pushed config");
+                PushConfigResponse {}.encode_to_vec()
+            }
+            "SetAuthToken" => {
+                let request = decode::<SetAuthTokenRequest>(&message)?;
+                if request.auth_token.is_empty() {
+                    return Err(Status::new(Code::InvalidArgument, "empty auth token"));
+                }
+                *self.state.auth_token.write().unwrap() = request.auth_token;
+                tracing::info!("Set auth token");
+                SetAuthTokenResponse {}.encode_to_vec()
+            }
+            "ReloadCredentials" => {
+                decode::<ReloadCredentialsRequest>(&message)?;
+                crate::reload_auth_token(&self.state)
+                    .map_err(|err| Status::new(Code::FailedPrecondition, format!("{err:#}")))?;
+                tracing::info!("Reloaded credentials");
+                ReloadCredentialsResponse {}.encode_to_vec()
+            }
+            "StreamStats" => {
+                let request = decode::<StreamStatsRequest>(&message)?;
+                let interval = match request.interval_ms {
+                    0 => DEFAULT_INTERVAL,
+                    interval => Duration::from_millis(interval.into()).max(MIN_INTERVAL),
+                };
+                return Ok(Reply::Stats(interval));
+            }
+            _ => {
+                return Err(Status::new(
+                    Code::Unimplemented,
+                    format!("unknown method `{method}`"),
+                ))
+            }
+        };
+        Ok(Reply::Message(reply))
+    }
+
+    fn authorized(&self, metadata: &HeaderMap) -> bool {
+        let Some(token) = &self.token else {
+            return true;
+        };
+        metadata
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|bearer| constant_time_eq(bearer, token))
+    }
+
+    /// Send the stats at `interval` until the client cancels the call.
+    async fn stream_stats(
+        &self,
+        respond: &mut SendResponse<Bytes>,
+        interval: Duration,
+    ) -> Result<(), h2::Error> {
+        let mut stream = respond.send_response(response(HeaderMap::new()), false)?;
+        let mut ticks = tokio::time::interval(interval);
+        ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            let reset = tokio::select! {
+                _ = ticks.tick() => None,
+                reset = poll_fn(|cx| stream.poll_reset(cx)) => Some(reset),
+            };
+            if let Some(reset) = reset {
+                tracing::debug!(reason = ?reset, "gRPC stats stream cancelled");
+                return Ok(());
+            }
+            let stats = Stats::from(metrics::snapshot(&self.state));
+            stream.send_data(frame(&stats.encode_to_vec()), false)?;
+        }
+    }
+}
+
+/// A response with the content type of gRPC and `headers`.
+fn response(headers: HeaderMap) -> Response<()> {
+    let mut response = Response::new(());
+    response.headers_mut().insert(
+        "content-type",
+        HeaderValue::from_static("application/grpc+proto"),
+    );
+    response.headers_mut().extend(headers);
+    response
+}
+
+fn send_message(respond: &mut SendResponse<Bytes>, message: &[u8]) -> Result<(), h2::Error> {
+    let mut stream = respond.send_response(response(HeaderMap::new()), false)?;
+    stream.send_data(frame(message), false)?;
+    stream.send_trailers(Status::new(Code::Ok, "").headers())
+}
+
+/// `message` with its prefix of a compression flag and a length.
+fn frame(message: &[u8]) -> Bytes {
+    let mut frame = Vec::with_capacity(5 + message.len());
+    frame.push(0);
+    frame.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    frame.extend_from_slice(message);
+    frame.into()
+}
+
+/// The message of a request `body`, of the unary and server-streaming methods.
+async fn read_message(mut body: RecvStream) -> Result<Vec<u8>, Status> {
+    let mut data = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|err| Status::new(Code::Internal, err.to_string()))?;
+        let _ = body.flow_control().release_capacity(chunk.len());
+        data.extend_from_slice(&chunk);
+        if data.len() > 5 + MAX_MESSAGE_SIZE {
+            return Err(Status::new(Code::ResourceExhausted, "message too large"));
+        }
+    }
+    unframe(&data).map(<[u8]>::to_vec)
+}
+
+/// The message of the request data `data`, which must be exactly one uncompressed message.
+fn unframe(data: &[u8]) -> Result<&[u8], Status> {
+    let Some((&[compressed, length @ ..], message)) = data.split_first_chunk::<5>() else {
+        return Err(Status::new(
+            Code::InvalidArgument,
+            "missing request message",
+        ));
+    };
+    if compressed != 0 {
+        return Err(Status::new(
+            Code::Unimplemented,
+            "compressed messages are not supported",
+        ));
+    }
+    if message.len() != u32::from_be_bytes(length) as usize {
+        return Err(Status::new(
+            Code::InvalidArgument,
+            "expected exactly one request message",
+        ));
+    }
+    Ok(message)
+}
+
+fn decode<M: Message + Default>(message: &[u8]) -> Result<M, Status> {
+    M::decode(message)
+        .map_err(|err| Status::new(Code::InvalidArgument, format!("invalid message: {err}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::ProxyBuilder;
+
+    /// The reply messages of a call of `method` with `message` to the admin API at `addr`, and the
+    /// trailers, or the headers of a reply without messages.
+    async fn call(
+        addr: std::net::SocketAddr,
+        method: &str,
+        message: &impl Message,
+        replies: usize,
+    ) -> (Vec<Vec<u8>>, HeaderMap) {
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (mut client, connection) = h2::client::handshake(stream).await.unwrap();
+        tokio::spawn(connection);
+        let request = Request::post(format!("http://{addr}{SERVICE}{method}"))
+            .header("content-type", "application/grpc")
+            .body(())
+            .unwrap();
+        let (response, mut body) = client.send_request(request, false).unwrap();
+        body.send_data(frame(&message.encode_to_vec()), true)
+            .unwrap();
+        let response = response.await.unwrap();
+        assert_eq!(response.status(), 200);
+        if response.headers().contains_key("grpc-status") {
+            return (Vec::new(), response.headers().clone());
+        }
+        let mut body = response.into_body();
+        let mut data = Vec::new();
+        let mut messages = Vec::new();
+        while messages.len() < replies {
+            data.extend_from_slice(&body.data().await.unwrap().unwrap());
+            while let Some((&[_, length @ ..], rest)) = data.split_first_chunk::<5>() {
+                let len = u32::from_be_bytes(length) as usize;
+                let Some(message) = rest.get(..len) else {
+                    break;
+                };
+                messages.push(message.to_vec());
+                data.drain(..5 + len);
+            }
+        }
+        if replies > 1 {
+            // the stream of the stats, which never ends
+            return (messages, HeaderMap::new());
+        }
+        let trailers = body.trailers().await.unwrap().unwrap();
+        (messages, trailers)
+    }
+
+    fn status(headers: &HeaderMap) -> (&str, Option<&str>) {
+        let value = |name| headers.get(name).map(|value| value.to_str().unwrap());
+        (value("grpc-status").unwrap(), value("grpc-message"))
+    }
+
+    #[test]
+    fn unframes_single_messages() {
+        assert_eq!(unframe(&frame(b"message")).unwrap(), b"message");
+        assert_eq!(unframe(&frame(b"")).unwrap(), b"");
+        let invalid = [
+            (&b""[..], Code::InvalidArgument),
+            (b"\0\0\0\0\x02a", Code::InvalidArgument),
+            (b"\0\0\0\0\x01ab", Code::InvalidArgument),
+            (b"\x01\0\0\0\x01a", Code::Unimplemented),
+        ];
+        for (data, code) in invalid {
+            assert_eq!(unframe(data).unwrap_err().code, code);
+        }
+    }
+
+    #[test]
+    fn encodes_status_messages() {
+        let headers = Status::new(Code::InvalidArgument, "line 1\n100% é").headers();
+        assert_eq!(status(&headers), ("3", Some("line 1%0A100%25 %C3%A9")));
+        assert_eq!(status(&Status::new(Code::Ok, "").headers()), ("0", None));
+    }
+
+    #[tokio::test]
+    async fn serves_the_control_plane_next_to_the_admin_api() {
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let args = ["--admin-addr".to_string(), addr.to_string()];
+        let proxy = ProxyBuilder::from_args("token", args)
+            .unwrap()
+            .addr("127.0.0.1:0".parse().unwrap())
+            .build()
+            .await
+            .unwrap()
+            .start()
+            .unwrap();
+
+        let (messages, trailers) = call(addr, "GetConfig", &GetConfigRequest {}, 1).await;
+        assert_eq!(status(&trailers), ("0", None));
+        let config = GetConfigResponse::decode(&messages[0][..]).unwrap();
+        assert!(config.settings.contains("admin_addr: Some("));
+
+        let request = SetAuthTokenRequest {
+            auth_token: "other".to_string(),
+        };
+        let (_, trailers) = call(addr, "SetAuthToken", &request, 1).await;
+        assert_eq!(status(&trailers), ("0", None));
+        assert_eq!(*proxy.state.auth_token.read().unwrap(), "other");
+
+        let request = PushConfigRequest {
+            config: "[[host_limits]]\nhost = \"example.com\"\nrate = 0.0\n".to_string(),
+        };
+        let (messages, headers) = call(addr, "PushConfig", &request, 1).await;
+        assert!(messages.is_empty());
+        assert_eq!(status(&headers).0, "3");
+
+        let request = StreamStatsRequest { interval_ms: 100 };
+        let (messages, _) = call(addr, "StreamStats", &request, 2).await;
+        let stats = Stats::decode(&messages[1][..]).unwrap();
+        assert_eq!(stats.responses.len(), 5);
+
+        let (_, headers) = call(addr, "Unknown", &GetConfigRequest {}, 1).await;
+        assert_eq!(status(&headers), ("12", Some("unknown method `Unknown`")));
+
+        // the HTTP/1 clients of the admin API on the same address
+        let response = reqwest::get(format!("http://{addr}/healthz"))
+            .await
+            .unwrap();
+        assert_eq!(response.text().await.unwrap(), "ok");
+    }
+}
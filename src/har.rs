@@ -0,0 +1,270 @@
+//! Recording of the upstream exchanges in the HAR format, for analysis in browser devtools.
+use std::{
+    collections::VecDeque,
+    fs,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use anyhow::Result;
+use axum::http::{header, HeaderMap, StatusCode, Version};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, SecondsFormat, Utc};
+use reqwest::Url;
+use serde::Serialize;
+
+use crate::redact;
+
+#[derive(Serialize)]
+struct Har<'a> {
+    log: Log<'a>,
+}
+
+#[derive(Serialize)]
+struct Log<'a> {
+    version: &'static str,
+    creator: Creator,
+    entries: &'a VecDeque<Entry>,
+}
+
+#[derive(Serialize)]
+struct Creator {
+    name: &'static str,
+    version: &'static str,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Entry {
+    started_date_time: String,
+    /// Total time in milliseconds.
+    time: f64,
+    request: Request,
+    response: Response,
+    cache: Empty,
+    timings: Timings,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Request {
+    method: &'static str,
+    url: String,
+    http_version: String,
+    cookies: [Empty; 0],
+    headers: Vec<Pair>,
+    query_string: Vec<Pair>,
+    headers_size: i64,
+    body_size: i64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Response {
+    status: u16,
+    status_text: &'static str,
+    http_version: String,
+    cookies: [Empty; 0],
+    headers: Vec<Pair>,
+    content: Content,
+    #[serde(rename = "redirectURL")]
+    redirect_url: String,
+    headers_size: i64,
+    body_size: i64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Content {
+    size: usize,
+    mime_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    encoding: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    comment: Option<&'static str>,
+}
+
+#[derive(Serialize)]
+struct Pair {
+    name: String,
+    value: String,
+}
+
+#[derive(Serialize)]
+struct Timings {
+    send: f64,
+    wait: f64,
+    receive: f64,
+}
+
+#[derive(Serialize)]
+struct Empty {}
+
+/// An upstream request and its response.
+#[derive(Clone, Copy)]
+pub struct Exchange<'a> {
+    pub started: DateTime<Utc>,
+    pub url: &'a Url,
+    pub request_headers: &'a HeaderMap,
+    pub status: StatusCode,
+    pub version: Version,
+    pub response_headers: &'a HeaderMap,
+    /// Response body, `None` if it was streamed without being buffered.
+    pub body: Option<&'a [u8]>,
+    /// Time until the response headers were received.
+    pub wait: Duration,
+    /// Time spent receiving the response body.
+    pub receive: Duration,
+}
+
+/// Keeps the most recent exchanges, which are periodically saved to a file and can be downloaded
+/// from the admin API.
+pub struct Recorder {
+    entries: Mutex<VecDeque<Entry>>,
+    max_entries: usize,
+    /// Response bodies larger than this are not recorded, only their size.
+    body_limit: usize,
+    /// Sent by the client on every request, so missing from the request headers.
+    user_agent: String,
+    path: PathBuf,
+    /// Whether entries were recorded since the last save.
+    dirty: AtomicBool,
+}
+
+impl Recorder {
+    pub fn new(path: PathBuf, max_entries: usize, body_limit: usize, user_agent: String) -> Self {
+        Self {
+            entries: Mutex::new(VecDeque::new()),
+            max_entries,
+            body_limit,
+            user_agent,
+            path,
+            dirty: AtomicBool::new(false),
+        }
+    }
+
+    pub fn record(&self, exchange: Exchange) {
+        let pairs = |headers: &HeaderMap| {
+            headers
+                .iter()
+                .map(|(name, value)| Pair {
+                    name: name.to_string(),
+                    value: redact::value(name, value).into_owned(),
+                })
+                .collect::<Vec<_>>()
+        };
+        let mut request_headers = pairs(exchange.request_headers);
+        if !exchange.request_headers.contains_key(header::USER_AGENT) {
+            request_headers.push(Pair {
+                name: header::USER_AGENT.to_string(),
+                value: self.user_agent.clone(),
+            });
+        }
+        let (text, encoding, comment) = match exchange.body {
+            None => (None, None, Some("body streamed without being recorded")),
+            Some(body) if body.len() > self.body_limit => {
+                (None, None, Some("body larger than the recording limit"))
+            }
+            Some(body) => match std::str::from_utf8(body) {
+                Ok(text) => (Some(text.to_string()), None, None),
+                Err(_) => (Some(STANDARD.encode(body)), Some("base64"), None),
+            },
+        };
+        let millis = |duration: Duration| duration.as_secs_f64() * 1e3;
+        let entry = Entry {
+            started_date_time: exchange
+                .started
+                .to_rfc3339_opts(SecondsFormat::Millis, true),
+            time: millis(exchange.wait + exchange.receive),
+            request: Request {
+                method: "GET",
+                url: exchange.url.to_string(),
+                http_version: format!("{:?}", exchange.version),
+                cookies: [],
+                headers: request_headers,
+                query_string: exchange
+                    .url
+                    .query_pairs()
+                    .map(|(name, value)| Pair {
+                        name: name.into_owned(),
+                        value: value.into_owned(),
+                    })
+                    .collect(),
+                headers_size: -1,
+                body_size: 0,
+            },
+            response: Response {
+                status: exchange.status.as_u16(),
+                status_text: exchange.status.canonical_reason().unwrap_or_default(),
+                http_version: format!("{:?}", exchange.version),
+                cookies: [],
+                headers: pairs(exchange.response_headers),
+                content: Content {
+                    size: exchange.body.map_or(0, <[u8]>::len),
+                    mime_type: exchange
+                        .response_headers
+                        .get(header::CONTENT_TYPE)
+                        .and_then(|value| value.to_str().ok())
+                        .unwrap_or("application/octet-stream")
+                        .to_string(),
+                    text,
+                    encoding,
+                    comment,
+                },
+                redirect_url: exchange
+                    .response_headers
+                    .get(header::LOCATION)
+                    .and_then(|value| value.to_str().ok())
+                    .unwrap_or_default()
+                    .to_string(),
+                headers_size: -1,
+                body_size: exchange.body.map_or(-1, |body| body.len() as i64),
+            },
+            cache: Empty {},
+            timings: Timings {
+                send: 0.0,
+                wait: millis(exchange.wait),
+                receive: millis(exchange.receive),
+            },
+        };
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.max_entries {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    /// The recorded exchanges, as a HAR document.
+    pub fn to_json(&self) -> String {
+        let entries = self.entries.lock().unwrap();
+        let har = Har {
+            log: Log {
+                version: "1.2",
+                creator: Creator {
+                    name: env!("CARGO_PKG_NAME"),
+                    version: env!("CARGO_PKG_VERSION"),
+                },
+                entries: &entries,
+            },
+        };
+        serde_json::to_string(&har).expect("HAR entries are serializable")
+    }
+
+    /// Write the recorded exchanges to the HAR file if there are new ones, replacing it atomically.
+    pub fn save(&self) -> Result<()> {
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, self.to_json())?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
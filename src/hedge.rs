@@ -0,0 +1,45 @@
+//! Hedged requests, duplicating the upstream requests to slow hosts.
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::{config::HostPattern, metrics::METRICS};
+
+/// Number of requests to a host before its latency percentile is trusted.
+const MIN_SAMPLES: u64 = 20;
+
+/// Duplicate the requests to the matching hosts that haven't answered within the latency
+/// percentile, using whichever response comes first.
+///
+/// The percentile is approximated with the upper bound of its latency histogram bucket, which
+/// includes receiving the body, so that hedges are sent late rather than too often.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HedgeRule {
+    pub host: HostPattern,
+    #[serde(default = "HedgeRule::default_percentile")]
+    pub percentile: f64,
+    /// Delay before the duplicate request while the percentile isn't known yet.
+    #[serde(default = "HedgeRule::default_delay", with = "humantime_serde")]
+    pub delay: Duration,
+}
+
+impl HedgeRule {
+    fn default_percentile() -> f64 {
+        0.95
+    }
+
+    fn default_delay() -> Duration {
+        Duration::from_millis(500)
+    }
+}
+
+/// Delay before a duplicate of a request to `host` is sent, `None` if it isn't hedged.
+pub fn delay(rules: &[HedgeRule], host: &str) -> Option<Duration> {
+    let rule = rules.iter().find(|rule| rule.host.matches(host))?;
+    Some(
+        METRICS
+            .host_latency_quantile(host, rule.percentile, MIN_SAMPLES)
+            .unwrap_or(rule.delay),
+    )
+}
@@ -0,0 +1,335 @@
+//! Usage history in an SQLite database, so that the usage per credential, destination and day
+//! survives restarts and can be queried with plain SQL. Requires the `sqlite` feature, which links
+//! the system SQLite library.
+//!
+//! The counters are accumulated in memory and added to the `usage` table periodically, in a single
+//! transaction. The days older than the retention are pruned at the same time.
+use std::{collections::HashMap, path::Path, sync::Mutex, time::Duration};
+
+use anyhow::Result;
+use axum::http::StatusCode;
+use chrono::Utc;
+
+#[derive(Clone, Copy, Default)]
+struct Counts {
+    requests: u64,
+    bytes: u64,
+    /// Requests answered with a 4xx or 5xx status.
+    errors: u64,
+}
+
+/// Day, credential id and destination host of the counters, the host being empty for the
+/// requests without one.
+type Key = (String, String, String);
+
+#[cfg_attr(not(feature = "sqlite"), allow(dead_code))]
+pub struct History {
+    #[cfg(feature = "sqlite")]
+    db: Mutex<sqlite::Connection>,
+    /// Counters not added to the database yet.
+    pending: Mutex<HashMap<Key, Counts>>,
+    /// Number of days kept in the database.
+    retention_days: u64,
+}
+
+impl History {
+    pub fn record(&self, credential: &str, host: &str, status: StatusCode) {
+        let mut pending = self.pending.lock().unwrap();
+        let counts = pending.entry(key(credential, host)).or_default();
+        counts.requests += 1;
+        if status.is_client_error() || status.is_server_error() {
+            counts.errors += 1;
+        }
+    }
+
+    pub fn record_bytes(&self, credential: &str, host: &str, bytes: u64) {
+        let mut pending = self.pending.lock().unwrap();
+        pending.entry(key(credential, host)).or_default().bytes += bytes;
+    }
+}
+
+fn key(credential: &str, host: &str) -> Key {
+    (
+        Utc::now().format("%Y-%m-%d").to_string(),
+        credential.to_string(),
+        host.to_string(),
+    )
+}
+
+#[cfg(feature = "sqlite")]
+impl History {
+    /// Open the database at `path`, creating it if needed.
+    pub fn open(path: &Path, retention: Duration) -> Result<Self> {
+        use anyhow::Context;
+
+        let db = sqlite::Connection::open(path).with_context(|| {
+            format!("could not open the statistics database {}", path.display())
+        })?;
+        db.execute(
+            "PRAGMA journal_mode = WAL;
+            CREATE TABLE IF NOT EXISTS usage (
+                day TEXT NOT NULL,
+                credential TEXT NOT NULL,
+                destination TEXT NOT NULL,
+                requests INTEGER NOT NULL,
+                bytes INTEGER NOT NULL,
+                errors INTEGER NOT NULL,
+                PRIMARY KEY (day, credential, destination)
+            );",
+        )?;
+        Ok(Self {
+            db: Mutex::new(db),
+            pending: Mutex::new(HashMap::new()),
+            retention_days: retention.as_secs() / (24 * 60 * 60),
+        })
+    }
+
+    /// Add the pending counters to the database, and prune the days past the retention.
+    pub fn flush(&self) -> Result<()> {
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+        let db = self.db.lock().unwrap();
+        db.execute("BEGIN")?;
+        let written = (|| {
+            let mut upsert = db.prepare(
+                "INSERT INTO usage (day, credential, destination, requests, bytes, errors)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+                ON CONFLICT DO UPDATE SET
+                    requests = requests + excluded.requests,
+                    bytes = bytes + excluded.bytes,
+                    errors = errors + excluded.errors",
+            )?;
+            for ((day, credential, destination), counts) in &pending {
+                upsert.run(&[
+                    sqlite::Value::Text(day),
+                    sqlite::Value::Text(credential),
+                    sqlite::Value::Text(destination),
+                    sqlite::Value::Integer(counts.requests as i64),
+                    sqlite::Value::Integer(counts.bytes as i64),
+                    sqlite::Value::Integer(counts.errors as i64),
+                ])?;
+            }
+            let oldest = (Utc::now() - chrono::Days::new(self.retention_days))
+                .format("%Y-%m-%d")
+                .to_string();
+            db.prepare("DELETE FROM usage WHERE day < ?1")?
+                .run(&[sqlite::Value::Text(&oldest)])
+        })();
+        match written {
+            Ok(()) => db.execute("COMMIT"),
+            Err(err) => {
+                let _ = db.execute("ROLLBACK");
+                // the counters are added again with the next flush
+                let mut current = self.pending.lock().unwrap();
+                for (key, counts) in pending {
+                    let current = current.entry(key).or_default();
+                    current.requests += counts.requests;
+                    current.bytes += counts.bytes;
+                    current.errors += counts.errors;
+                }
+                Err(err)
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "sqlite"))]
+impl History {
+    pub fn open(_path: &Path, _retention: Duration) -> Result<Self> {
+        anyhow::bail!(
+            "the statistics database requires the proxy to be built with the `sqlite` feature"
+        )
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Minimal bindings of the system SQLite library.
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use std::{
+        ffi::{c_char, c_int, c_void, CStr, CString},
+        os::unix::ffi::OsStrExt,
+        path::Path,
+        ptr,
+    };
+
+    use anyhow::{anyhow, bail, Result};
+
+    const SQLITE_OK: c_int = 0;
+    const SQLITE_DONE: c_int = 101;
+    const SQLITE_OPEN_READWRITE: c_int = 0x2;
+    const SQLITE_OPEN_CREATE: c_int = 0x4;
+    const SQLITE_OPEN_NOMUTEX: c_int = 0x8000;
+    /// Destructor telling SQLite to copy the bound values.
+    const SQLITE_TRANSIENT: isize = -1;
+
+    #[link(name = "sqlite3")]
+    extern "C" {
+        fn sqlite3_open_v2(
+            filename: *const c_char,
+            db: *mut *mut c_void,
+            flags: c_int,
+            vfs: *const c_char,
+        ) -> c_int;
+        fn sqlite3_close(db: *mut c_void) -> c_int;
+        fn sqlite3_errmsg(db: *mut c_void) -> *const c_char;
+        fn sqlite3_exec(
+            db: *mut c_void,
+            sql: *const c_char,
+            callback: *const c_void,
+            arg: *mut c_void,
+            errmsg: *mut *mut c_char,
+        ) -> c_int;
+        fn sqlite3_prepare_v2(
+            db: *mut c_void,
+            sql: *const c_char,
+            bytes: c_int,
+            statement: *mut *mut c_void,
+            tail: *mut *const c_char,
+        ) -> c_int;
+        fn sqlite3_bind_text(
+            statement: *mut c_void,
+            index: c_int,
+            text: *const c_char,
+            bytes: c_int,
+            destructor: isize,
+        ) -> c_int;
+        fn sqlite3_bind_int64(statement: *mut c_void, index: c_int, value: i64) -> c_int;
+        fn sqlite3_step(statement: *mut c_void) -> c_int;
+        fn sqlite3_reset(statement: *mut c_void) -> c_int;
+        fn sqlite3_finalize(statement: *mut c_void) -> c_int;
+    }
+
+    pub enum Value<'a> {
+        Text(&'a str),
+        Integer(i64),
+    }
+
+    pub struct Connection(*mut c_void);
+
+    // SAFETY: the connection is opened without SQLite's mutexes and only used behind a `Mutex`
+    unsafe impl Send for Connection {}
+
+    impl Connection {
+        pub fn open(path: &Path) -> Result<Self> {
+            let path = CString::new(path.as_os_str().as_bytes())?;
+            let mut db = ptr::null_mut();
+            // SAFETY: the path is a valid C string and `db` receives the connection
+            let code = unsafe {
+                sqlite3_open_v2(
+                    path.as_ptr(),
+                    &mut db,
+                    SQLITE_OPEN_READWRITE | SQLITE_OPEN_CREATE | SQLITE_OPEN_NOMUTEX,
+                    ptr::null(),
+                )
+            };
+            // the connection is allocated even if it couldn't be opened, so that it has the error
+            let connection = Self(db);
+            if code != SQLITE_OK {
+                bail!(connection.error());
+            }
+            Ok(connection)
+        }
+
+        fn error(&self) -> String {
+            if self.0.is_null() {
+                return "out of memory".to_string();
+            }
+            // SAFETY: the message is a C string owned by the connection
+            unsafe { CStr::from_ptr(sqlite3_errmsg(self.0)) }
+                .to_string_lossy()
+                .into_owned()
+        }
+
+        fn check(&self, code: c_int) -> Result<()> {
+            if code == SQLITE_OK {
+                Ok(())
+            } else {
+                Err(anyhow!(self.error()))
+            }
+        }
+
+        /// Run the `sql` statements, without parameters.
+        pub fn execute(&self, sql: &str) -> Result<()> {
+            let sql = CString::new(sql)?;
+            // SAFETY: the SQL is a valid C string, and there is no callback
+            let code = unsafe {
+                sqlite3_exec(
+                    self.0,
+                    sql.as_ptr(),
+                    ptr::null(),
+                    ptr::null_mut(),
+                    ptr::null_mut(),
+                )
+            };
+            self.check(code)
+        }
+
+        pub fn prepare(&self, sql: &str) -> Result<Statement<'_>> {
+            let sql = CString::new(sql)?;
+            let mut statement = ptr::null_mut();
+            // SAFETY: the SQL is a valid C string and `statement` receives the prepared statement
+            let code = unsafe {
+                sqlite3_prepare_v2(self.0, sql.as_ptr(), -1, &mut statement, ptr::null_mut())
+            };
+            self.check(code)?;
+            Ok(Statement {
+                connection: self,
+                statement,
+            })
+        }
+    }
+
+    impl Drop for Connection {
+        fn drop(&mut self) {
+            // SAFETY: the statements borrow the connection, so they are all finalized
+            unsafe { sqlite3_close(self.0) };
+        }
+    }
+
+    pub struct Statement<'a> {
+        connection: &'a Connection,
+        statement: *mut c_void,
+    }
+
+    impl Statement<'_> {
+        /// Run the statement with the `values` of its parameters, in order.
+        pub fn run(&mut self, values: &[Value]) -> Result<()> {
+            for (index, value) in (1..).zip(values) {
+                // SAFETY: the statement is prepared, and SQLite copies the text
+                let code = unsafe {
+                    match value {
+                        Value::Text(text) => sqlite3_bind_text(
+                            self.statement,
+                            index,
+                            text.as_ptr().cast(),
+                            c_int::try_from(text.len())?,
+                            SQLITE_TRANSIENT,
+                        ),
+                        Value::Integer(integer) => {
+                            sqlite3_bind_int64(self.statement, index, *integer)
+                        }
+                    }
+                };
+                self.connection.check(code)?;
+            }
+            // SAFETY: the statement is prepared, with all its parameters bound
+            let code = unsafe { sqlite3_step(self.statement) };
+            // SAFETY: the statement is prepared
+            unsafe { sqlite3_reset(self.statement) };
+            if code != SQLITE_DONE {
+                bail!(self.connection.error());
+            }
+            Ok(())
+        }
+    }
+
+    impl Drop for Statement<'_> {
+        fn drop(&mut self) {
+            // SAFETY: the statement is prepared, and not used anymore
+            unsafe { sqlite3_finalize(self.statement) };
+        }
+    }
+}
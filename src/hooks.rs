@@ -0,0 +1,94 @@
+//! Hooks intercepting the traffic of the proxy, registered with
+//! [`ProxyBuilder::hook`](crate::ProxyBuilder::hook).
+//!
+//! The request hooks run in the order the hooks were registered, once the request is logged and
+//! before it is handled, and the response hooks in the reverse order, before the error responses
+//! are rendered. The error responses of the hooks are rendered like the ones of the proxy.
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use axum::{
+    extract::{Request, State},
+    http::{HeaderMap, Method, Uri, Version},
+    middleware::Next,
+    response::Response,
+};
+
+pub use crate::errors::ProxyError;
+
+/// What happens to a request once a hook saw it.
+pub enum Flow {
+    /// Pass the request, possibly modified, to the next hook and then to the proxy.
+    Continue(Request),
+    /// Answer the request right away, the response going through the response hooks.
+    Respond(Response),
+}
+
+/// The request a response answers, as the client sent it.
+pub struct RequestHead {
+    pub method: Method,
+    pub uri: Uri,
+    pub version: Version,
+    pub headers: HeaderMap,
+}
+
+// the futures boxed by `async_trait` are `must_use` already
+#[allow(clippy::double_must_use)]
+#[async_trait]
+pub trait Hook: Send + Sync + 'static {
+    async fn on_request(&self, request: Request) -> Flow {
+        Flow::Continue(request)
+    }
+
+    /// Called with the responses that aren't error responses of the proxy.
+    async fn on_response(&self, _request: &RequestHead, response: Response) -> Response {
+        response
+    }
+
+    /// Called with the error responses of the proxy, e.g. when the origin is unreachable or the
+    /// client is rate limited.
+    async fn on_error(
+        &self,
+        _request: &RequestHead,
+        _error: &ProxyError,
+        response: Response,
+    ) -> Response {
+        response
+    }
+}
+
+/// Middleware running the hooks.
+pub async fn intercept(
+    State(hooks): State<Arc<[Arc<dyn Hook>]>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let head = RequestHead {
+        method: request.method().clone(),
+        uri: request.uri().clone(),
+        version: request.version(),
+        headers: request.headers().clone(),
+    };
+    let mut response = match on_request(&hooks, request).await {
+        Flow::Continue(request) => next.run(request).await,
+        Flow::Respond(response) => response,
+    };
+    for hook in hooks.iter().rev() {
+        response = match response.extensions().get::<ProxyError>().cloned() {
+            Some(error) => hook.on_error(&head, &error, response).await,
+            None => hook.on_response(&head, response).await,
+        };
+    }
+    response
+}
+
+/// The request once all the hooks passed it, or the response of the one answering it.
+async fn on_request(hooks: &[Arc<dyn Hook>], mut request: Request) -> Flow {
+    for hook in hooks {
+        request = match hook.on_request(request).await {
+            Flow::Continue(request) => request,
+            answered => return answered,
+        };
+    }
+    Flow::Continue(request)
+}
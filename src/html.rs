@@ -0,0 +1,624 @@
+//! Rewriting of the HTML pages of the matching hosts, as they are streamed: snippets injected in
+//! their `<head>` and `<body>`, absolute links pointed back through the proxy, for browsing
+//! gateways, and the scripts of analytics and ad services removed, for privacy.
+//!
+//! Compressed pages are decoded first, the compression layer encoding them again for the client.
+//! The pages are scanned tag by tag instead of being parsed, which is enough for well-formed
+//! markup: only the start of a tag cut by a chunk boundary is held back.
+use std::sync::Arc;
+
+use axum::{
+    body::{Body, Bytes},
+    http::{header, HeaderMap},
+};
+use futures_util::{Stream, StreamExt};
+use reqwest::Url;
+use serde::Deserialize;
+
+use crate::config::HostPattern;
+
+/// Longest tag or comment held back, past which it is passed through unchanged.
+const MAX_TAG: usize = 64 * 1024;
+
+/// Attributes holding links.
+const LINK_ATTRIBUTES: [&str; 4] = ["href", "src", "action", "poster"];
+
+/// Domains of analytics and ad services, whose subdomains are also matched.
+const TRACKERS: &[&str] = &[
+    "google-analytics.com",
+    "googletagmanager.com",
+    "googletagservices.com",
+    "googlesyndication.com",
+    "googleadservices.com",
+    "doubleclick.net",
+    "connect.facebook.net",
+    "analytics.tiktok.com",
+    "static.ads-twitter.com",
+    "snap.licdn.com",
+    "bat.bing.com",
+    "clarity.ms",
+    "hotjar.com",
+    "cdn.segment.com",
+    "cdn.mxpnl.com",
+    "mixpanel.com",
+    "amplitude.com",
+    "heap.io",
+    "fullstory.com",
+    "scorecardresearch.com",
+    "quantserve.com",
+    "chartbeat.com",
+    "amazon-adsystem.com",
+    "adnxs.com",
+    "criteo.com",
+    "criteo.net",
+    "taboola.com",
+    "outbrain.com",
+    "pubmatic.com",
+    "rubiconproject.com",
+];
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HtmlRule {
+    pub host: HostPattern,
+    /// Snippet inserted right after the opening `<head>` tag, e.g. a monitoring script.
+    #[serde(default)]
+    pub head: String,
+    /// Snippet inserted right after the opening `<body>` tag, e.g. a banner.
+    #[serde(default)]
+    pub body: String,
+    /// Prefix of the rewritten absolute links, followed by the percent-encoded link, e.g.
+    /// `https://gateway.example.com/?url=`. Links aren't rewritten if not set.
+    pub link_prefix: Option<String>,
+    /// Remove the `<script>` tags loading from known analytics and ad services.
+    #[serde(default)]
+    pub strip_trackers: bool,
+    /// Script hosts removed, in addition to the known trackers.
+    #[serde(default)]
+    pub deny_scripts: Vec<HostPattern>,
+    /// Script hosts kept, even if they are known trackers.
+    #[serde(default)]
+    pub allow_scripts: Vec<HostPattern>,
+}
+
+pub struct Html {
+    rules: Vec<Arc<HtmlRule>>,
+}
+
+impl Html {
+    pub fn new(rules: Vec<HtmlRule>) -> Self {
+        Self {
+            rules: rules.into_iter().map(Arc::new).collect(),
+        }
+    }
+
+    /// The rule rewriting the response to a request of `url`, if it's an HTML page.
+    pub fn rule(&self, url: &Url, headers: &HeaderMap) -> Option<&Arc<HtmlRule>> {
+        if !is_html(headers) {
+            return None;
+        }
+        let host = url.host_str().unwrap_or_default();
+        self.rules.iter().find(|rule| rule.host.matches(host))
+    }
+}
+
+/// Whether a response with `headers` is an HTML page.
+pub fn is_html(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| {
+            value
+                .split(';')
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .eq_ignore_ascii_case("text/html")
+        })
+}
+
+/// Same-origin scripts, stylesheets, images and preloads of the page at `url`, in order.
+///
+/// Links to other pages are left out, as following them may have effects, e.g. logging out.
+pub fn subresources(url: &Url, page: &[u8]) -> Vec<Url> {
+    let mut found = Vec::new();
+    let mut pos = 0;
+    while let Some(start) = page[pos..].iter().position(|&byte| byte == b'<') {
+        let start = pos + start;
+        let Some(len) = page[start..].iter().position(|&byte| byte == b'>') else {
+            break;
+        };
+        let Some(tag) = Tag::parse(&page[start..=start + len]) else {
+            // a stray `<` in text
+            pos = start + 1;
+            continue;
+        };
+        pos = start + len + 1;
+        if tag.closing {
+            continue;
+        }
+        let link = match tag.name.as_str() {
+            "script" | "img" => tag.attribute("src"),
+            "link"
+                if tag.attribute("rel").is_some_and(|rel| {
+                    rel.split_ascii_whitespace().any(|rel| {
+                        ["stylesheet", "preload", "modulepreload", "icon"]
+                            .iter()
+                            .any(|kind| rel.eq_ignore_ascii_case(kind))
+                    })
+                }) =>
+            {
+                tag.attribute("href")
+            }
+            _ => None,
+        };
+        let Some(mut link) = link.and_then(|link| url.join(link.trim()).ok()) else {
+            continue;
+        };
+        link.set_fragment(None);
+        if link.origin() == url.origin() && !found.contains(&link) {
+            found.push(link);
+        }
+    }
+    found
+}
+
+impl HtmlRule {
+    /// `body` of the page at `url` rewritten by the rule.
+    pub fn rewrite(self: Arc<Self>, url: Url, body: Body) -> Body {
+        let rewriter = Rewriter {
+            rule: self,
+            url,
+            head_injected: false,
+            body_injected: false,
+            pending: Vec::new(),
+            raw_text: None,
+            removing: false,
+        };
+        Body::from_stream(rewritten(body.into_data_stream(), rewriter))
+    }
+
+    /// Whether the script loaded from `src` is removed.
+    fn removes(&self, src: &Url) -> bool {
+        let host = src.host_str().unwrap_or_default();
+        if self
+            .allow_scripts
+            .iter()
+            .any(|pattern| pattern.matches(host))
+        {
+            return false;
+        }
+        self.deny_scripts
+            .iter()
+            .any(|pattern| pattern.matches(host))
+            || (self.strip_trackers
+                && TRACKERS.iter().any(|domain| {
+                    host.eq_ignore_ascii_case(domain)
+                        || host
+                            .to_ascii_lowercase()
+                            .strip_suffix(domain)
+                            .is_some_and(|subdomain| subdomain.ends_with('.'))
+                }))
+    }
+}
+
+fn rewritten<S>(body: S, rewriter: Rewriter) -> impl Stream<Item = Result<Bytes, axum::Error>>
+where
+    S: Stream<Item = Result<Bytes, axum::Error>> + Unpin,
+{
+    futures_util::stream::unfold(Some((body, rewriter)), |state| async move {
+        let (mut body, mut rewriter) = state?;
+        match body.next().await {
+            Some(Ok(chunk)) => Some((Ok(rewriter.feed(&chunk).into()), Some((body, rewriter)))),
+            Some(Err(err)) => Some((Err(err), None)),
+            None => Some((Ok(std::mem::take(&mut rewriter.pending).into()), None)),
+        }
+    })
+    // the compression layer polls again after the end
+    .fuse()
+}
+
+struct Rewriter {
+    rule: Arc<HtmlRule>,
+    /// URL of the page, against which the script sources are resolved.
+    url: Url,
+    head_injected: bool,
+    body_injected: bool,
+    /// Start of a tag held back until its end is received.
+    pending: Vec<u8>,
+    /// Name of the element whose contents are passed through until its end tag, like `script`.
+    raw_text: Option<&'static str>,
+    /// Whether the current raw text element is removed, with its end tag.
+    removing: bool,
+}
+
+impl Rewriter {
+    fn feed(&mut self, chunk: &[u8]) -> Vec<u8> {
+        let mut input = std::mem::take(&mut self.pending);
+        input.extend_from_slice(chunk);
+        let mut out = Vec::with_capacity(input.len());
+        let mut pos = 0;
+        while pos < input.len() {
+            let rest = &input[pos..];
+            if let Some(name) = self.raw_text {
+                let end_tag = format!("</{name}");
+                match find_ignore_case(rest, end_tag.as_bytes()) {
+                    Some(index) => {
+                        if !self.removing {
+                            out.extend_from_slice(&rest[..index]);
+                        }
+                        pos += index;
+                        self.raw_text = None;
+                    }
+                    None => {
+                        // the end tag may start in this chunk
+                        let kept = rest.len().saturating_sub(end_tag.len() - 1);
+                        if !self.removing {
+                            out.extend_from_slice(&rest[..kept]);
+                        }
+                        self.pending = rest[kept..].to_vec();
+                        break;
+                    }
+                }
+                continue;
+            }
+            let Some(start) = rest.iter().position(|&byte| byte == b'<') else {
+                out.extend_from_slice(rest);
+                break;
+            };
+            out.extend_from_slice(&rest[..start]);
+            let rest = &rest[start..];
+            if rest
+                .get(1)
+                .is_some_and(|&byte| !byte.is_ascii_alphabetic() && !matches!(byte, b'/' | b'!'))
+            {
+                // not a tag, like in `a < b`
+                out.push(b'<');
+                pos += start + 1;
+                continue;
+            }
+            let end = if rest.starts_with(b"<!--") {
+                find_ignore_case(rest, b"-->").map(|index| index + 3)
+            } else if b"<!--".starts_with(rest) {
+                None
+            } else {
+                rest.iter()
+                    .position(|&byte| byte == b'>')
+                    .map(|index| index + 1)
+            };
+            let Some(end) = end else {
+                if rest.len() > MAX_TAG {
+                    out.extend_from_slice(rest);
+                } else {
+                    self.pending = rest.to_vec();
+                }
+                break;
+            };
+            self.tag(&rest[..end], &mut out);
+            pos += start + end;
+        }
+        out
+    }
+
+    /// Write `tag` rewritten to `out`.
+    fn tag(&mut self, tag: &[u8], out: &mut Vec<u8>) {
+        let Some(parsed) = Tag::parse(tag) else {
+            out.extend_from_slice(tag);
+            return;
+        };
+        if parsed.name == "script" {
+            if parsed.closing && self.removing {
+                self.removing = false;
+                return;
+            }
+            let src = parsed
+                .attribute("src")
+                .and_then(|src| self.url.join(src.trim()).ok());
+            if let Some(src) = src.filter(|src| !parsed.closing && self.rule.removes(src)) {
+                tracing::debug!(src = %src, "Removed script");
+                self.removing = !parsed.self_closing;
+                self.raw_text = self.removing.then_some("script");
+                return;
+            }
+        }
+        match &self.rule.link_prefix {
+            Some(prefix) if !parsed.closing => out.extend_from_slice(&parsed.rewrite_links(prefix)),
+            _ => out.extend_from_slice(tag),
+        }
+        if parsed.closing {
+            return;
+        }
+        match parsed.name.as_str() {
+            "head" if !self.head_injected => {
+                self.head_injected = true;
+                out.extend_from_slice(self.rule.head.as_bytes());
+            }
+            "body" if !self.body_injected => {
+                self.body_injected = true;
+                out.extend_from_slice(self.rule.body.as_bytes());
+            }
+            "script" if !parsed.self_closing => self.raw_text = Some("script"),
+            "style" if !parsed.self_closing => self.raw_text = Some("style"),
+            _ => {}
+        }
+    }
+}
+
+/// A start or end tag.
+struct Tag<'a> {
+    raw: &'a [u8],
+    /// Lowercase name.
+    name: String,
+    closing: bool,
+    self_closing: bool,
+}
+
+impl<'a> Tag<'a> {
+    fn parse(raw: &'a [u8]) -> Option<Self> {
+        let inner = raw.strip_prefix(b"<")?.strip_suffix(b">")?;
+        let (closing, inner) = match inner.strip_prefix(b"/") {
+            Some(inner) => (true, inner),
+            None => (false, inner),
+        };
+        let len = inner
+            .iter()
+            .position(|byte| !byte.is_ascii_alphanumeric())
+            .unwrap_or(inner.len());
+        if len == 0 || !inner[0].is_ascii_alphabetic() {
+            return None;
+        }
+        Some(Self {
+            raw,
+            name: String::from_utf8_lossy(&inner[..len]).to_ascii_lowercase(),
+            closing,
+            self_closing: inner.ends_with(b"/"),
+        })
+    }
+
+    /// Value of the attribute `name`, if the tag has it.
+    fn attribute(&self, name: &str) -> Option<String> {
+        self.attributes()
+            .find(|attribute| attribute.name.eq_ignore_ascii_case(name))
+            .and_then(|attribute| attribute.value)
+            .map(|(start, end)| decode_entities(&String::from_utf8_lossy(&self.raw[start..end])))
+    }
+
+    fn attributes(&self) -> Attributes<'a> {
+        Attributes {
+            raw: self.raw,
+            pos: 1 + usize::from(self.closing) + self.name.len(),
+        }
+    }
+
+    /// The tag with its absolute links replaced by `prefix` and the percent-encoded link.
+    fn rewrite_links(&self, prefix: &str) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.raw.len());
+        let mut copied = 0;
+        for attribute in self.attributes() {
+            let Some((start, end)) = attribute.value else {
+                continue;
+            };
+            if !LINK_ATTRIBUTES
+                .iter()
+                .any(|name| attribute.name.eq_ignore_ascii_case(name))
+            {
+                continue;
+            }
+            let link = decode_entities(&String::from_utf8_lossy(&self.raw[start..end]));
+            let link = link.trim();
+            let absolute = if link.starts_with("//") {
+                format!("https:{link}")
+            } else if link.starts_with("http://") || link.starts_with("https://") {
+                link.to_string()
+            } else {
+                continue;
+            };
+            out.extend_from_slice(&self.raw[copied..start]);
+            out.extend_from_slice(prefix.as_bytes());
+            out.extend_from_slice(percent_encode(&absolute).as_bytes());
+            copied = end;
+        }
+        out.extend_from_slice(&self.raw[copied..]);
+        out
+    }
+}
+
+struct Attribute<'a> {
+    name: &'a str,
+    /// Bounds of the value in the tag, without its quotes.
+    value: Option<(usize, usize)>,
+}
+
+struct Attributes<'a> {
+    raw: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Iterator for Attributes<'a> {
+    type Item = Attribute<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let raw = self.raw;
+        let end = raw.len() - 1;
+        let skip = |pos: &mut usize, pred: fn(u8) -> bool| {
+            while *pos < end && pred(raw[*pos]) {
+                *pos += 1;
+            }
+        };
+        skip(&mut self.pos, |byte| {
+            byte.is_ascii_whitespace() || byte == b'/'
+        });
+        if self.pos >= end {
+            return None;
+        }
+        let start = self.pos;
+        skip(&mut self.pos, |byte| {
+            !byte.is_ascii_whitespace() && !matches!(byte, b'=' | b'>' | b'/')
+        });
+        let name = std::str::from_utf8(&raw[start..self.pos]).unwrap_or_default();
+        skip(&mut self.pos, |byte| byte.is_ascii_whitespace());
+        if raw[self.pos] != b'=' {
+            return Some(Attribute { name, value: None });
+        }
+        self.pos += 1;
+        skip(&mut self.pos, |byte| byte.is_ascii_whitespace());
+        let value = match raw[self.pos] {
+            quote @ (b'"' | b'\'') => {
+                let start = self.pos + 1;
+                let len = raw[start..end]
+                    .iter()
+                    .position(|&byte| byte == quote)
+                    .unwrap_or(end - start);
+                self.pos = (start + len + 1).min(end);
+                (start, start + len)
+            }
+            _ => {
+                let start = self.pos;
+                skip(&mut self.pos, |byte| !byte.is_ascii_whitespace());
+                (start, self.pos)
+            }
+        };
+        Some(Attribute {
+            name,
+            value: Some(value),
+        })
+    }
+}
+
+fn find_ignore_case(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window.eq_ignore_ascii_case(needle))
+}
+
+/// `value` with the character references links commonly have decoded.
+fn decode_entities(value: &str) -> String {
+    value
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+fn percent_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~') {
+            encoded.push(char::from(byte));
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(toml: &str) -> Arc<HtmlRule> {
+        Arc::new(toml::from_str(&format!("host = \"example.com\"\n{toml}")).unwrap())
+    }
+
+    /// The page made of `chunks` rewritten by `rule`.
+    fn rewrite(rule: Arc<HtmlRule>, chunks: &[&str]) -> String {
+        let mut rewriter = Rewriter {
+            rule,
+            url: Url::parse("https://example.com/page").unwrap(),
+            head_injected: false,
+            body_injected: false,
+            pending: Vec::new(),
+            raw_text: None,
+            removing: false,
+        };
+        let mut out = Vec::new();
+        for chunk in chunks {
+            out.extend(rewriter.feed(chunk.as_bytes()));
+        }
+        out.extend(rewriter.pending);
+        String::from_utf8(out).unwrap()
+    }
+
+    /// `page` split at every position.
+    fn splits(page: &str) -> impl Iterator<Item = [&str; 2]> {
+        (0..=page.len()).map(|at| [&page[..at], &page[at..]])
+    }
+
+    #[test]
+    fn inject_snippets() {
+        let rule = rule("head = \"<script>monitor()</script>\"\nbody = \"<div>banner</div>\"");
+        let page = "<!DOCTYPE html><HTML><!-- <head> --><Head lang=en><script>'<body>'</script>\
+                    </head><body class=\"a\">a < b</body></html>";
+        let expected =
+            "<!DOCTYPE html><HTML><!-- <head> --><Head lang=en><script>monitor()</script>\
+                        <script>'<body>'</script></head><body class=\"a\"><div>banner</div>a < b\
+                        </body></html>";
+        for chunks in splits(page) {
+            assert_eq!(rewrite(rule.clone(), &chunks), expected, "{chunks:?}");
+        }
+    }
+
+    #[test]
+    fn rewrite_links() {
+        let rule = rule("link_prefix = \"https://gw.example/?url=\"");
+        let page = concat!(
+            "<a href=\"https://other.example/a?b=1&amp;c=2\">a</a><img SRC='//cdn.example/i.png'>",
+            "<a href=/relative>r</a><form action=http://other.example/post></form>",
+        );
+        let expected = concat!(
+            "<a href=\"https://gw.example/?url=https%3A%2F%2Fother.example%2Fa%3Fb%3D1%26c%3D2\">a</a>",
+            "<img SRC='https://gw.example/?url=https%3A%2F%2Fcdn.example%2Fi.png'>",
+            "<a href=/relative>r</a>",
+            "<form action=https://gw.example/?url=http%3A%2F%2Fother.example%2Fpost></form>",
+        );
+        for chunks in splits(page) {
+            assert_eq!(rewrite(rule.clone(), &chunks), expected, "{chunks:?}");
+        }
+    }
+
+    #[test]
+    fn strip_trackers() {
+        let rule = rule(concat!(
+            "strip_trackers = true\n",
+            "deny_scripts = [\"*.ads.example\"]\n",
+            "allow_scripts = [\"www.googletagmanager.com\"]",
+        ));
+        let page = concat!(
+            "<head><script async src=\"https://www.google-analytics.com/analytics.js\"></script>",
+            "<SCRIPT src=//x.ads.example/a.js>if (a </b) {}</SCRIPT >",
+            "<script src=https://www.googletagmanager.com/gtm.js></script>",
+            "<script src=\"/app.js\" /><script>track('https://mixpanel.com')</script>",
+            "<script src=https://notmixpanel.com/s.js></script></head>",
+        );
+        let expected = concat!(
+            "<head>",
+            "<script src=https://www.googletagmanager.com/gtm.js></script>",
+            "<script src=\"/app.js\" /><script>track('https://mixpanel.com')</script>",
+            "<script src=https://notmixpanel.com/s.js></script></head>",
+        );
+        for chunks in splits(page) {
+            assert_eq!(rewrite(rule.clone(), &chunks), expected, "{chunks:?}");
+        }
+    }
+
+    #[test]
+    fn same_origin_subresources() {
+        let url = Url::parse("https://example.com/dir/page").unwrap();
+        let page = br#"<link rel="stylesheet" href="style.css"><script src="/app.js#x"></script>
+            <img src="https://cdn.example/logo.png"><a href="/logout">out</a>
+            <link rel=icon href=/favicon.ico><script src="/app.js"></script>"#;
+        let found: Vec<_> = subresources(&url, page)
+            .iter()
+            .map(Url::to_string)
+            .collect();
+        assert_eq!(
+            found,
+            [
+                "https://example.com/dir/style.css",
+                "https://example.com/app.js",
+                "https://example.com/favicon.ico",
+            ]
+        );
+    }
+}
@@ -0,0 +1,545 @@
+//! Verification of the response bodies against their digests, as announced by the origins in
+//! `Content-Digest`, `Digest` or `Content-MD5`, or configured per URL.
+//!
+//! Buffered bodies that don't match are answered with `502` and not cached. Streamed bodies are
+//! held back by one chunk, so that the last one is replaced with an error aborting the response
+//! when they don't match, and clients never see the corrupted body complete.
+use std::{collections::HashMap, io, sync::atomic::Ordering};
+
+use anyhow::{anyhow, bail, Context, Result};
+use axum::{
+    body::Bytes,
+    http::{HeaderMap, StatusCode},
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures_util::{stream::BoxStream, Stream, StreamExt};
+use reqwest::Url;
+use serde::Deserialize;
+use sha2::{Digest, Sha256, Sha512};
+
+use crate::metrics::METRICS;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IntegrityRule {
+    /// URL of the body, matched exactly.
+    pub url: String,
+    /// SHA-256 of the body, in hex.
+    pub sha256: String,
+}
+
+pub struct Integrity {
+    /// Whether the digests announced by the origins are verified.
+    headers: bool,
+    /// Expected SHA-256 of the bodies, by URL.
+    rules: HashMap<String, Vec<u8>>,
+}
+
+impl Integrity {
+    pub fn new(headers: bool, rules: Vec<IntegrityRule>) -> Result<Self> {
+        let rules = rules
+            .into_iter()
+            .map(|rule| {
+                let url: Url = rule
+                    .url
+                    .parse()
+                    .with_context(|| format!("invalid integrity URL {}", rule.url))?;
+                let digest = unhex(&rule.sha256)
+                    .filter(|digest| digest.len() == 32)
+                    .ok_or_else(|| anyhow!("invalid SHA-256 for {}", rule.url))?;
+                Ok((url.to_string(), digest))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { headers, rules })
+    }
+
+    /// The verifier of the body of the response to `url`, if its digest is known.
+    ///
+    /// Only complete bodies are verified, as the digests of the partial ones may be of the whole
+    /// representation.
+    pub fn verifier(&self, url: &Url, status: StatusCode, headers: &HeaderMap) -> Option<Verifier> {
+        if status != StatusCode::OK {
+            return None;
+        }
+        if let Some(digest) = self.rules.get(url.as_str()) {
+            return Some(Verifier::new(
+                Algorithm::Sha256,
+                digest.clone(),
+                "the config file",
+            ));
+        }
+        if !self.headers {
+            return None;
+        }
+        let (algorithm, expected, source) = announced(headers)?;
+        Some(Verifier::new(algorithm, expected, source))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Algorithm {
+    Md5,
+    Sha256,
+    Sha512,
+}
+
+impl Algorithm {
+    fn parse(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "md5" => Some(Self::Md5),
+            "sha-256" => Some(Self::Sha256),
+            "sha-512" => Some(Self::Sha512),
+            _ => None,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Md5 => "md5",
+            Self::Sha256 => "sha-256",
+            Self::Sha512 => "sha-512",
+        }
+    }
+}
+
+/// The strongest digest announced in `headers`, and the header it is from.
+fn announced(headers: &HeaderMap) -> Option<(Algorithm, Vec<u8>, &'static str)> {
+    // e.g. `sha-256=:X48E9qOokqqrvdts8nOJRJN3OWDUoyWxBf7kbu9DBPE=:`
+    let content_digest = strongest(headers, "content-digest", |value| {
+        value.strip_prefix(':')?.strip_suffix(':')
+    });
+    // e.g. `SHA-256=X48E9qOokqqrvdts8nOJRJN3OWDUoyWxBf7kbu9DBPE=`
+    let digest = || strongest(headers, "digest", |value| Some(value));
+    let content_md5 = || {
+        let value = headers.get("content-md5")?.to_str().ok()?;
+        Some((Algorithm::Md5, STANDARD.decode(value.trim()).ok()?))
+    };
+    content_digest
+        .map(|(algorithm, digest)| (algorithm, digest, "Content-Digest"))
+        .or_else(|| digest().map(|(algorithm, digest)| (algorithm, digest, "Digest")))
+        .or_else(|| content_md5().map(|(algorithm, digest)| (algorithm, digest, "Content-MD5")))
+}
+
+/// The strongest supported digest of the `name` headers, whose values are unwrapped by `unwrap`.
+fn strongest(
+    headers: &HeaderMap,
+    name: &str,
+    unwrap: impl Fn(&str) -> Option<&str>,
+) -> Option<(Algorithm, Vec<u8>)> {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|member| {
+            let (name, value) = member.split_once('=')?;
+            let algorithm = Algorithm::parse(name)?;
+            let digest = STANDARD.decode(unwrap(value.trim())?).ok()?;
+            Some((algorithm, digest))
+        })
+        .max_by_key(|(algorithm, _)| *algorithm)
+}
+
+enum Hasher {
+    Md5(Md5),
+    Sha256(Sha256),
+    Sha512(Sha512),
+}
+
+/// Hashing of a body, to compare it with its expected digest.
+pub struct Verifier {
+    hasher: Hasher,
+    algorithm: Algorithm,
+    expected: Vec<u8>,
+    /// Where the expected digest is from, for the logs.
+    source: &'static str,
+}
+
+impl Verifier {
+    fn new(algorithm: Algorithm, expected: Vec<u8>, source: &'static str) -> Self {
+        let hasher = match algorithm {
+            Algorithm::Md5 => Hasher::Md5(Md5::new()),
+            Algorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            Algorithm::Sha512 => Hasher::Sha512(Sha512::new()),
+        };
+        Self {
+            hasher,
+            algorithm,
+            expected,
+            source,
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match &mut self.hasher {
+            Hasher::Md5(hasher) => hasher.update(data),
+            Hasher::Sha256(hasher) => hasher.update(data),
+            Hasher::Sha512(hasher) => hasher.update(data),
+        }
+    }
+
+    /// Check the body hashed so far against the expected digest, counting and logging mismatches.
+    pub fn verify(self, url: &str) -> Result<()> {
+        let actual = match self.hasher {
+            Hasher::Md5(hasher) => hasher.finalize().to_vec(),
+            Hasher::Sha256(hasher) => hasher.finalize().to_vec(),
+            Hasher::Sha512(hasher) => hasher.finalize().to_vec(),
+        };
+        if actual == self.expected {
+            tracing::debug!(
+                url,
+                algorithm = self.algorithm.as_str(),
+                "Verified body digest"
+            );
+            return Ok(());
+        }
+        METRICS.integrity_failures.fetch_add(1, Ordering::Relaxed);
+        tracing::error!(
+            url,
+            algorithm = self.algorithm.as_str(),
+            source = self.source,
+            expected = STANDARD.encode(&self.expected),
+            actual = STANDARD.encode(&actual),
+            "Body did not match its digest"
+        );
+        bail!(
+            "the {} of the body did not match the one of {}",
+            self.algorithm.as_str(),
+            self.source
+        )
+    }
+}
+
+/// State of a streamed body being verified.
+struct Verification {
+    body: BoxStream<'static, reqwest::Result<Bytes>>,
+    verifier: Verifier,
+    url: String,
+    /// The last chunk received, sent once the next one is received or the body is verified.
+    pending: Option<Bytes>,
+}
+
+/// The streamed `body` of `url`, ending with an error instead of its last chunk if it doesn't
+/// match the digest of `verifier`.
+pub fn verified(
+    body: BoxStream<'static, reqwest::Result<Bytes>>,
+    verifier: Option<Verifier>,
+    url: String,
+) -> BoxStream<'static, io::Result<Bytes>> {
+    let Some(verifier) = verifier else {
+        return body.map(|chunk| chunk.map_err(io::Error::other)).boxed();
+    };
+    let verification = Verification {
+        body,
+        verifier,
+        url,
+        pending: None,
+    };
+    unfold(verification).boxed()
+}
+
+fn unfold(verification: Verification) -> impl Stream<Item = io::Result<Bytes>> {
+    futures_util::stream::unfold(Some(verification), |verification| async move {
+        let mut verification = verification?;
+        loop {
+            match verification.body.next().await {
+                Some(Ok(chunk)) => {
+                    verification.verifier.update(&chunk);
+                    if let Some(previous) = verification.pending.replace(chunk) {
+                        return Some((Ok(previous), Some(verification)));
+                    }
+                }
+                Some(Err(err)) => return Some((Err(io::Error::other(err)), None)),
+                None => {
+                    let Verification {
+                        verifier,
+                        url,
+                        pending,
+                        ..
+                    } = verification;
+                    return match verifier.verify(&url) {
+                        Ok(()) => pending.map(|last| (Ok(last), None)),
+                        Err(err) => {
+                            Some((Err(io::Error::new(io::ErrorKind::InvalidData, err)), None))
+                        }
+                    };
+                }
+            }
+        }
+    })
+}
+
+fn unhex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Shifts of the rounds of MD5.
+const MD5_SHIFTS: [u32; 16] = [7, 12, 17, 22, 5, 9, 14, 20, 4, 11, 16, 23, 6, 10, 15, 21];
+
+/// Constants of the steps of MD5, the integer parts of `abs(sin(i + 1)) * 2^32`.
+const MD5_CONSTANTS: [u32; 64] = [
+    0xd76aa478, 0xe8c7b756, 0x242070db, 0xc1bdceee, 0xf57c0faf, 0x4787c62a, 0xa8304613, 0xfd469501,
+    0x698098d8, 0x8b44f7af, 0xffff5bb1, 0x895cd7be, 0x6b901122, 0xfd987193, 0xa679438e, 0x49b40821,
+    0xf61e2562, 0xc040b340, 0x265e5a51, 0xe9b6c7aa, 0xd62f105d, 0x02441453, 0xd8a1e681, 0xe7d3fbc8,
+    0x21e1cde6, 0xc33707d6, 0xf4d50d87, 0x455a14ed, 0xa9e3e905, 0xfcefa3f8, 0x676f02d9, 0x8d2a4c8a,
+    0xfffa3942, 0x8771f681, 0x6d9d6122, 0xfde5380c, 0xa4beea44, 0x4bdecfa9, 0xf6bb4b60, 0xbebfbc70,
+    0x289b7ec6, 0xeaa127fa, 0xd4ef3085, 0x04881d05, 0xd9d4d039, 0xe6db99e5, 0x1fa27cf8, 0xc4ac5665,
+    0xf4292244, 0x432aff97, 0xab9423a7, 0xfc93a039, 0x655b59c3, 0x8f0ccc92, 0xffeff47d, 0x85845dd1,
+    0x6fa87e4f, 0xfe2ce6e0, 0xa3014314, 0x4e0811a1, 0xf7537e82, 0xbd3af235, 0x2ad7d2bb, 0xeb86d391,
+];
+
+/// MD5 (RFC 1321), only to verify the digests of the origins still announcing it.
+struct Md5 {
+    state: [u32; 4],
+    /// Bytes not yet hashed, less than a block.
+    buffer: Vec<u8>,
+    len: u64,
+}
+
+impl Md5 {
+    fn new() -> Self {
+        Self {
+            state: [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476],
+            buffer: Vec::with_capacity(64),
+            len: 0,
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        self.len += data.len() as u64;
+        if !self.buffer.is_empty() {
+            let taken = data.len().min(64 - self.buffer.len());
+            self.buffer.extend_from_slice(&data[..taken]);
+            data = &data[taken..];
+            let Ok(block) = <[u8; 64]>::try_from(self.buffer.as_slice()) else {
+                return;
+            };
+            self.block(&block);
+            self.buffer.clear();
+        }
+        let (blocks, remainder) = data.as_chunks::<64>();
+        for block in blocks {
+            self.block(block);
+        }
+        self.buffer.extend_from_slice(remainder);
+    }
+
+    fn finalize(mut self) -> [u8; 16] {
+        let bits = self.len.wrapping_mul(8);
+        let padding = (119 - self.len % 64) % 64 + 1;
+        let mut tail = vec![0; padding as usize];
+        tail[0] = 0x80;
+        tail.extend_from_slice(&bits.to_le_bytes());
+        self.update(&tail);
+        let mut digest = [0; 16];
+        for (bytes, word) in digest.as_chunks_mut::<4>().0.iter_mut().zip(self.state) {
+            *bytes = word.to_le_bytes();
+        }
+        digest
+    }
+
+    fn block(&mut self, block: &[u8; 64]) {
+        let (words, _) = block.as_chunks::<4>();
+        let [mut a, mut b, mut c, mut d] = self.state;
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let shift = MD5_SHIFTS[i / 16 * 4 + i % 4];
+            let rotated = a
+                .wrapping_add(f)
+                .wrapping_add(MD5_CONSTANTS[i])
+                .wrapping_add(u32::from_le_bytes(words[g]))
+                .rotate_left(shift);
+            (a, b, c, d) = (d, b.wrapping_add(rotated), b, c);
+        }
+        for (word, value) in self.state.iter_mut().zip([a, b, c, d]) {
+            *word = word.wrapping_add(value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HELLO_SHA256: &str = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+    const HELLO_SHA512: &str = concat!(
+        "SHA-512=MJ7MSJwS1utMxA9QyQLytNDtd+5RGnx6m808qG1M2G+YndNb",
+        "xf9JlnDaNCVbRbDP2DDoH2Bdz33FVC6TrpzXbw==",
+    );
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    async fn collect(
+        chunks: &[&'static str],
+        verifier: Option<Verifier>,
+    ) -> Vec<io::Result<Bytes>> {
+        let body = futures_util::stream::iter(
+            chunks
+                .iter()
+                .map(|chunk| Ok(Bytes::from(*chunk)))
+                .collect::<Vec<_>>(),
+        );
+        verified(body.boxed(), verifier, "https://example.com/".to_string())
+            .collect()
+            .await
+    }
+
+    #[test]
+    fn md5() {
+        let vectors = [
+            ("", "d41d8cd98f00b204e9800998ecf8427e"),
+            ("abc", "900150983cd24fb0d6963f7d28e17f72"),
+            (
+                "abcdefghijklmnopqrstuvwxyz",
+                "c3fcd3d76192e4007dfb496cca67e13b",
+            ),
+            (
+                "12345678901234567890123456789012345678901234567890123456789012345678901234567890",
+                "57edf4a22be3c955ac49da2e2107b67a",
+            ),
+        ];
+        for (input, expected) in vectors {
+            let mut whole = Md5::new();
+            whole.update(input.as_bytes());
+            assert_eq!(whole.finalize().to_vec(), unhex(expected).unwrap());
+            // The same digest when the input is hashed in uneven parts, across the blocks.
+            for split in [1, 7, 63] {
+                let mut parts = Md5::new();
+                for part in input.as_bytes().chunks(split) {
+                    parts.update(part);
+                }
+                assert_eq!(parts.finalize().to_vec(), unhex(expected).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn announced_digests() {
+        let (algorithm, digest, source) = announced(&headers(&[
+            ("content-md5", "XrY7u+Ae7tCTyyK7j1rNww=="),
+            ("digest", HELLO_SHA512),
+            (
+                "content-digest",
+                concat!(
+                    "md5=:XrY7u+Ae7tCTyyK7j1rNww==:, ",
+                    "sha-256=:uU0nuZNNPgilLlLX2n2r+sSE7+N6U4DukIj3rOLvzek=:",
+                ),
+            ),
+        ]))
+        .unwrap();
+        assert_eq!(algorithm, Algorithm::Sha256);
+        assert_eq!(digest, unhex(HELLO_SHA256).unwrap());
+        assert_eq!(source, "Content-Digest");
+
+        let (algorithm, _, source) = announced(&headers(&[
+            ("content-md5", "XrY7u+Ae7tCTyyK7j1rNww=="),
+            ("digest", "unixsum=30637"),
+            ("digest", HELLO_SHA512),
+        ]))
+        .unwrap();
+        assert_eq!(algorithm, Algorithm::Sha512);
+        assert_eq!(source, "Digest");
+
+        let (algorithm, _, source) =
+            announced(&headers(&[("content-md5", "XrY7u+Ae7tCTyyK7j1rNww==")])).unwrap();
+        assert_eq!(algorithm, Algorithm::Md5);
+        assert_eq!(source, "Content-MD5");
+
+        // Unwrapped byte sequences and unknown algorithms are ignored.
+        assert!(announced(&headers(&[(
+            "content-digest",
+            "sha-256=uU0nuZNNPgilLlLX2n2r+sSE7+N6U4DukIj3rOLvzek=, crc32c=:yZRlqg==:"
+        )]))
+        .is_none());
+    }
+
+    #[test]
+    fn verifiers() {
+        let url: Url = "https://example.com/hello".parse().unwrap();
+        let wrong = headers(&[(
+            "content-digest",
+            "sha-256=:47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=:",
+        )]);
+        let rules = vec![IntegrityRule {
+            url: url.to_string(),
+            sha256: HELLO_SHA256.to_string(),
+        }];
+        let integrity = Integrity::new(true, rules).unwrap();
+
+        // The configured digest is preferred to the announced one.
+        let mut verifier = integrity.verifier(&url, StatusCode::OK, &wrong).unwrap();
+        verifier.update(b"hello world");
+        verifier.verify(url.as_str()).unwrap();
+
+        let other: Url = "https://example.com/other".parse().unwrap();
+        let mut verifier = integrity.verifier(&other, StatusCode::OK, &wrong).unwrap();
+        verifier.update(b"hello world");
+        assert!(verifier.verify(other.as_str()).is_err());
+
+        // Partial bodies aren't verified, nor the announced digests when disabled.
+        assert!(integrity
+            .verifier(&url, StatusCode::PARTIAL_CONTENT, &wrong)
+            .is_none());
+        let integrity = Integrity::new(false, Vec::new()).unwrap();
+        assert!(integrity.verifier(&other, StatusCode::OK, &wrong).is_none());
+
+        let invalid = |sha256: &str| {
+            Integrity::new(
+                true,
+                vec![IntegrityRule {
+                    url: url.to_string(),
+                    sha256: sha256.to_string(),
+                }],
+            )
+            .is_err()
+        };
+        assert!(invalid("b94d27"));
+        assert!(invalid(&HELLO_SHA256.replace('b', "g")));
+    }
+
+    #[tokio::test]
+    async fn verified_streams() {
+        let verifier = || {
+            Some(Verifier::new(
+                Algorithm::Sha256,
+                unhex(HELLO_SHA256).unwrap(),
+                "the config file",
+            ))
+        };
+
+        let chunks = collect(&["hello", " ", "world"], verifier()).await;
+        let chunks: Vec<_> = chunks.into_iter().map(Result::unwrap).collect();
+        assert_eq!(chunks, ["hello", " ", "world"]);
+
+        // The last chunk of a corrupted body is replaced with an error.
+        let chunks = collect(&["hello", " ", "wor1d"], verifier()).await;
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[0].as_ref().unwrap(), "hello");
+        assert_eq!(chunks[1].as_ref().unwrap(), " ");
+        let err = chunks[2].as_ref().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let chunks = collect(&[], verifier()).await;
+        assert!(chunks[0].is_err());
+
+        let chunks = collect(&["hello"], None).await;
+        assert_eq!(chunks[0].as_ref().unwrap(), "hello");
+    }
+}
@@ -0,0 +1,1054 @@
+//! Recompression of JPEG images at a lower quality, for the data saver.
+//!
+//! The sequential Huffman JPEGs, most of the photos of the web, are requantized: their quantized
+//! DCT coefficients are decoded, divided again by coarser tables and encoded with Huffman tables
+//! optimized for them, without going back to the pixels. The progressive and arithmetic JPEGs
+//! only lose their metadata. The JFIF, ICC and Adobe segments, which change how the image is
+//! displayed, are kept, as is the orientation of the Exif metadata.
+
+/// Markers of the segments.
+const SOF0: u8 = 0xc0;
+const SOF1: u8 = 0xc1;
+const DHT: u8 = 0xc4;
+const SOI: u8 = 0xd8;
+const EOI: u8 = 0xd9;
+const SOS: u8 = 0xda;
+const DQT: u8 = 0xdb;
+const DRI: u8 = 0xdd;
+const APP0: u8 = 0xe0;
+const APP1: u8 = 0xe1;
+const APP2: u8 = 0xe2;
+const APP14: u8 = 0xee;
+const APP15: u8 = 0xef;
+const COM: u8 = 0xfe;
+
+/// Natural index of the coefficients in zigzag order, the order of the tables and scans.
+const ZIGZAG: [usize; 64] = [
+    0, 1, 8, 16, 9, 2, 3, 10, 17, 24, 32, 25, 18, 11, 4, 5, 12, 19, 26, 33, 40, 48, 41, 34, 27, 20,
+    13, 6, 7, 14, 21, 28, 35, 42, 49, 56, 57, 50, 43, 36, 29, 22, 15, 23, 30, 37, 44, 51, 58, 59,
+    52, 45, 38, 31, 39, 46, 53, 60, 61, 54, 47, 55, 62, 63,
+];
+
+/// Quantization tables of the luminance and chrominance of Annex K, for a quality of 50.
+const LUMINANCE: [u16; 64] = [
+    16, 11, 10, 16, 24, 40, 51, 61, 12, 12, 14, 19, 26, 58, 60, 55, 14, 13, 16, 24, 40, 57, 69, 56,
+    14, 17, 22, 29, 51, 87, 80, 62, 18, 22, 37, 56, 68, 109, 103, 77, 24, 35, 55, 64, 81, 104, 113,
+    92, 49, 64, 78, 87, 103, 121, 120, 101, 72, 92, 95, 98, 112, 100, 103, 99,
+];
+const CHROMINANCE: [u16; 64] = [
+    17, 18, 24, 47, 99, 99, 99, 99, 18, 21, 26, 66, 99, 99, 99, 99, 24, 26, 56, 99, 99, 99, 99, 99,
+    47, 66, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99,
+];
+
+/// Bits of the codes looked up at once when decoding, the longer ones being decoded bit by bit.
+const LOOKUP_BITS: u32 = 9;
+
+/// `data` recompressed with the quantization tables of `quality`, or only without its metadata if
+/// it can't be requantized, or `None` if it isn't a well-formed JPEG.
+pub fn recompress(data: &[u8], quality: u8) -> Option<Vec<u8>> {
+    let segments = segments(data)?;
+    Some(requantize(&segments, quality).unwrap_or_else(|| stripped(&segments)))
+}
+
+struct Segment<'a> {
+    marker: u8,
+    /// The segment without its marker and length.
+    body: &'a [u8],
+    /// The entropy-coded data following a scan header.
+    data: &'a [u8],
+}
+
+/// The segments of `data`, up to the end of the image.
+fn segments(data: &[u8]) -> Option<Vec<Segment<'_>>> {
+    if data.get(..2)? != [0xff, SOI] {
+        return None;
+    }
+    let mut segments = Vec::new();
+    let mut pos = 2;
+    loop {
+        if *data.get(pos)? != 0xff {
+            return None;
+        }
+        // markers may be preceded by fill bytes
+        while *data.get(pos + 1)? == 0xff {
+            pos += 1;
+        }
+        let marker = data[pos + 1];
+        pos += 2;
+        match marker {
+            EOI => return Some(segments),
+            0x01 | 0xd0..=0xd7 => return None,
+            _ => {}
+        }
+        let len = usize::from(u16::from_be_bytes([*data.get(pos)?, *data.get(pos + 1)?]));
+        let body = data.get(pos + 2..pos + len.checked_sub(2)? + 2)?;
+        pos += len;
+        let start = pos;
+        if marker == SOS {
+            // up to the next marker other than a restart one
+            while !(*data.get(pos)? == 0xff && !matches!(*data.get(pos + 1)?, 0x00 | 0xd0..=0xd7)) {
+                pos += 1;
+            }
+        }
+        segments.push(Segment {
+            marker,
+            body,
+            data: &data[start..pos],
+        });
+    }
+}
+
+/// The image of `segments` without its metadata.
+fn stripped(segments: &[Segment]) -> Vec<u8> {
+    let mut out = vec![0xff, SOI];
+    for segment in segments {
+        match metadata(segment) {
+            Metadata::Dropped => {}
+            Metadata::Kept(body) => write_segment(&mut out, segment.marker, &body),
+            Metadata::None => {
+                write_segment(&mut out, segment.marker, segment.body);
+                out.extend_from_slice(segment.data);
+            }
+        }
+    }
+    out.extend_from_slice(&[0xff, EOI]);
+    out
+}
+
+enum Metadata {
+    /// Not a metadata segment.
+    None,
+    Dropped,
+    /// Kept, with this body.
+    Kept(Vec<u8>),
+}
+
+fn metadata(segment: &Segment) -> Metadata {
+    let body = segment.body;
+    match segment.marker {
+        APP0 if body.starts_with(b"JFIF\0") => Metadata::Kept(body.to_vec()),
+        APP1 => match orientation(body) {
+            Some(orientation) => Metadata::Kept(exif(orientation)),
+            None => Metadata::Dropped,
+        },
+        APP2 if body.starts_with(b"ICC_PROFILE\0") => Metadata::Kept(body.to_vec()),
+        APP14 if body.starts_with(b"Adobe") => Metadata::Kept(body.to_vec()),
+        APP0..=APP15 | COM => Metadata::Dropped,
+        _ => Metadata::None,
+    }
+}
+
+/// The orientation of the Exif segment `body`, if the image is to be rotated or flipped.
+fn orientation(body: &[u8]) -> Option<u16> {
+    let tiff = body.strip_prefix(b"Exif\0\0")?;
+    let big_endian = match tiff.get(..4)? {
+        b"MM\0*" => true,
+        b"II*\0" => false,
+        _ => return None,
+    };
+    let u16_at = |at: usize| {
+        let bytes = [*tiff.get(at)?, *tiff.get(at + 1)?];
+        Some(match big_endian {
+            true => u16::from_be_bytes(bytes),
+            false => u16::from_le_bytes(bytes),
+        })
+    };
+    let (high, low) = match big_endian {
+        true => (u16_at(4)?, u16_at(6)?),
+        false => (u16_at(6)?, u16_at(4)?),
+    };
+    let ifd = usize::from(high) << 16 | usize::from(low);
+    // the entries of the first IFD, of a tag, a type, a count and a value
+    (0..usize::from(u16_at(ifd)?))
+        .find_map(|i| {
+            let entry = ifd + 2 + 12 * i;
+            (u16_at(entry)? == 0x0112 && u16_at(entry + 2)? == 3)
+                .then(|| u16_at(entry + 8))
+                .flatten()
+        })
+        .filter(|orientation| (2..=8).contains(orientation))
+}
+
+/// An Exif segment with only `orientation`.
+fn exif(orientation: u16) -> Vec<u8> {
+    let mut body = b"Exif\0\0MM\0*\0\0\0\x08\0\x01\x01\x12\0\x03\0\0\0\x01".to_vec();
+    body.extend_from_slice(&orientation.to_be_bytes());
+    body.extend_from_slice(&[0; 6]);
+    body
+}
+
+fn write_segment(out: &mut Vec<u8>, marker: u8, body: &[u8]) {
+    out.extend_from_slice(&[0xff, marker]);
+    out.extend_from_slice(&(body.len() as u16 + 2).to_be_bytes());
+    out.extend_from_slice(body);
+}
+
+struct Component {
+    id: u8,
+    h: usize,
+    v: usize,
+    /// Quantization table.
+    table: usize,
+    /// Blocks per row, in the grid of the MCUs.
+    width: usize,
+    /// Blocks per row and column of the component alone, coded in its non-interleaved scans.
+    coded_width: usize,
+    coded_height: usize,
+    /// Quantized coefficients of the blocks, in zigzag order.
+    blocks: Vec<[i16; 64]>,
+}
+
+struct Frame<'a> {
+    /// The SOF segment, written back as is.
+    marker: u8,
+    body: &'a [u8],
+    components: Vec<Component>,
+    mcus_x: usize,
+    mcus_y: usize,
+}
+
+impl<'a> Frame<'a> {
+    fn parse(marker: u8, body: &'a [u8]) -> Option<Self> {
+        let (&[precision, h1, h0, w1, w0, count], rest) = body.split_first_chunk::<6>()?;
+        let height = usize::from(u16::from_be_bytes([h1, h0]));
+        let width = usize::from(u16::from_be_bytes([w1, w0]));
+        let (components, []) = rest.as_chunks::<3>() else {
+            return None;
+        };
+        // the heights given by a DNL segment after the first scan are not supported
+        if precision != 8 || height == 0 || width == 0 || components.len() != usize::from(count) {
+            return None;
+        }
+        let sampling =
+            |[_, factors, _]: &[u8; 3]| (usize::from(factors >> 4), usize::from(factors & 0xf));
+        if components.is_empty()
+            || components
+                .iter()
+                .map(sampling)
+                .any(|(h, v)| !(1..=4).contains(&h) || !(1..=4).contains(&v))
+        {
+            return None;
+        }
+        let h_max = components.iter().map(|c| sampling(c).0).max()?;
+        let v_max = components.iter().map(|c| sampling(c).1).max()?;
+        let mcus_x = width.div_ceil(8 * h_max);
+        let mcus_y = height.div_ceil(8 * v_max);
+        let components = components
+            .iter()
+            .map(|component| {
+                let (h, v) = sampling(component);
+                let table = usize::from(component[2]);
+                (table < 4).then(|| Component {
+                    id: component[0],
+                    h,
+                    v,
+                    table,
+                    width: mcus_x * h,
+                    coded_width: (width * h).div_ceil(h_max).div_ceil(8),
+                    coded_height: (height * v).div_ceil(v_max).div_ceil(8),
+                    blocks: vec![[0; 64]; mcus_x * h * mcus_y * v],
+                })
+            })
+            .collect::<Option<_>>()?;
+        Some(Self {
+            marker,
+            body,
+            components,
+            mcus_x,
+            mcus_y,
+        })
+    }
+
+    /// Call `f` with the MCU, the position in the scan and the block of each block of the scan of
+    /// `components`, in their coding order.
+    fn for_each_block(
+        &self,
+        components: &[usize],
+        mut f: impl FnMut(usize, usize, usize) -> Option<()>,
+    ) -> Option<()> {
+        if let [index] = components {
+            // non-interleaved scans have MCUs of one block, and skip the padding ones
+            let component = &self.components[*index];
+            for y in 0..component.coded_height {
+                for x in 0..component.coded_width {
+                    f(y * component.coded_width + x, 0, y * component.width + x)?;
+                }
+            }
+            return Some(());
+        }
+        for mcu_y in 0..self.mcus_y {
+            for mcu_x in 0..self.mcus_x {
+                let mcu = mcu_y * self.mcus_x + mcu_x;
+                for (position, &index) in components.iter().enumerate() {
+                    let component = &self.components[index];
+                    for y in 0..component.v {
+                        for x in 0..component.h {
+                            let block = (mcu_y * component.v + y) * component.width
+                                + mcu_x * component.h
+                                + x;
+                            f(mcu, position, block)?;
+                        }
+                    }
+                }
+            }
+        }
+        Some(())
+    }
+
+    /// Decode the scan of `header` and `data` into the blocks of its components.
+    fn decode_scan(
+        &mut self,
+        header: &[u8],
+        data: &[u8],
+        tables: &HuffmanTables,
+        restart_interval: usize,
+    ) -> Option<()> {
+        let (&count, rest) = header.split_first()?;
+        let (selectors, &[0, 63, 0]) = rest.split_at_checked(2 * usize::from(count))? else {
+            return None;
+        };
+        let mut components = Vec::new();
+        let mut codes = Vec::new();
+        for [id, selector] in selectors.as_chunks::<2>().0 {
+            let index = self.components.iter().position(|c| c.id == *id)?;
+            let dc = tables.dc.get(usize::from(selector >> 4))?.as_ref()?;
+            let ac = tables.ac.get(usize::from(selector & 0xf))?.as_ref()?;
+            components.push(index);
+            codes.push((dc, ac));
+        }
+        if components.is_empty() {
+            return None;
+        }
+        let mut reader = BitReader::new(data);
+        let mut predictions = vec![0; components.len()];
+        let mut current = 0;
+        let mut blocks: Vec<_> = self
+            .components
+            .iter_mut()
+            .map(|component| std::mem::take(&mut component.blocks))
+            .collect();
+        let decoded = self.for_each_block(&components, |mcu, position, block| {
+            if mcu != current {
+                current = mcu;
+                if restart_interval > 0 && mcu % restart_interval == 0 {
+                    reader.restart()?;
+                    predictions.fill(0);
+                }
+            }
+            let (dc, ac) = codes[position];
+            let block = &mut blocks[components[position]][block];
+            decode_block(&mut reader, dc, ac, &mut predictions[position], block)
+        });
+        for (component, blocks) in self.components.iter_mut().zip(blocks) {
+            component.blocks = blocks;
+        }
+        decoded
+    }
+
+    /// The scans the image is encoded in, all the components in one when they fit in an MCU.
+    fn scans(&self) -> Vec<Vec<usize>> {
+        let blocks: usize = self.components.iter().map(|c| c.h * c.v).sum();
+        if self.components.len() > 1 && self.components.len() <= 4 && blocks <= 10 {
+            vec![(0..self.components.len()).collect()]
+        } else {
+            (0..self.components.len())
+                .map(|index| vec![index])
+                .collect()
+        }
+    }
+
+    /// Call `f` with the class, the table and the symbol, value and length of the value of each
+    /// code of the scan of `components`, coded with the tables of [`Self::table`].
+    fn for_each_code(&self, components: &[usize], mut f: impl FnMut(usize, usize, u8, u32, u32)) {
+        let mut predictions = vec![0; components.len()];
+        self.for_each_block(components, |_, position, block| {
+            let index = components[position];
+            let table = Self::table(index);
+            let coefficients = &self.components[index].blocks[block];
+            let prediction = &mut predictions[position];
+            let (size, value) = magnitude(i32::from(coefficients[0]) - *prediction);
+            *prediction = i32::from(coefficients[0]);
+            f(0, table, size, value, size.into());
+            let mut run = 0;
+            for &coefficient in &coefficients[1..] {
+                if coefficient == 0 {
+                    run += 1;
+                    continue;
+                }
+                while run > 15 {
+                    f(1, table, 0xf0, 0, 0);
+                    run -= 16;
+                }
+                let (size, value) = magnitude(coefficient.into());
+                f(1, table, run << 4 | size, value, size.into());
+                run = 0;
+            }
+            if run > 0 {
+                f(1, table, 0x00, 0, 0);
+            }
+            Some(())
+        });
+    }
+
+    /// Huffman table of the component `index`, the first of the components having its own.
+    fn table(index: usize) -> usize {
+        usize::from(index > 0)
+    }
+}
+
+#[derive(Default)]
+struct HuffmanTables {
+    dc: [Option<Huffman>; 4],
+    ac: [Option<Huffman>; 4],
+}
+
+/// A Huffman table, as read from the `counts` of the codes of each length and their `values`.
+struct Huffman {
+    /// Length and value of the codes of up to [`LOOKUP_BITS`], by their first bits.
+    lookup: Vec<(u8, u8)>,
+    /// Largest code of each length, -1 if there is none.
+    max_code: [i32; 17],
+    /// Offset from the codes of each length to the index of their values.
+    offsets: [i32; 17],
+    values: Vec<u8>,
+}
+
+impl Huffman {
+    fn new(counts: &[u8; 16], values: &[u8]) -> Option<Self> {
+        let mut lookup = vec![(0, 0); 1 << LOOKUP_BITS];
+        let mut max_code = [-1; 17];
+        let mut offsets = [0; 17];
+        let mut code = 0_i32;
+        let mut index = 0_usize;
+        for len in 1..=16 {
+            let count = usize::from(counts[len - 1]);
+            // the codes of a length can't run out of bits
+            if code + count as i32 > 1 << len {
+                return None;
+            }
+            offsets[len] = index as i32 - code;
+            for value in values.get(index..index + count)? {
+                if len as u32 <= LOOKUP_BITS {
+                    let shift = LOOKUP_BITS - len as u32;
+                    let first = (code as usize) << shift;
+                    lookup[first..first + (1 << shift)].fill((len as u8, *value));
+                }
+                code += 1;
+            }
+            if count > 0 {
+                max_code[len] = code - 1;
+            }
+            index += count;
+            code <<= 1;
+        }
+        Some(Self {
+            lookup,
+            max_code,
+            offsets,
+            values: values[..index].to_vec(),
+        })
+    }
+
+    /// The tables of a DHT segment.
+    fn parse(mut body: &[u8], tables: &mut HuffmanTables) -> Option<()> {
+        while let Some((&class_id, rest)) = body.split_first() {
+            let (counts, rest) = rest.split_first_chunk::<16>()?;
+            let count = counts.iter().map(|&count| usize::from(count)).sum();
+            let (values, rest) = rest.split_at_checked(count)?;
+            let tables = match class_id >> 4 {
+                0 => &mut tables.dc,
+                1 => &mut tables.ac,
+                _ => return None,
+            };
+            *tables.get_mut(usize::from(class_id & 0xf))? = Some(Self::new(counts, values)?);
+            body = rest;
+        }
+        Some(())
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Option<u8> {
+        let (len, value) = self.lookup[reader.peek(LOOKUP_BITS) as usize];
+        if len > 0 {
+            reader.consume(len.into())?;
+            return Some(value);
+        }
+        for len in LOOKUP_BITS + 1..=16 {
+            let code = reader.peek(len) as i32;
+            if code <= self.max_code[len as usize] {
+                reader.consume(len)?;
+                return self
+                    .values
+                    .get((self.offsets[len as usize] + code) as usize)
+                    .copied();
+            }
+        }
+        None
+    }
+}
+
+/// Reader of the bits of entropy-coded data, stopping at its markers.
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    buffer: u64,
+    bits: u32,
+    /// Bits of the buffer past the data, 1s as in the padding of the last byte.
+    padding: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            pos: 0,
+            buffer: 0,
+            bits: 0,
+            padding: 0,
+        }
+    }
+
+    fn fill(&mut self) {
+        while self.bits <= 56 {
+            let byte = match (self.data.get(self.pos), self.data.get(self.pos + 1)) {
+                (Some(0xff), Some(0x00)) => {
+                    self.pos += 2;
+                    0xff
+                }
+                (Some(0xff), _) | (None, _) => {
+                    self.padding += 8;
+                    0xff
+                }
+                (Some(&byte), _) => {
+                    self.pos += 1;
+                    byte
+                }
+            };
+            self.buffer = self.buffer << 8 | u64::from(byte);
+            self.bits += 8;
+        }
+    }
+
+    fn peek(&mut self, len: u32) -> u32 {
+        if self.bits < len {
+            self.fill();
+        }
+        ((self.buffer >> (self.bits - len)) & ((1 << len) - 1)) as u32
+    }
+
+    /// Consume `len` bits, failing if the data is truncated.
+    fn consume(&mut self, len: u32) -> Option<()> {
+        if len > self.bits.saturating_sub(self.padding) {
+            return None;
+        }
+        self.bits -= len;
+        Some(())
+    }
+
+    fn receive(&mut self, size: u8) -> Option<i32> {
+        if size == 0 {
+            return Some(0);
+        }
+        let size = u32::from(size);
+        let value = self.peek(size) as i32;
+        self.consume(size)?;
+        // the negative values are the ones starting with a 0 bit
+        Some(if value < 1 << (size - 1) {
+            value - (1 << size) + 1
+        } else {
+            value
+        })
+    }
+
+    /// Skip the rest of the interval and its restart marker.
+    fn restart(&mut self) -> Option<()> {
+        (self.buffer, self.bits, self.padding) = (0, 0, 0);
+        while !(*self.data.get(self.pos)? == 0xff
+            && matches!(*self.data.get(self.pos + 1)?, 0xd0..=0xd7))
+        {
+            self.pos += 1;
+        }
+        self.pos += 2;
+        Some(())
+    }
+}
+
+fn decode_block(
+    reader: &mut BitReader,
+    dc: &Huffman,
+    ac: &Huffman,
+    prediction: &mut i32,
+    block: &mut [i16; 64],
+) -> Option<()> {
+    let size = dc.decode(reader)?;
+    if size > 11 {
+        return None;
+    }
+    *prediction += reader.receive(size)?;
+    block[0] = i16::try_from(*prediction).ok()?;
+    let mut k = 1;
+    while k < 64 {
+        let symbol = ac.decode(reader)?;
+        let (run, size) = (usize::from(symbol >> 4), symbol & 0xf);
+        if size == 0 {
+            if run != 15 {
+                break;
+            }
+            k += 16;
+            continue;
+        }
+        k += run;
+        *block.get_mut(k)? = i16::try_from(reader.receive(size)?).ok()?;
+        k += 1;
+    }
+    Some(())
+}
+
+/// The category of `value` and its bits, the negative values being offset to have a 0 first bit.
+fn magnitude(value: i32) -> (u8, u32) {
+    let size = 32 - value.unsigned_abs().leading_zeros();
+    let bits = if value < 0 { value - 1 } else { value } as u32;
+    (size as u8, bits & ((1 << size) - 1))
+}
+
+/// The tables of a DQT segment, in zigzag order.
+fn quantization_tables(mut body: &[u8], tables: &mut [Option<[u16; 64]>; 4]) -> Option<()> {
+    while let Some((&precision_id, rest)) = body.split_first() {
+        let mut table = [0; 64];
+        body = match precision_id >> 4 {
+            0 => {
+                let (values, rest) = rest.split_first_chunk::<64>()?;
+                for (entry, value) in table.iter_mut().zip(values) {
+                    *entry = u16::from(*value);
+                }
+                rest
+            }
+            1 => {
+                let (values, rest) = rest.split_first_chunk::<128>()?;
+                for (entry, value) in table.iter_mut().zip(values.as_chunks::<2>().0) {
+                    *entry = u16::from_be_bytes(*value);
+                }
+                rest
+            }
+            _ => return None,
+        };
+        if table.contains(&0) {
+            return None;
+        }
+        *tables.get_mut(usize::from(precision_id & 0xf))? = Some(table);
+    }
+    Some(())
+}
+
+/// A quantization table of Annex K scaled for `quality` as by libjpeg, in zigzag order.
+fn scaled(table: &[u16; 64], quality: u8) -> [u16; 64] {
+    let quality = u32::from(quality.clamp(1, 100));
+    let scale = if quality < 50 {
+        5000 / quality
+    } else {
+        200 - 2 * quality
+    };
+    ZIGZAG.map(|index| ((u32::from(table[index]) * scale + 50) / 100).clamp(1, 255) as u16)
+}
+
+/// `segments` requantized for `quality`, if they are of a sequential Huffman JPEG.
+fn requantize(segments: &[Segment], quality: u8) -> Option<Vec<u8>> {
+    let mut out = vec![0xff, SOI];
+    let mut quantization = [None; 4];
+    let mut huffman = HuffmanTables::default();
+    let mut restart_interval = 0;
+    let mut frame: Option<Frame> = None;
+    for segment in segments {
+        match segment.marker {
+            DQT => quantization_tables(segment.body, &mut quantization)?,
+            DHT => Huffman::parse(segment.body, &mut huffman)?,
+            DRI => {
+                let &[high, low] = segment.body else {
+                    return None;
+                };
+                restart_interval = usize::from(u16::from_be_bytes([high, low]));
+            }
+            SOF0 | SOF1 if frame.is_none() => {
+                frame = Some(Frame::parse(segment.marker, segment.body)?);
+            }
+            SOS => frame.as_mut()?.decode_scan(
+                segment.body,
+                segment.data,
+                &huffman,
+                restart_interval,
+            )?,
+            _ => match metadata(segment) {
+                Metadata::Dropped => {}
+                Metadata::Kept(body) => write_segment(&mut out, segment.marker, &body),
+                // other frames, arithmetic coding or heights defined after the first scan
+                Metadata::None => return None,
+            },
+        }
+    }
+    let mut frame = frame?;
+
+    // coarser tables, the luminance one being the table of the first component
+    let mut requantized = [None; 4];
+    for (id, table) in quantization.iter().enumerate() {
+        let (Some(table), true) = (table, frame.components.iter().any(|c| c.table == id)) else {
+            continue;
+        };
+        let standard = match frame.components[0].table == id {
+            true => &LUMINANCE,
+            false => &CHROMINANCE,
+        };
+        let target = scaled(standard, quality);
+        requantized[id] = Some((
+            *table,
+            std::array::from_fn::<u16, 64, _>(|k| table[k].max(target[k])),
+        ));
+    }
+    for component in &mut frame.components {
+        let (old, new) = requantized[component.table]?;
+        for block in &mut component.blocks {
+            for ((coefficient, old), new) in block.iter_mut().zip(old).zip(new) {
+                if old != new {
+                    // rounded half away from zero
+                    let scaled = i32::from(*coefficient) * i32::from(old);
+                    let half = i32::from(new) / 2 * scaled.signum();
+                    *coefficient = ((scaled + half) / i32::from(new)) as i16;
+                }
+            }
+        }
+    }
+    let mut dqt = Vec::new();
+    for (id, tables) in requantized.iter().enumerate() {
+        let Some((_, table)) = tables else {
+            continue;
+        };
+        if table.iter().any(|&value| value > 255) {
+            dqt.push(0x10 | id as u8);
+            dqt.extend(table.iter().flat_map(|value| value.to_be_bytes()));
+        } else {
+            dqt.push(id as u8);
+            dqt.extend(table.iter().map(|&value| value as u8));
+        }
+    }
+    write_segment(&mut out, DQT, &dqt);
+    write_segment(&mut out, frame.marker, frame.body);
+
+    // Huffman tables optimized for the codes of all the scans
+    let scans = frame.scans();
+    let mut frequencies = [[[0_u32; 256]; 2]; 2];
+    for scan in &scans {
+        frame.for_each_code(scan, |class, table, symbol, _, _| {
+            frequencies[class][table][usize::from(symbol)] += 1;
+        });
+    }
+    let mut dht = Vec::new();
+    let mut codes = [[[(0_u16, 0_u8); 256]; 2]; 2];
+    for (class, tables) in frequencies.iter().enumerate() {
+        for (table, frequencies) in tables.iter().enumerate() {
+            if frequencies.iter().all(|&frequency| frequency == 0) {
+                continue;
+            }
+            let (counts, values) = optimized(frequencies);
+            dht.push((class as u8) << 4 | table as u8);
+            dht.extend_from_slice(&counts);
+            dht.extend_from_slice(&values);
+            codes[class][table] = canonical(&counts, &values);
+        }
+    }
+    write_segment(&mut out, DHT, &dht);
+
+    for scan in &scans {
+        let mut header = vec![scan.len() as u8];
+        for &index in scan {
+            let table = Frame::table(index) as u8;
+            header.extend_from_slice(&[frame.components[index].id, table << 4 | table]);
+        }
+        header.extend_from_slice(&[0, 63, 0]);
+        write_segment(&mut out, SOS, &header);
+        let mut writer = BitWriter {
+            out,
+            buffer: 0,
+            bits: 0,
+        };
+        frame.for_each_code(scan, |class, table, symbol, value, len| {
+            let (code, code_len) = codes[class][table][usize::from(symbol)];
+            writer.write(code.into(), code_len.into());
+            writer.write(value, len);
+        });
+        out = writer.finish();
+    }
+    out.extend_from_slice(&[0xff, EOI]);
+    Some(out)
+}
+
+/// The counts of the codes of each length and the values of a Huffman table optimized for the
+/// `frequencies` of the symbols, of codes of up to 16 bits as by Annex K.2.
+fn optimized(frequencies: &[u32; 256]) -> ([u8; 16], Vec<u8>) {
+    // with a symbol of its own reserving the code of only 1s, which isn't allowed
+    let mut frequencies: Vec<u64> = frequencies.iter().map(|&f| f.into()).chain([1]).collect();
+    let mut sizes = [0_usize; 257];
+    let mut others = [None::<usize>; 257];
+    loop {
+        // the two least frequent symbols, merged into one
+        let (mut first, mut second) = (None::<usize>, None::<usize>);
+        for (symbol, &frequency) in frequencies.iter().enumerate() {
+            if frequency == 0 {
+                continue;
+            }
+            if first.is_none_or(|first| frequency <= frequencies[first]) {
+                (first, second) = (Some(symbol), first);
+            } else if second.is_none_or(|second| frequency <= frequencies[second]) {
+                second = Some(symbol);
+            }
+        }
+        let (Some(mut first), Some(mut second)) = (first, second) else {
+            break;
+        };
+        frequencies[first] += frequencies[second];
+        frequencies[second] = 0;
+        sizes[first] += 1;
+        while let Some(other) = others[first] {
+            first = other;
+            sizes[first] += 1;
+        }
+        others[first] = Some(second);
+        sizes[second] += 1;
+        while let Some(other) = others[second] {
+            second = other;
+            sizes[second] += 1;
+        }
+    }
+    let mut counts = [0_usize; 258];
+    for &size in &sizes {
+        if size > 0 {
+            counts[size] += 1;
+        }
+    }
+    // the codes longer than 16 bits moved to shorter lengths, by pairs
+    for len in (17..counts.len()).rev() {
+        while counts[len] > 0 {
+            let mut shorter = len - 2;
+            while counts[shorter] == 0 {
+                shorter -= 1;
+            }
+            counts[len] -= 2;
+            counts[len - 1] += 1;
+            counts[shorter + 1] += 2;
+            counts[shorter] -= 1;
+        }
+    }
+    if let Some(longest) = (1..=16).rev().find(|&len| counts[len] > 0) {
+        counts[longest] -= 1;
+    }
+    let mut symbols: Vec<_> = (0..256).filter(|&symbol| sizes[symbol] > 0).collect();
+    symbols.sort_by_key(|&symbol| sizes[symbol]);
+    let values = symbols.into_iter().map(|symbol| symbol as u8).collect();
+    (std::array::from_fn(|len| counts[len + 1] as u8), values)
+}
+
+/// The code and length of each value of a Huffman table.
+fn canonical(counts: &[u8; 16], values: &[u8]) -> [(u16, u8); 256] {
+    let mut codes = [(0, 0); 256];
+    let mut values = values.iter();
+    let mut code = 0_u16;
+    for (len, &count) in (1..=16).zip(counts) {
+        for value in values.by_ref().take(count.into()) {
+            codes[usize::from(*value)] = (code, len);
+            code += 1;
+        }
+        code <<= 1;
+    }
+    codes
+}
+
+/// Writer of the bits of entropy-coded data, stuffing a 0 after each `0xff`.
+struct BitWriter {
+    out: Vec<u8>,
+    buffer: u64,
+    bits: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, len: u32) {
+        self.buffer = self.buffer << len | u64::from(value);
+        self.bits += len;
+        while self.bits >= 8 {
+            self.bits -= 8;
+            let byte = (self.buffer >> self.bits) as u8;
+            self.out.push(byte);
+            if byte == 0xff {
+                self.out.push(0);
+            }
+        }
+        self.buffer &= (1 << self.bits) - 1;
+    }
+
+    /// The data, its last byte padded with 1s.
+    fn finish(mut self) -> Vec<u8> {
+        if self.bits > 0 {
+            let padding = 8 - self.bits;
+            self.write((1 << padding) - 1, padding);
+        }
+        self.out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COLOR: &[u8] = include_bytes!("../testdata/jpeg/color.jpg");
+    const GRAY: &[u8] = include_bytes!("../testdata/jpeg/gray.jpg");
+
+    /// The quantization table and the coefficients of each component of the sequential `data`.
+    fn decoded(data: &[u8]) -> Vec<([u16; 64], Vec<[i16; 64]>)> {
+        let mut quantization = [None; 4];
+        let mut huffman = HuffmanTables::default();
+        let mut restart_interval = 0;
+        let mut frame = None;
+        for segment in segments(data).unwrap() {
+            match segment.marker {
+                DQT => quantization_tables(segment.body, &mut quantization).unwrap(),
+                DHT => Huffman::parse(segment.body, &mut huffman).unwrap(),
+                DRI => restart_interval = usize::from(segment.body[1]),
+                SOF0 => frame = Frame::parse(segment.marker, segment.body),
+                SOS => frame
+                    .as_mut()
+                    .unwrap()
+                    .decode_scan(segment.body, segment.data, &huffman, restart_interval)
+                    .unwrap(),
+                _ => {}
+            }
+        }
+        frame
+            .unwrap()
+            .components
+            .into_iter()
+            .map(|component| (quantization[component.table].unwrap(), component.blocks))
+            .collect()
+    }
+
+    fn markers(data: &[u8]) -> Vec<u8> {
+        segments(data)
+            .unwrap()
+            .iter()
+            .map(|segment| segment.marker)
+            .collect()
+    }
+
+    #[test]
+    fn requantizes_sequential_images() {
+        let recompressed = recompress(COLOR, 50).unwrap();
+        assert!(recompressed.len() < COLOR.len());
+        let original = decoded(COLOR);
+        let requantized = decoded(&recompressed);
+        assert_eq!(original.len(), 3);
+        for (index, ((old, before), (new, after))) in original.iter().zip(&requantized).enumerate()
+        {
+            let standard = if index == 0 { &LUMINANCE } else { &CHROMINANCE };
+            let target = scaled(standard, 50);
+            for k in 0..64 {
+                assert_eq!(new[k], old[k].max(target[k]));
+            }
+            assert_eq!(before.len(), after.len());
+            for (before, after) in before.iter().zip(after) {
+                for k in 0..64 {
+                    let value = f64::from(before[k]) * f64::from(old[k]) / f64::from(new[k]);
+                    assert_eq!(after[k], value.round() as i16);
+                }
+            }
+        }
+        // the restart interval is dropped along with the markers
+        assert!(!markers(&recompressed).contains(&DRI));
+    }
+
+    #[test]
+    fn transcodes_losslessly_at_full_quality() {
+        let recompressed = recompress(COLOR, 100).unwrap();
+        // the Huffman tables of the image have codes longer than needed
+        assert!(recompressed.len() < COLOR.len());
+        assert_eq!(decoded(&recompressed), decoded(COLOR));
+    }
+
+    #[test]
+    fn requantizes_non_interleaved_scans() {
+        let recompressed = recompress(GRAY, 30).unwrap();
+        assert!(recompressed.len() < GRAY.len());
+        let (old, before) = &decoded(GRAY)[0];
+        let (new, after) = &decoded(&recompressed)[0];
+        assert_eq!(*new, scaled(&LUMINANCE, 30).map(|value| value.max(1)));
+        assert!(old.iter().zip(new).all(|(old, new)| old <= new));
+        // 3 by 2 blocks, with none padding the MCUs
+        assert_eq!(before.len(), 6);
+        assert_eq!(after.len(), 6);
+    }
+
+    #[test]
+    fn keeps_only_the_metadata_changing_the_display() {
+        let recompressed = recompress(COLOR, 50).unwrap();
+        assert_eq!(
+            markers(COLOR),
+            [APP0, APP1, 0xed, COM, DQT, SOF0, DHT, DRI, SOS]
+        );
+        assert_eq!(markers(&recompressed), [APP0, APP1, DQT, SOF0, DHT, SOS]);
+        let segments = segments(&recompressed).unwrap();
+        assert!(segments[0].body.starts_with(b"JFIF\0"));
+        assert_eq!(orientation(segments[1].body), Some(6));
+        assert_eq!(segments[1].body, exif(6));
+    }
+
+    #[test]
+    fn rejects_malformed_images() {
+        assert!(recompress(&COLOR[..COLOR.len() / 2], 50).is_none());
+        assert!(recompress(b"\xff\xd8\xff\xd0", 50).is_none());
+        assert!(recompress(&[0x89, b'P', b'N', b'G'], 50).is_none());
+        // the entropy-coded data cut short
+        let mut truncated = COLOR[..COLOR.len() - 100].to_vec();
+        truncated.extend_from_slice(&[0xff, EOI]);
+        let segments = segments(&truncated).unwrap();
+        assert!(requantize(&segments, 50).is_none());
+    }
+
+    #[test]
+    fn optimizes_huffman_tables() {
+        let mut frequencies = [0; 256];
+        // frequencies of Fibonacci, which give codes longer than 16 bits without a limit
+        let (mut a, mut b) = (1, 1);
+        for frequency in &mut frequencies[..30] {
+            *frequency = a;
+            (a, b) = (b, a + b);
+        }
+        let (counts, values) = optimized(&frequencies);
+        assert_eq!(values.len(), 30);
+        // the most frequent symbols first
+        assert_eq!(values[..2], [28, 29]);
+        assert_eq!(
+            counts
+                .iter()
+                .map(|&count| usize::from(count))
+                .sum::<usize>(),
+            30
+        );
+        // complete but for the code of only 1s
+        let kraft: u32 = (1..=16)
+            .zip(counts)
+            .map(|(len, count)| u32::from(count) << (16 - len))
+            .sum();
+        assert_eq!(kraft, (1 << 16) - 1);
+        let huffman = Huffman::new(&counts, &values).unwrap();
+        let codes = canonical(&counts, &values);
+        let mut writer = BitWriter {
+            out: Vec::new(),
+            buffer: 0,
+            bits: 0,
+        };
+        for &value in &values {
+            let (code, len) = codes[usize::from(value)];
+            writer.write(code.into(), len.into());
+        }
+        let data = writer.finish();
+        let mut reader = BitReader::new(&data);
+        for &value in &values {
+            assert_eq!(huffman.decode(&mut reader), Some(value));
+        }
+    }
+}
@@ -0,0 +1,405 @@
+//! A simple proxy that forwards requests to a given URL with a custom User-Agent.
+//!
+//! The binary runs it with [`run`], and other programs can embed it with a [`ProxyBuilder`].
+use std::{
+    ffi::OsString,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    os::fd::RawFd,
+    path::PathBuf,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context, Result};
+pub use async_trait::async_trait;
+use axum::{routing::MethodRouter, Extension, Router};
+use clap::Parser;
+
+pub use auth::{Authenticator, Decision};
+pub use cache::Stats as CacheStats;
+pub use cli::{run, Cli};
+use config::Config;
+pub use connector::{Connector, Io};
+pub use dns::DnsStats;
+pub use hooks::{Flow, Hook, ProxyError, RequestHead};
+pub use metrics::Snapshot;
+use metrics::METRICS;
+use state::AppState;
+use tenant::ListenerTenant;
+
+mod access_log;
+mod admin;
+mod alerts;
+mod anonymize;
+mod auth;
+mod bench;
+mod body;
+mod cache;
+mod capture;
+mod challenge;
+mod chaos;
+mod circuit;
+mod clamav;
+mod cli;
+mod coalesce;
+mod compression;
+mod concurrency;
+mod config;
+mod connector;
+mod cors;
+mod data_saver;
+mod decompress;
+mod dns;
+mod drain;
+mod errors;
+mod forwarded;
+mod geoip;
+mod grpc;
+mod har;
+mod hedge;
+mod history;
+mod hooks;
+mod html;
+mod integrity;
+mod jpeg;
+mod listener;
+mod log_file;
+mod maintenance;
+mod memory;
+mod metrics;
+mod mock;
+mod oauth;
+mod ocsp;
+mod pacing;
+mod pcap;
+mod persona;
+mod pipeline;
+mod plugins;
+mod png;
+mod prefetch;
+mod presign;
+mod profile;
+mod quota;
+mod rate_limit;
+mod redact;
+mod redirect;
+mod redis;
+mod replay;
+mod resume;
+mod retry;
+mod rewrite;
+mod routes;
+mod schedule;
+mod scripts;
+mod server;
+mod session;
+mod sigv4;
+mod state;
+mod statsd;
+mod syslog;
+mod tail;
+mod telemetry;
+mod tenant;
+mod throttle;
+mod timing;
+mod tls;
+mod upgrade;
+mod upstream;
+mod usage;
+mod validate;
+
+/// Address the proxy listens on when none is set.
+const DEFAULT_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 7788);
+
+/// Builder of a proxy, to run it within another program.
+///
+/// The options are the ones of the command line, the proxy doesn't log unless the program sets up
+/// a `tracing` subscriber, and doesn't handle the process signals.
+pub struct ProxyBuilder {
+    cli: Cli,
+    auth_token: String,
+    addr: SocketAddr,
+    listener: Option<std::net::TcpListener>,
+    /// Whether the proxy drains on `SIGTERM`, upgrades on `SIGHUP` and logs its metrics on
+    /// `SIGUSR1`.
+    signals: bool,
+    routes: Vec<(String, MethodRouter)>,
+    hooks: Vec<Arc<dyn Hook>>,
+    connector: Option<Arc<dyn Connector>>,
+    authenticator: Option<Arc<dyn Authenticator>>,
+}
+
+impl ProxyBuilder {
+    /// A proxy with the default options, whose clients authenticate with `auth_token`.
+    pub fn new(auth_token: impl Into<String>) -> Self {
+        Self::from_cli(Cli::parse_from(["simple-proxy"]), auth_token.into())
+    }
+
+    /// A proxy with the options parsed from command line arguments, e.g. `["--cache"]`.
+    pub fn from_args<I, T>(auth_token: impl Into<String>, args: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        let args =
+            std::iter::once(OsString::from("simple-proxy")).chain(args.into_iter().map(Into::into));
+        Ok(Self::from_cli(
+            Cli::try_parse_from(args)?,
+            auth_token.into(),
+        ))
+    }
+
+    fn from_cli(cli: Cli, auth_token: String) -> Self {
+        Self {
+            cli,
+            auth_token,
+            addr: DEFAULT_ADDR,
+            listener: None,
+            signals: false,
+            routes: Vec::new(),
+            hooks: Vec::new(),
+            connector: None,
+            authenticator: None,
+        }
+    }
+
+    /// Listen on `addr`, e.g. `127.0.0.1:0` for a port chosen by the system.
+    pub fn addr(mut self, addr: SocketAddr) -> Self {
+        self.addr = addr;
+        self
+    }
+
+    /// Accept the connections of a bound listener, instead of listening on an address.
+    pub fn listener(mut self, listener: std::net::TcpListener) -> Self {
+        self.listener = Some(listener);
+        self
+    }
+
+    /// `User-Agent` of the default persona, sent to the origins.
+    pub fn user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.cli.user_agent = Some(user_agent.into());
+        self
+    }
+
+    /// Read the settings that don't fit on the command line from a TOML file.
+    pub fn config_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.cli.config = Some(path.into());
+        self
+    }
+
+    /// Limit the requests per second of each client IP.
+    pub fn rate_limit(mut self, rate: f64, burst: u32) -> Self {
+        self.cli.rate_limit = Some(rate);
+        self.cli.rate_limit_burst = burst;
+        self
+    }
+
+    /// Limit the requests handled at once, the other ones waiting or being rejected.
+    pub fn max_concurrent_requests(mut self, max: usize) -> Self {
+        self.cli.max_concurrent_requests = Some(max);
+        self
+    }
+
+    /// Serve `path` with a custom handler, next to the proxy route at `/`.
+    ///
+    /// The handler goes through the same middlewares as the proxy route, but isn't authenticated.
+    pub fn route(mut self, path: &str, handler: MethodRouter) -> Self {
+        self.routes.push((path.to_string(), handler));
+        self
+    }
+
+    /// Intercept the requests and responses with `hook`, after the hooks registered before.
+    pub fn hook(mut self, hook: impl Hook) -> Self {
+        self.hooks.push(Arc::new(hook));
+        self
+    }
+
+    /// Open the upstream connections with `connector`, instead of connecting to the origins.
+    pub fn connector(mut self, connector: impl Connector) -> Self {
+        self.connector = Some(Arc::new(connector));
+        self
+    }
+
+    /// Authenticate the clients with `authenticator`, instead of the auth token.
+    pub fn authenticator(mut self, authenticator: impl Authenticator) -> Self {
+        self.authenticator = Some(Arc::new(authenticator));
+        self
+    }
+
+    fn handle_signals(mut self) -> Self {
+        self.signals = true;
+        self
+    }
+}
+
+/// A proxy whose listeners are bound, see [`ProxyBuilder`].
+pub struct Proxy {
+    state: AppState,
+    app: Router,
+    listeners: Vec<tokio::net::TcpListener>,
+    /// Admin API listener, passed on upgrades.
+    admin_fd: Option<RawFd>,
+    signals: bool,
+    config_path: Option<PathBuf>,
+    client_idle_timeout: Duration,
+}
+
+impl Proxy {
+    /// Address of the first listener, e.g. to find the port chosen by the system.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listeners[0].local_addr()?)
+    }
+
+    /// Serve the clients in the background, controlled with the returned handle.
+    pub fn start(self) -> Result<ProxyHandle> {
+        let (state, addr) = (self.state.clone(), self.local_addr()?);
+        let config_path = self.config_path.clone();
+        Ok(ProxyHandle {
+            state,
+            addr,
+            config_path,
+            task: tokio::spawn(self.serve()),
+        })
+    }
+
+    /// Serve the clients until the connections are drained.
+    pub async fn serve(self) -> Result<()> {
+        let Self {
+            state: app_state,
+            app,
+            listeners,
+            admin_fd,
+            signals,
+            config_path: _,
+            client_idle_timeout,
+        } = self;
+        app_state.ready.store(true, Ordering::Relaxed);
+        if signals {
+            upgrade::take_over();
+            let listener_fds = upgrade::raw_fds(&listeners);
+            tokio::spawn(async move {
+                if let Err(err) = upgrade::upgrade_on_sighup(listener_fds, admin_fd).await {
+                    tracing::error!(error = %err, "Could not listen for SIGHUP");
+                }
+            });
+            let (drain, ready) = (app_state.drain.clone(), app_state.ready.clone());
+            tokio::spawn(async move {
+                match drain::signal_received().await {
+                    Ok(()) => {
+                        ready.store(false, Ordering::Relaxed);
+                        drain.stop().await;
+                    }
+                    Err(err) => tracing::error!(error = %err, "Could not listen for SIGTERM"),
+                }
+            });
+        }
+        let servers = listeners.into_iter().map(|listener| {
+            let tenant = listener
+                .local_addr()
+                .ok()
+                .and_then(|addr| app_state.tenants.by_listener(addr));
+            let app = match tenant {
+                Some(tenant) => app.clone().layer(Extension(ListenerTenant(tenant.clone()))),
+                None => app.clone(),
+            };
+            tokio::spawn(server::serve(
+                listener,
+                app,
+                app_state.drain.clone(),
+                client_idle_timeout,
+                app_state.geoip.clone(),
+            ))
+        });
+        let servers = futures_util::future::join_all(servers);
+        let results = tokio::select! {
+            results = servers => results,
+            () = app_state.drain.expired() => {
+                tracing::warn!(
+                    active_connections = METRICS.active_connections.load(Ordering::Relaxed),
+                    "Drain timed out, closing the remaining connections"
+                );
+                Vec::new()
+            }
+        };
+        for result in results {
+            result??;
+        }
+        save_state(&app_state);
+        Ok(())
+    }
+}
+
+/// Handle of a proxy serving in the background, see [`Proxy::start`].
+pub struct ProxyHandle {
+    state: AppState,
+    addr: SocketAddr,
+    config_path: Option<PathBuf>,
+    task: tokio::task::JoinHandle<Result<()>>,
+}
+
+impl ProxyHandle {
+    /// Address of the first listener.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Current stats, the ones served by the `/stats` endpoint of the admin API.
+    pub fn metrics(&self) -> Snapshot {
+        metrics::snapshot(&self.state)
+    }
+
+    /// Authenticate the clients with `auth_token` from now on, unless they authenticate with a
+    /// custom authenticator.
+    pub fn set_auth_token(&self, auth_token: impl Into<String>) {
+        *self.state.auth_token.write().unwrap() = auth_token.into();
+    }
+
+    /// Read the config file again, and apply the settings that can change at runtime: the
+    /// `host_limits` for now.
+    pub fn reload_config(&self) -> Result<()> {
+        let path = self
+            .config_path
+            .as_ref()
+            .ok_or_else(|| anyhow!("the proxy has no config file"))?;
+        self.state.apply_config(Config::load(path)?)
+    }
+
+    /// Stop accepting connections, and wait until the open ones are drained.
+    pub async fn shutdown(self) -> Result<()> {
+        self.state.ready.store(false, Ordering::Relaxed);
+        self.state.drain.start();
+        self.task.await?
+    }
+}
+
+/// Persist the state that is otherwise saved periodically, before exiting.
+fn save_state(state: &AppState) {
+    for quotas in state.all_quotas() {
+        if let Err(err) = quotas.save() {
+            tracing::error!(error = %err, "Could not save usage");
+        }
+    }
+    if let Some(har) = &state.har {
+        if let Err(err) = har.save() {
+            tracing::error!(error = %err, "Could not save HAR file");
+        }
+    }
+    if let Err(err) = state.usage.flush_history() {
+        tracing::error!(error = %err, "Could not save usage statistics");
+    }
+    if let Err(err) = state.usage.export() {
+        tracing::error!(error = %err, "Could not export usage");
+    }
+}
+
+/// Read the auth token again from the `.env` file, as the process environment can't change.
+fn reload_auth_token(state: &AppState) -> Result<()> {
+    let token = dotenvy::dotenv_iter()
+        .context("could not read the .env file")?
+        .filter_map(Result::ok)
+        .find(|(key, _)| key == "AUTH_TOKEN")
+        .map(|(_, value)| value)
+        .ok_or_else(|| anyhow!("AUTH_TOKEN is not set in the .env file"))?;
+    *state.auth_token.write().unwrap() = token;
+    Ok(())
+}
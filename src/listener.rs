@@ -0,0 +1,81 @@
+//! Binding of the proxy listener sockets.
+use std::{net::SocketAddr, time::Duration};
+
+use anyhow::{ensure, Context, Result};
+use serde::Deserialize;
+use socket2::{SockRef, TcpKeepalive};
+use tokio::net::{TcpListener, TcpSocket};
+
+use crate::config::ByteSize;
+
+/// Socket options of the listener, which the accepted client connections inherit.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenerConfig {
+    /// Disable Nagle's algorithm.
+    pub nodelay: bool,
+    /// Idle time before TCP keepalive probes are sent, disabled if not set.
+    #[serde(with = "humantime_serde")]
+    pub keepalive: Option<Duration>,
+    /// Size of the kernel send buffer, left to autotuning if not set.
+    pub send_buffer: Option<ByteSize>,
+    /// Size of the kernel receive buffer, left to autotuning if not set.
+    pub recv_buffer: Option<ByteSize>,
+    /// Length of the queue of connections waiting to be accepted.
+    pub backlog: u32,
+}
+
+impl Default for ListenerConfig {
+    fn default() -> Self {
+        Self {
+            nodelay: false,
+            keepalive: None,
+            send_buffer: None,
+            recv_buffer: None,
+            backlog: 1024,
+        }
+    }
+}
+
+/// Bind `acceptors` listeners to `addr`.
+///
+/// With more than one, each socket sets `SO_REUSEPORT` so that the kernel balances the incoming
+/// connections between them, and each one can be served by a different worker thread.
+pub fn bind(
+    addr: SocketAddr,
+    acceptors: usize,
+    config: &ListenerConfig,
+) -> Result<Vec<TcpListener>> {
+    ensure!(acceptors > 0, "The number of acceptors must be positive");
+    (0..acceptors)
+        .map(|_| {
+            let socket = if addr.is_ipv4() {
+                TcpSocket::new_v4()?
+            } else {
+                TcpSocket::new_v6()?
+            };
+            socket.set_reuseaddr(true)?;
+            if acceptors > 1 {
+                socket.set_reuseport(true)?;
+            }
+            let options = SockRef::from(&socket);
+            options.set_nodelay(config.nodelay)?;
+            if let Some(keepalive) = config.keepalive {
+                let keepalive = TcpKeepalive::new()
+                    .with_time(keepalive)
+                    .with_interval(keepalive);
+                options.set_tcp_keepalive(&keepalive)?;
+            }
+            if let Some(ByteSize(size)) = config.send_buffer {
+                options.set_send_buffer_size(size as usize)?;
+            }
+            if let Some(ByteSize(size)) = config.recv_buffer {
+                options.set_recv_buffer_size(size as usize)?;
+            }
+            socket
+                .bind(addr)
+                .with_context(|| format!("could not listen on {addr}"))?;
+            Ok(socket.listen(config.backlog)?)
+        })
+        .collect()
+}
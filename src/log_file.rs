@@ -0,0 +1,111 @@
+//! Log files rotated by size and time, keeping a bounded number of old files.
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+use clap::ValueEnum;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Period {
+    Never,
+    Hourly,
+    Daily,
+}
+
+impl Period {
+    /// Name of the period containing `time`, files are rotated when it changes.
+    fn of(self, time: DateTime<Local>) -> String {
+        match self {
+            Self::Never => String::new(),
+            Self::Hourly => time.format("%Y-%m-%d %H").to_string(),
+            Self::Daily => time.format("%Y-%m-%d").to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Rotation {
+    pub period: Period,
+    /// Size past which the file is rotated, unlimited if not set.
+    pub max_size: Option<u64>,
+    /// Number of rotated files kept, as `<path>.1` (the most recent) to `<path>.<max_files>`.
+    pub max_files: usize,
+}
+
+/// Append-only file which is rotated before a write when it is full or its period is over.
+pub struct RotatingFile {
+    path: PathBuf,
+    rotation: Rotation,
+    file: File,
+    size: u64,
+    period: String,
+}
+
+impl RotatingFile {
+    pub fn open(path: &Path, rotation: Rotation) -> Result<Self> {
+        let file = open(path).with_context(|| format!("could not open {}", path.display()))?;
+        let metadata = file.metadata()?;
+        let modified = metadata
+            .modified()
+            .map(DateTime::from)
+            .unwrap_or(Local::now());
+        Ok(Self {
+            path: path.to_path_buf(),
+            rotation,
+            file,
+            size: metadata.len(),
+            period: rotation.period.of(modified),
+        })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let rotated = |index: usize| {
+            let mut path = self.path.clone().into_os_string();
+            path.push(format!(".{index}"));
+            PathBuf::from(path)
+        };
+        if self.rotation.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for index in (1..self.rotation.max_files).rev() {
+                match fs::rename(rotated(index), rotated(index + 1)) {
+                    Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                    _ => {}
+                }
+            }
+            fs::rename(&self.path, rotated(1))?;
+        }
+        self.file = open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let period = self.rotation.period.of(Local::now());
+        let full = self
+            .rotation
+            .max_size
+            .is_some_and(|max_size| self.size > 0 && self.size + buf.len() as u64 > max_size);
+        if full || period != self.period {
+            self.rotate()?;
+            self.period = period;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn open(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
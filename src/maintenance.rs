@@ -0,0 +1,109 @@
+//! Maintenance mode, toggled with the admin API, in which the proxy answers all the requests with
+//! a 503 and a `Retry-After` so that the clients back off while the egress path is down.
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
+};
+
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderValue, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::errors;
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MaintenanceConfig {
+    /// Start in maintenance mode.
+    pub enabled: bool,
+    /// Delay after which the clients are told to try again.
+    #[serde(with = "humantime_serde")]
+    pub retry_after: Duration,
+    /// Message of the error page.
+    pub message: String,
+}
+
+impl Default for MaintenanceConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            retry_after: Duration::from_secs(5 * 60),
+            message: "The proxy is under maintenance".to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Serialize)]
+pub struct Status {
+    pub enabled: bool,
+    pub retry_after: u64,
+    pub message: String,
+}
+
+pub struct Maintenance {
+    enabled: AtomicBool,
+    /// `Retry-After` in seconds, and the message.
+    page: RwLock<(u64, String)>,
+}
+
+impl Maintenance {
+    pub fn new(config: MaintenanceConfig) -> Self {
+        Self {
+            enabled: AtomicBool::new(config.enabled),
+            page: RwLock::new((config.retry_after.as_secs(), config.message)),
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        let (retry_after, message) = self.page.read().unwrap().clone();
+        Status {
+            enabled: self.enabled.load(Ordering::Relaxed),
+            retry_after,
+            message,
+        }
+    }
+
+    /// Enter maintenance mode, with another `Retry-After` or message if set.
+    pub fn enable(&self, retry_after: Option<Duration>, message: Option<String>) {
+        {
+            let mut page = self.page.write().unwrap();
+            if let Some(retry_after) = retry_after {
+                page.0 = retry_after.as_secs();
+            }
+            if let Some(message) = message {
+                page.1 = message;
+            }
+        }
+        self.enabled.store(true, Ordering::Relaxed);
+        tracing::warn!("Entered maintenance mode");
+    }
+
+    pub fn disable(&self) {
+        self.enabled.store(false, Ordering::Relaxed);
+        tracing::warn!("Left maintenance mode");
+    }
+}
+
+/// Middleware answering the requests with the maintenance page while in maintenance mode.
+pub async fn reject(
+    State(maintenance): State<Arc<Maintenance>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if !maintenance.enabled.load(Ordering::Relaxed) {
+        return next.run(request).await;
+    }
+    let (retry_after, message) = maintenance.page.read().unwrap().clone();
+    let mut response = errors::response(StatusCode::SERVICE_UNAVAILABLE, "maintenance", message);
+    response
+        .headers_mut()
+        .insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+    response
+}
@@ -0,0 +1,90 @@
+//! Approximate accounting of the memory held by response bodies, shedding new requests when it
+//! exceeds a budget.
+//!
+//! The cached bodies count towards the budget, but the cache is shrunk first when it is exceeded,
+//! the requests being only shed while the bodies in flight exceed it on their own.
+use std::sync::{atomic::Ordering, Arc};
+
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::Response,
+};
+
+use crate::{cache::Cache, errors, metrics::METRICS};
+
+/// Bytes of a response body buffered outside of the cache, released when dropped.
+#[derive(Default)]
+pub struct Reservation(u64);
+
+impl Reservation {
+    pub fn add(&mut self, bytes: usize) {
+        self.0 += bytes as u64;
+        METRICS
+            .buffered_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        METRICS.buffered_bytes.fetch_sub(self.0, Ordering::Relaxed);
+    }
+}
+
+pub struct MemoryBudget {
+    limit: u64,
+    cache: Option<Arc<Cache>>,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: u64, cache: Option<Arc<Cache>>) -> Self {
+        Self { limit, cache }
+    }
+
+    /// Bytes of the bodies being buffered or sent, and of the cached ones.
+    fn used(&self) -> u64 {
+        let cached = self.cache.as_ref().map_or(0, |cache| cache.stored_bytes());
+        METRICS.buffered_bytes.load(Ordering::Relaxed) + cached
+    }
+
+    /// Bytes used once the cache is shrunk to fit in the budget, if needed.
+    fn reclaim(&self) -> u64 {
+        let used = self.used();
+        match &self.cache {
+            Some(cache) if used > self.limit => {
+                let freed = cache.shrink(used - self.limit);
+                tracing::info!(
+                    freed_bytes = freed,
+                    "Memory budget exceeded, shrunk the cache"
+                );
+                used.saturating_sub(freed)
+            }
+            _ => used,
+        }
+    }
+}
+
+/// Middleware shedding requests with `503 Service Unavailable` while the budget is exceeded.
+pub async fn shed(
+    State(budget): State<Arc<MemoryBudget>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let used = budget.reclaim();
+    if used > budget.limit {
+        METRICS.shed.fetch_add(1, Ordering::Relaxed);
+        tracing::warn!(
+            used_bytes = used,
+            budget_bytes = budget.limit,
+            "Memory budget exceeded, shedding load"
+        );
+        return errors::response(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "overloaded",
+            "Server overloaded",
+        );
+    }
+    next.run(request).await
+}
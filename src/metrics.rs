@@ -0,0 +1,682 @@
+//! Process-wide metrics, exposed in the Prometheus text format by the admin API.
+//!
+//! Scrapers accepting the OpenMetrics format also get exemplars on the latency histograms: the
+//! trace id of the last sampled request of each bucket, when traces are exported.
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Result;
+use axum::{extract::Request, http::StatusCode, middleware::Next, response::Response};
+use serde::Serialize;
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::{cache::Stats, dns::DnsStats, statsd::STATSD, AppState};
+
+pub static METRICS: Metrics = Metrics::new();
+
+/// Upper bounds of the latency histogram buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+/// Number of hosts with their own latency histogram in the metrics, by number of requests.
+const TOP_HOSTS: usize = 20;
+/// Number of hosts whose latency is tracked, the others are grouped together.
+const MAX_TRACKED_HOSTS: usize = 1000;
+/// Label of the hosts that are not in the top hosts.
+const OTHER_HOSTS: &str = "other";
+/// Number of hosts listed in the stats snapshots, by number of requests.
+const SNAPSHOT_TOP_HOSTS: usize = 10;
+
+/// Runtime stats, served by the admin API and logged on `SIGUSR1`.
+#[derive(Serialize)]
+pub struct Snapshot {
+    pub uptime_secs: u64,
+    pub active_connections: u64,
+    /// Active connections without a request in flight.
+    pub idle_connections: u64,
+    /// Responses by status class.
+    pub responses: BTreeMap<String, u64>,
+    pub auth_failures: u64,
+    pub shed_requests: u64,
+    pub upstream_bytes_received: u64,
+    pub client_bytes_sent: u64,
+    /// Upstream hosts with the most requests, and their number of requests.
+    pub top_hosts: Vec<(String, u64)>,
+    /// Resident memory of the process, only known on Linux.
+    pub memory_bytes: Option<u64>,
+    pub cache: Option<Stats>,
+    pub dns_cache: Option<DnsStats>,
+    /// Sessions that have not expired, if sessions are enabled.
+    pub active_sessions: Option<usize>,
+}
+
+/// Representative sample of a histogram bucket.
+#[derive(Clone)]
+struct Exemplar {
+    trace_id: String,
+    seconds: f64,
+    /// Unix time of the sample, in seconds.
+    timestamp: f64,
+}
+
+pub struct Histogram {
+    /// Non-cumulative counts, the last one being the `+Inf` bucket.
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    /// Last traced sample of each bucket.
+    exemplars: [Mutex<Option<Exemplar>>; LATENCY_BUCKETS.len() + 1],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub const fn new() -> Self {
+        Self {
+            buckets: [const { AtomicU64::new(0) }; LATENCY_BUCKETS.len() + 1],
+            exemplars: [const { Mutex::new(None) }; LATENCY_BUCKETS.len() + 1],
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    /// Record a sample, kept as the exemplar of its bucket if it has a `trace_id`.
+    pub fn observe(&self, duration: Duration, trace_id: Option<&str>) {
+        let seconds = duration.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        if let Some(trace_id) = trace_id {
+            let timestamp = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64();
+            *self.exemplars[bucket].lock().unwrap() = Some(Exemplar {
+                trace_id: trace_id.to_string(),
+                seconds,
+                timestamp,
+            });
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Upper bound of the bucket of the `quantile`, `None` if it is above the largest bucket.
+    fn quantile(&self, quantile: f64) -> Option<Duration> {
+        let target = (self.count() as f64 * quantile).ceil() as u64;
+        let mut cumulative = 0;
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            if cumulative >= target {
+                return Some(Duration::from_secs_f64(*bound));
+            }
+        }
+        None
+    }
+
+    /// Add the samples of `other` to this histogram.
+    fn merge(&self, other: &Histogram) {
+        for (bucket, other) in self.buckets.iter().zip(&other.buckets) {
+            bucket.fetch_add(other.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+        for (exemplar, other) in self.exemplars.iter().zip(&other.exemplars) {
+            let mut exemplar = exemplar.lock().unwrap();
+            if exemplar.is_none() {
+                exemplar.clone_from(&other.lock().unwrap());
+            }
+        }
+        self.count.fetch_add(other.count(), Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(other.sum_micros.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
+
+    /// Write the samples of the histogram, with `labels` being a possibly empty label list, and
+    /// their exemplars if `exemplars` is set.
+    fn render(&self, out: &mut String, name: &str, labels: &str, exemplars: bool) {
+        let separator = if labels.is_empty() { "" } else { "," };
+        let bounds = LATENCY_BUCKETS.iter().map(f64::to_string);
+        let mut cumulative = 0;
+        for ((bound, bucket), exemplar) in bounds
+            .chain(["+Inf".to_string()])
+            .zip(&self.buckets)
+            .zip(&self.exemplars)
+        {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = write!(
+                out,
+                "{name}_bucket{{{labels}{separator}le=\"{bound}\"}} {cumulative}"
+            );
+            if let Some(exemplar) = exemplar.lock().unwrap().as_ref().filter(|_| exemplars) {
+                let _ = write!(
+                    out,
+                    " # {{trace_id=\"{}\"}} {} {:.3}",
+                    exemplar.trace_id, exemplar.seconds, exemplar.timestamp
+                );
+            }
+            out.push('\n');
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let labels = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{labels}}}")
+        };
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "{name}_sum{labels} {sum}");
+        let _ = writeln!(out, "{name}_count{labels} {count}");
+    }
+}
+
+pub struct Metrics {
+    /// Responses to clients, by status class (`1xx` to `5xx`).
+    pub responses: [AtomicU64; 5],
+    pub auth_failures: AtomicU64,
+    /// Requests whose handling panicked.
+    pub panics: AtomicU64,
+    /// Requests shed because of the concurrency limit or of the memory budget.
+    pub shed: AtomicU64,
+    /// Body bytes received from origins.
+    pub bytes_received: AtomicU64,
+    /// Body bytes sent to clients.
+    pub bytes_sent: AtomicU64,
+    pub active_connections: AtomicU64,
+    /// Client connections without a request in flight.
+    pub idle_connections: AtomicU64,
+    /// Response bodies held in memory outside of the cache, see [`crate::memory`].
+    pub buffered_bytes: AtomicU64,
+    pub upstream_requests: AtomicU64,
+    /// Upstream requests that failed or were answered with a server error.
+    pub upstream_errors: AtomicU64,
+    /// Upstream requests sent again after a failure, also counted in the upstream requests.
+    pub upstream_retries: AtomicU64,
+    /// Duplicate upstream requests sent to slow hosts, also counted in the upstream requests.
+    pub upstream_hedges: AtomicU64,
+    /// Responses blocked by the antivirus.
+    pub infected_responses: AtomicU64,
+    /// Response bodies that didn't match their digest.
+    pub integrity_failures: AtomicU64,
+    /// Images recompressed for the clients saving data, and the bytes it saved them.
+    pub data_saver_images: AtomicU64,
+    pub data_saver_bytes_saved: AtomicU64,
+    pub upstream_latency: Histogram,
+    /// Upstream latency by destination host, up to [`MAX_TRACKED_HOSTS`] hosts.
+    host_latency: Mutex<Option<HashMap<String, Histogram>>>,
+    /// Requests rejected by the proxy's limits, by rule name.
+    rate_limited: Mutex<BTreeMap<&'static str, u64>>,
+    /// Upstream responses detected as anti-bot challenges, by signature name.
+    challenges: Mutex<BTreeMap<String, u64>>,
+    /// Faults injected by the chaos rules, by kind.
+    faults: Mutex<BTreeMap<&'static str, u64>>,
+    /// Requests rejected by the strict validation, by reason.
+    invalid: Mutex<BTreeMap<&'static str, u64>>,
+    /// Client connections by country, and whether they were accepted.
+    geoip: Mutex<BTreeMap<(String, bool), u64>>,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            responses: [const { AtomicU64::new(0) }; 5],
+            auth_failures: AtomicU64::new(0),
+            panics: AtomicU64::new(0),
+            shed: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            active_connections: AtomicU64::new(0),
+            idle_connections: AtomicU64::new(0),
+            buffered_bytes: AtomicU64::new(0),
+            upstream_requests: AtomicU64::new(0),
+            upstream_errors: AtomicU64::new(0),
+            upstream_retries: AtomicU64::new(0),
+            upstream_hedges: AtomicU64::new(0),
+            infected_responses: AtomicU64::new(0),
+            integrity_failures: AtomicU64::new(0),
+            data_saver_images: AtomicU64::new(0),
+            data_saver_bytes_saved: AtomicU64::new(0),
+            upstream_latency: Histogram::new(),
+            host_latency: Mutex::new(None),
+            rate_limited: Mutex::new(BTreeMap::new()),
+            challenges: Mutex::new(BTreeMap::new()),
+            faults: Mutex::new(BTreeMap::new()),
+            invalid: Mutex::new(BTreeMap::new()),
+            geoip: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn record_response(&self, status: StatusCode) {
+        let class = usize::from(status.as_u16() / 100).clamp(1, 5) - 1;
+        self.responses[class].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record the latency of a request to `host`, along with the overall latency, and the trace of
+    /// the request as an exemplar.
+    pub fn observe_upstream_latency(&self, host: &str, duration: Duration, trace_id: Option<&str>) {
+        self.upstream_latency.observe(duration, trace_id);
+        if let Some(statsd) = STATSD.get() {
+            statsd.timing("upstream_latency", &[("host", host)], duration);
+        }
+        let mut hosts = self.host_latency.lock().unwrap();
+        let hosts = hosts.get_or_insert_with(HashMap::new);
+        if let Some(histogram) = hosts.get(host) {
+            histogram.observe(duration, trace_id);
+        } else {
+            let key = if hosts.len() < MAX_TRACKED_HOSTS {
+                host
+            } else {
+                OTHER_HOSTS
+            };
+            hosts
+                .entry(key.to_string())
+                .or_insert_with(Histogram::new)
+                .observe(duration, trace_id);
+        }
+    }
+
+    /// Approximate `quantile` of the upstream latency of `host`, if it has at least `min_samples`.
+    pub fn host_latency_quantile(
+        &self,
+        host: &str,
+        quantile: f64,
+        min_samples: u64,
+    ) -> Option<Duration> {
+        let hosts = self.host_latency.lock().unwrap();
+        let histogram = hosts.as_ref()?.get(host)?;
+        (histogram.count() >= min_samples)
+            .then(|| histogram.quantile(quantile))
+            .flatten()
+    }
+
+    pub fn record_rate_limited(&self, rule: &'static str) {
+        *self.rate_limited.lock().unwrap().entry(rule).or_default() += 1;
+    }
+
+    pub fn record_challenge(&self, signature: &str) {
+        let mut challenges = self.challenges.lock().unwrap();
+        match challenges.get_mut(signature) {
+            Some(count) => *count += 1,
+            None => {
+                challenges.insert(signature.to_string(), 1);
+            }
+        }
+    }
+
+    pub fn record_fault(&self, fault: &'static str) {
+        *self.faults.lock().unwrap().entry(fault).or_default() += 1;
+    }
+
+    pub fn record_invalid(&self, reason: &'static str) {
+        *self.invalid.lock().unwrap().entry(reason).or_default() += 1;
+    }
+
+    pub fn record_geoip(&self, country: &str, accepted: bool) {
+        let mut geoip = self.geoip.lock().unwrap();
+        *geoip.entry((country.to_string(), accepted)).or_default() += 1;
+    }
+
+    /// Hosts with the most upstream requests, by number of requests.
+    fn top_hosts(&self, count: usize) -> Vec<(String, u64)> {
+        let hosts = self.host_latency.lock().unwrap();
+        let mut top: Vec<_> = hosts
+            .iter()
+            .flatten()
+            .map(|(host, histogram)| (host.clone(), histogram.count()))
+            .collect();
+        top.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        top.truncate(count);
+        top
+    }
+
+    /// Number of rejected requests, by rule name.
+    pub fn rate_limited(&self) -> Vec<(&'static str, u64)> {
+        let rate_limited = self.rate_limited.lock().unwrap();
+        rate_limited
+            .iter()
+            .map(|(rule, count)| (*rule, *count))
+            .collect()
+    }
+
+    /// Render all metrics in the Prometheus text exposition format, or in the OpenMetrics one
+    /// with the exemplars if `openmetrics` is set.
+    pub fn render(&self, state: &AppState, openmetrics: bool) -> String {
+        let mut out = String::new();
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+        out.push_str("# TYPE simple_proxy_responses_total counter\n");
+        for (class, counter) in self.responses.iter().enumerate() {
+            let _ = writeln!(
+                out,
+                "simple_proxy_responses_total{{class=\"{}xx\"}} {}",
+                class + 1,
+                load(counter)
+            );
+        }
+        for (name, kind, value) in [
+            ("auth_failures_total", "counter", load(&self.auth_failures)),
+            ("panics_total", "counter", load(&self.panics)),
+            ("shed_requests_total", "counter", load(&self.shed)),
+            (
+                "upstream_bytes_received_total",
+                "counter",
+                load(&self.bytes_received),
+            ),
+            ("client_bytes_sent_total", "counter", load(&self.bytes_sent)),
+            (
+                "upstream_requests_total",
+                "counter",
+                load(&self.upstream_requests),
+            ),
+            (
+                "upstream_errors_total",
+                "counter",
+                load(&self.upstream_errors),
+            ),
+            (
+                "upstream_retries_total",
+                "counter",
+                load(&self.upstream_retries),
+            ),
+            (
+                "upstream_hedges_total",
+                "counter",
+                load(&self.upstream_hedges),
+            ),
+            (
+                "infected_responses_total",
+                "counter",
+                load(&self.infected_responses),
+            ),
+            (
+                "integrity_failures_total",
+                "counter",
+                load(&self.integrity_failures),
+            ),
+            (
+                "data_saver_images_total",
+                "counter",
+                load(&self.data_saver_images),
+            ),
+            (
+                "data_saver_bytes_saved_total",
+                "counter",
+                load(&self.data_saver_bytes_saved),
+            ),
+            (
+                "active_connections",
+                "gauge",
+                load(&self.active_connections),
+            ),
+            ("idle_connections", "gauge", load(&self.idle_connections)),
+            ("buffered_bytes", "gauge", load(&self.buffered_bytes)),
+        ] {
+            let _ = writeln!(out, "# TYPE simple_proxy_{name} {kind}");
+            let _ = writeln!(out, "simple_proxy_{name} {value}");
+        }
+        out.push_str("# TYPE simple_proxy_rate_limited_total counter\n");
+        for (rule, count) in self.rate_limited.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "simple_proxy_rate_limited_total{{rule=\"{rule}\"}} {count}"
+            );
+        }
+        out.push_str("# TYPE simple_proxy_upstream_challenges_total counter\n");
+        for (signature, count) in self.challenges.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "simple_proxy_upstream_challenges_total{{signature=\"{signature}\"}} {count}"
+            );
+        }
+        out.push_str("# TYPE simple_proxy_injected_faults_total counter\n");
+        for (fault, count) in self.faults.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "simple_proxy_injected_faults_total{{fault=\"{fault}\"}} {count}"
+            );
+        }
+        out.push_str("# TYPE simple_proxy_invalid_requests_total counter\n");
+        for (reason, count) in self.invalid.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "simple_proxy_invalid_requests_total{{reason=\"{reason}\"}} {count}"
+            );
+        }
+        out.push_str("# TYPE simple_proxy_geoip_connections_total counter\n");
+        for ((country, accepted), count) in self.geoip.lock().unwrap().iter() {
+            let decision = if *accepted { "accepted" } else { "rejected" };
+            let _ = writeln!(
+                out,
+                "simple_proxy_geoip_connections_total{{country=\"{country}\",decision=\"{decision}\"}} {count}"
+            );
+        }
+        out.push_str("# TYPE simple_proxy_upstream_latency_seconds histogram\n");
+        self.upstream_latency.render(
+            &mut out,
+            "simple_proxy_upstream_latency_seconds",
+            "",
+            openmetrics,
+        );
+        self.render_host_latency(&mut out, openmetrics);
+        render_runtime(&mut out);
+
+        if let Some(cache) = &state.cache {
+            let stats = cache.stats();
+            out.push_str("# TYPE simple_proxy_cache_lookups_total counter\n");
+            for (status, count) in [
+                ("hit", stats.hits),
+                ("miss", stats.misses),
+                ("stale", stats.stale),
+                ("bypass", stats.bypasses),
+            ] {
+                let _ = writeln!(
+                    out,
+                    "simple_proxy_cache_lookups_total{{status=\"{status}\"}} {count}"
+                );
+            }
+            for (name, kind, value) in [
+                ("cache_evictions_total", "counter", stats.evictions),
+                ("cache_entries", "gauge", stats.entries as u64),
+                ("cache_stored_bytes", "gauge", stats.stored_bytes as u64),
+            ] {
+                let _ = writeln!(out, "# TYPE simple_proxy_{name} {kind}");
+                let _ = writeln!(out, "simple_proxy_{name} {value}");
+            }
+        }
+        if let Some(stats) = state.dns.stats() {
+            out.push_str("# TYPE simple_proxy_dns_cache_lookups_total counter\n");
+            for (status, count) in [("hit", stats.hits), ("miss", stats.misses)] {
+                let _ = writeln!(
+                    out,
+                    "simple_proxy_dns_cache_lookups_total{{status=\"{status}\"}} {count}"
+                );
+            }
+            out.push_str("# TYPE simple_proxy_dns_prefetches_total counter\n");
+            let _ = writeln!(
+                out,
+                "simple_proxy_dns_prefetches_total {}",
+                stats.prefetches
+            );
+            out.push_str("# TYPE simple_proxy_dns_cache_entries gauge\n");
+            let _ = writeln!(out, "simple_proxy_dns_cache_entries {}", stats.entries);
+        }
+        if !openmetrics {
+            return out;
+        }
+        // OpenMetrics names the counters without their `_total` suffix
+        let mut families = String::with_capacity(out.len());
+        for line in out.lines() {
+            match line.strip_suffix("_total counter") {
+                Some(family) => families.push_str(&format!("{family} counter\n")),
+                None => families.push_str(&format!("{line}\n")),
+            }
+        }
+        families.push_str("# EOF\n");
+        families
+    }
+}
+
+impl Metrics {
+    /// Write the latency histograms of the busiest hosts, the others being merged together.
+    fn render_host_latency(&self, out: &mut String, exemplars: bool) {
+        let hosts = self.host_latency.lock().unwrap();
+        let Some(hosts) = hosts.as_ref() else {
+            return;
+        };
+        let mut by_count: Vec<_> = hosts
+            .iter()
+            .filter(|(host, _)| *host != OTHER_HOSTS)
+            .collect();
+        by_count.sort_unstable_by_key(|(_, histogram)| std::cmp::Reverse(histogram.count()));
+        let rest = by_count.split_off(by_count.len().min(TOP_HOSTS));
+        let name = "simple_proxy_upstream_host_latency_seconds";
+        let _ = writeln!(out, "# TYPE {name} histogram");
+        for (host, histogram) in by_count {
+            let host = host.replace('\\', "\\\\").replace('"', "\\\"");
+            histogram.render(out, name, &format!("host=\"{host}\""), exemplars);
+        }
+        let other = Histogram::new();
+        for (_, histogram) in rest {
+            other.merge(histogram);
+        }
+        if let Some(histogram) = hosts.get(OTHER_HOSTS) {
+            other.merge(histogram);
+        }
+        if other.count() > 0 {
+            other.render(out, name, &format!("host=\"{OTHER_HOSTS}\""), exemplars);
+        }
+    }
+}
+
+/// Write the metrics of the tokio runtime, to diagnose the stalls of the event loop: the busy time
+/// of the workers and the tasks waiting to run. The ones of the blocking pool are only known to
+/// the builds with `RUSTFLAGS="--cfg tokio_unstable"`.
+fn render_runtime(out: &mut String) {
+    let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+        return;
+    };
+    let metrics = runtime.metrics();
+    let gauge = |out: &mut String, name: &str, value: usize| {
+        let _ = writeln!(out, "# TYPE simple_proxy_{name} gauge");
+        let _ = writeln!(out, "simple_proxy_{name} {value}");
+    };
+    gauge(out, "runtime_workers", metrics.num_workers());
+    gauge(out, "runtime_alive_tasks", metrics.num_alive_tasks());
+    gauge(
+        out,
+        "runtime_global_queue_depth",
+        metrics.global_queue_depth(),
+    );
+    #[cfg(tokio_unstable)]
+    {
+        gauge(
+            out,
+            "runtime_blocking_threads",
+            metrics.num_blocking_threads(),
+        );
+        gauge(
+            out,
+            "runtime_idle_blocking_threads",
+            metrics.num_idle_blocking_threads(),
+        );
+        gauge(
+            out,
+            "runtime_blocking_queue_depth",
+            metrics.blocking_queue_depth(),
+        );
+    }
+    // the rate of the busy time is the utilization of each worker
+    out.push_str("# TYPE simple_proxy_runtime_worker_busy_seconds_total counter\n");
+    for worker in 0..metrics.num_workers() {
+        let _ = writeln!(
+            out,
+            "simple_proxy_runtime_worker_busy_seconds_total{{worker=\"{worker}\"}} {}",
+            metrics.worker_total_busy_duration(worker).as_secs_f64()
+        );
+    }
+    out.push_str("# TYPE simple_proxy_runtime_worker_parks_total counter\n");
+    for worker in 0..metrics.num_workers() {
+        let _ = writeln!(
+            out,
+            "simple_proxy_runtime_worker_parks_total{{worker=\"{worker}\"}} {}",
+            metrics.worker_park_count(worker)
+        );
+    }
+}
+
+pub fn snapshot(state: &AppState) -> Snapshot {
+    let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+    Snapshot {
+        uptime_secs: state.started.elapsed().as_secs(),
+        active_connections: load(&METRICS.active_connections),
+        idle_connections: load(&METRICS.idle_connections),
+        responses: METRICS
+            .responses
+            .iter()
+            .enumerate()
+            .map(|(class, counter)| (format!("{}xx", class + 1), load(counter)))
+            .collect(),
+        auth_failures: load(&METRICS.auth_failures),
+        shed_requests: load(&METRICS.shed),
+        upstream_bytes_received: load(&METRICS.bytes_received),
+        client_bytes_sent: load(&METRICS.bytes_sent),
+        top_hosts: METRICS.top_hosts(SNAPSHOT_TOP_HOSTS),
+        memory_bytes: resident_memory(),
+        cache: state.cache.as_ref().map(|cache| cache.stats()),
+        dns_cache: state.dns.stats(),
+        active_sessions: state.sessions.as_ref().map(|sessions| sessions.active()),
+    }
+}
+
+/// Resident set size of the process, from `/proc/self/status`.
+pub fn resident_memory() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?;
+    let kilobytes: u64 = line.trim().strip_suffix("kB")?.trim().parse().ok()?;
+    Some(kilobytes * 1024)
+}
+
+/// Log a stats snapshot on each `SIGUSR1`, for hosts where the admin API is not reachable.
+pub async fn log_on_sigusr1(state: AppState) -> Result<()> {
+    let mut signals = signal(SignalKind::user_defined1())?;
+    while signals.recv().await.is_some() {
+        let snapshot = serde_json::to_string(&snapshot(&state)).expect("stats are serializable");
+        tracing::info!(stats = snapshot, "Stats snapshot");
+    }
+    Ok(())
+}
+
+/// Middleware counting responses by status class.
+pub async fn track_responses(request: Request, next: Next) -> Response {
+    let response = next.run(request).await;
+    METRICS.record_response(response.status());
+    response
+}
+
+/// Counts a client connection in the active connections gauge, until it is closed.
+pub struct ConnectionGuard;
+
+impl ConnectionGuard {
+    pub fn new() -> Self {
+        METRICS.active_connections.fetch_add(1, Ordering::Relaxed);
+        Self
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        METRICS.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
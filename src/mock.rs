@@ -0,0 +1,129 @@
+//! Canned responses to the requests matching the mock rules, answered without reaching the origins,
+//! to stub endpoints out during development or while an origin is under maintenance.
+use std::{collections::BTreeMap, fs, path::PathBuf};
+
+use anyhow::{ensure, Context, Result};
+use axum::{
+    body::{Body, Bytes},
+    http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode},
+    response::Response,
+};
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MockRule {
+    /// Method of the client request, any if not set.
+    pub method: Option<String>,
+    /// Target URL, where `*` matches any characters.
+    pub url: String,
+    #[serde(default = "MockRule::default_status")]
+    pub status: u16,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    #[serde(default)]
+    pub body: String,
+    /// File whose contents are the body, instead of `body`.
+    pub body_file: Option<PathBuf>,
+}
+
+impl MockRule {
+    fn default_status() -> u16 {
+        200
+    }
+}
+
+struct Mock {
+    method: Option<Method>,
+    url: String,
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+pub struct Mocks {
+    mocks: Vec<Mock>,
+}
+
+impl Mocks {
+    pub fn new(rules: Vec<MockRule>) -> Result<Self> {
+        let mocks = rules
+            .into_iter()
+            .map(|rule| {
+                let url = rule.url;
+                let body = match &rule.body_file {
+                    Some(path) => {
+                        ensure!(
+                            rule.body.is_empty(),
+                            "the mock of {url} has both a `body` and a `body_file`"
+                        );
+                        fs::read(path)
+                            .with_context(|| {
+                                format!("could not read mock body {}", path.display())
+                            })?
+                            .into()
+                    }
+                    None => rule.body.into(),
+                };
+                let headers = rule
+                    .headers
+                    .iter()
+                    .map(|(name, value)| {
+                        Ok((
+                            HeaderName::try_from(name.as_str())?,
+                            HeaderValue::try_from(value.as_str())?,
+                        ))
+                    })
+                    .collect::<Result<_>>()
+                    .with_context(|| format!("invalid headers in the mock of {url}"))?;
+                Ok(Mock {
+                    method: rule
+                        .method
+                        .map(|method| Method::from_bytes(method.to_uppercase().as_bytes()))
+                        .transpose()?,
+                    status: StatusCode::from_u16(rule.status)?,
+                    url,
+                    headers,
+                    body,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { mocks })
+    }
+
+    /// The canned response of the first rule matching the request, if any.
+    pub fn respond(&self, method: &Method, url: &str) -> Option<Response> {
+        let mock = self.mocks.iter().find(|mock| {
+            mock.method
+                .as_ref()
+                .is_none_or(|expected| expected == method)
+                && matches_glob(&mock.url, url)
+        })?;
+        tracing::info!(pattern = mock.url, "Answered from mock");
+        let mut response = Response::new(Body::from(mock.body.clone()));
+        *response.status_mut() = mock.status;
+        *response.headers_mut() = mock.headers.clone();
+        Some(response)
+    }
+}
+
+/// Whether `text` matches `pattern`, where `*` matches any characters.
+fn matches_glob(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts: Vec<_> = parts.collect();
+    let Some(last) = parts.pop() else {
+        // no wildcard
+        return rest.is_empty();
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
@@ -0,0 +1,150 @@
+//! OAuth2 access tokens of the client credentials grant, attached to the upstream requests to the
+//! matching hosts so that the internal clients don't need to handle the tokens themselves.
+//!
+//! The tokens are cached and fetched again before they expire, or once the origin rejects them.
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use axum::http::HeaderValue;
+use reqwest::Url;
+use serde::Deserialize;
+
+use crate::{config::HostPattern, redact::Secret, upstream::Upstream};
+
+/// Margin before the expiration of the tokens, when they are fetched again.
+const REFRESH_MARGIN: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OAuthRule {
+    pub host: HostPattern,
+    /// Token endpoint of the authorization server.
+    pub token_url: String,
+    pub client_id: String,
+    pub client_secret: Secret<String>,
+    /// Space-separated scopes of the requested tokens.
+    pub scope: Option<String>,
+    /// Audience of the requested tokens, for the servers requiring one.
+    pub audience: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    token_type: Option<String>,
+    expires_in: Option<u64>,
+}
+
+#[derive(Clone)]
+struct Token {
+    authorization: HeaderValue,
+    /// `None` for the tokens that don't expire.
+    expires: Option<Instant>,
+}
+
+pub struct TokenSource {
+    rule: OAuthRule,
+    token_url: Url,
+    token: Mutex<Option<Token>>,
+}
+
+impl TokenSource {
+    /// The `Authorization` of the upstream requests, fetching a token with `upstream` if the
+    /// cached one is about to expire.
+    pub async fn authorization(&self, upstream: &Upstream) -> Result<HeaderValue> {
+        let cached = self.token.lock().unwrap().clone();
+        let fresh = cached.filter(|token| {
+            token
+                .expires
+                .is_none_or(|expires| expires > Instant::now() + REFRESH_MARGIN)
+        });
+        if let Some(token) = fresh {
+            return Ok(token.authorization);
+        }
+        let token = self
+            .fetch(upstream)
+            .await
+            .with_context(|| format!("could not get an access token for {}", self.rule.host))?;
+        tracing::info!(host = %self.rule.host, "Fetched OAuth2 access token");
+        let authorization = token.authorization.clone();
+        *self.token.lock().unwrap() = Some(token);
+        Ok(authorization)
+    }
+
+    /// Forget the cached token, after the origin rejected it.
+    pub fn invalidate(&self) {
+        if self.token.lock().unwrap().take().is_some() {
+            tracing::warn!(host = %self.rule.host, "Upstream rejected the OAuth2 access token");
+        }
+    }
+
+    async fn fetch(&self, upstream: &Upstream) -> Result<Token> {
+        let mut form = vec![("grant_type", "client_credentials")];
+        if let Some(scope) = &self.rule.scope {
+            form.push(("scope", scope));
+        }
+        if let Some(audience) = &self.rule.audience {
+            form.push(("audience", audience));
+        }
+        let fetched = Instant::now();
+        let response = upstream
+            .client(&self.token_url, None)
+            .post(self.token_url.clone())
+            .basic_auth(&self.rule.client_id, Some(&*self.rule.client_secret))
+            .form(&form)
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        let response: TokenResponse = serde_json::from_slice(&response)?;
+        // the token type is case-insensitive, but some origins only accept `Bearer`
+        let token_type = match response.token_type {
+            Some(token_type) if !token_type.eq_ignore_ascii_case("bearer") => token_type,
+            _ => "Bearer".to_string(),
+        };
+        let mut authorization =
+            HeaderValue::try_from(format!("{token_type} {}", response.access_token))?;
+        authorization.set_sensitive(true);
+        Ok(Token {
+            authorization,
+            expires: response
+                .expires_in
+                .map(|expires_in| fetched + Duration::from_secs(expires_in)),
+        })
+    }
+}
+
+pub struct OAuth {
+    sources: Vec<TokenSource>,
+}
+
+impl OAuth {
+    pub fn new(rules: Vec<OAuthRule>) -> Result<Self> {
+        let sources = rules
+            .into_iter()
+            .map(|rule| {
+                let token_url = rule
+                    .token_url
+                    .parse()
+                    .with_context(|| format!("invalid token URL of {}", rule.host))?;
+                Ok(TokenSource {
+                    rule,
+                    token_url,
+                    token: Mutex::new(None),
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { sources })
+    }
+
+    /// The tokens of the requests to `host`, the first matching rule applying.
+    pub fn source(&self, host: &str) -> Option<&TokenSource> {
+        self.sources
+            .iter()
+            .find(|source| source.rule.host.matches(host))
+    }
+}
@@ -0,0 +1,374 @@
+//! Checks of the OCSP responses stapled by the origins to their certificates (RFC 6960).
+//!
+//! Only the status of the certificate of the origin is checked, in a response signed by its issuer
+//! or by a responder the issuer delegated to, the issuer being one of the intermediates sent by the
+//! origin.
+use chrono::NaiveDateTime;
+use ring::digest;
+use rustls::{crypto::ring::default_provider, pki_types::CertificateDer};
+
+use crate::tls::{der_element, DerElement};
+
+const SEQUENCE: u8 = 0x30;
+const INTEGER: u8 = 0x02;
+const BIT_STRING: u8 = 0x03;
+const OCTET_STRING: u8 = 0x04;
+const OID: u8 = 0x06;
+const ENUMERATED: u8 = 0x0a;
+const UTC_TIME: u8 = 0x17;
+const GENERALIZED_TIME: u8 = 0x18;
+
+/// `id-pkix-ocsp-basic`, the type of the responses.
+const BASIC_RESPONSE: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x30, 0x01, 0x01];
+/// `id-ce-extKeyUsage` and `id-kp-OCSPSigning`, the purpose of the delegated responders.
+const EXTENDED_KEY_USAGE: &[u8] = &[0x55, 0x1d, 0x25];
+const OCSP_SIGNING: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x03, 0x09];
+/// Hash algorithms of the certificate ids.
+const SHA1: &[u8] = &[0x2b, 0x0e, 0x03, 0x02, 0x1a];
+const SHA256: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01];
+
+/// Tolerated difference between the clocks of the responders and the proxy, in seconds.
+const CLOCK_SKEW: u64 = 5 * 60;
+/// How long a response without a next update is fresh, in seconds.
+const MAX_AGE: u64 = 7 * 24 * 60 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Good,
+    Revoked,
+    /// The responder doesn't know the certificate.
+    Unknown,
+}
+
+/// The status of `cert` in the stapled `response` at `now` (Unix time), `None` if the response is
+/// invalid, stale, about another certificate or from an untrusted responder.
+pub fn status(
+    cert: &CertificateDer<'_>,
+    intermediates: &[CertificateDer<'_>],
+    response: &[u8],
+    now: u64,
+) -> Option<Status> {
+    let cert = Certificate::parse(cert)?;
+    let issuer = intermediates
+        .iter()
+        .filter_map(|intermediate| Certificate::parse(intermediate))
+        .find(|intermediate| {
+            intermediate.subject == cert.issuer
+                && verify(cert.tbs, cert.algorithm, cert.signature, intermediate.key)
+        })?;
+
+    // OCSPResponse
+    let mut fields = element(response, SEQUENCE)?.0;
+    if next(&mut fields, ENUMERATED)? != [0] {
+        return None;
+    }
+    let mut bytes = element(next(&mut fields, 0xa0)?, SEQUENCE)?.0;
+    if next(&mut bytes, OID)? != BASIC_RESPONSE {
+        return None;
+    }
+    // BasicOCSPResponse
+    let mut basic = element(next(&mut bytes, OCTET_STRING)?, SEQUENCE)?.0;
+    let (data, rest) = der_element(basic)?;
+    basic = rest;
+    let algorithm = next(&mut basic, SEQUENCE)?;
+    let signature = next(&mut basic, BIT_STRING)?;
+    let signed_by = |key: &[u8]| verify(data.raw, algorithm, signature, key);
+    // certificates of the delegated responders
+    let certs = optional(&mut basic, 0xa0)
+        .and_then(|certs| element(certs, SEQUENCE))
+        .map_or(&[][..], |(certs, _)| certs);
+    let trusted = signed_by(issuer.key)
+        || elements(certs)
+            .filter_map(|responder| Certificate::parse(responder.raw))
+            .any(|responder| responder.delegated_by(&issuer, now) && signed_by(responder.key));
+    if !trusted {
+        return None;
+    }
+
+    // ResponseData
+    let mut data = data.content;
+    optional(&mut data, 0xa0);
+    // responder id, by name or key
+    data = der_element(data)?.1;
+    next(&mut data, GENERALIZED_TIME)?;
+    let mut responses = next(&mut data, SEQUENCE)?;
+    while !responses.is_empty() {
+        let mut single = next(&mut responses, SEQUENCE)?;
+        let id = next(&mut single, SEQUENCE)?;
+        let (status, rest) = der_element(single)?;
+        single = rest;
+        if !cert.identified_by(id, &issuer) {
+            continue;
+        }
+        let this_update = time(GENERALIZED_TIME, next(&mut single, GENERALIZED_TIME)?)?;
+        let next_update = match optional(&mut single, 0xa0) {
+            Some(mut next_update) => {
+                time(GENERALIZED_TIME, next(&mut next_update, GENERALIZED_TIME)?)?
+            }
+            None => this_update + MAX_AGE,
+        };
+        if this_update > now + CLOCK_SKEW || next_update + CLOCK_SKEW < now {
+            tracing::debug!("Stale stapled OCSP response");
+            return None;
+        }
+        return match status.raw[0] {
+            0x80 => Some(Status::Good),
+            0xa1 => Some(Status::Revoked),
+            0x82 => Some(Status::Unknown),
+            _ => None,
+        };
+    }
+    None
+}
+
+/// The fields of a certificate used by the checks (RFC 5280, section 4.1).
+struct Certificate<'a> {
+    tbs: &'a [u8],
+    algorithm: &'a [u8],
+    signature: &'a [u8],
+    serial: &'a [u8],
+    issuer: &'a [u8],
+    not_before: u64,
+    not_after: u64,
+    subject: &'a [u8],
+    /// The `SubjectPublicKeyInfo`.
+    key: &'a [u8],
+    extensions: &'a [u8],
+}
+
+impl<'a> Certificate<'a> {
+    fn parse(der: &'a [u8]) -> Option<Self> {
+        let mut certificate = element(der, SEQUENCE)?.0;
+        let (tbs, rest) = der_element(certificate)?;
+        certificate = rest;
+        let algorithm = next(&mut certificate, SEQUENCE)?;
+        let signature = next(&mut certificate, BIT_STRING)?;
+        let mut fields = tbs.content;
+        optional(&mut fields, 0xa0);
+        let serial = next(&mut fields, INTEGER)?;
+        next(&mut fields, SEQUENCE)?;
+        let issuer = raw(&mut fields, SEQUENCE)?;
+        let mut validity = next(&mut fields, SEQUENCE)?;
+        let not_before = any_time(&mut validity)?;
+        let not_after = any_time(&mut validity)?;
+        let subject = raw(&mut fields, SEQUENCE)?;
+        let key = raw(&mut fields, SEQUENCE)?;
+        optional(&mut fields, 0x81);
+        optional(&mut fields, 0x82);
+        let extensions = match optional(&mut fields, 0xa3) {
+            Some(extensions) => element(extensions, SEQUENCE)?.0,
+            None => &[],
+        };
+        Some(Self {
+            tbs: tbs.raw,
+            algorithm,
+            signature,
+            serial,
+            issuer,
+            not_before,
+            not_after,
+            subject,
+            key,
+            extensions,
+        })
+    }
+
+    /// Whether the certificate id of a response is the one of this certificate.
+    fn identified_by(&self, id: &[u8], issuer: &Certificate<'_>) -> bool {
+        let identified = |mut id: &[u8]| {
+            let mut algorithm = next(&mut id, SEQUENCE)?;
+            let algorithm = match next(&mut algorithm, OID)? {
+                SHA1 => &digest::SHA1_FOR_LEGACY_USE_ONLY,
+                SHA256 => &digest::SHA256,
+                _ => return None,
+            };
+            let name_hash = next(&mut id, OCTET_STRING)?;
+            let key_hash = next(&mut id, OCTET_STRING)?;
+            let serial = next(&mut id, INTEGER)?;
+            let (_, key) = public_key(issuer.key)?;
+            Some(
+                digest::digest(algorithm, issuer.subject).as_ref() == name_hash
+                    && digest::digest(algorithm, key).as_ref() == key_hash
+                    && serial == self.serial,
+            )
+        };
+        identified(id).unwrap_or(false)
+    }
+
+    /// Whether this certificate is of a responder that `issuer` delegated to.
+    fn delegated_by(&self, issuer: &Certificate<'_>, now: u64) -> bool {
+        let ocsp_signing = elements(self.extensions).any(|extension| {
+            let mut fields = extension.content;
+            next(&mut fields, OID) == Some(EXTENDED_KEY_USAGE)
+                // after the optional critical flag
+                && elements(fields)
+                    .find(|field| field.raw[0] == OCTET_STRING)
+                    .and_then(|value| element(value.content, SEQUENCE))
+                    .is_some_and(|(purposes, _)| {
+                        elements(purposes)
+                            .any(|purpose| purpose.raw[0] == OID && purpose.content == OCSP_SIGNING)
+                    })
+        });
+        ocsp_signing
+            && self.issuer == issuer.subject
+            && (self.not_before..=self.not_after).contains(&now)
+            && verify(self.tbs, self.algorithm, self.signature, issuer.key)
+    }
+}
+
+/// Whether `signature` of `message` with `algorithm` is made by the key of the DER
+/// `SubjectPublicKeyInfo`.
+fn verify(message: &[u8], algorithm: &[u8], signature: &[u8], key: &[u8]) -> bool {
+    let (Some((key_algorithm, key)), Some(signature)) = (public_key(key), bits(signature)) else {
+        return false;
+    };
+    default_provider()
+        .signature_verification_algorithms
+        .all
+        .iter()
+        .filter(|supported| {
+            supported.signature_alg_id().as_ref() == algorithm
+                && supported.public_key_alg_id().as_ref() == key_algorithm
+        })
+        .any(|supported| supported.verify_signature(key, message, signature).is_ok())
+}
+
+/// The algorithm and the key of a DER `SubjectPublicKeyInfo`.
+fn public_key(spki: &[u8]) -> Option<(&[u8], &[u8])> {
+    let mut fields = element(spki, SEQUENCE)?.0;
+    let algorithm = next(&mut fields, SEQUENCE)?;
+    let key = bits(next(&mut fields, BIT_STRING)?)?;
+    Some((algorithm, key))
+}
+
+/// The bytes of a bit string, with no unused bits.
+fn bits(content: &[u8]) -> Option<&[u8]> {
+    match content.split_first()? {
+        (0, bits) => Some(bits),
+        _ => None,
+    }
+}
+
+/// The elements of `input`, up to the first invalid one.
+fn elements(mut input: &[u8]) -> impl Iterator<Item = DerElement<'_>> {
+    std::iter::from_fn(move || {
+        let (element, rest) = der_element(input)?;
+        input = rest;
+        Some(element)
+    })
+}
+
+/// The content of the first element of `input` if it has `tag`, and the rest of it.
+fn element(input: &[u8], tag: u8) -> Option<(&[u8], &[u8])> {
+    let (element, rest) = der_element(input)?;
+    (element.raw[0] == tag).then_some((element.content, rest))
+}
+
+/// The content of the element at the start of `input` with `tag`, moving past it.
+fn next<'a>(input: &mut &'a [u8], tag: u8) -> Option<&'a [u8]> {
+    raw(input, tag).and_then(|raw| der_element(raw).map(|(element, _)| element.content))
+}
+
+/// The element at the start of `input` with `tag`, with its tag and length, moving past it.
+fn raw<'a>(input: &mut &'a [u8], tag: u8) -> Option<&'a [u8]> {
+    let (DerElement { raw, .. }, rest) = der_element(input)?;
+    if raw[0] != tag {
+        return None;
+    }
+    *input = rest;
+    Some(raw)
+}
+
+/// Like [`next`], for an optional element.
+fn optional<'a>(input: &mut &'a [u8], tag: u8) -> Option<&'a [u8]> {
+    (input.first() == Some(&tag))
+        .then(|| next(input, tag))
+        .flatten()
+}
+
+/// The Unix time of the UTC or generalized time at the start of `input`, moving past it.
+fn any_time(input: &mut &[u8]) -> Option<u64> {
+    let tag = *input.first()?;
+    time(tag, next(input, tag)?)
+}
+
+fn time(tag: u8, content: &[u8]) -> Option<u64> {
+    let content = std::str::from_utf8(content).ok()?;
+    let time = match tag {
+        // two-digit years from 1950 to 2049
+        UTC_TIME => {
+            let century = if content.get(..2)? < "50" { "20" } else { "19" };
+            NaiveDateTime::parse_from_str(&format!("{century}{content}"), "%Y%m%d%H%M%SZ")
+        }
+        GENERALIZED_TIME => NaiveDateTime::parse_from_str(content, "%Y%m%d%H%M%SZ"),
+        _ => return None,
+    };
+    time.ok()?.and_utc().timestamp().try_into().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // certificates of `example.com` issued by an intermediate, and responses of `openssl ocsp`
+    // produced at 2026-10-14 10:13:26 UTC, with a next update 30 days later
+    const LEAF: &[u8] = include_bytes!("../testdata/ocsp/leaf.crt.der");
+    const REVOKED: &[u8] = include_bytes!("../testdata/ocsp/revoked.crt.der");
+    const ISSUER: &[u8] = include_bytes!("../testdata/ocsp/ica.crt.der");
+    /// Signed by the intermediate.
+    const GOOD: &[u8] = include_bytes!("../testdata/ocsp/good.ocsp.der");
+    const GOOD_SHA256: &[u8] = include_bytes!("../testdata/ocsp/sha256.ocsp.der");
+    const REVOKED_RESPONSE: &[u8] = include_bytes!("../testdata/ocsp/revoked.ocsp.der");
+    /// Signed by a responder of the intermediate, with the `OCSPSigning` purpose.
+    const DELEGATED: &[u8] = include_bytes!("../testdata/ocsp/delegated.ocsp.der");
+    /// Signed by the key of `LEAF`, without the `OCSPSigning` purpose.
+    const UNDELEGATED: &[u8] = include_bytes!("../testdata/ocsp/undelegated.ocsp.der");
+
+    const NOW: u64 = 1_791_972_900;
+
+    fn status(cert: &[u8], response: &[u8], now: u64) -> Option<Status> {
+        let issuer = [CertificateDer::from(ISSUER)];
+        super::status(&CertificateDer::from(cert), &issuer, response, now)
+    }
+
+    #[test]
+    fn good_and_revoked() {
+        assert_eq!(status(LEAF, GOOD, NOW), Some(Status::Good));
+        assert_eq!(status(LEAF, GOOD_SHA256, NOW), Some(Status::Good));
+        assert_eq!(
+            status(REVOKED, REVOKED_RESPONSE, NOW),
+            Some(Status::Revoked)
+        );
+        // about another certificate
+        assert_eq!(status(REVOKED, GOOD, NOW), None);
+    }
+
+    #[test]
+    fn responders() {
+        assert_eq!(status(LEAF, DELEGATED, NOW), Some(Status::Good));
+        assert_eq!(status(LEAF, UNDELEGATED, NOW), None);
+        // without the intermediate to check the signature
+        assert_eq!(
+            super::status(&CertificateDer::from(LEAF), &[], GOOD, NOW),
+            None
+        );
+    }
+
+    #[test]
+    fn tampered() {
+        let mut response = GOOD.to_vec();
+        let last = response.len() - 1;
+        response[last] ^= 1;
+        assert_eq!(status(LEAF, &response, NOW), None);
+        assert_eq!(status(LEAF, &GOOD[..GOOD.len() - 1], NOW), None);
+        assert_eq!(status(LEAF, &[], NOW), None);
+    }
+
+    #[test]
+    fn freshness() {
+        let day = 24 * 60 * 60;
+        assert_eq!(status(LEAF, GOOD, NOW + 29 * day), Some(Status::Good));
+        assert_eq!(status(LEAF, GOOD, NOW + 31 * day), None);
+        assert_eq!(status(LEAF, GOOD, NOW - day), None);
+    }
+}
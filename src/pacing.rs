@@ -0,0 +1,225 @@
+//! Pacing of the requests to each target host, so that their timing doesn't look robotic.
+//!
+//! Unlike the host limits, which let bursts through and only delay the requests above a rate, the
+//! pacing spaces out all the requests to a host by a minimum interval plus a random delay.
+//!
+//! A request to a host waits for its host limit, then for the backoff of the host, and then for its
+//! pacing, last so that it spaces out the requests as they are actually sent. The random delay is
+//! only added once: the host limit skips its jitter for the hosts whose pacing has one.
+//!
+//! Sessions can also follow a pacing profile, sending their requests in bursts separated by idle
+//! gaps, like a person browsing.
+use std::{
+    collections::HashMap,
+    ops::RangeInclusive,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::{ensure, Result};
+use rand::Rng;
+use serde::Deserialize;
+
+use crate::config::HostPattern;
+
+/// Distribution of the random delay added to the interval between two requests.
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Distribution {
+    /// Between zero and the `jitter`.
+    #[default]
+    Uniform,
+    /// Mostly short delays with the `jitter` as their mean, and now and then a long one, like
+    /// the pauses of a person browsing.
+    Exponential,
+    /// Absolute value of a normal distribution whose standard deviation is the `jitter`.
+    Normal,
+}
+
+impl Distribution {
+    /// Exponential and normal delays are capped at this multiple of the `jitter`.
+    const MAX_FACTOR: f64 = 5.0;
+
+    fn sample(self, jitter: Duration, rng: &mut impl Rng) -> Duration {
+        let factor = match self {
+            Self::Uniform => rng.gen::<f64>(),
+            // 1 - x is in (0, 1], whose logarithm is finite
+            Self::Exponential => -(1.0 - rng.gen::<f64>()).ln(),
+            Self::Normal => {
+                // Box-Muller transform
+                let (u, v) = (1.0 - rng.gen::<f64>(), rng.gen::<f64>());
+                ((-2.0 * u.ln()).sqrt() * (std::f64::consts::TAU * v).cos()).abs()
+            }
+        };
+        jitter.mul_f64(factor.min(Self::MAX_FACTOR))
+    }
+}
+
+/// A random delay.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Delay {
+    #[serde(with = "humantime_serde")]
+    pub min: Duration,
+    /// Scale of the random delay added to the minimum.
+    #[serde(with = "humantime_serde")]
+    pub jitter: Duration,
+    pub distribution: Distribution,
+}
+
+impl Delay {
+    fn sample(&self) -> Duration {
+        self.min
+            + self
+                .distribution
+                .sample(self.jitter, &mut rand::thread_rng())
+    }
+}
+
+/// Shape of the traffic of the sessions following the profile.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PacingProfile {
+    pub name: String,
+    /// Bounds of the number of requests of a burst.
+    pub burst: [u32; 2],
+    /// Time between two requests of a burst.
+    #[serde(default)]
+    pub interval: Delay,
+    /// Time between two bursts.
+    pub idle: Delay,
+}
+
+/// Pacing state of a session following a profile.
+pub struct Pace {
+    profile: PacingProfile,
+    /// Requests left in the current burst, and when the next request may be sent.
+    state: Mutex<(u32, Instant)>,
+}
+
+impl Pace {
+    pub fn new(profile: PacingProfile) -> Result<Self> {
+        let [min, max] = profile.burst;
+        ensure!(
+            0 < min && min <= max,
+            "The burst of the pacing profile {} must be a positive range",
+            profile.name
+        );
+        let burst = Self::burst(min..=max);
+        Ok(Self {
+            profile,
+            state: Mutex::new((burst, Instant::now())),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.profile.name
+    }
+
+    fn burst(range: RangeInclusive<u32>) -> u32 {
+        rand::thread_rng().gen_range(range)
+    }
+
+    /// Wait for the turn of the next request of the session.
+    pub async fn wait(&self) {
+        let now = Instant::now();
+        let slot = {
+            let mut state = self.state.lock().unwrap();
+            let (remaining, next) = &mut *state;
+            let slot = (*next).max(now);
+            *remaining -= 1;
+            let delay = if *remaining == 0 {
+                let [min, max] = self.profile.burst;
+                *remaining = Self::burst(min..=max);
+                self.profile.idle.sample()
+            } else {
+                self.profile.interval.sample()
+            };
+            *next = slot + delay;
+            slot
+        };
+        if slot > now {
+            tracing::debug!(profile = self.profile.name, delay = ?(slot - now), "Pacing session request");
+            tokio::time::sleep_until(slot.into()).await;
+        }
+    }
+}
+
+/// Spacing of the requests to each of the matching hosts, shared by all clients.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PacingRule {
+    pub host: HostPattern,
+    /// Minimum time between two requests to a host.
+    #[serde(with = "humantime_serde")]
+    pub min_interval: Duration,
+    /// Scale of the random delay added to the interval.
+    #[serde(default, with = "humantime_serde")]
+    pub jitter: Duration,
+    #[serde(default)]
+    pub distribution: Distribution,
+}
+
+/// Delays the requests to paced hosts until their turn.
+pub struct Pacer {
+    rules: Vec<PacingRule>,
+    /// When the next request to each host may be sent.
+    next: Mutex<HashMap<String, Instant>>,
+}
+
+impl Pacer {
+    /// Past this number of hosts, the ones whose next slot is past are dropped.
+    const PRUNE_THRESHOLD: usize = 10_000;
+
+    pub fn new(rules: Vec<PacingRule>) -> Result<Self> {
+        for rule in &rules {
+            ensure!(
+                !rule.min_interval.is_zero() || !rule.jitter.is_zero(),
+                "The pacing of {} needs a `min_interval` or a `jitter`",
+                rule.host
+            );
+        }
+        Ok(Self {
+            rules,
+            next: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// The first rule matching `host`, if any.
+    fn rule(&self, host: &str) -> Option<&PacingRule> {
+        self.rules.iter().find(|rule| rule.host.matches(host))
+    }
+
+    /// Whether the pacing of `host` adds a random delay, in which case its host limit doesn't.
+    pub fn jitters(&self, host: &str) -> bool {
+        self.rule(host).is_some_and(|rule| !rule.jitter.is_zero())
+    }
+
+    /// Wait for the turn of a request to `host`, if the first matching rule paces it.
+    ///
+    /// Each request books the slot after the one of the previous request, so concurrent requests
+    /// queue up and are sent one after the other.
+    pub async fn wait(&self, host: &str) {
+        let Some(rule) = self.rule(host) else {
+            return;
+        };
+        let now = Instant::now();
+        let slot = {
+            let mut next = self.next.lock().unwrap();
+            if next.len() >= Self::PRUNE_THRESHOLD {
+                next.retain(|_, slot| *slot > now);
+            }
+            let slot = next.get(host).map_or(now, |slot| (*slot).max(now));
+            let interval = rule.min_interval
+                + rule
+                    .distribution
+                    .sample(rule.jitter, &mut rand::thread_rng());
+            next.insert(host.to_string(), slot + interval);
+            slot
+        };
+        if slot > now {
+            tracing::debug!(host, delay = ?(slot - now), "Pacing request");
+            tokio::time::sleep_until(slot.into()).await;
+        }
+    }
+}
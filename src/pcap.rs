@@ -0,0 +1,426 @@
+//! Capture of the upstream exchanges of selected hosts to a pcapng file, for Wireshark.
+//!
+//! The proxy only sees the exchanges once decrypted, so they are written as plain HTTP/1.1 over
+//! made-up TCP connections, one per exchange, from `10.0.0.1` to port 80 of `10.0.0.2` whatever
+//! the origin. Streamed response bodies are not kept, so only their headers are captured.
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use axum::http::{header, HeaderMap};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+use crate::{
+    config::{ByteSize, HostPattern},
+    har::Exchange,
+    redact,
+};
+
+/// Link type of the packets, raw IP.
+const LINKTYPE_RAW: u16 = 101;
+/// Payload of a TCP segment, as with Ethernet.
+const MSS: usize = 1460;
+const CLIENT_IP: [u8; 4] = [10, 0, 0, 1];
+const SERVER_IP: [u8; 4] = [10, 0, 0, 2];
+const SERVER_PORT: u16 = 80;
+
+const FIN: u8 = 0x01;
+const SYN: u8 = 0x02;
+const PSH: u8 = 0x08;
+const ACK: u8 = 0x10;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PcapConfig {
+    pub path: PathBuf,
+    /// Hosts whose exchanges are captured.
+    pub hosts: Vec<HostPattern>,
+    /// Size of the file at which the capture stops.
+    #[serde(default = "PcapConfig::default_max_size")]
+    pub max_size: ByteSize,
+    /// How long after the start of the proxy the capture stops, never if not set.
+    #[serde(default, with = "humantime_serde")]
+    pub max_duration: Option<Duration>,
+}
+
+impl PcapConfig {
+    fn default_max_size() -> ByteSize {
+        ByteSize(100_000_000)
+    }
+}
+
+struct Writer {
+    file: BufWriter<File>,
+    written: u64,
+    /// Client port of the next exchange, so that each one is a TCP stream of its own.
+    next_port: u16,
+    /// Whether the capture stopped, because of its caps or of an error.
+    stopped: bool,
+}
+
+pub struct Pcap {
+    hosts: Vec<HostPattern>,
+    max_size: u64,
+    deadline: Option<Instant>,
+    /// Sent by the client on every request, so missing from the request headers.
+    user_agent: String,
+    writer: Mutex<Writer>,
+}
+
+impl Pcap {
+    pub fn new(config: PcapConfig, user_agent: String) -> Result<Self> {
+        let file = File::create(&config.path)
+            .with_context(|| format!("could not create {}", config.path.display()))?;
+        let mut writer = Writer {
+            file: BufWriter::new(file),
+            written: 0,
+            next_port: 1024,
+            stopped: false,
+        };
+        writer.write(&section_header())?;
+        writer.write(&interface_description())?;
+        writer.file.flush()?;
+        tracing::warn!(
+            path = %config.path.display(),
+            "Capturing the upstream exchanges to a PCAP file, with their bodies"
+        );
+        Ok(Self {
+            hosts: config.hosts,
+            max_size: config.max_size.0,
+            deadline: config
+                .max_duration
+                .map(|duration| Instant::now() + duration),
+            user_agent,
+            writer: Mutex::new(writer),
+        })
+    }
+
+    pub fn enabled(&self, host: &str) -> bool {
+        self.hosts.iter().any(|pattern| pattern.matches(host))
+    }
+
+    pub fn record(&self, exchange: Exchange) {
+        if !self.enabled(exchange.url.host_str().unwrap_or_default()) {
+            return;
+        }
+        let mut writer = self.writer.lock().unwrap();
+        if writer.stopped {
+            return;
+        }
+        if writer.written >= self.max_size
+            || self
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            tracing::info!("Stopped the PCAP capture, its cap was reached");
+            writer.stopped = true;
+            return;
+        }
+        let port = writer.next_port;
+        writer.next_port = writer.next_port.checked_add(1).unwrap_or(1024);
+        let responded = exchange.started + exchange.wait;
+        let mut packets = Vec::new();
+        let mut client = Flow::new([CLIENT_IP, SERVER_IP], [port, SERVER_PORT], 1);
+        let mut server = Flow::new([SERVER_IP, CLIENT_IP], [SERVER_PORT, port], 1_000_001);
+        packets.push((exchange.started, client.segment(SYN, 0, b"")));
+        packets.push((exchange.started, server.segment(SYN | ACK, client.seq, b"")));
+        packets.push((exchange.started, client.segment(ACK, server.seq, b"")));
+        for chunk in self.request(&exchange).chunks(MSS) {
+            packets.push((
+                exchange.started,
+                client.segment(PSH | ACK, server.seq, chunk),
+            ));
+        }
+        for chunk in response(&exchange).chunks(MSS) {
+            packets.push((responded, server.segment(PSH | ACK, client.seq, chunk)));
+        }
+        let ended = exchange.started + exchange.wait + exchange.receive;
+        packets.push((ended, server.segment(FIN | ACK, client.seq, b"")));
+        packets.push((ended, client.segment(FIN | ACK, server.seq, b"")));
+        packets.push((ended, server.segment(ACK, client.seq, b"")));
+        let result = packets
+            .into_iter()
+            .try_for_each(|(time, packet)| writer.write(&enhanced_packet(time, &packet)))
+            .and_then(|()| writer.file.flush());
+        if let Err(err) = result {
+            tracing::error!(error = %err, "Could not write the PCAP capture, stopping it");
+            writer.stopped = true;
+        }
+    }
+
+    fn request(&self, exchange: &Exchange) -> Vec<u8> {
+        let url = exchange.url;
+        let mut path = url.path().to_string();
+        if let Some(query) = url.query() {
+            path = format!("{path}?{query}");
+        }
+        let mut request = format!("GET {path} HTTP/1.1\r\n");
+        let host = url.host_str().unwrap_or_default();
+        match url.port() {
+            Some(port) => request.push_str(&format!("host: {host}:{port}\r\n")),
+            None => request.push_str(&format!("host: {host}\r\n")),
+        }
+        if !exchange.request_headers.contains_key(header::USER_AGENT) {
+            request.push_str(&format!("user-agent: {}\r\n", self.user_agent));
+        }
+        push_headers(&mut request, exchange.request_headers);
+        request.push_str("\r\n");
+        request.into_bytes()
+    }
+}
+
+fn response(exchange: &Exchange) -> Vec<u8> {
+    let status = exchange.status;
+    let mut headers = exchange.response_headers.clone();
+    if let Some(body) = exchange.body {
+        // the body was received whole, whatever its framing
+        headers.remove(header::TRANSFER_ENCODING);
+        headers.insert(header::CONTENT_LENGTH, body.len().into());
+    }
+    let mut response = format!(
+        "HTTP/1.1 {} {}\r\n",
+        status.as_u16(),
+        status.canonical_reason().unwrap_or_default()
+    );
+    push_headers(&mut response, &headers);
+    response.push_str("\r\n");
+    let mut response = response.into_bytes();
+    response.extend_from_slice(exchange.body.unwrap_or_default());
+    response
+}
+
+fn push_headers(out: &mut String, headers: &HeaderMap) {
+    for (name, value) in headers {
+        out.push_str(&format!("{name}: {}\r\n", redact::value(name, value)));
+    }
+}
+
+/// One direction of a made-up TCP connection.
+struct Flow {
+    ips: [[u8; 4]; 2],
+    ports: [u16; 2],
+    seq: u32,
+}
+
+impl Flow {
+    fn new(ips: [[u8; 4]; 2], ports: [u16; 2], seq: u32) -> Self {
+        Self { ips, ports, seq }
+    }
+
+    /// IPv4 packet of a TCP segment, acknowledging the peer up to `ack`.
+    fn segment(&mut self, flags: u8, ack: u32, payload: &[u8]) -> Vec<u8> {
+        let mut tcp = Vec::with_capacity(20 + payload.len());
+        tcp.extend_from_slice(&self.ports[0].to_be_bytes());
+        tcp.extend_from_slice(&self.ports[1].to_be_bytes());
+        tcp.extend_from_slice(&self.seq.to_be_bytes());
+        tcp.extend_from_slice(&(if flags & ACK != 0 { ack } else { 0 }).to_be_bytes());
+        // header of 5 words, without options
+        tcp.extend_from_slice(&[5 << 4, flags]);
+        tcp.extend_from_slice(&u16::MAX.to_be_bytes());
+        tcp.extend_from_slice(&[0; 4]);
+        tcp.extend_from_slice(payload);
+        let mut pseudo_header = Vec::with_capacity(12 + tcp.len());
+        pseudo_header.extend_from_slice(&self.ips[0]);
+        pseudo_header.extend_from_slice(&self.ips[1]);
+        pseudo_header.extend_from_slice(&[0, 6]);
+        pseudo_header.extend_from_slice(&(tcp.len() as u16).to_be_bytes());
+        pseudo_header.extend_from_slice(&tcp);
+        tcp[16..18].copy_from_slice(&checksum(&pseudo_header).to_be_bytes());
+        // SYN and FIN count as one byte of the sequence
+        let length = payload.len() as u32 + u32::from(flags & (SYN | FIN) != 0);
+        self.seq = self.seq.wrapping_add(length);
+
+        let mut packet = Vec::with_capacity(20 + tcp.len());
+        packet.extend_from_slice(&[0x45, 0]);
+        packet.extend_from_slice(&((20 + tcp.len()) as u16).to_be_bytes());
+        // id, don't fragment, TTL of 64 and TCP
+        packet.extend_from_slice(&[0, 0, 0x40, 0, 64, 6, 0, 0]);
+        packet.extend_from_slice(&self.ips[0]);
+        packet.extend_from_slice(&self.ips[1]);
+        let header_checksum = checksum(&packet);
+        packet[10..12].copy_from_slice(&header_checksum.to_be_bytes());
+        packet.extend_from_slice(&tcp);
+        packet
+    }
+}
+
+/// Internet checksum of `data`.
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data
+        .chunks(2)
+        .map(|pair| u32::from(u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)])))
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+impl Writer {
+    fn write(&mut self, block: &[u8]) -> std::io::Result<()> {
+        self.file.write_all(block)?;
+        self.written += block.len() as u64;
+        Ok(())
+    }
+}
+
+/// pcapng block of `kind` with `body`, padded to 32 bits.
+fn block(kind: u32, body: &[u8]) -> Vec<u8> {
+    let padding = (4 - body.len() % 4) % 4;
+    let length = (12 + body.len() + padding) as u32;
+    let mut block = Vec::with_capacity(length as usize);
+    block.extend_from_slice(&kind.to_le_bytes());
+    block.extend_from_slice(&length.to_le_bytes());
+    block.extend_from_slice(body);
+    block.resize(block.len() + padding, 0);
+    block.extend_from_slice(&length.to_le_bytes());
+    block
+}
+
+fn section_header() -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&0x1A2B_3C4D_u32.to_le_bytes());
+    // version 1.0, of an unknown length
+    body.extend_from_slice(&1_u16.to_le_bytes());
+    body.extend_from_slice(&0_u16.to_le_bytes());
+    body.extend_from_slice(&(-1_i64).to_le_bytes());
+    block(0x0A0D_0D0A, &body)
+}
+
+fn interface_description() -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+    body.extend_from_slice(&[0; 2]);
+    // no snapshot length, timestamps in microseconds by default
+    body.extend_from_slice(&0_u32.to_le_bytes());
+    block(1, &body)
+}
+
+fn enhanced_packet(time: DateTime<Utc>, packet: &[u8]) -> Vec<u8> {
+    let micros = time.timestamp_micros() as u64;
+    let mut body = Vec::with_capacity(20 + packet.len());
+    body.extend_from_slice(&0_u32.to_le_bytes());
+    body.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+    body.extend_from_slice(&(micros as u32).to_le_bytes());
+    body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    body.extend_from_slice(packet);
+    block(6, &body)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{StatusCode, Version};
+    use reqwest::Url;
+
+    use super::*;
+
+    fn u32_at(bytes: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn global_headers() {
+        let section = section_header();
+        assert_eq!(
+            section,
+            [
+                0x0a, 0x0d, 0x0d, 0x0a, 28, 0, 0, 0, 0x4d, 0x3c, 0x2b, 0x1a, 1, 0, 0, 0, 0xff,
+                0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 28, 0, 0, 0,
+            ]
+        );
+        let interface = interface_description();
+        assert_eq!(
+            interface,
+            [1, 0, 0, 0, 20, 0, 0, 0, 101, 0, 0, 0, 0, 0, 0, 0, 20, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn record_header() {
+        let time = DateTime::from_timestamp(1_700_000_000, 123_456_000).unwrap();
+        let block = enhanced_packet(time, &[0xaa; 5]);
+        // padded to 32 bits
+        assert_eq!(block.len(), 12 + 20 + 8);
+        assert_eq!(u32_at(&block, 0), 6);
+        assert_eq!(u32_at(&block, 4), 40);
+        assert_eq!(u32_at(&block, 8), 0);
+        let micros = 1_700_000_000_123_456_u64;
+        assert_eq!(u32_at(&block, 12), (micros >> 32) as u32);
+        assert_eq!(u32_at(&block, 16), micros as u32);
+        assert_eq!(u32_at(&block, 20), 5);
+        assert_eq!(u32_at(&block, 24), 5);
+        assert_eq!(block[28..33], [0xaa; 5]);
+        assert_eq!(block[33..36], [0; 3]);
+        assert_eq!(u32_at(&block, 36), 40);
+    }
+
+    #[test]
+    fn segments() {
+        let mut client = Flow::new([CLIENT_IP, SERVER_IP], [1024, SERVER_PORT], 1);
+        let syn = client.segment(SYN, 0, b"");
+        assert_eq!(client.seq, 2);
+        let data = client.segment(PSH | ACK, 7, b"GET /");
+        assert_eq!(client.seq, 7);
+        assert_eq!(data.len(), 20 + 20 + 5);
+        // the checksums of valid headers sum up to zero
+        assert_eq!(checksum(&data[..20]), 0);
+        let mut pseudo_header = [&data[12..20], &[0, 6], &25_u16.to_be_bytes()].concat();
+        pseudo_header.extend_from_slice(&data[20..]);
+        assert_eq!(checksum(&pseudo_header), 0);
+        assert_eq!(data[20..22], 1024_u16.to_be_bytes());
+        assert_eq!(data[24..28], 2_u32.to_be_bytes());
+        assert_eq!(data[28..32], 7_u32.to_be_bytes());
+        assert_eq!(&data[40..], b"GET /");
+        // no acknowledgment number without the flag
+        assert_eq!(syn[28..32], [0; 4]);
+    }
+
+    #[test]
+    fn capture() {
+        let path = std::env::temp_dir().join(format!("pcap-test-{}.pcapng", std::process::id()));
+        let config = PcapConfig {
+            path: path.clone(),
+            hosts: vec![serde_json::from_str("\"example.com\"").unwrap()],
+            max_size: PcapConfig::default_max_size(),
+            max_duration: None,
+        };
+        let pcap = Pcap::new(config, "simple-proxy".to_string()).unwrap();
+        let url = Url::parse("https://example.com/path?q=1").unwrap();
+        let other = Url::parse("https://example.org/").unwrap();
+        let body = vec![b'x'; 2 * MSS];
+        for url in [&url, &other] {
+            pcap.record(Exchange {
+                started: Utc::now(),
+                url,
+                request_headers: &HeaderMap::new(),
+                status: StatusCode::OK,
+                version: Version::HTTP_11,
+                response_headers: &HeaderMap::new(),
+                body: Some(&body),
+                wait: Duration::from_millis(10),
+                receive: Duration::from_millis(5),
+            });
+        }
+        let file = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let mut blocks = Vec::new();
+        let mut pos = 0;
+        while pos < file.len() {
+            let length = u32_at(&file, pos + 4) as usize;
+            assert_eq!(u32_at(&file, pos + length - 4) as usize, length);
+            blocks.push(&file[pos..pos + length]);
+            pos += length;
+        }
+        // the handshake, the request, the response in 3 segments and the close
+        assert_eq!(blocks.len(), 2 + 3 + 1 + 3 + 3);
+        let request = &blocks[5][28 + 40..];
+        assert!(request.starts_with(b"GET /path?q=1 HTTP/1.1\r\nhost: example.com\r\n"));
+    }
+}
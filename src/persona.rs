@@ -0,0 +1,356 @@
+//! Personas, named bundles of the `User-Agent` and headers sent to the origins so that the requests
+//! look like the ones of a given app or browser.
+//!
+//! The persona of a request is the one asked for with the [`PERSONA_HEADER`], or the first one
+//! configured for the target host, or the default one, which can be switched with the admin API
+//! along with its `User-Agent`. The cipher suites, key exchange groups and ALPN protocols of the
+//! ClientHello can be the ones of the persona, but not its extensions (see [`crate::tls`]), and
+//! origins are only spoken to in HTTP/1.
+//!
+//! The header values of a persona can be templates with `{field}` placeholders, filled by a device
+//! drawn from the `devices` of the persona for each session, or for each request outside of a
+//! session. The `uuid` and `android_id` fields are random for each drawn device, unless the device
+//! sets them.
+use std::collections::HashMap;
+
+use anyhow::{anyhow, ensure, Context, Result};
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue};
+use rand::{seq::SliceRandom, Rng};
+use serde::Deserialize;
+
+use crate::{config::HostPattern, tls::TlsProfile};
+
+/// Request header selecting the persona by name.
+pub const PERSONA_HEADER: &str = "x-proxy-persona";
+/// Name of the built-in persona.
+pub const BUILT_IN: &str = "instagram";
+
+const INSTAGRAM_USER_AGENT: &str = "Instagram 310.0.0.37.328 Android (31/12; 440dpi; 1080x2180; Xiaomi; M2007J3SG; apollo; qcom; de_DE; 543594164)";
+
+/// Fields of a device, like its model or screen resolution.
+type Device = HashMap<String, String>;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PersonaConfig {
+    pub name: String,
+    pub user_agent: String,
+    /// Headers sent with the `User-Agent`, e.g. client hints like `sec-ch-ua`, as `[name, value]`
+    /// pairs in the order they are sent. The `User-Agent` comes first unless it is listed.
+    #[serde(default)]
+    pub headers: Vec<(String, String)>,
+    /// Target hosts using the persona unless the request asks for another one.
+    #[serde(default)]
+    pub hosts: Vec<HostPattern>,
+    /// Devices filling the placeholders of the header values.
+    #[serde(default)]
+    pub devices: Vec<Device>,
+    /// ClientHello of the requests with the persona, which get connections of their own.
+    pub tls: Option<TlsProfile>,
+}
+
+#[derive(Clone)]
+pub struct Persona {
+    pub name: String,
+    /// The headers of the persona, in order, with its first device.
+    pub headers: HeaderMap,
+    hosts: Vec<HostPattern>,
+    /// The header values before their placeholders are filled, in order.
+    templates: Vec<(HeaderName, String)>,
+    devices: Vec<Device>,
+    tls: Option<TlsProfile>,
+}
+
+impl Persona {
+    fn new(config: PersonaConfig) -> Result<Self> {
+        let mut templates = Vec::new();
+        let listed = config
+            .headers
+            .iter()
+            .any(|(name, _)| name.eq_ignore_ascii_case(header::USER_AGENT.as_str()));
+        if !listed {
+            templates.push((header::USER_AGENT, config.user_agent.clone()));
+        }
+        for (name, value) in config.headers {
+            let value = if name.eq_ignore_ascii_case(header::USER_AGENT.as_str()) {
+                config.user_agent.clone()
+            } else {
+                value
+            };
+            templates.push((HeaderName::try_from(name)?, value));
+        }
+        let mut persona = Self {
+            name: config.name,
+            headers: HeaderMap::new(),
+            hosts: config.hosts,
+            templates,
+            devices: config.devices,
+            tls: config.tls,
+        };
+        persona.render_headers()?;
+        Ok(persona)
+    }
+
+    /// The persona with another `User-Agent`, which can be a template too.
+    fn with_user_agent(&self, user_agent: &str) -> Result<Self> {
+        let mut persona = self.clone();
+        for (name, template) in &mut persona.templates {
+            if *name == header::USER_AGENT {
+                *template = user_agent.to_string();
+            }
+        }
+        persona.render_headers()?;
+        Ok(persona)
+    }
+
+    /// Fill the headers with the first device, once checked that all the devices fill the
+    /// templates with valid values.
+    fn render_headers(&mut self) -> Result<()> {
+        for device in &self.devices {
+            self.render(&self.generated(device))?;
+        }
+        self.headers =
+            self.render(&self.generated(self.devices.first().unwrap_or(&Device::new())))?;
+        Ok(())
+    }
+
+    pub fn user_agent(&self) -> &str {
+        self.headers
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+    }
+
+    /// Whether `user_agent` is the one of the persona, with any of its devices.
+    pub fn owns_user_agent(&self, user_agent: &HeaderValue) -> bool {
+        let Some((_, template)) = self
+            .templates
+            .iter()
+            .find(|(name, _)| name == header::USER_AGENT)
+        else {
+            return false;
+        };
+        matches_template(template, user_agent.to_str().unwrap_or_default())
+    }
+
+    /// The headers of the persona with a random device.
+    pub fn draw(&self) -> HeaderMap {
+        let device = self.devices.choose(&mut rand::thread_rng());
+        let is_static = device.is_none()
+            && self
+                .templates
+                .iter()
+                .all(|(_, template)| !template.contains('{'));
+        if is_static {
+            return self.headers.clone();
+        }
+        self.render(&self.generated(device.unwrap_or(&Device::new())))
+            // the templates were checked with all the devices
+            .unwrap_or_else(|_| self.headers.clone())
+    }
+
+    /// `device` with the random fields it doesn't set.
+    fn generated(&self, device: &Device) -> Device {
+        let mut rng = rand::thread_rng();
+        let mut hex = |len| {
+            (0..len)
+                .map(|_| char::from_digit(rng.gen_range(0..16), 16).unwrap_or('0'))
+                .collect::<String>()
+        };
+        let mut device = device.clone();
+        device.entry("uuid".to_string()).or_insert_with(|| {
+            let uuid = hex(32);
+            // version 4, variant 1
+            format!(
+                "{}-{}-4{}-a{}-{}",
+                &uuid[..8],
+                &uuid[8..12],
+                &uuid[13..16],
+                &uuid[17..20],
+                &uuid[20..]
+            )
+        });
+        device
+            .entry("android_id".to_string())
+            .or_insert_with(|| hex(16));
+        device
+    }
+
+    fn render(&self, device: &Device) -> Result<HeaderMap> {
+        let mut headers = HeaderMap::new();
+        for (name, template) in &self.templates {
+            let value = fill(template, device)
+                .with_context(|| format!("invalid {name} header of persona {}", self.name))?;
+            headers.append(name, HeaderValue::try_from(value)?);
+        }
+        Ok(headers)
+    }
+}
+
+/// `template` with its `{field}` placeholders replaced by the fields of `device`.
+fn fill(template: &str, device: &Device) -> Result<String> {
+    let mut filled = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let Some(end) = rest[start..].find('}') else {
+            break;
+        };
+        let field = &rest[start + 1..start + end];
+        let value = device
+            .get(field)
+            .ok_or_else(|| anyhow!("the device has no `{field}`"))?;
+        filled.push_str(&rest[..start]);
+        filled.push_str(value);
+        rest = &rest[start + end + 1..];
+    }
+    filled.push_str(rest);
+    Ok(filled)
+}
+
+/// Whether `value` is `template` with any values in its placeholders.
+fn matches_template(template: &str, value: &str) -> bool {
+    let mut literals = Vec::new();
+    let mut rest = template;
+    while let Some((start, end)) = rest
+        .find('{')
+        .and_then(|start| Some((start, start + rest[start..].find('}')?)))
+    {
+        literals.push(&rest[..start]);
+        rest = &rest[end + 1..];
+    }
+    if literals.is_empty() {
+        return template == value;
+    }
+    let Some(mut value) = value.strip_prefix(literals[0]) else {
+        return false;
+    };
+    for literal in &literals[1..] {
+        match value.find(literal) {
+            Some(index) => value = &value[index + literal.len()..],
+            None => return false,
+        }
+    }
+    value.ends_with(rest)
+}
+
+#[derive(Clone)]
+pub struct Personas {
+    personas: Vec<Persona>,
+    default: usize,
+}
+
+impl Personas {
+    /// The configured personas and the built-in one, whose `User-Agent` can be overridden.
+    pub fn new(
+        mut configs: Vec<PersonaConfig>,
+        default: &str,
+        user_agent: Option<String>,
+    ) -> Result<Self> {
+        if !configs.iter().any(|config| config.name == BUILT_IN) {
+            configs.push(PersonaConfig {
+                name: BUILT_IN.to_string(),
+                user_agent: INSTAGRAM_USER_AGENT.to_string(),
+                headers: Vec::new(),
+                hosts: Vec::new(),
+                devices: Vec::new(),
+                tls: None,
+            });
+        }
+        let default = configs
+            .iter()
+            .position(|config| config.name == default)
+            .with_context(|| format!("unknown default persona {default}"))?;
+        if let Some(user_agent) = user_agent {
+            configs[default].user_agent = user_agent;
+        }
+        for (index, config) in configs.iter().enumerate() {
+            ensure!(
+                configs[..index]
+                    .iter()
+                    .all(|other| other.name != config.name),
+                "duplicate persona {}",
+                config.name
+            );
+        }
+        let personas = configs
+            .into_iter()
+            .map(|config| {
+                let name = config.name.clone();
+                Persona::new(config).with_context(|| format!("invalid persona {name}"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self { personas, default })
+    }
+
+    pub fn default(&self) -> &Persona {
+        &self.personas[self.default]
+    }
+
+    /// The personas with the persona `name` as the default one if set, and the `User-Agent` of the
+    /// default one replaced by `user_agent` if set.
+    pub fn switched(&self, name: Option<&str>, user_agent: Option<&str>) -> Result<Self> {
+        let mut switched = self.clone();
+        if let Some(name) = name {
+            switched.default = self
+                .personas
+                .iter()
+                .position(|persona| persona.name == name)
+                .with_context(|| format!("unknown persona {name}"))?;
+        }
+        if let Some(user_agent) = user_agent {
+            let persona = &mut switched.personas[switched.default];
+            *persona = persona
+                .with_user_agent(user_agent)
+                .with_context(|| format!("invalid User-Agent of persona {}", persona.name))?;
+        }
+        Ok(switched)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Persona> {
+        self.personas.iter().find(|persona| persona.name == name)
+    }
+
+    /// The persona whose `User-Agent` the forwarded `headers` have, if any.
+    pub fn owner(&self, headers: &HeaderMap) -> Option<&Persona> {
+        let user_agent = headers.get(header::USER_AGENT)?;
+        self.personas
+            .iter()
+            .find(|persona| persona.owns_user_agent(user_agent))
+    }
+
+    /// The personas with a TLS profile, and their profile.
+    pub fn tls_profiles(&self) -> impl Iterator<Item = (&str, &TlsProfile)> {
+        self.personas.iter().filter_map(|persona| {
+            let profile = persona.tls.as_ref()?;
+            Some((persona.name.as_str(), profile))
+        })
+    }
+
+    /// Forwarded `headers` with the ones of `persona` instead of the ones of any other persona.
+    pub fn swap(&self, headers: &HeaderMap, persona: &Persona) -> HeaderMap {
+        let mut swapped = persona.draw();
+        for (name, value) in headers {
+            let other = self
+                .personas
+                .iter()
+                .any(|persona| persona.headers.contains_key(name));
+            if !other {
+                swapped.append(name, value.clone());
+            }
+        }
+        swapped
+    }
+
+    /// The persona of a request to `host`, `Err` with the name asked for if it doesn't exist.
+    pub fn select<'a>(&self, host: &str, headers: &'a HeaderMap) -> Result<&Persona, &'a str> {
+        if let Some(value) = headers.get(PERSONA_HEADER) {
+            let name = value.to_str().unwrap_or_default();
+            return self.get(name).ok_or(name);
+        }
+        Ok(self
+            .personas
+            .iter()
+            .find(|persona| persona.hosts.iter().any(|pattern| pattern.matches(host)))
+            .unwrap_or(self.default()))
+    }
+}
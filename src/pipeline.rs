@@ -0,0 +1,1157 @@
+//! Pipeline of the proxied requests: authenticating the client, fetching the response from the
+//! cache or the origin, and building the response sent to the client.
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{atomic::Ordering, Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context, Result};
+use axum::{
+    body::{Body, Bytes},
+    extract::{ConnectInfo, Query, State},
+    http::{header, HeaderMap, Method, StatusCode},
+    response::IntoResponse,
+    Extension,
+};
+use axum_auth::{AuthBearer, Rejection};
+use futures_util::{future::Either, StreamExt};
+use reqwest::{header::HeaderValue, Url};
+use tracing::Instrument;
+
+use crate::{
+    anonymize,
+    auth::Decision,
+    body::Counted,
+    cache::{CacheStatus, CachedResponse, Failure, Lookup},
+    challenge,
+    chaos::{Injected, Truncated},
+    circuit::CircuitOpen,
+    data_saver::{self, DataSaver},
+    decompress,
+    errors::{self, Gateway},
+    forwarded,
+    har::Exchange,
+    hedge, integrity,
+    memory::Reservation,
+    metrics::METRICS,
+    persona::PERSONA_HEADER,
+    presign::UrlSigner,
+    redirect,
+    replay::NotRecorded,
+    resume,
+    retry::RetryPolicy,
+    session::{Session, SESSION_HEADER},
+    state::AppState,
+    telemetry,
+    tenant::ListenerTenant,
+    timing,
+};
+
+/// Request header that makes the proxy skip its caches and contact the origin.
+pub const CACHE_BYPASS_HEADER: &str = "x-proxy-cache-bypass";
+/// Request header overriding the upstream timeout, up to `--max-upstream-timeout`.
+pub const TIMEOUT_HEADER: &str = "x-proxy-timeout";
+/// Response header telling how the cache was involved in the response.
+pub const CACHE_STATUS_HEADER: &str = "x-proxy-cache";
+/// Response header with the body bytes received from the origin, 0 for cached responses.
+pub const BYTES_RECEIVED_HEADER: &str = "x-proxy-bytes-received";
+/// Response header with the body bytes sent to the client, before the HTTP compression.
+pub const BYTES_SENT_HEADER: &str = "x-proxy-bytes-sent";
+/// Response header with the time taken until the response headers, in milliseconds.
+pub const DURATION_HEADER: &str = "x-proxy-duration";
+
+/// Outcome of an upstream fetch, cloneable so it can be shared by coalesced requests.
+pub type SharedFetch = Result<(Fetched, CacheStatus), Arc<anyhow::Error>>;
+
+/// Response of the origin, or of the cache.
+#[derive(Clone)]
+pub enum Fetched {
+    Buffered(CachedResponse),
+    /// Response whose body is streamed to the client without being buffered, which can only be
+    /// sent once.
+    Streamed {
+        status: StatusCode,
+        headers: HeaderMap,
+        body: Arc<Mutex<Option<Body>>>,
+    },
+}
+
+impl Fetched {
+    fn status(&self) -> StatusCode {
+        match self {
+            Self::Buffered(response) => response.status,
+            Self::Streamed { status, .. } => *status,
+        }
+    }
+}
+
+pub async fn handler(
+    method: Method,
+    bearer: Result<AuthBearer, Rejection>,
+    Query(params): Query<HashMap<String, String>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    listener_tenant: Option<Extension<ListenerTenant>>,
+    State(state): State<AppState>,
+    mut headers: HeaderMap,
+) -> Result<impl IntoResponse, AppError> {
+    let start = Instant::now();
+    // pre-signed URLs stand for the bearer token
+    let signer = (state.url_signer.as_ref()).filter(|_| UrlSigner::is_signed(&params));
+    let token = match (bearer, signer) {
+        (_, Some(_)) => String::new(),
+        (Ok(AuthBearer(token)), None) => token,
+        (Err(rejection), None) => return Ok(rejection.into_response()),
+    };
+    let tenant = match listener_tenant {
+        Some(Extension(ListenerTenant(tenant))) => Some(tenant),
+        None if signer.is_some() => None,
+        None => state.tenants.by_token(&token).cloned(),
+    };
+    let authenticated = match (&tenant, signer) {
+        (_, Some(signer)) => signer.verify(&params),
+        (Some(tenant), None) if tenant.has_tokens() => tenant.owns(&token),
+        _ => state.authenticator.authenticate(&token, addr).await == Decision::Allow,
+    };
+    if !authenticated {
+        tracing::error!(peer = anonymize::peer(addr), "Unauthorized access attempt");
+        METRICS.auth_failures.fetch_add(1, Ordering::Relaxed);
+        return Ok(errors::response(
+            StatusCode::UNAUTHORIZED,
+            "unauthorized",
+            "Unauthorized",
+        ));
+    }
+    let Some(url) = params.get("url") else {
+        tracing::error!(peer = anonymize::peer(addr), "Missing `url` param");
+        return Ok(errors::response(
+            StatusCode::BAD_REQUEST,
+            "missing_url",
+            "Missing `url` param",
+        ));
+    };
+    let state = match &tenant {
+        Some(tenant) => state.for_tenant(tenant.clone()),
+        None => state,
+    };
+    let timeout = match (state.max_upstream_timeout, headers.get(TIMEOUT_HEADER)) {
+        (Some(max), Some(value)) => {
+            let Some(timeout) = value
+                .to_str()
+                .ok()
+                .and_then(|value| humantime::parse_duration(value).ok())
+            else {
+                return Ok(errors::response(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_timeout",
+                    format!("Invalid `{TIMEOUT_HEADER}` header"),
+                ));
+            };
+            Some(timeout.min(max))
+        }
+        _ => None,
+    };
+    let Ok(target) = url.parse::<Url>() else {
+        return Ok(errors::response(
+            StatusCode::BAD_REQUEST,
+            "invalid_url",
+            "Invalid `url` param",
+        ));
+    };
+    if let Some(tenant) = &state.tenant {
+        if !tenant.allows(target.host_str().unwrap_or_default()) {
+            tracing::warn!(tenant = tenant.name, url, "Host not allowed for the tenant");
+            return Ok(errors::response(
+                StatusCode::FORBIDDEN,
+                "host_not_allowed",
+                "Host not allowed",
+            ));
+        }
+        if let Some(persona) = tenant
+            .persona
+            .as_deref()
+            .filter(|_| !headers.contains_key(PERSONA_HEADER))
+        {
+            headers.insert(PERSONA_HEADER, HeaderValue::from_str(persona)?);
+        }
+    }
+    let tenant_name = state.tenant.as_ref().map(|tenant| tenant.name.as_str());
+    if let Some(denied) =
+        state
+            .schedules
+            .check(target.host_str().unwrap_or_default(), tenant_name, &token)
+    {
+        return Ok(denied);
+    }
+    if let Some(forwarded) = &state.forwarded {
+        forwarded.apply(addr.ip(), &mut headers);
+    }
+    if let Some(response) = state.mocks.respond(&method, url) {
+        return Ok(response);
+    }
+    let fault = state
+        .chaos
+        .draw(target.host_str().unwrap_or_default(), &headers);
+    match fault {
+        Some(Injected::Latency(latency)) => tokio::time::sleep(latency).await,
+        Some(Injected::Error(status)) => {
+            return Ok(errors::response(status, "injected_fault", "Injected fault"));
+        }
+        Some(Injected::Reset) => return Ok(Truncated::wrap(Body::empty(), 0).into_response()),
+        Some(Injected::Truncate(_)) | None => {}
+    }
+    let personas = state.personas();
+    let persona = match personas.select(target.host_str().unwrap_or_default(), &headers) {
+        Ok(persona) => persona,
+        Err(name) => {
+            return Ok(errors::response(
+                StatusCode::BAD_REQUEST,
+                "unknown_persona",
+                format!("Unknown persona `{name}`"),
+            ))
+        }
+    };
+    let mut key = match &state.cache {
+        Some(cache) => cache.key(&target, &headers, &token),
+        None => url.clone(),
+    };
+    // what follows applies to any URL requested in the same way
+    let key_len = key.len();
+    let tenant = state.tenant.as_ref().map(|tenant| tenant.name.as_str());
+    if let Some(tenant) = tenant {
+        key.push_str(&format!(" tenant={tenant}"));
+    }
+    let session = match (&state.sessions, headers.get(SESSION_HEADER)) {
+        (Some(sessions), Some(id)) => {
+            let id = id.to_str().unwrap_or_default();
+            // sessions have cookies, so their responses are their own
+            key.push_str(&format!(" session={id}"));
+            let id = match tenant {
+                Some(tenant) => format!("{tenant}/{id}"),
+                None => id.to_string(),
+            };
+            Some(sessions.get(&id, &persona.name, &personas, &state.upstream)?)
+        }
+        _ => None,
+    };
+    let persona = session
+        .as_ref()
+        .and_then(|session| personas.get(&session.persona))
+        .unwrap_or(persona);
+    if !std::ptr::eq(persona, personas.default()) {
+        key.push_str(&format!(" persona={}", persona.name));
+    }
+    // compressed responses are passed through, so they vary on the accepted encodings
+    if let Some(encoding) = forwarded_headers(&state, &target, &headers, session.as_deref())
+        .get(header::ACCEPT_ENCODING)
+    {
+        key.push_str(&format!(
+            " accept-encoding={}",
+            encoding.to_str().unwrap_or_default()
+        ));
+    }
+    // origins may build absolute URLs from where the clients reached the proxy
+    if state.forwarded.is_some() {
+        let values = forwarded::HEADERS.map(|name| {
+            let value = headers.get(name).map(HeaderValue::to_str);
+            value.and_then(Result::ok).unwrap_or_default()
+        });
+        key.push_str(&format!(" forwarded={}", values.join(",")));
+    }
+    // once the request is known to be valid, so that the rejected ones don't use up the quotas
+    if let Some(quotas) = &state.quotas {
+        if let Err(exceeded) = quotas.check(&token).await {
+            tracing::warn!(peer = anonymize::peer(addr), "Quota exceeded");
+            return Ok(exceeded.into_response());
+        }
+    }
+    let capture = state
+        .capture
+        .as_ref()
+        .filter(|capture| capture.enabled(target.host_str().unwrap_or_default(), &headers));
+    let (mut response, cache_status) = proxy(
+        &state,
+        &target,
+        &key,
+        &headers,
+        session.clone(),
+        capture.is_some(),
+        timeout,
+    )
+    .await?;
+    if let (Some(prefetcher), Some(cache), Fetched::Buffered(page), Some(CacheStatus::Miss)) =
+        (&state.prefetcher, &state.cache, &response, cache_status)
+    {
+        let (cache, headers, token) = (cache.clone(), headers.clone(), token.clone());
+        let suffix = key[key_len..].to_string();
+        prefetcher.spawn(
+            state.clone(),
+            target.clone(),
+            page,
+            headers.clone(),
+            session,
+            move |url| cache.key(url, &headers, &token) + suffix.as_str(),
+        );
+    }
+    match (capture, &response) {
+        (Some(capture), Fetched::Buffered(response)) => capture.log(url, &[], response),
+        (Some(_), Fetched::Streamed { .. }) => {
+            tracing::info!(url, "Response too large to be captured");
+        }
+        (None, _) => {}
+    }
+    if let (Some(cache), Some(status)) = (&state.cache, cache_status) {
+        cache.record(status);
+    }
+    if let (Some(clamav), Fetched::Buffered(response)) = (&state.clamav, &response) {
+        if clamav.scans(response.body.len()) {
+            if let Some(blocked) = clamav.check(url, &response.body).await {
+                return Ok(blocked);
+            }
+        }
+    }
+    let mut reservation = Reservation::default();
+    let received = match (&response, cache_status) {
+        // cached bodies are already accounted for
+        (_, Some(CacheStatus::Hit | CacheStatus::Stale)) => Some(0),
+        (Fetched::Buffered(response), _) => {
+            reservation.add(response.body.len());
+            Some(response.body.len() as u64)
+        }
+        (Fetched::Streamed { headers, .. }, _) => headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok()?.parse().ok()),
+    };
+    if let (Some(saver), Fetched::Buffered(response)) = (&state.data_saver, &mut response) {
+        if response.status == StatusCode::OK
+            && !response.headers.contains_key(header::CONTENT_ENCODING)
+            && DataSaver::requested(&headers)
+        {
+            let content_type = response.headers.get(header::CONTENT_TYPE);
+            if let Some(body) = saver.recompress(url, content_type, &response.body).await {
+                response.body = body;
+            }
+        }
+    }
+    // so that the caches between the proxy and the clients don't mix up the recompressed images
+    let varies = state.data_saver.is_some()
+        && match &response {
+            Fetched::Buffered(response) => &response.headers,
+            Fetched::Streamed { headers, .. } => headers,
+        }
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|content_type| DataSaver::applies(Some(content_type)));
+    // the length of streamed bodies, kept as long as they are passed through as is
+    let mut length = match &response {
+        Fetched::Streamed { headers, .. } => headers.get(header::CONTENT_LENGTH).cloned(),
+        Fetched::Buffered(_) => None,
+    };
+    let (status, mut response_headers, mut body) = into_parts(response, cache_status);
+    if varies {
+        response_headers.append(header::VARY, HeaderValue::from_static(data_saver::VARY));
+    }
+    // the ranges of partial bodies are ranges of their encoded form
+    let partial = status == StatusCode::PARTIAL_CONTENT;
+    let encoding = response_headers
+        .get(header::CONTENT_ENCODING)
+        .and_then(|value| decompress::Encoding::parse(value.to_str().ok()?));
+    if let Some(encoding) =
+        encoding.filter(|encoding| state.decompress && !partial && !encoding.accepted(&headers))
+    {
+        // the compression layer may still compress it with an accepted encoding
+        response_headers.remove(header::CONTENT_ENCODING);
+        body = encoding.decode(body);
+        length = None;
+    }
+    if let Some(rule) = state
+        .html
+        .rule(&target, &response_headers)
+        .filter(|_| !partial)
+    {
+        body = match decoded(url, &mut response_headers, body) {
+            Ok(decoded) => {
+                length = None;
+                rule.clone().rewrite(target.clone(), decoded)
+            }
+            Err(body) => body,
+        };
+    }
+    if !partial && state.rewrites.applies(&target, &response_headers) {
+        body = match decoded(url, &mut response_headers, body) {
+            Ok(decoded) => {
+                length = None;
+                state.rewrites.rewrite(&target, &response_headers, decoded)
+            }
+            Err(body) => body,
+        };
+    }
+    let quotas = state.quotas.clone();
+    let credential = token.clone();
+    let body = Counted::wrap(body, move |bytes| {
+        drop(reservation);
+        METRICS.bytes_sent.fetch_add(bytes, Ordering::Relaxed);
+        if let Some(quotas) = quotas {
+            quotas.record_bytes(&credential, bytes);
+        }
+    });
+    let body = match &state.throttle {
+        Some(throttle) => throttle.body(addr, token, body),
+        None => body,
+    };
+    let body = match fault {
+        Some(Injected::Truncate(limit)) => {
+            length = None;
+            Truncated::wrap(body, limit)
+        }
+        _ => body,
+    };
+    // so that the clients can tell the progress of downloads and the size of ranges
+    if let Some(length) = length {
+        response_headers.insert(header::CONTENT_LENGTH, length);
+    }
+    if state.accounting_headers {
+        let sent = http_body::Body::size_hint(&body).exact();
+        for (name, value) in [
+            (BYTES_RECEIVED_HEADER, received),
+            (BYTES_SENT_HEADER, sent),
+            (DURATION_HEADER, Some(start.elapsed().as_millis() as u64)),
+        ] {
+            if let Some(value) = value {
+                response_headers.insert(name, HeaderValue::from(value));
+            }
+        }
+    }
+    Ok((status, response_headers, body).into_response())
+}
+
+/// `body` decoded so that it can be rewritten, given back as is if its encoding isn't supported.
+fn decoded(url: &str, headers: &mut HeaderMap, body: Body) -> Result<Body, Body> {
+    let Some(value) = headers.get(header::CONTENT_ENCODING) else {
+        return Ok(body);
+    };
+    match value.to_str().ok().and_then(decompress::Encoding::parse) {
+        Some(encoding) => {
+            headers.remove(header::CONTENT_ENCODING);
+            Ok(encoding.decode(body))
+        }
+        None => {
+            tracing::debug!(url, "Body not rewritten, its encoding isn't supported");
+            Err(body)
+        }
+    }
+}
+
+/// Get the response for `url`, from the cache when possible.
+///
+/// The cache status is `None` if the cache is disabled. The `timeout` overrides the one of the
+/// client, except for background revalidations.
+pub async fn proxy(
+    state: &AppState,
+    target: &Url,
+    key: &str,
+    headers: &HeaderMap,
+    session: Option<Arc<Session>>,
+    capture: bool,
+    timeout: Option<Duration>,
+) -> Result<(Fetched, Option<CacheStatus>)> {
+    let url = target.as_str();
+    // partial responses aren't cached, nor shared with the clients of the whole body
+    let ranged = headers.contains_key(header::RANGE);
+    let bypass = ranged || headers.contains_key(CACHE_BYPASS_HEADER);
+    // the body is needed once the response is sent when it's kept around or logged
+    let buffer = !ranged
+        && (state.cache.is_some()
+            || state.failures.is_some()
+            || state.har.is_some()
+            || state.clamav.is_some()
+            || state.data_saver.is_some() && DataSaver::requested(headers)
+            || state
+                .pcap
+                .as_ref()
+                .is_some_and(|pcap| pcap.enabled(target.host_str().unwrap_or_default()))
+            || capture);
+    let forwarded = forwarded_headers(state, target, headers, session.as_deref());
+    let mut validators = forwarded.clone();
+    let mut fallback = None;
+    if let Some(cache) = state.cache.as_ref().filter(|_| !bypass) {
+        match cache.lookup(key) {
+            Lookup::Fresh(cached) => {
+                tracing::info!(data_len = cached.body.len(), "Served from cache");
+                return Ok((Fetched::Buffered(cached), Some(CacheStatus::Hit)));
+            }
+            Lookup::Revalidating { cached, refresh } => {
+                if refresh {
+                    let (state, url, key) = (state.clone(), url.to_string(), key.to_string());
+                    let session = session.clone();
+                    let mut validators = cached.validators();
+                    validators.extend(forwarded);
+                    tokio::spawn(async move {
+                        let refreshed = fetch(&state, &url, &key, validators, session, true, None)
+                            .await
+                            .is_ok_and(|(response, _)| !response.status().is_server_error());
+                        if !refreshed {
+                            tracing::warn!("Background revalidation failed");
+                        }
+                        // the entry is still stale unless the response was stored, which it isn't
+                        // when it is an error, isn't cacheable or is too large to be buffered
+                        if let Some(cache) = &state.cache {
+                            cache.abort_refresh(&key);
+                        }
+                    });
+                }
+                tracing::info!(
+                    data_len = cached.body.len(),
+                    "Served stale response from cache"
+                );
+                return Ok((Fetched::Buffered(cached), Some(CacheStatus::Stale)));
+            }
+            Lookup::Stale {
+                cached,
+                usable_on_error,
+            } => {
+                validators.extend(cached.validators());
+                fallback = usable_on_error.then_some(cached);
+            }
+            Lookup::Miss => {}
+        }
+    }
+    let failure = state
+        .failures
+        .as_ref()
+        .filter(|_| !bypass)
+        .and_then(|failures| failures.lookup(target));
+    let response = if let Some(failure) = failure {
+        tracing::info!("Replaying recent upstream failure");
+        match failure {
+            Failure::Response(response) => Ok((Fetched::Buffered(response), CacheStatus::Miss)),
+            Failure::Error(err) => Err(anyhow!(err).context(Gateway::Connect)),
+        }
+    } else if state.cache.is_some() && !ranged {
+        let (shared_state, shared_url, shared_key) =
+            (state.clone(), url.to_string(), key.to_string());
+        let (shared_validators, shared_session) = (validators.clone(), session.clone());
+        let shared = state
+            .inflight
+            .run(key, move || async move {
+                fetch(
+                    &shared_state,
+                    &shared_url,
+                    &shared_key,
+                    shared_validators,
+                    shared_session,
+                    true,
+                    timeout,
+                )
+                .await
+                .map_err(Arc::new)
+            })
+            .await
+            .map_err(|err| unshare(&err));
+        match shared {
+            Ok((
+                Fetched::Streamed {
+                    status,
+                    headers,
+                    body,
+                },
+                cache_status,
+            )) => {
+                // a streamed body can only be sent to one of the coalesced clients
+                let taken = body.lock().unwrap().take();
+                match taken {
+                    Some(taken) => Ok((
+                        Fetched::Streamed {
+                            status,
+                            headers,
+                            body: Arc::new(Mutex::new(Some(taken))),
+                        },
+                        cache_status,
+                    )),
+                    None => fetch(state, url, key, validators, session, true, timeout).await,
+                }
+            }
+            other => other,
+        }
+    } else {
+        fetch(state, url, key, validators, session, buffer, timeout).await
+    };
+    let cache_status = |status| {
+        state
+            .cache
+            .as_ref()
+            .map(|_| if bypass { CacheStatus::Bypass } else { status })
+    };
+    match (response, fallback) {
+        (Ok((response, _)), Some(cached)) if response.status().is_server_error() => {
+            tracing::warn!(
+                status_code = response.status().as_u16(),
+                "Origin failed, serving stale response from cache"
+            );
+            Ok((Fetched::Buffered(cached), Some(CacheStatus::Stale)))
+        }
+        (Ok((response, status)), _) => Ok((response, cache_status(status))),
+        (Err(err), Some(cached)) => {
+            tracing::warn!(error = %err, "Origin failed, serving stale response from cache");
+            Ok((Fetched::Buffered(cached), Some(CacheStatus::Stale)))
+        }
+        (Err(err), None) => Err(err),
+    }
+}
+
+/// Copy of the error of a coalesced fetch, keeping the types telling the status of the response.
+fn unshare(err: &anyhow::Error) -> anyhow::Error {
+    if let Some(open) = err.downcast_ref::<CircuitOpen>() {
+        return anyhow!(open.clone());
+    }
+    if let Some(not_recorded) = err.downcast_ref::<NotRecorded>() {
+        return anyhow!(not_recorded.clone());
+    }
+    match err.downcast_ref::<Gateway>() {
+        Some(gateway) => {
+            // the context is the first error of the chain
+            let causes: Vec<_> = err.chain().skip(1).map(ToString::to_string).collect();
+            anyhow!(causes.join(": ")).context(*gateway)
+        }
+        None => anyhow!("{err:#}"),
+    }
+}
+
+/// Headers sent to the origin: the ones of the persona, of the `session` if any, and some of the
+/// client request.
+fn forwarded_headers(
+    state: &AppState,
+    target: &Url,
+    headers: &HeaderMap,
+    session: Option<&Session>,
+) -> HeaderMap {
+    let personas = state.personas();
+    let mut forwarded = match session {
+        Some(session) => session.headers.clone(),
+        None => personas
+            .select(target.host_str().unwrap_or_default(), headers)
+            .unwrap_or(personas.default())
+            .draw(),
+    };
+    let accept_encoding = state
+        .accept_encoding
+        .as_ref()
+        .or_else(|| headers.get(header::ACCEPT_ENCODING));
+    if let Some(encoding) = accept_encoding {
+        forwarded.insert(header::ACCEPT_ENCODING, encoding.clone());
+    }
+    if let Some(tenant) = &state.tenant {
+        forwarded.extend(tenant.headers.clone());
+    }
+    for name in [header::RANGE, header::IF_RANGE] {
+        if let Some(value) = headers.get(&name) {
+            forwarded.insert(name, value.clone());
+        }
+    }
+    if state.forwarded.is_some() {
+        for name in forwarded::HEADERS {
+            if let Some(value) = headers.get(name) {
+                forwarded.insert(name, value.clone());
+            }
+        }
+    }
+    forwarded
+}
+
+/// Request `url` from the origin, keeping the cache entry `key` up to date with the response.
+///
+/// The `request_headers` are the validators of conditional requests and the forwarded headers.
+/// A `304 Not Modified` answer to a conditional request is resolved to the revalidated cache entry,
+/// which is reported as a cache hit. The response body is streamed unless `buffer` is set and it
+/// fits in the buffer limit. The requests of a `session` carry its cookies and use its connections.
+pub async fn fetch(
+    state: &AppState,
+    url: &str,
+    key: &str,
+    request_headers: HeaderMap,
+    session: Option<Arc<Session>>,
+    buffer: bool,
+    timeout: Option<Duration>,
+) -> Result<(Fetched, CacheStatus)> {
+    let target: Url = url.parse()?;
+    if let Some(replay) = &state.replay {
+        let response = replay.get(&target)?;
+        tracing::info!(data_len = response.body.len(), "Replayed recorded response");
+        return Ok((Fetched::Buffered(response), CacheStatus::Miss));
+    }
+    if let (Some(circuit), Some(host)) = (&state.circuit, target.host_str()) {
+        circuit.check(host)?;
+    }
+    if let Some(host) = target.host_str() {
+        // in the order documented in `pacing`, the pacing adding the jitter if it has one
+        let host_limits = state.host_limits.read().unwrap().clone();
+        host_limits.wait(host, !state.pacing.jitters(host)).await;
+        if let Some(backoff) = &state.backoff {
+            backoff.wait(host).await;
+        }
+        state.pacing.wait(host).await;
+        if let Some(pace) = session.as_ref().and_then(|session| session.pace.as_ref()) {
+            pace.wait().await;
+        }
+    }
+    let span = tracing::info_span!(
+        "upstream",
+        %url,
+        dns_ms = tracing::field::Empty,
+        tcp_ms = tracing::field::Empty,
+        tls_ms = tracing::field::Empty,
+        ttfb_ms = tracing::field::Empty,
+        body_ms = tracing::field::Empty,
+    );
+    let mut headers = request_headers;
+    telemetry::inject(&span, &mut headers);
+    if let Some(cookie) = session
+        .as_ref()
+        .and_then(|session| session.cookie_header(&target))
+    {
+        headers.insert(header::COOKIE, cookie);
+    }
+    let upstream = session
+        .as_ref()
+        .map_or(&*state.upstream, |session| &session.upstream);
+    let started = chrono::Utc::now();
+    let start = Instant::now();
+    let hedge_delay = target
+        .host_str()
+        .and_then(|host| hedge::delay(&state.hedge_rules, host));
+    let signer = target.host_str().and_then(|host| state.sigv4.signer(host));
+    let credentials = match signer {
+        Some(signer) => Some(signer.credentials().await?),
+        None => None,
+    };
+    let tokens = target.host_str().and_then(|host| state.oauth.source(host));
+    let authorization = match tokens {
+        Some(tokens) => Some(tokens.authorization(upstream).await?),
+        None => None,
+    };
+    if let Some(proxy) = upstream.proxy(&target) {
+        // without the credentials of the proxy
+        let proxy = format!(
+            "{}:{}",
+            proxy.host_str().unwrap_or_default(),
+            proxy.port_or_known_default().unwrap_or_default()
+        );
+        tracing::debug!(proxy, "Sending through upstream proxy");
+    }
+    let personas = state.personas();
+    // the connections of the persona of the headers, if it has its own TLS profile
+    let persona = |headers: &HeaderMap| personas.owner(headers).map(|persona| persona.name.clone());
+    let send = |headers: &HeaderMap| {
+        METRICS.upstream_requests.fetch_add(1, Ordering::Relaxed);
+        let persona = persona(headers);
+        let mut headers = headers.clone();
+        if let Some(authorization) = &authorization {
+            headers.insert(header::AUTHORIZATION, authorization.clone());
+        }
+        if let (Some(signer), Some(credentials)) = (signer, &credentials) {
+            signer.sign(credentials, &target, &mut headers);
+        }
+        let sensitive = state.redirects.sensitive(&headers);
+        let mut request = upstream.get(&target, persona.as_deref()).headers(headers);
+        if let Some(timeout) = timeout {
+            request = request.timeout(timeout);
+        }
+        Box::pin(redirect::scoped(sensitive, request.send()).instrument(span.clone()))
+    };
+    let mut attempt = 0;
+    let mut retry_personas = state
+        .challenges
+        .iter()
+        .flat_map(|challenges| challenges.retry_personas())
+        .filter_map(|name| personas.get(name));
+    let mut tried = Vec::new();
+    let mut stale_retried = false;
+    let sent = loop {
+        let mut first = send(&headers);
+        let sent = match hedge_delay {
+            Some(delay) => tokio::select! {
+                sent = &mut first => sent,
+                () = tokio::time::sleep(delay) => {
+                    tracing::debug!(delay = ?delay, "Hedging slow upstream request");
+                    METRICS.upstream_hedges.fetch_add(1, Ordering::Relaxed);
+                    // whichever succeeds first wins, the other one is dropped
+                    match futures_util::future::select(first, send(&headers)).await {
+                        Either::Left((sent, other)) | Either::Right((sent, other)) => {
+                            if sent.is_err() { other.await } else { sent }
+                        }
+                    }
+                }
+            },
+            None => first.await,
+        };
+        let sent = match (&state.challenges, sent) {
+            (Some(challenges), Ok(response)) => match challenges.inspect(response).await {
+                Ok((response, Some(challenge))) => {
+                    tracing::warn!(
+                        challenge,
+                        status_code = response.status().as_u16(),
+                        "Upstream answered with a challenge"
+                    );
+                    // the personas the request was already sent with are skipped
+                    tried.extend(headers.get(header::USER_AGENT).cloned());
+                    if let Some(persona) = retry_personas.find(|persona| {
+                        !tried
+                            .iter()
+                            .any(|user_agent| persona.owns_user_agent(user_agent))
+                    }) {
+                        tracing::info!(persona = persona.name, "Retrying with another persona");
+                        METRICS.upstream_retries.fetch_add(1, Ordering::Relaxed);
+                        headers = personas.swap(&headers, persona);
+                        continue;
+                    }
+                    Ok(response)
+                }
+                inspected => inspected.map(|(response, _)| response),
+            },
+            (_, sent) => sent,
+        };
+        if let Err(err) = &sent {
+            if !stale_retried && RetryPolicy::is_stale_connection(err) {
+                tracing::debug!(error = %err, "Retrying on a new connection, the pooled one was stale");
+                METRICS.upstream_retries.fetch_add(1, Ordering::Relaxed);
+                stale_retried = true;
+                continue;
+            }
+        }
+        if sent
+            .as_ref()
+            .map_or(true, |response| response.status().is_server_error())
+        {
+            METRICS.upstream_errors.fetch_add(1, Ordering::Relaxed);
+        }
+        if !state.retry.should_retry(attempt, &sent) {
+            break sent;
+        }
+        attempt += 1;
+        METRICS.upstream_retries.fetch_add(1, Ordering::Relaxed);
+        let delay = state.retry.delay(attempt, &sent);
+        match &sent {
+            Ok(response) => tracing::warn!(
+                attempt,
+                status_code = response.status().as_u16(),
+                delay_ms = delay.as_millis() as u64,
+                "Retrying upstream request"
+            ),
+            Err(err) => tracing::warn!(
+                attempt,
+                error = %err,
+                delay_ms = delay.as_millis() as u64,
+                "Retrying upstream request"
+            ),
+        }
+        tokio::time::sleep(delay).await;
+    };
+    if let (Some(circuit), Some(host)) = (&state.circuit, target.host_str()) {
+        let success = sent
+            .as_ref()
+            .is_ok_and(|response| !response.status().is_server_error());
+        circuit.record(host, success);
+    }
+    if let (Some(tokens), Ok(response)) = (tokens, &sent) {
+        if response.status() == StatusCode::UNAUTHORIZED {
+            tokens.invalidate();
+        }
+    }
+    let mut request = match sent {
+        Ok(request) => request,
+        Err(err) => {
+            if let Some(failures) = state.failures.as_ref().filter(|_| err.is_connect()) {
+                failures.record(&target, Failure::Error(err.to_string()));
+            }
+            let gateway = Gateway::of(&err);
+            return Err(anyhow::Error::new(err).context(gateway));
+        }
+    };
+    if let Some(session) = &session {
+        session.store_cookies(&target, request.headers());
+    }
+    if let (Some(host), Some(addr)) = (target.host_str(), request.remote_addr()) {
+        state.dns.connected(host, addr);
+    }
+    if let (Some(backoff), Some(host)) = (&state.backoff, target.host_str()) {
+        backoff.record(host, request.status(), request.headers());
+    }
+    let wait = start.elapsed();
+    let (status, version) = (request.status(), request.version());
+    // returns the timings, whose body phase is recorded once a streamed `body` is over
+    let record = |response_headers: &HeaderMap, body: Option<&[u8]>| {
+        let receive = start.elapsed() - wait;
+        let timings = timing::record(wait, body.map(|_| receive));
+        for (field, duration) in [
+            ("dns_ms", timings.dns),
+            ("tcp_ms", timings.tcp),
+            ("tls_ms", timings.tls),
+        ] {
+            if let Some(duration) = duration {
+                span.record(field, duration.as_secs_f64() * 1e3);
+            }
+        }
+        span.record("ttfb_ms", wait.as_secs_f64() * 1e3);
+        if body.is_some() {
+            span.record("body_ms", receive.as_secs_f64() * 1e3);
+        }
+        let exchange = Exchange {
+            started,
+            url: &target,
+            request_headers: &headers,
+            status,
+            version,
+            response_headers,
+            body,
+            wait,
+            receive,
+        };
+        if let Some(har) = &state.har {
+            har.record(exchange);
+        }
+        if let Some(pcap) = &state.pcap {
+            pcap.record(exchange);
+        }
+        timings
+    };
+    if request.status() == StatusCode::NOT_MODIFIED {
+        record(request.headers(), Some(&[]));
+        if let Some(cached) = state
+            .cache
+            .as_ref()
+            .and_then(|cache| cache.revalidate(key, request.headers()))
+        {
+            tracing::info!(data_len = cached.body.len(), "Revalidated cached response");
+            return Ok((Fetched::Buffered(cached), CacheStatus::Hit));
+        }
+        // the validators are the ones of a cache entry that is gone since, not the client's
+        let validators = [header::IF_NONE_MATCH, header::IF_MODIFIED_SINCE];
+        if validators.iter().any(|name| headers.contains_key(name)) {
+            tracing::debug!("Revalidated cache entry is gone, requesting the whole response");
+            let mut headers = headers.clone();
+            for name in validators {
+                headers.remove(name);
+            }
+            return Box::pin(fetch(state, url, key, headers, session, buffer, timeout)).await;
+        }
+    }
+    let limit = if buffer { state.max_buffered_body } else { 0 };
+    let mut chunks = Vec::new();
+    let mut reservation = Reservation::default();
+    let mut buffered = 0;
+    let mut complete = false;
+    if limit > 0
+        && request
+            .content_length()
+            .is_none_or(|len| len <= limit as u64)
+    {
+        while buffered <= limit {
+            let chunk = request.chunk().await.map_err(|err| {
+                let gateway = Gateway::of(&err);
+                anyhow::Error::new(err).context(gateway)
+            })?;
+            match chunk {
+                Some(chunk) => {
+                    buffered += chunk.len();
+                    reservation.add(chunk.len());
+                    chunks.push(chunk);
+                }
+                None => {
+                    complete = true;
+                    break;
+                }
+            }
+        }
+    }
+    if !complete {
+        let timings = record(request.headers(), None);
+        tracing::info!(status_code = status.as_u16(), "Streaming proxied response");
+        let recording = state
+            .recording
+            .clone()
+            .map(|recording| (recording, headers.clone()));
+        // the same request, sent again with a range to resume the body
+        let resume = (state.resume_downloads > 0).then(|| {
+            let request = upstream
+                .get(&target, persona(&headers).as_deref())
+                .headers(headers.clone());
+            match timeout {
+                Some(timeout) => request.timeout(timeout),
+                None => request,
+            }
+        });
+        let (status, headers) = (request.status(), request.headers().clone());
+        let host = target.host_str().unwrap_or_default().to_string();
+        // the body ends outside of the span of the request
+        let trace_id = telemetry::trace_id();
+        let prefix = futures_util::stream::iter(chunks.into_iter().map(Ok));
+        let verifier = state.integrity.verifier(&target, status, &headers);
+        let mut stream = prefix.chain(request.bytes_stream()).boxed();
+        if let Some((request, validator)) = resume.zip(resume::validator(status, &headers)) {
+            stream = resume::resumable(stream, request, validator, state.resume_downloads).boxed();
+        }
+        let stream = integrity::verified(stream, verifier, url.to_string());
+        let body = match recording {
+            Some((recording, request_headers)) => Body::from_stream(recording.tee(
+                target.clone(),
+                request_headers,
+                status,
+                headers.clone(),
+                stream,
+            )),
+            None => Body::from_stream(stream),
+        };
+        let body = Counted::wrap(body, move |bytes| {
+            let _ = timings.body.set(start.elapsed() - wait);
+            // the buffered prefix is released along with the body
+            drop(reservation);
+            METRICS.observe_upstream_latency(&host, start.elapsed(), trace_id.as_deref());
+            METRICS.bytes_received.fetch_add(bytes, Ordering::Relaxed);
+        });
+        return Ok((
+            Fetched::Streamed {
+                status,
+                headers,
+                body: Arc::new(Mutex::new(Some(body))),
+            },
+            CacheStatus::Miss,
+        ));
+    }
+    let body = match <[Bytes; 1]>::try_from(chunks) {
+        Ok([chunk]) => chunk,
+        Err(chunks) => Bytes::from(chunks.concat()),
+    };
+    let response = CachedResponse {
+        status: request.status(),
+        headers: request.headers().clone(),
+        body,
+    };
+    record(&response.headers, Some(&response.body));
+    if let Some(mut verifier) =
+        state
+            .integrity
+            .verifier(&target, response.status, &response.headers)
+    {
+        verifier.update(&response.body);
+        verifier.verify(url).context(Gateway::Integrity)?;
+    }
+    if let Some(recording) = &state.recording {
+        recording.record(
+            &target,
+            &headers,
+            response.status,
+            &response.headers,
+            &response.body,
+        );
+    }
+    METRICS.observe_upstream_latency(
+        target.host_str().unwrap_or_default(),
+        start.elapsed(),
+        telemetry::trace_id().as_deref(),
+    );
+    METRICS
+        .bytes_received
+        .fetch_add(response.body.len() as u64, Ordering::Relaxed);
+    if response.status == StatusCode::OK {
+        tracing::info!(data_len = response.body.len(), "Proxied request");
+        if let Some(cache) = &state.cache {
+            cache.store(key.to_string(), url, response.clone());
+        }
+    } else {
+        tracing::error!(
+            status_code = response.status.as_u16(),
+            "Error during proxy request"
+        );
+        if let Some(failures) = state
+            .failures
+            .as_ref()
+            .filter(|_| response.status.is_server_error())
+        {
+            failures.record(&target, Failure::Response(response.clone()));
+        }
+    }
+    Ok((Fetched::Buffered(response), CacheStatus::Miss))
+}
+
+/// Build the response sent to the client from an upstream response.
+fn into_parts(
+    response: Fetched,
+    cache_status: Option<CacheStatus>,
+) -> (StatusCode, HeaderMap, Body) {
+    let (status, upstream_headers, body) = match response {
+        Fetched::Buffered(response) => {
+            (response.status, response.headers, Body::from(response.body))
+        }
+        Fetched::Streamed {
+            status,
+            headers,
+            body,
+        } => {
+            let body = body.lock().unwrap().take().unwrap_or_default();
+            (status, headers, body)
+        }
+    };
+    let mut headers = HeaderMap::new();
+    if let Some(status) = cache_status {
+        headers.insert(
+            CACHE_STATUS_HEADER,
+            HeaderValue::from_static(status.as_str()),
+        );
+    }
+    if let Some(challenge) = upstream_headers.get(challenge::CHALLENGE_HEADER) {
+        headers.insert(challenge::CHALLENGE_HEADER, challenge.clone());
+    }
+    // compressed bodies are passed through, and left alone by the compression layer
+    if let Some(encoding) = upstream_headers.get(header::CONTENT_ENCODING) {
+        headers.insert(header::CONTENT_ENCODING, encoding.clone());
+    }
+    for name in [header::ACCEPT_RANGES, header::CONTENT_RANGE] {
+        if let Some(value) = upstream_headers.get(&name) {
+            headers.insert(name, value.clone());
+        }
+    }
+    if matches!(status, StatusCode::OK | StatusCode::PARTIAL_CONTENT) {
+        headers.insert(
+            header::CONTENT_TYPE,
+            upstream_headers
+                .get(header::CONTENT_TYPE)
+                .cloned()
+                .unwrap_or(HeaderValue::from_static("text/plain")),
+        );
+    }
+    (status, headers, body)
+}
+
+pub async fn handler_404() -> impl IntoResponse {
+    (StatusCode::NOT_FOUND, "nothing to see here")
+}
+
+pub struct AppError(anyhow::Error);
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> axum::response::Response {
+        if let Some(open) = self.0.downcast_ref::<CircuitOpen>() {
+            return open.clone().into_response();
+        }
+        if let Some(not_recorded) = self.0.downcast_ref::<NotRecorded>() {
+            return not_recorded.clone().into_response();
+        }
+        if let Some(gateway) = self.0.downcast_ref::<Gateway>() {
+            return errors::response(gateway.status(), gateway.code(), format!("{:#}", self.0));
+        }
+        errors::response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal_error",
+            format!("Something went wrong: {}", self.0),
+        )
+    }
+}
+
+impl<E> From<E> for AppError
+where
+    E: Into<anyhow::Error>,
+{
+    fn from(err: E) -> Self {
+        Self(err.into())
+    }
+}
@@ -0,0 +1,314 @@
+//! WASM plugins implementing [`Hook`]s, so that policies can be deployed independently of the proxy
+//! and sandboxed from it. Requires the `wasm` feature.
+//!
+//! A plugin is a module without imports, exporting its `memory`, an `alloc(len: i32) -> i32`
+//! function giving the proxy room for its input, and `on_request` or `on_response` functions. These
+//! take the pointer and length of a JSON document, and return the location of the JSON document of
+//! their answer as `ptr << 32 | len`, or 0 to leave the traffic unchanged. Headers are lists of
+//! `[name, value]` pairs.
+//!
+//! - `on_request` gets `{"method", "uri", "headers"}`, and answers `{"headers"}` to replace the
+//!   headers of the request, or `{"respond": {"status", "headers", "body"}}` to answer it.
+//! - `on_response` gets `{"request": {"method", "uri", "headers"}, "status", "headers", "error"}`,
+//!   the `error` being the code of the error responses of the proxy, and answers `{"status"}`
+//!   and/or `{"headers"}` to replace them.
+//!
+//! Each call runs in a fresh instance, with bounded memory and fuel. A plugin that fails, runs out
+//! of fuel or answers an invalid document makes the proxy answer `500 Internal Server Error`.
+use std::{path::PathBuf, sync::Arc};
+
+use anyhow::Result;
+use serde::Deserialize;
+
+use crate::{config::ByteSize, hooks::Hook};
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[cfg_attr(not(feature = "wasm"), allow(dead_code))]
+pub struct PluginConfig {
+    /// WASM module, in the binary or the text format.
+    pub path: PathBuf,
+    /// Fuel of each call, roughly the number of instructions it can run.
+    #[serde(default = "PluginConfig::default_fuel")]
+    pub fuel: u64,
+    /// Memory of each instance.
+    #[serde(default = "PluginConfig::default_memory")]
+    pub memory: ByteSize,
+}
+
+impl PluginConfig {
+    fn default_fuel() -> u64 {
+        10_000_000
+    }
+
+    fn default_memory() -> ByteSize {
+        ByteSize(16 << 20)
+    }
+}
+
+/// Hooks of the plugins, in the configured order.
+#[cfg(feature = "wasm")]
+pub fn load(configs: &[PluginConfig]) -> Result<Vec<Arc<dyn Hook>>> {
+    configs
+        .iter()
+        .map(|config| Ok(Arc::new(wasm::Plugin::load(config)?) as Arc<dyn Hook>))
+        .collect()
+}
+
+#[cfg(not(feature = "wasm"))]
+pub fn load(configs: &[PluginConfig]) -> Result<Vec<Arc<dyn Hook>>> {
+    anyhow::ensure!(
+        configs.is_empty(),
+        "WASM plugins require the proxy to be built with the `wasm` feature"
+    );
+    Ok(Vec::new())
+}
+
+#[cfg(feature = "wasm")]
+mod wasm {
+    use anyhow::{bail, ensure, Context, Result};
+    use async_trait::async_trait;
+    use axum::{
+        body::Body,
+        extract::Request,
+        http::{HeaderMap, HeaderName, HeaderValue, StatusCode},
+        response::Response,
+    };
+    use serde::{de::DeserializeOwned, Deserialize, Serialize};
+    use wasmtime::{
+        Config, Engine, InstancePre, Linker, Module, Store, StoreLimits, StoreLimitsBuilder,
+    };
+
+    use super::PluginConfig;
+    use crate::{
+        config::ByteSize,
+        errors,
+        hooks::{Flow, Hook, ProxyError, RequestHead},
+    };
+
+    type Headers = Vec<(String, String)>;
+
+    #[derive(Serialize)]
+    struct RequestInput<'a> {
+        method: &'a str,
+        uri: String,
+        headers: Headers,
+    }
+
+    #[derive(Serialize)]
+    struct ResponseInput<'a> {
+        request: RequestInput<'a>,
+        status: u16,
+        headers: Headers,
+        error: Option<&'static str>,
+    }
+
+    #[derive(Deserialize)]
+    struct RequestOutput {
+        headers: Option<Headers>,
+        respond: Option<Answer>,
+    }
+
+    #[derive(Deserialize)]
+    struct Answer {
+        status: u16,
+        #[serde(default)]
+        headers: Headers,
+        #[serde(default)]
+        body: String,
+    }
+
+    #[derive(Deserialize)]
+    struct ResponseOutput {
+        status: Option<u16>,
+        headers: Option<Headers>,
+    }
+
+    pub struct Plugin {
+        name: String,
+        engine: Engine,
+        instance: InstancePre<StoreLimits>,
+        fuel: u64,
+        memory: usize,
+        on_request: bool,
+        on_response: bool,
+    }
+
+    impl Plugin {
+        pub fn load(config: &PluginConfig) -> Result<Self> {
+            let engine = Engine::new(Config::new().consume_fuel(true))?;
+            let module = Module::from_file(&engine, &config.path)
+                .with_context(|| format!("could not load plugin {}", config.path.display()))?;
+            let exports = |name| module.get_export(name).is_some();
+            ensure!(
+                exports("memory") && exports("alloc"),
+                "plugin {} must export `memory` and `alloc`",
+                config.path.display()
+            );
+            let (on_request, on_response) = (exports("on_request"), exports("on_response"));
+            // no imports are linked, so the plugins can't reach anything outside their memory
+            let instance = Linker::new(&engine).instantiate_pre(&module)?;
+            let ByteSize(memory) = config.memory;
+            Ok(Self {
+                name: config.path.display().to_string(),
+                engine,
+                instance,
+                fuel: config.fuel,
+                memory: memory as usize,
+                on_request,
+                on_response,
+            })
+        }
+
+        /// Call `function` of a fresh instance with `input`, `None` if it answered 0.
+        fn call<T: DeserializeOwned>(
+            &self,
+            function: &str,
+            input: &impl Serialize,
+        ) -> Result<Option<T>> {
+            let limits = StoreLimitsBuilder::new().memory_size(self.memory).build();
+            let mut store = Store::new(&self.engine, limits);
+            store.limiter(|limits| limits);
+            store.set_fuel(self.fuel)?;
+            let instance = self.instance.instantiate(&mut store)?;
+            let memory = instance
+                .get_memory(&mut store, "memory")
+                .context("`memory` is not a memory")?;
+            let alloc = instance.get_typed_func::<i32, i32>(&mut store, "alloc")?;
+            let function = instance.get_typed_func::<(i32, i32), i64>(&mut store, function)?;
+
+            let input = serde_json::to_vec(input)?;
+            let len = i32::try_from(input.len())?;
+            let ptr = alloc.call(&mut store, len)?;
+            memory.write(&mut store, ptr as u32 as usize, &input)?;
+            let location = function.call(&mut store, (ptr, len))? as u64;
+            if location == 0 {
+                return Ok(None);
+            }
+            let (ptr, len) = ((location >> 32) as usize, (location & 0xffff_ffff) as usize);
+            let Some(output) = memory.data(&store).get(ptr..ptr + len) else {
+                bail!("answer out of the memory bounds");
+            };
+            Ok(Some(serde_json::from_slice(output)?))
+        }
+
+        fn failed(&self, err: anyhow::Error) -> Response {
+            tracing::error!(plugin = self.name, error = %err, "Plugin failed");
+            errors::response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "plugin_error",
+                "Something went wrong",
+            )
+        }
+
+        fn response(
+            &self,
+            request: &RequestHead,
+            error: Option<&'static str>,
+            mut response: Response,
+        ) -> Response {
+            if !self.on_response {
+                return response;
+            }
+            let input = ResponseInput {
+                request: request_input(request.method.as_str(), &request.uri, &request.headers),
+                status: response.status().as_u16(),
+                headers: headers_input(response.headers()),
+                error,
+            };
+            let output: ResponseOutput = match self.call("on_response", &input) {
+                Ok(Some(output)) => output,
+                Ok(None) => return response,
+                Err(err) => return self.failed(err),
+            };
+            if let Some(status) = output.status {
+                match StatusCode::from_u16(status) {
+                    Ok(status) => *response.status_mut() = status,
+                    Err(err) => return self.failed(err.into()),
+                }
+            }
+            if let Some(headers) = output.headers {
+                match header_map(headers) {
+                    Ok(headers) => *response.headers_mut() = headers,
+                    Err(err) => return self.failed(err),
+                }
+            }
+            response
+        }
+    }
+
+    fn request_input<'a>(
+        method: &'a str,
+        uri: &axum::http::Uri,
+        headers: &HeaderMap,
+    ) -> RequestInput<'a> {
+        RequestInput {
+            method,
+            uri: uri.to_string(),
+            headers: headers_input(headers),
+        }
+    }
+
+    fn headers_input(headers: &HeaderMap) -> Headers {
+        headers
+            .iter()
+            .map(|(name, value)| {
+                let value = String::from_utf8_lossy(value.as_bytes()).into_owned();
+                (name.to_string(), value)
+            })
+            .collect()
+    }
+
+    fn header_map(headers: Headers) -> Result<HeaderMap> {
+        headers
+            .into_iter()
+            .map(|(name, value)| Ok((HeaderName::try_from(name)?, HeaderValue::try_from(value)?)))
+            .collect()
+    }
+
+    #[async_trait]
+    impl Hook for Plugin {
+        async fn on_request(&self, mut request: Request) -> Flow {
+            if !self.on_request {
+                return Flow::Continue(request);
+            }
+            let input = request_input(request.method().as_str(), request.uri(), request.headers());
+            let output: RequestOutput = match self.call("on_request", &input) {
+                Ok(Some(output)) => output,
+                Ok(None) => return Flow::Continue(request),
+                Err(err) => return Flow::Respond(self.failed(err)),
+            };
+            if let Some(answer) = output.respond {
+                let response = StatusCode::from_u16(answer.status)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|status| {
+                        let mut response = Response::new(Body::from(answer.body));
+                        *response.status_mut() = status;
+                        *response.headers_mut() = header_map(answer.headers)?;
+                        Ok(response)
+                    });
+                return Flow::Respond(response.unwrap_or_else(|err| self.failed(err)));
+            }
+            if let Some(headers) = output.headers {
+                match header_map(headers) {
+                    Ok(headers) => *request.headers_mut() = headers,
+                    Err(err) => return Flow::Respond(self.failed(err)),
+                }
+            }
+            Flow::Continue(request)
+        }
+
+        async fn on_response(&self, request: &RequestHead, response: Response) -> Response {
+            self.response(request, None, response)
+        }
+
+        async fn on_error(
+            &self,
+            request: &RequestHead,
+            error: &ProxyError,
+            response: Response,
+        ) -> Response {
+            self.response(request, Some(error.code()), response)
+        }
+    }
+}
@@ -0,0 +1,446 @@
+//! Recompression of PNG images, for the data saver.
+//!
+//! The image data is deflated again at the highest level. The 8 and 16-bit images that aren't
+//! interlaced are also filtered again with the heuristic of libpng, after their 16-bit samples are
+//! reduced to 8 bits and their alpha channel is dropped if they are fully opaque. The ancillary
+//! chunks are dropped, except the ones changing how the image is displayed like its transparency
+//! or color space. Animated PNGs only lose their metadata.
+use std::io::{Read, Write};
+
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression, Crc};
+
+const SIGNATURE: &[u8; 8] = b"\x89PNG\r\n\x1a\n";
+
+/// Ancillary chunks kept, along with the critical ones.
+const KEPT: [&[u8; 4]; 11] = [
+    b"tRNS", b"gAMA", b"cHRM", b"sRGB", b"iCCP", b"cICP", b"mDCV", b"cLLI", b"eXIf", b"acTL",
+    b"fcTL",
+];
+
+/// Largest image decoded, whose pixels are held in memory twice.
+const MAX_PIXELS: u64 = 50_000_000;
+
+/// Size of the IDAT chunks written.
+const IDAT_SIZE: usize = 1 << 20;
+
+/// `data` recompressed, or `None` if it isn't a well-formed PNG.
+pub fn recompress(data: &[u8]) -> Option<Vec<u8>> {
+    let chunks = chunks(data)?;
+    let (_, header) = chunks.first().filter(|(kind, _)| kind == b"IHDR")?;
+    let mut header = Header::parse(header)?;
+    let animated = chunks.iter().any(|(kind, _)| kind == b"acTL");
+    let compressed: Vec<u8> = chunks
+        .iter()
+        .filter(|(kind, _)| kind == b"IDAT")
+        .flat_map(|(_, data)| data.iter().copied())
+        .collect();
+    let mut image = compressed.clone();
+    if !animated {
+        let transparent = chunks.iter().any(|(kind, _)| kind == b"tRNS");
+        if let Some((reduced, filtered)) = header.reencode(&compressed, transparent) {
+            let deflated = deflate(&filtered)?;
+            if deflated.len() < image.len() {
+                (header, image) = (reduced, deflated);
+            }
+        }
+    }
+    let mut out = SIGNATURE.to_vec();
+    let mut written = false;
+    for (kind, body) in &chunks {
+        match kind {
+            b"IHDR" => write_chunk(&mut out, kind, &header.to_bytes()),
+            b"IDAT" if !written => {
+                for part in image.chunks(IDAT_SIZE) {
+                    write_chunk(&mut out, b"IDAT", part);
+                }
+                written = true;
+            }
+            b"IDAT" => {}
+            b"PLTE" | b"IEND" | b"fdAT" => write_chunk(&mut out, kind, body),
+            kind if KEPT.contains(&kind) => write_chunk(&mut out, kind, body),
+            // the critical chunks that aren't known can't be dropped
+            [first, ..] if first.is_ascii_uppercase() => return None,
+            _ => {}
+        }
+    }
+    Some(out)
+}
+
+/// The kind and data of each chunk of `data`, up to `IEND` included.
+fn chunks(data: &[u8]) -> Option<Vec<([u8; 4], &[u8])>> {
+    let mut rest = data.strip_prefix(SIGNATURE)?;
+    let mut chunks = Vec::new();
+    loop {
+        let (len, after) = rest.split_first_chunk::<4>()?;
+        let (kind, after) = after.split_first_chunk::<4>()?;
+        let (body, after) = after.split_at_checked(u32::from_be_bytes(*len) as usize)?;
+        let (crc, after) = after.split_first_chunk::<4>()?;
+        let mut expected = Crc::new();
+        expected.update(kind);
+        expected.update(body);
+        if expected.sum() != u32::from_be_bytes(*crc) {
+            return None;
+        }
+        chunks.push((*kind, body));
+        if kind == b"IEND" {
+            return Some(chunks);
+        }
+        rest = after;
+    }
+}
+
+fn write_chunk(out: &mut Vec<u8>, kind: &[u8; 4], body: &[u8]) {
+    let mut crc = Crc::new();
+    crc.update(kind);
+    crc.update(body);
+    out.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    out.extend_from_slice(kind);
+    out.extend_from_slice(body);
+    out.extend_from_slice(&crc.sum().to_be_bytes());
+}
+
+fn deflate(data: &[u8]) -> Option<Vec<u8>> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(data).ok()?;
+    encoder.finish().ok()
+}
+
+#[derive(Clone, Copy)]
+struct Header {
+    width: u32,
+    height: u32,
+    depth: u8,
+    /// Gray, RGB, palette, gray and alpha or RGBA, as 0, 2, 3, 4 and 6.
+    color: u8,
+    interlaced: bool,
+}
+
+impl Header {
+    fn parse(data: &[u8]) -> Option<Self> {
+        let &[w3, w2, w1, w0, h3, h2, h1, h0, depth, color, 0, 0, interlace] = data else {
+            return None;
+        };
+        let header = Self {
+            width: u32::from_be_bytes([w3, w2, w1, w0]),
+            height: u32::from_be_bytes([h3, h2, h1, h0]),
+            depth,
+            color,
+            interlaced: interlace == 1,
+        };
+        let valid = match color {
+            0 => matches!(depth, 1 | 2 | 4 | 8 | 16),
+            3 => matches!(depth, 1 | 2 | 4 | 8),
+            2 | 4 | 6 => matches!(depth, 8 | 16),
+            _ => false,
+        };
+        (valid && interlace <= 1 && header.width > 0 && header.height > 0).then_some(header)
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(13);
+        data.extend_from_slice(&self.width.to_be_bytes());
+        data.extend_from_slice(&self.height.to_be_bytes());
+        data.extend_from_slice(&[self.depth, self.color, 0, 0, u8::from(self.interlaced)]);
+        data
+    }
+
+    fn channels(&self) -> usize {
+        match self.color {
+            2 => 3,
+            4 => 2,
+            6 => 4,
+            _ => 1,
+        }
+    }
+
+    /// Bytes of a row of `width` pixels, without its filter type.
+    fn stride(&self, width: u32) -> usize {
+        (width as usize * self.channels() * usize::from(self.depth)).div_ceil(8)
+    }
+
+    /// Bytes of the filtered image data.
+    fn filtered_len(&self) -> Option<usize> {
+        if u64::from(self.width) * u64::from(self.height) > MAX_PIXELS {
+            return None;
+        }
+        if !self.interlaced {
+            return Some(self.height as usize * (1 + self.stride(self.width)));
+        }
+        // the 7 passes of Adam7, of their first column and row and spacing
+        let passes = [
+            (0, 0, 8, 8),
+            (4, 0, 8, 8),
+            (0, 4, 4, 8),
+            (2, 0, 4, 4),
+            (0, 2, 2, 4),
+            (1, 0, 2, 2),
+            (0, 1, 1, 2),
+        ];
+        Some(
+            passes
+                .into_iter()
+                .map(|(x, y, dx, dy)| {
+                    let width = self.width.saturating_sub(x).div_ceil(dx);
+                    let height = self.height.saturating_sub(y).div_ceil(dy) as usize;
+                    match width {
+                        0 => 0,
+                        width => height * (1 + self.stride(width)),
+                    }
+                })
+                .sum(),
+        )
+    }
+
+    /// The header and the filtered data of the image of the `compressed` data, re-encoded.
+    ///
+    /// The samples of the images without `transparent` colors are reduced to 8 bits, which would
+    /// make other colors transparent along with theirs.
+    fn reencode(&self, compressed: &[u8], transparent: bool) -> Option<(Self, Vec<u8>)> {
+        let len = self.filtered_len()?;
+        let mut filtered = Vec::with_capacity(len);
+        ZlibDecoder::new(compressed)
+            .take(len as u64)
+            .read_to_end(&mut filtered)
+            .ok()?;
+        if filtered.len() != len {
+            return None;
+        }
+        if self.interlaced || self.depth < 8 {
+            return Some((*self, filtered));
+        }
+        let stride = self.stride(self.width);
+        let bytes_per_pixel = stride / self.width as usize;
+        let mut pixels = unfilter(&filtered, stride, bytes_per_pixel)?;
+        let mut header = *self;
+        if header.depth == 16 && !transparent {
+            pixels = pixels
+                .as_chunks::<2>()
+                .0
+                .iter()
+                .map(|sample| {
+                    ((u32::from(u16::from_be_bytes(*sample)) * 255 + 32767) / 65535) as u8
+                })
+                .collect();
+            header.depth = 8;
+        }
+        let channels = header.channels();
+        let sample = usize::from(header.depth / 8);
+        if matches!(header.color, 4 | 6)
+            && pixels.chunks(channels * sample).all(|pixel| {
+                pixel[(channels - 1) * sample..]
+                    .iter()
+                    .all(|&byte| byte == 0xff)
+            })
+        {
+            pixels = pixels
+                .chunks(channels * sample)
+                .flat_map(|pixel| &pixel[..(channels - 1) * sample])
+                .copied()
+                .collect();
+            header.color -= 4;
+        }
+        let stride = header.stride(header.width);
+        let bytes_per_pixel = stride / header.width as usize;
+        Some((
+            header,
+            filter(&pixels, stride, bytes_per_pixel, header.color == 3),
+        ))
+    }
+}
+
+/// The rows of `filtered` data, of `stride` bytes and each preceded by its filter type, unfiltered.
+fn unfilter(filtered: &[u8], stride: usize, bytes_per_pixel: usize) -> Option<Vec<u8>> {
+    let mut pixels = vec![0; filtered.len() / (stride + 1) * stride];
+    let mut previous = vec![0; stride];
+    for (row, line) in pixels
+        .chunks_exact_mut(stride)
+        .zip(filtered.chunks_exact(stride + 1))
+    {
+        let (&filter, line) = line.split_first()?;
+        for x in 0..stride {
+            let a = if x >= bytes_per_pixel {
+                row[x - bytes_per_pixel]
+            } else {
+                0
+            };
+            let b = previous[x];
+            let c = if x >= bytes_per_pixel {
+                previous[x - bytes_per_pixel]
+            } else {
+                0
+            };
+            row[x] = line[x].wrapping_add(predict(filter, a, b, c)?);
+        }
+        previous.copy_from_slice(row);
+    }
+    Some(pixels)
+}
+
+/// The rows of `pixels`, of `stride` bytes, each filtered with the type minimizing the sum of its
+/// bytes as signed values, or with none if they are `indexed` colors.
+fn filter(pixels: &[u8], stride: usize, bytes_per_pixel: usize, indexed: bool) -> Vec<u8> {
+    let mut filtered = Vec::with_capacity(pixels.len() + pixels.len() / stride);
+    let mut candidate = vec![0_u8; stride];
+    let mut best = vec![0_u8; stride];
+    let zeros = vec![0; stride];
+    let mut previous: &[u8] = &zeros;
+    for row in pixels.chunks_exact(stride) {
+        let mut best_type = 0;
+        let mut best_sum = u64::MAX;
+        for filter in if indexed { 0..1 } else { 0..5 } {
+            for x in 0..stride {
+                let a = if x >= bytes_per_pixel {
+                    row[x - bytes_per_pixel]
+                } else {
+                    0
+                };
+                let c = if x >= bytes_per_pixel {
+                    previous[x - bytes_per_pixel]
+                } else {
+                    0
+                };
+                let prediction = predict(filter, a, previous[x], c).unwrap_or_default();
+                candidate[x] = row[x].wrapping_sub(prediction);
+            }
+            let sum = candidate
+                .iter()
+                .map(|&byte| u64::from((byte as i8).unsigned_abs()))
+                .sum();
+            if sum < best_sum {
+                (best_type, best_sum) = (filter, sum);
+                best.copy_from_slice(&candidate);
+            }
+        }
+        filtered.push(best_type);
+        filtered.extend_from_slice(&best);
+        previous = row;
+    }
+    filtered
+}
+
+/// The prediction of a byte by `filter`, from the bytes to its left `a`, above `b` and above left
+/// `c`.
+fn predict(filter: u8, a: u8, b: u8, c: u8) -> Option<u8> {
+    Some(match filter {
+        0 => 0,
+        1 => a,
+        2 => b,
+        3 => ((u16::from(a) + u16::from(b)) / 2) as u8,
+        4 => {
+            let (a16, b16, c16) = (i16::from(a), i16::from(b), i16::from(c));
+            let p = a16 + b16 - c16;
+            let (pa, pb, pc) = ((p - a16).abs(), (p - b16).abs(), (p - c16).abs());
+            if pa <= pb && pa <= pc {
+                a
+            } else if pb <= pc {
+                b
+            } else {
+                c
+            }
+        }
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RGBA16: &[u8] = include_bytes!("../testdata/png/rgba16.png");
+    const INTERLACED: &[u8] = include_bytes!("../testdata/png/interlaced.png");
+
+    /// The header and the pixels of the non-interlaced `data`.
+    fn decoded(data: &[u8]) -> (Header, Vec<u8>) {
+        let chunks = chunks(data).unwrap();
+        let header = Header::parse(chunks[0].1).unwrap();
+        let compressed: Vec<u8> = chunks
+            .iter()
+            .filter(|(kind, _)| kind == b"IDAT")
+            .flat_map(|(_, data)| data.iter().copied())
+            .collect();
+        let mut filtered = Vec::new();
+        ZlibDecoder::new(&compressed[..])
+            .read_to_end(&mut filtered)
+            .unwrap();
+        assert_eq!(filtered.len(), header.filtered_len().unwrap());
+        let stride = header.stride(header.width);
+        let pixels = unfilter(&filtered, stride, stride / header.width as usize).unwrap();
+        (header, pixels)
+    }
+
+    fn kinds(data: &[u8]) -> Vec<[u8; 4]> {
+        chunks(data)
+            .unwrap()
+            .into_iter()
+            .map(|(kind, _)| kind)
+            .collect()
+    }
+
+    #[test]
+    fn reduces_opaque_16_bit_images() {
+        let recompressed = recompress(RGBA16).unwrap();
+        assert!(recompressed.len() < RGBA16.len());
+        assert_eq!(
+            kinds(&recompressed),
+            [*b"IHDR", *b"gAMA", *b"IDAT", *b"IEND"]
+        );
+        let (header, pixels) = decoded(&recompressed);
+        assert_eq!(
+            (header.width, header.height, header.depth, header.color),
+            (19, 11, 8, 2)
+        );
+        let reduced = |sample: u32| ((sample % 65536 * 255 + 32767) / 65535) as u8;
+        let expected: Vec<u8> = (0..11)
+            .flat_map(|y| {
+                (0..19).flat_map(move |x| [x * 3000 + y * 700, y * 5000, x * y * 400].map(reduced))
+            })
+            .collect();
+        assert_eq!(pixels, expected);
+    }
+
+    #[test]
+    fn keeps_the_filtered_data_of_interlaced_images() {
+        let recompressed = recompress(INTERLACED).unwrap();
+        assert!(recompressed.len() < INTERLACED.len());
+        assert_eq!(kinds(&recompressed), [*b"IHDR", *b"IDAT", *b"IEND"]);
+        let header = Header::parse(chunks(&recompressed).unwrap()[0].1).unwrap();
+        assert!(header.interlaced);
+        // the passes of 2, 2, 4, 3, 7, 6 and 13 pixels, on 2, 2, 1, 3, 2, 5 and 4 rows
+        let rows = [(2, 2), (2, 2), (4, 1), (3, 3), (7, 2), (6, 5), (13, 4)];
+        let len = rows
+            .iter()
+            .map(|(width, height)| (1 + width) * height)
+            .sum::<usize>();
+        assert_eq!(header.filtered_len(), Some(len));
+    }
+
+    #[test]
+    fn filters_reversibly() {
+        let (header, pixels) = decoded(RGBA16);
+        let stride = header.stride(header.width);
+        for bytes_per_pixel in [1, 8] {
+            let filtered = filter(&pixels, stride, bytes_per_pixel, false);
+            assert_eq!(filtered.len(), pixels.len() + 11);
+            assert_eq!(
+                unfilter(&filtered, stride, bytes_per_pixel).unwrap(),
+                pixels
+            );
+        }
+        let indexed = filter(&pixels, stride, 8, true);
+        assert!(indexed.chunks(stride + 1).all(|row| row[0] == 0));
+    }
+
+    #[test]
+    fn rejects_malformed_images() {
+        assert!(recompress(&RGBA16[..RGBA16.len() - 1]).is_none());
+        let mut corrupted = RGBA16.to_vec();
+        corrupted[40] ^= 1;
+        assert!(recompress(&corrupted).is_none());
+        // an unknown critical chunk
+        let mut unknown = SIGNATURE.to_vec();
+        write_chunk(&mut unknown, b"IHDR", chunks(RGBA16).unwrap()[0].1);
+        write_chunk(&mut unknown, b"ABCD", b"");
+        write_chunk(&mut unknown, b"IEND", b"");
+        assert!(recompress(&unknown).is_none());
+    }
+}
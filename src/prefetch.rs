@@ -0,0 +1,100 @@
+//! Experimental warming of the cache with the subresources of the HTML pages, so that the browsers
+//! loading them through the proxy find them cached.
+//!
+//! The subresources are requested like the page, with the headers of its client, so that they are
+//! cached under the keys of the requests of that client.
+use std::sync::Arc;
+
+use axum::{
+    body::Body,
+    http::{header, HeaderMap},
+};
+use futures_util::StreamExt;
+use reqwest::Url;
+use tokio::sync::Semaphore;
+
+use crate::{cache::CachedResponse, decompress, html, session::Session, AppState};
+
+/// Number of subresources requested at once, over all the pages.
+const CONCURRENCY: usize = 4;
+
+pub struct Prefetcher {
+    /// Number of subresources requested per page.
+    max_per_page: usize,
+    permits: Arc<Semaphore>,
+}
+
+impl Prefetcher {
+    pub fn new(max_per_page: usize) -> Self {
+        Self {
+            max_per_page,
+            permits: Arc::new(Semaphore::new(CONCURRENCY)),
+        }
+    }
+
+    /// Request in the background the subresources of `page`, fetched for `url` with `headers`, and
+    /// whose cache keys are given by `key`.
+    pub fn spawn(
+        &self,
+        state: AppState,
+        url: Url,
+        page: &CachedResponse,
+        headers: HeaderMap,
+        session: Option<Arc<Session>>,
+        key: impl Fn(&Url) -> String + Send + Sync + 'static,
+    ) {
+        if !html::is_html(&page.headers) || !page.status.is_success() {
+            return;
+        }
+        let encoding = match page.headers.get(header::CONTENT_ENCODING) {
+            Some(value) => match value.to_str().ok().and_then(decompress::Encoding::parse) {
+                Some(encoding) => Some(encoding),
+                None => return,
+            },
+            None => None,
+        };
+        let (body, max_per_page) = (page.body.clone(), self.max_per_page);
+        let permits = self.permits.clone();
+        tokio::spawn(async move {
+            let body = match encoding {
+                Some(encoding) => {
+                    let decoded = encoding.decode(Body::from(body));
+                    match axum::body::to_bytes(decoded, state.max_buffered_body).await {
+                        Ok(decoded) => decoded,
+                        Err(err) => {
+                            tracing::debug!(error = %err, "Could not decode page to prefetch");
+                            return;
+                        }
+                    }
+                }
+                None => body,
+            };
+            let links = html::subresources(&url, &body);
+            futures_util::stream::iter(links.into_iter().take(max_per_page))
+                .for_each_concurrent(None, |link| {
+                    let (state, headers, session) = (&state, &headers, session.clone());
+                    let (key, permits) = (key(&link), permits.clone());
+                    async move {
+                        let Ok(_permit) = permits.acquire().await else {
+                            return;
+                        };
+                        let result = crate::pipeline::proxy(
+                            state, &link, &key, headers, session, false, None,
+                        )
+                        .await;
+                        match result {
+                            Ok((_, status)) => tracing::debug!(
+                                url = %link,
+                                cache_status = status.map(|status| status.as_str()),
+                                "Prefetched subresource"
+                            ),
+                            Err(err) => {
+                                tracing::debug!(url = %link, error = %err, "Could not prefetch");
+                            }
+                        }
+                    }
+                })
+                .await;
+        });
+    }
+}
@@ -0,0 +1,171 @@
+//! Pre-signed URLs of the proxy, standing for the bearer token in the contexts that can't send it,
+//! like the `<img>` tags of browsers or the webhooks of other services.
+//!
+//! A pre-signed URL has the `url` of the target, the Unix time it `expires` at and the `signature`,
+//! the hex HMAC-SHA256 of `{url}\n{expires}` with the `URL_SIGNING_KEY`, as query params. They are
+//! made by the `sign` command, or by any program knowing the key. The requests with pre-signed URLs
+//! belong to no tenant and share their quotas.
+use std::{
+    collections::HashMap,
+    env,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use clap::Args;
+use reqwest::Url;
+
+use crate::sigv4::{hex, hmac};
+
+const EXPIRES_PARAM: &str = "expires";
+const SIGNATURE_PARAM: &str = "signature";
+
+#[derive(Debug, Args)]
+pub struct SignArgs {
+    /// URL to request through the proxy
+    url: Url,
+    /// How long the signed URL is valid for
+    #[arg(long, default_value = "1h", value_parser = humantime::parse_duration)]
+    expires_in: Duration,
+    /// Address of the proxy the signed URL points to
+    #[arg(long, default_value = "http://127.0.0.1:7788/")]
+    proxy: Url,
+}
+
+/// Print the pre-signed URL of the proxy requesting `args.url`, with the `URL_SIGNING_KEY`.
+pub fn run(args: SignArgs) -> Result<()> {
+    let key = env::var("URL_SIGNING_KEY").context("URL_SIGNING_KEY is not set")?;
+    let expires = unix_time(SystemTime::now() + args.expires_in);
+    let url = args.url.as_str();
+    let mut signed = args.proxy.clone();
+    signed
+        .query_pairs_mut()
+        .append_pair("url", url)
+        .append_pair(EXPIRES_PARAM, &expires.to_string())
+        .append_pair(SIGNATURE_PARAM, &signature(key.as_bytes(), url, expires));
+    println!("{signed}");
+    Ok(())
+}
+
+pub struct UrlSigner {
+    key: Vec<u8>,
+}
+
+impl UrlSigner {
+    pub fn new(key: String) -> Self {
+        Self {
+            key: key.into_bytes(),
+        }
+    }
+
+    /// Whether the query `params` of a request are the ones of a pre-signed URL, valid or not.
+    pub fn is_signed(params: &HashMap<String, String>) -> bool {
+        params.contains_key(SIGNATURE_PARAM)
+    }
+
+    /// Whether the query `params` of a request are the ones of a valid pre-signed URL.
+    pub fn verify(&self, params: &HashMap<String, String>) -> bool {
+        let (Some(url), Some(expires), Some(signature)) = (
+            params.get("url"),
+            params.get(EXPIRES_PARAM),
+            params.get(SIGNATURE_PARAM),
+        ) else {
+            return false;
+        };
+        let Ok(expires) = expires.parse::<u64>() else {
+            return false;
+        };
+        let expected = self::signature(&self.key, url, expires);
+        if !constant_time_eq(&expected, signature) {
+            tracing::debug!(url, "Invalid URL signature");
+            return false;
+        }
+        if expires < unix_time(SystemTime::now()) {
+            tracing::debug!(url, expires, "Expired pre-signed URL");
+            return false;
+        }
+        true
+    }
+}
+
+/// Whether the secrets `a` and `b` are equal, compared in constant time so that they can't be
+/// guessed byte by byte. Only their lengths can be told apart.
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn signature(key: &[u8], url: &str, expires: u64) -> String {
+    hex(&hmac(key, format!("{url}\n{expires}").as_bytes()))
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URL: &str = "https://example.com/cat.png";
+
+    fn params(url: &str, expires: u64, signature: &str) -> HashMap<String, String> {
+        HashMap::from([
+            ("url".to_string(), url.to_string()),
+            (EXPIRES_PARAM.to_string(), expires.to_string()),
+            (SIGNATURE_PARAM.to_string(), signature.to_string()),
+        ])
+    }
+
+    #[test]
+    fn signatures() {
+        // As computed by any HMAC-SHA256 implementation, e.g. Python's `hmac`.
+        assert_eq!(
+            signature(b"secret", URL, 4102444800),
+            "ab79ed8c9f788cf2668ba67e3469dfdf00df4aebf3b184e4675c43f24a028702"
+        );
+    }
+
+    #[test]
+    fn verify() {
+        let signer = UrlSigner::new("secret".to_string());
+        let expires = unix_time(SystemTime::now()) + 3600;
+        let valid = signature(b"secret", URL, expires);
+        assert!(signer.verify(&params(URL, expires, &valid)));
+
+        // Expired, even though signed with the key.
+        let expired = unix_time(SystemTime::now()) - 1;
+        let params_expired = params(URL, expired, &signature(b"secret", URL, expired));
+        assert!(UrlSigner::is_signed(&params_expired));
+        assert!(!signer.verify(&params_expired));
+
+        // Tampered with: another URL, a later expiry or another key.
+        assert!(!signer.verify(&params("https://example.com/dog.png", expires, &valid)));
+        assert!(!signer.verify(&params(URL, expires + 3600, &valid)));
+        let other_key = signature(b"guess", URL, expires);
+        assert!(!signer.verify(&params(URL, expires, &other_key)));
+        assert!(!signer.verify(&params(URL, expires, &valid[..63])));
+        assert!(!signer.verify(&params(URL, expires, &valid.to_uppercase())));
+
+        let mut missing = params(URL, expires, &valid);
+        missing.remove(EXPIRES_PARAM);
+        assert!(!signer.verify(&missing));
+        assert!(!UrlSigner::is_signed(&HashMap::from([(
+            "url".to_string(),
+            URL.to_string()
+        )])));
+    }
+
+    #[test]
+    fn constant_time() {
+        assert!(constant_time_eq("asdf", "asdf"));
+        assert!(!constant_time_eq("asdf", "asdg"));
+        assert!(!constant_time_eq("asdf", "asd"));
+        assert!(constant_time_eq("", ""));
+    }
+}
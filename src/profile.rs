@@ -0,0 +1,527 @@
+//! CPU profiles and heap statistics of the running proxy, taken on demand with the admin API so
+//! that performance problems can be diagnosed in production without an instrumented build.
+//!
+//! The CPU profiles sample the stacks of the threads using the CPU, [`FREQUENCY`] times per
+//! CPU-second with `SIGPROF`, and are rendered in the pprof format, or as folded stacks for the
+//! flamegraph tools. One profile is taken at a time, for a bounded duration.
+//!
+//! The unwinders aren't async-signal-safe, so the signal handler walks the frame pointers instead,
+//! which the builds keep (see `.cargo/config.toml`). It only walks the stacks of the threads that
+//! registered their bounds, as the proxy threads do when serving requests, the samples of the other
+//! threads having their interrupted function only.
+use std::{
+    cell::Cell,
+    collections::HashMap,
+    ffi::c_void,
+    fmt::Write as _,
+    ptr,
+    sync::{
+        atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering},
+        Once,
+    },
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{bail, Result};
+use serde::Serialize;
+
+use crate::metrics::{self, METRICS};
+
+/// Samples per CPU-second.
+const FREQUENCY: i64 = 100;
+
+/// Frames of a sampled stack, the outermost ones being dropped.
+const MAX_DEPTH: usize = 64;
+
+/// Samples of a profile at most, beyond which they are dropped.
+const MAX_SAMPLES: usize = 16 * 1024;
+
+/// Longest profile.
+pub const MAX_DURATION: Duration = Duration::from_secs(60);
+
+/// Return addresses of a stack, from the innermost frame.
+#[derive(Clone, Copy)]
+struct Stack {
+    depth: usize,
+    ips: [usize; MAX_DEPTH],
+}
+
+/// Stacks written by the signal handler, null when not profiling.
+static STACKS: AtomicPtr<Stack> = AtomicPtr::new(ptr::null_mut());
+/// Number of stacks of the buffer.
+static CAPACITY: AtomicUsize = AtomicUsize::new(0);
+/// Stacks taken, including the dropped ones.
+static TAKEN: AtomicUsize = AtomicUsize::new(0);
+/// Signal handlers running, which may be writing to the stacks.
+static HANDLERS: AtomicUsize = AtomicUsize::new(0);
+static PROFILING: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    /// Lowest and highest addresses of the stack of the thread, zero if not registered.
+    static STACK_BOUNDS: Cell<(usize, usize)> = const { Cell::new((0, 0)) };
+}
+
+/// Register the bounds of the stack of the current thread, so that its samples have their whole
+/// stack.
+#[cfg(target_os = "linux")]
+pub fn register_thread() {
+    if STACK_BOUNDS.get() != (0, 0) {
+        return;
+    }
+    // SAFETY: the attributes are initialized by `pthread_getattr_np` before being read, and
+    // destroyed once read
+    unsafe {
+        let mut attr = std::mem::zeroed();
+        if libc::pthread_getattr_np(libc::pthread_self(), &mut attr) != 0 {
+            return;
+        }
+        let (mut low, mut size) = (ptr::null_mut(), 0);
+        if libc::pthread_attr_getstack(&attr, &mut low, &mut size) == 0 {
+            STACK_BOUNDS.set((low as usize, low as usize + size));
+        }
+        libc::pthread_attr_destroy(&mut attr);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn register_thread() {}
+
+/// Program counter, frame pointer and stack pointer of the interrupted thread.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+unsafe fn registers(context: *mut c_void) -> Option<(usize, usize, usize)> {
+    let registers = &(*context.cast::<libc::ucontext_t>()).uc_mcontext.gregs;
+    let register = |index: libc::c_int| registers[index as usize] as usize;
+    Some((
+        register(libc::REG_RIP),
+        register(libc::REG_RBP),
+        register(libc::REG_RSP),
+    ))
+}
+
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+unsafe fn registers(context: *mut c_void) -> Option<(usize, usize, usize)> {
+    let context = &(*context.cast::<libc::ucontext_t>()).uc_mcontext;
+    Some((
+        context.pc as usize,
+        context.regs[29] as usize,
+        context.sp as usize,
+    ))
+}
+
+#[cfg(not(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+)))]
+unsafe fn registers(_context: *mut c_void) -> Option<(usize, usize, usize)> {
+    None
+}
+
+/// Walk the frame pointers of the interrupted thread into `stack`, from its interrupted
+/// instruction.
+///
+/// # Safety
+///
+/// `context` must be the context of the signal interrupting the current thread.
+unsafe fn walk(context: *mut c_void, stack: &mut Stack) {
+    let Some((pc, mut fp, sp)) = registers(context) else {
+        stack.depth = 0;
+        return;
+    };
+    stack.ips[0] = pc;
+    let mut depth = 1;
+    let (low, high) = STACK_BOUNDS.get();
+    // each frame holds the frame pointer of its caller, followed by its return address, and the
+    // frames of the functions without frame pointers are skipped or stop the walk
+    while depth < MAX_DEPTH
+        && fp >= sp.max(low)
+        && fp.saturating_add(2 * size_of::<usize>()) <= high
+        && fp % size_of::<usize>() == 0
+    {
+        let frame = fp as *const usize;
+        let (caller, ip) = (ptr::read_volatile(frame), ptr::read_volatile(frame.add(1)));
+        if ip == 0 {
+            break;
+        }
+        stack.ips[depth] = ip;
+        depth += 1;
+        // the stack grows downwards
+        if caller <= fp {
+            break;
+        }
+        fp = caller;
+    }
+    stack.depth = depth;
+}
+
+extern "C" fn on_sigprof(_signal: libc::c_int, _info: *mut libc::siginfo_t, context: *mut c_void) {
+    HANDLERS.fetch_add(1, Ordering::SeqCst);
+    let stacks = STACKS.load(Ordering::SeqCst);
+    let capacity = CAPACITY.load(Ordering::SeqCst);
+    let index = if stacks.is_null() {
+        capacity
+    } else {
+        TAKEN.fetch_add(1, Ordering::Relaxed)
+    };
+    if index < capacity {
+        // SAFETY: the buffer outlives the running handlers, and each index is written once. The
+        // walk only reads the registered stack of the thread, without locking nor allocating
+        unsafe { walk(context, &mut *stacks.add(index)) };
+    }
+    HANDLERS.fetch_sub(1, Ordering::SeqCst);
+}
+
+/// Set the CPU time interval of the `SIGPROF` signals, stopping them if zero.
+fn set_timer(interval: Duration) -> Result<()> {
+    let interval = libc::timeval {
+        tv_sec: interval.as_secs() as libc::time_t,
+        tv_usec: interval.subsec_micros() as libc::suseconds_t,
+    };
+    let timer = libc::itimerval {
+        it_interval: interval,
+        it_value: interval,
+    };
+    // SAFETY: the timer is a valid `itimerval`, and the previous one isn't asked for
+    if unsafe { libc::setitimer(libc::ITIMER_PROF, &timer, ptr::null_mut()) } != 0 {
+        bail!(
+            "could not set the profiling timer: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+    Ok(())
+}
+
+/// Sampling in progress, stopped when dropped.
+struct Sampling {
+    stacks: Vec<Stack>,
+}
+
+impl Sampling {
+    /// Start sampling, into a buffer of `capacity` stacks.
+    fn start(capacity: usize) -> Result<Self> {
+        static HANDLER: Once = Once::new();
+        // the handler stays installed, as signals may still be pending once the timer is stopped
+        HANDLER.call_once(|| {
+            // SAFETY: the handler only touches atomics, its own slot of the buffer and the stack
+            unsafe {
+                let mut action: libc::sigaction = std::mem::zeroed();
+                action.sa_sigaction = on_sigprof as *const () as usize;
+                action.sa_flags = libc::SA_RESTART | libc::SA_SIGINFO;
+                libc::sigemptyset(&mut action.sa_mask);
+                libc::sigaction(libc::SIGPROF, &action, ptr::null_mut());
+            }
+        });
+        let empty = Stack {
+            depth: 0,
+            ips: [0; MAX_DEPTH],
+        };
+        let mut sampling = Self {
+            stacks: vec![empty; capacity],
+        };
+        TAKEN.store(0, Ordering::SeqCst);
+        CAPACITY.store(capacity, Ordering::SeqCst);
+        STACKS.store(sampling.stacks.as_mut_ptr(), Ordering::SeqCst);
+        set_timer(Duration::from_secs(1) / FREQUENCY as u32)?;
+        Ok(sampling)
+    }
+
+    /// The sampled stacks, and the number of dropped ones.
+    fn stop(mut self) -> (Vec<Stack>, usize) {
+        self.disarm();
+        let taken = TAKEN.load(Ordering::SeqCst);
+        let mut stacks = std::mem::take(&mut self.stacks);
+        let capacity = stacks.len();
+        stacks.truncate(taken.min(capacity));
+        (stacks, taken.saturating_sub(capacity))
+    }
+
+    fn disarm(&mut self) {
+        let _ = set_timer(Duration::ZERO);
+        STACKS.store(ptr::null_mut(), Ordering::SeqCst);
+        while HANDLERS.load(Ordering::SeqCst) > 0 {
+            std::hint::spin_loop();
+        }
+    }
+}
+
+impl Drop for Sampling {
+    fn drop(&mut self) {
+        if !self.stacks.is_empty() {
+            self.disarm();
+        }
+        PROFILING.store(false, Ordering::SeqCst);
+    }
+}
+
+/// A function of a sampled stack, with its file and line if known.
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct Line {
+    function: String,
+    file: String,
+    line: i64,
+}
+
+/// CPU profile, with the sampled stacks resolved to functions.
+pub struct Profile {
+    /// Number of samples of each stack of return addresses, from the innermost frame.
+    stacks: HashMap<Vec<usize>, i64>,
+    /// Functions of each address, from the innermost inlined one.
+    lines: HashMap<usize, Vec<Line>>,
+    start: SystemTime,
+    duration: Duration,
+    pub dropped: usize,
+}
+
+impl Profile {
+    /// Sample the stacks for `duration`, failing if a profile is already being taken.
+    pub async fn take(duration: Duration) -> Result<Self> {
+        if PROFILING.swap(true, Ordering::SeqCst) {
+            bail!("a profile is already being taken");
+        }
+        let start = SystemTime::now();
+        // the signals come at the frequency for each thread using the CPU
+        let threads = std::thread::available_parallelism().map_or(1, |threads| threads.get());
+        let samples = duration.as_secs_f64() * FREQUENCY as f64 * threads as f64;
+        let sampling = match Sampling::start((samples.ceil() as usize).clamp(1, MAX_SAMPLES)) {
+            Ok(sampling) => sampling,
+            Err(err) => {
+                PROFILING.store(false, Ordering::SeqCst);
+                return Err(err);
+            }
+        };
+        tokio::time::sleep(duration).await;
+        let (samples, dropped) = sampling.stop();
+        let profile = tokio::task::spawn_blocking(move || {
+            let mut profile = Self {
+                stacks: HashMap::new(),
+                lines: HashMap::new(),
+                start,
+                duration,
+                dropped,
+            };
+            for sample in samples {
+                profile.add(&sample.ips[..sample.depth]);
+            }
+            profile
+        });
+        Ok(profile.await?)
+    }
+
+    /// Count the sample of the stack `ips`.
+    fn add(&mut self, ips: &[usize]) {
+        for (index, ip) in ips.iter().enumerate() {
+            // the return addresses are after the calls, unlike the interrupted instruction
+            self.lines
+                .entry(*ip)
+                .or_insert_with(|| resolve(ip.saturating_sub(usize::from(index > 0))));
+        }
+        if !ips.is_empty() {
+            *self.stacks.entry(ips.to_vec()).or_default() += 1;
+        }
+    }
+
+    /// The stacks in the folded format of the flamegraph tools, one line per stack with its
+    /// functions from the outermost one and its number of samples.
+    pub fn folded(&self) -> String {
+        let mut folded = String::new();
+        for (ips, count) in &self.stacks {
+            let functions: Vec<_> = ips
+                .iter()
+                .rev()
+                .flat_map(|ip| self.lines[ip].iter().rev())
+                .map(|line| line.function.as_str())
+                .collect();
+            let _ = writeln!(folded, "{} {count}", functions.join(";"));
+        }
+        folded
+    }
+
+    /// The profile in the protobuf format of pprof, uncompressed.
+    pub fn pprof(&self) -> Vec<u8> {
+        let mut strings = Strings::default();
+        let period = 1_000_000_000 / FREQUENCY;
+        let (samples, nanoseconds) = (strings.id("samples"), strings.id("nanoseconds"));
+        let (count, cpu) = (strings.id("count"), strings.id("cpu"));
+        let mut profile = Vec::new();
+        for (kind, unit) in [(samples, count), (cpu, nanoseconds)] {
+            message(&mut profile, 1, |value_type| {
+                field(value_type, 1, kind);
+                field(value_type, 2, unit);
+            });
+        }
+        // locations and functions are numbered from 1
+        let mut locations = HashMap::new();
+        let mut functions: HashMap<&Line, u64> = HashMap::new();
+        for (ips, count) in &self.stacks {
+            for ip in ips {
+                let next = locations.len() as u64 + 1;
+                locations.entry(*ip).or_insert(next);
+            }
+            message(&mut profile, 2, |sample| {
+                packed(sample, 1, ips.iter().map(|ip| locations[ip]));
+                packed(sample, 2, [*count as u64, (*count * period) as u64]);
+            });
+        }
+        for (ip, id) in &locations {
+            message(&mut profile, 4, |location| {
+                field(location, 1, *id);
+                field(location, 3, *ip as u64);
+                for line in &self.lines[ip] {
+                    let next = functions.len() as u64 + 1;
+                    let function = *functions.entry(line).or_insert(next);
+                    message(location, 4, |entry| {
+                        field(entry, 1, function);
+                        field(entry, 2, line.line as u64);
+                    });
+                }
+            });
+        }
+        for (line, id) in &functions {
+            let (name, file) = (strings.id(&line.function), strings.id(&line.file));
+            message(&mut profile, 5, |function| {
+                field(function, 1, *id);
+                field(function, 2, name);
+                field(function, 3, name);
+                field(function, 4, file);
+            });
+        }
+        let start = self
+            .start
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        field(&mut profile, 9, start.as_nanos() as u64);
+        field(&mut profile, 10, self.duration.as_nanos() as u64);
+        message(&mut profile, 11, |value_type| {
+            field(value_type, 1, cpu);
+            field(value_type, 2, nanoseconds);
+        });
+        field(&mut profile, 12, period as u64);
+        // the string table comes last, once all the strings are known
+        for string in &strings.table {
+            bytes(&mut profile, 6, string.as_bytes());
+        }
+        profile
+    }
+}
+
+/// The functions of the instruction at `ip`, from the innermost inlined one.
+fn resolve(ip: usize) -> Vec<Line> {
+    let mut lines = Vec::new();
+    backtrace::resolve(ip as *mut c_void, |symbol| {
+        lines.push(Line {
+            function: symbol
+                .name()
+                .map_or_else(|| format!("{ip:#x}"), |name| format!("{name:#}")),
+            file: symbol
+                .filename()
+                .map(|file| file.display().to_string())
+                .unwrap_or_default(),
+            line: symbol.lineno().map_or(0, i64::from),
+        });
+    });
+    if lines.is_empty() {
+        lines.push(Line {
+            function: format!("{ip:#x}"),
+            file: String::new(),
+            line: 0,
+        });
+    }
+    lines
+}
+
+/// String table of a pprof profile, whose first string is empty.
+struct Strings {
+    table: Vec<String>,
+    ids: HashMap<String, u64>,
+}
+
+impl Default for Strings {
+    fn default() -> Self {
+        Self {
+            table: vec![String::new()],
+            ids: HashMap::from([(String::new(), 0)]),
+        }
+    }
+}
+
+impl Strings {
+    fn id(&mut self, string: &str) -> u64 {
+        if let Some(id) = self.ids.get(string) {
+            return *id;
+        }
+        let id = self.table.len() as u64;
+        self.table.push(string.to_string());
+        self.ids.insert(string.to_string(), id);
+        id
+    }
+}
+
+fn varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// Integer field `number`.
+fn field(out: &mut Vec<u8>, number: u64, value: u64) {
+    varint(out, number << 3);
+    varint(out, value);
+}
+
+/// Length-delimited field `number`.
+fn bytes(out: &mut Vec<u8>, number: u64, value: &[u8]) {
+    varint(out, number << 3 | 2);
+    varint(out, value.len() as u64);
+    out.extend_from_slice(value);
+}
+
+/// Embedded message field `number`, written by `write`.
+fn message(out: &mut Vec<u8>, number: u64, write: impl FnOnce(&mut Vec<u8>)) {
+    let mut embedded = Vec::new();
+    write(&mut embedded);
+    bytes(out, number, &embedded);
+}
+
+/// Packed repeated integer field `number`.
+fn packed(out: &mut Vec<u8>, number: u64, values: impl IntoIterator<Item = u64>) {
+    let mut packed = Vec::new();
+    for value in values {
+        varint(&mut packed, value);
+    }
+    bytes(out, number, &packed);
+}
+
+/// Memory of the process.
+#[derive(Serialize)]
+pub struct Heap {
+    resident_bytes: Option<u64>,
+    /// Heap bytes in use and free, as reported by the allocator.
+    allocated_bytes: Option<u64>,
+    free_bytes: Option<u64>,
+    /// Bytes held by the response bodies outside of the cache.
+    buffered_bytes: u64,
+}
+
+pub fn heap() -> Heap {
+    let (allocated_bytes, free_bytes) = allocator_stats().unzip();
+    Heap {
+        resident_bytes: metrics::resident_memory(),
+        allocated_bytes,
+        free_bytes,
+        buffered_bytes: METRICS.buffered_bytes.load(Ordering::Relaxed),
+    }
+}
+
+/// Bytes allocated and free in the heap of glibc, including its `mmap` allocations.
+#[cfg(target_env = "gnu")]
+fn allocator_stats() -> Option<(u64, u64)> {
+    // SAFETY: `mallinfo2` only reads the statistics of the allocator
+    let info = unsafe { libc::mallinfo2() };
+    Some(((info.uordblks + info.hblkhd) as u64, info.fordblks as u64))
+}
+
+#[cfg(not(target_env = "gnu"))]
+fn allocator_stats() -> Option<(u64, u64)> {
+    None
+}
@@ -0,0 +1,418 @@
+//! Per-credential usage tracking and quota enforcement, persisted across restarts.
+//!
+//! The usage can also be shared by the instances of a fleet through Redis, in which case the
+//! quotas apply to the requests of a credential to all of them. A credential may then exceed its
+//! request quotas by the number of its requests handled concurrently.
+use std::{
+    collections::HashMap,
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use axum::{
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use chrono::{Datelike, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    errors,
+    rate_limit::too_many_requests,
+    redis::{Redis, Reply},
+};
+
+/// How long the shared counters outlive their period, so that they are eventually deleted.
+const DAILY_KEY_TTL: Duration = Duration::from_secs(2 * 24 * 60 * 60);
+const MONTHLY_KEY_TTL: Duration = Duration::from_secs(32 * 24 * 60 * 60);
+
+/// Quotas applying to each credential, unlimited when unset.
+#[derive(Clone, Copy, Default)]
+pub struct Limits {
+    pub daily_requests: Option<u64>,
+    pub daily_bytes: Option<u64>,
+    pub monthly_requests: Option<u64>,
+    pub monthly_bytes: Option<u64>,
+}
+
+impl Limits {
+    /// Whether a credential with the `daily` and `monthly` usage as of `today` exhausted a quota.
+    fn check(&self, daily: Usage, monthly: Usage, today: NaiveDate) -> Result<(), Exceeded> {
+        let exceeded = |used: u64, limit: Option<u64>| limit.is_some_and(|limit| used >= limit);
+        if exceeded(daily.requests, self.daily_requests) {
+            return Err(Exceeded::Requests(
+                "daily-requests",
+                until(today.succ_opt()),
+            ));
+        }
+        if exceeded(monthly.requests, self.monthly_requests) {
+            let next_month = today
+                .with_day(1)
+                .and_then(|day| day.checked_add_months(chrono::Months::new(1)));
+            return Err(Exceeded::Requests("monthly-requests", until(next_month)));
+        }
+        if exceeded(daily.bytes, self.daily_bytes) || exceeded(monthly.bytes, self.monthly_bytes) {
+            return Err(Exceeded::Bytes);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Default, Deserialize, Serialize)]
+pub struct Usage {
+    pub requests: u64,
+    pub bytes: u64,
+}
+
+/// Usage of a credential over the current day and month, in UTC.
+#[derive(Default, Deserialize, Serialize)]
+struct Periods {
+    day: String,
+    daily: Usage,
+    month: String,
+    monthly: Usage,
+}
+
+impl Periods {
+    /// Reset the counters of the periods that are over.
+    fn roll(&mut self, today: NaiveDate) {
+        let (day, month) = (
+            today.format("%Y-%m-%d").to_string(),
+            today.format("%Y-%m").to_string(),
+        );
+        if self.day != day {
+            self.day = day;
+            self.daily = Usage::default();
+        }
+        if self.month != month {
+            self.month = month;
+            self.monthly = Usage::default();
+        }
+    }
+}
+
+/// An exhausted quota.
+pub enum Exceeded {
+    /// Request quota, with its name and the time until it resets.
+    Requests(&'static str, Duration),
+    Bytes,
+}
+
+impl IntoResponse for Exceeded {
+    /// Exhausted request quotas are reported with `429 Too Many Requests`, and exhausted byte
+    /// quotas with `402 Payment Required`.
+    fn into_response(self) -> Response {
+        match self {
+            Self::Requests(rule, reset) => too_many_requests(rule, reset),
+            Self::Bytes => errors::response(
+                StatusCode::PAYMENT_REQUIRED,
+                "quota_exceeded",
+                "Transfer quota exceeded",
+            ),
+        }
+    }
+}
+
+pub struct Quotas {
+    limits: Limits,
+    /// Usage per credential id, see [`credential_id`].
+    usage: Mutex<HashMap<String, Periods>>,
+    path: PathBuf,
+    /// Usage shared with the other instances, with the prefix of its keys.
+    shared: Option<(Arc<Redis>, String)>,
+}
+
+impl Quotas {
+    /// Load the usage persisted at `path`, if any.
+    pub fn load(limits: Limits, path: PathBuf) -> Result<Self> {
+        let usage = match fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .with_context(|| format!("invalid usage file {}", path.display()))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(err) => {
+                return Err(err).with_context(|| format!("could not read {}", path.display()))
+            }
+        };
+        Ok(Self {
+            limits,
+            usage: Mutex::new(usage),
+            path,
+            shared: None,
+        })
+    }
+
+    /// Share the usage through `redis`, under keys starting with `scope`.
+    pub fn shared(self, redis: Arc<Redis>, scope: &str) -> Self {
+        Self {
+            shared: Some((redis, scope.to_string())),
+            ..self
+        }
+    }
+
+    /// Account for a new request, or reject it if the credential exhausted one of its quotas.
+    ///
+    /// The shared usage is checked if any, or the one of this instance while Redis is unreachable.
+    pub async fn check(&self, credential: &str) -> Result<(), Exceeded> {
+        let Some((redis, scope)) = &self.shared else {
+            return self.check_local(credential, true);
+        };
+        match self.check_shared(redis, scope, credential).await {
+            Ok(Ok(())) => {
+                // keep the usage of this instance, for when Redis is unreachable
+                let _ = self.check_local(credential, false);
+                Ok(())
+            }
+            Ok(Err(exceeded)) => Err(exceeded),
+            Err(err) => {
+                tracing::warn!(error = %err, "Could not check the shared quotas");
+                self.check_local(credential, true)
+            }
+        }
+    }
+
+    /// Account for a new request in the usage of this instance, checking the quotas if `enforce`.
+    fn check_local(&self, credential: &str, enforce: bool) -> Result<(), Exceeded> {
+        let today = Utc::now().date_naive();
+        let mut usage = self.usage.lock().unwrap();
+        let periods = usage.entry(credential_id(credential)).or_default();
+        periods.roll(today);
+        if enforce {
+            self.limits.check(periods.daily, periods.monthly, today)?;
+        }
+        periods.daily.requests += 1;
+        periods.monthly.requests += 1;
+        Ok(())
+    }
+
+    async fn check_shared(
+        &self,
+        redis: &Redis,
+        scope: &str,
+        credential: &str,
+    ) -> Result<Result<(), Exceeded>> {
+        let today = Utc::now().date_naive();
+        let id = credential_id(credential);
+        let [daily_requests, daily_bytes, monthly_requests, monthly_bytes] =
+            shared_keys(redis, scope, &id, today);
+        let replies = redis
+            .pipeline(&[vec![
+                "MGET".to_string(),
+                daily_requests.clone(),
+                daily_bytes,
+                monthly_requests.clone(),
+                monthly_bytes,
+            ]])
+            .await?;
+        let Some(Reply::Array(counts)) = replies.first() else {
+            anyhow::bail!("unexpected Redis reply {replies:?}");
+        };
+        let count = |index: usize| -> Result<u64> {
+            let count = counts
+                .get(index)
+                .context("missing Redis reply")?
+                .integer()?;
+            Ok(u64::try_from(count).unwrap_or_default())
+        };
+        let daily = Usage {
+            requests: count(0)?,
+            bytes: count(1)?,
+        };
+        let monthly = Usage {
+            requests: count(2)?,
+            bytes: count(3)?,
+        };
+        if let Err(exceeded) = self.limits.check(daily, monthly, today) {
+            return Ok(Err(exceeded));
+        }
+        let increment = |key: String, ttl: Duration| {
+            [
+                vec!["INCR".to_string(), key.clone()],
+                vec!["EXPIRE".to_string(), key, ttl.as_secs().to_string()],
+            ]
+        };
+        let commands: Vec<_> = increment(daily_requests, DAILY_KEY_TTL)
+            .into_iter()
+            .chain(increment(monthly_requests, MONTHLY_KEY_TTL))
+            .collect();
+        redis.pipeline(&commands).await?;
+        Ok(Ok(()))
+    }
+
+    /// Account for the bytes transferred on behalf of a credential.
+    pub fn record_bytes(&self, credential: &str, bytes: u64) {
+        let today = Utc::now().date_naive();
+        let id = credential_id(credential);
+        if let Some((redis, scope)) = &self.shared {
+            let [_, daily_bytes, _, monthly_bytes] = shared_keys(redis, scope, &id, today);
+            let redis = redis.clone();
+            tokio::spawn(async move {
+                let increment = |key: String, ttl: Duration| {
+                    [
+                        vec!["INCRBY".to_string(), key.clone(), bytes.to_string()],
+                        vec!["EXPIRE".to_string(), key, ttl.as_secs().to_string()],
+                    ]
+                };
+                let commands: Vec<_> = increment(daily_bytes, DAILY_KEY_TTL)
+                    .into_iter()
+                    .chain(increment(monthly_bytes, MONTHLY_KEY_TTL))
+                    .collect();
+                if let Err(err) = redis.pipeline(&commands).await {
+                    tracing::warn!(error = %err, "Could not record the shared usage");
+                }
+            });
+        }
+        let mut usage = self.usage.lock().unwrap();
+        let periods = usage.entry(id).or_default();
+        periods.roll(today);
+        periods.daily.bytes += bytes;
+        periods.monthly.bytes += bytes;
+    }
+
+    /// Persist the usage, replacing the file atomically.
+    pub fn save(&self) -> Result<()> {
+        let contents = serde_json::to_string(&*self.usage.lock().unwrap())?;
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, contents)?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+/// Keys of the shared daily requests, daily bytes, monthly requests and monthly bytes of the
+/// credential `id`.
+fn shared_keys(redis: &Redis, scope: &str, id: &str, today: NaiveDate) -> [String; 4] {
+    let (day, month) = (
+        today.format("%Y-%m-%d").to_string(),
+        today.format("%Y-%m").to_string(),
+    );
+    [
+        redis.key(&format!("quota:{scope}{id}:{day}:requests")),
+        redis.key(&format!("quota:{scope}{id}:{day}:bytes")),
+        redis.key(&format!("quota:{scope}{id}:{month}:requests")),
+        redis.key(&format!("quota:{scope}{id}:{month}:bytes")),
+    ]
+}
+
+/// Time until the start of `day`, in UTC.
+fn until(day: Option<NaiveDate>) -> Duration {
+    day.and_then(|day| day.and_hms_opt(0, 0, 0))
+        .map(|start| start.and_utc() - Utc::now())
+        .and_then(|delta| delta.to_std().ok())
+        .unwrap_or_default()
+}
+
+/// Credential of a request, if it has a bearer token.
+pub fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+}
+
+/// Stable identifier of a credential, so that credentials are never written to disk.
+pub fn credential_id(credential: &str) -> String {
+    let digest = Sha256::digest(credential.as_bytes());
+    digest[..8]
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(year, month, day).unwrap()
+    }
+
+    fn used(periods: &Periods) -> (u64, u64) {
+        (periods.daily.requests, periods.monthly.requests)
+    }
+
+    fn periods(today: NaiveDate) -> Periods {
+        let mut periods = Periods::default();
+        periods.roll(today);
+        let usage = Usage {
+            requests: 3,
+            bytes: 100,
+        };
+        periods.daily = usage;
+        periods.monthly = usage;
+        periods
+    }
+
+    #[test]
+    fn same_day() {
+        let mut periods = periods(date(2024, 3, 14));
+        periods.roll(date(2024, 3, 14));
+        assert_eq!(used(&periods), (3, 3));
+        assert_eq!(periods.daily.bytes, 100);
+    }
+
+    #[test]
+    fn next_day() {
+        let mut periods = periods(date(2024, 3, 14));
+        periods.roll(date(2024, 3, 15));
+        assert_eq!(used(&periods), (0, 3));
+        assert_eq!((periods.daily.bytes, periods.monthly.bytes), (0, 100));
+        assert_eq!(periods.day, "2024-03-15");
+    }
+
+    #[test]
+    fn next_month() {
+        let mut march = periods(date(2024, 2, 29));
+        march.roll(date(2024, 3, 1));
+        assert_eq!(used(&march), (0, 0));
+        assert_eq!(march.month, "2024-03");
+
+        // the same day of another month is another day
+        let mut april = periods(date(2024, 3, 14));
+        april.roll(date(2024, 4, 14));
+        assert_eq!(used(&april), (0, 0));
+    }
+
+    #[test]
+    fn next_year() {
+        let mut periods = periods(date(2024, 12, 31));
+        periods.roll(date(2025, 1, 1));
+        assert_eq!(used(&periods), (0, 0));
+        assert_eq!(
+            (periods.day.as_str(), periods.month.as_str()),
+            ("2025-01-01", "2025-01")
+        );
+    }
+
+    #[test]
+    fn limits() {
+        let limits = Limits {
+            daily_requests: Some(3),
+            monthly_requests: Some(5),
+            daily_bytes: Some(100),
+            ..Limits::default()
+        };
+        let usage = |requests, bytes| Usage { requests, bytes };
+        let today = Utc::now().date_naive();
+        assert!(limits.check(usage(2, 99), usage(4, 99), today).is_ok());
+        assert!(matches!(
+            limits.check(usage(3, 0), usage(3, 0), today),
+            Err(Exceeded::Requests("daily-requests", _))
+        ));
+        assert!(matches!(
+            limits.check(usage(0, 0), usage(5, 0), today),
+            Err(Exceeded::Requests("monthly-requests", _))
+        ));
+        assert!(matches!(
+            limits.check(usage(0, 100), usage(0, 100), today),
+            Err(Exceeded::Bytes)
+        ));
+        assert!(Limits::default()
+            .check(usage(u64::MAX, u64::MAX), usage(u64::MAX, u64::MAX), today)
+            .is_ok());
+    }
+}
@@ -0,0 +1,397 @@
+//! Request rate limiting.
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::{ensure, Result};
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use rand::Rng;
+use serde::Deserialize;
+
+use crate::{
+    anonymize,
+    config::HostPattern,
+    errors,
+    metrics::METRICS,
+    redis::{Redis, Reply},
+};
+
+/// Response header naming the proxy limit that rejected a request.
+const RULE_HEADER: &str = "x-proxy-rate-limit";
+
+/// Token bucket refilling at `rate` tokens per second, holding at most `burst` tokens.
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(burst: f64) -> Self {
+        Self {
+            tokens: burst,
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, rate: f64, burst: f64) {
+        let now = Instant::now();
+        let elapsed = (now - self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.updated = now;
+    }
+
+    /// Take a token, or return how long to wait until one is available.
+    fn try_take(&mut self, rate: f64, burst: f64) -> Result<(), Duration> {
+        self.refill(rate, burst);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / rate))
+        }
+    }
+
+    /// Take `amount` tokens even if they are not available yet, returning how long to wait before
+    /// using them.
+    ///
+    /// Going into debt queues the callers: each one waits for the tokens reserved before it.
+    fn reserve(&mut self, amount: f64, rate: f64, burst: f64) -> Duration {
+        self.refill(rate, burst);
+        self.tokens -= amount;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / rate)
+        }
+    }
+}
+
+/// A set of token buckets sharing the same rate, one per key.
+pub struct KeyedLimiter<K> {
+    buckets: Mutex<HashMap<K, TokenBucket>>,
+    rate: f64,
+    burst: f64,
+}
+
+impl<K: Eq + Hash> KeyedLimiter<K> {
+    /// Past this number of buckets, the ones that are full again are dropped.
+    const PRUNE_THRESHOLD: usize = 10_000;
+
+    pub fn new(rate: f64, burst: u32) -> Self {
+        Self {
+            buckets: Mutex::new(HashMap::new()),
+            rate,
+            burst: f64::from(burst.max(1)),
+        }
+    }
+
+    /// Take a token for `key`, or return how long to wait until one is available.
+    pub fn check(&self, key: K) -> Result<(), Duration> {
+        self.with_bucket(key, |bucket, rate, burst| bucket.try_take(rate, burst))
+    }
+
+    /// Take `amount` tokens for `key`, returning how long to wait before using them.
+    pub fn reserve(&self, key: K, amount: f64) -> Duration {
+        self.with_bucket(key, |bucket, rate, burst| {
+            bucket.reserve(amount, rate, burst)
+        })
+    }
+
+    fn with_bucket<T>(&self, key: K, f: impl FnOnce(&mut TokenBucket, f64, f64) -> T) -> T {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= Self::PRUNE_THRESHOLD {
+            let (rate, burst) = (self.rate, self.burst);
+            buckets.retain(|_, bucket| {
+                bucket.refill(rate, burst);
+                bucket.tokens < burst
+            });
+        }
+        let bucket = buckets
+            .entry(key)
+            .or_insert_with(|| TokenBucket::new(self.burst));
+        f(bucket, self.rate, self.burst)
+    }
+}
+
+/// Request rate limit of each client IP, shared by the instances of a fleet through Redis if set.
+///
+/// The shared limit counts the requests in fixed windows of `burst / rate` seconds, allowing
+/// `burst` requests in each, for the same average rate as the token buckets.
+pub struct ClientLimiter {
+    /// Limiter of this instance, used while Redis is unreachable.
+    local: KeyedLimiter<IpAddr>,
+    redis: Option<Arc<Redis>>,
+    window: Duration,
+    burst: u32,
+}
+
+impl ClientLimiter {
+    pub fn new(rate: f64, burst: u32, redis: Option<Arc<Redis>>) -> Self {
+        let burst = burst.max(1);
+        Self {
+            local: KeyedLimiter::new(rate, burst),
+            redis,
+            window: Duration::from_secs_f64(f64::from(burst) / rate),
+            burst,
+        }
+    }
+
+    /// Count a request of `ip`, or return how long to wait until it is allowed.
+    async fn check(&self, ip: IpAddr) -> Result<(), Duration> {
+        let Some(redis) = &self.redis else {
+            return self.local.check(ip);
+        };
+        match self.check_shared(redis, ip).await {
+            Ok(allowed) => allowed,
+            Err(err) => {
+                tracing::warn!(error = %err, "Could not check the shared rate limit");
+                self.local.check(ip)
+            }
+        }
+    }
+
+    async fn check_shared(&self, redis: &Redis, ip: IpAddr) -> Result<Result<(), Duration>> {
+        let key = redis.key(&format!("rate-limit:{ip}"));
+        // in a transaction, so that the window of the counter is always set, even if it expires
+        // right before being incremented
+        let replies = redis
+            .pipeline(&[
+                vec!["MULTI".to_string()],
+                vec!["INCR".to_string(), key.clone()],
+                vec![
+                    "PEXPIRE".to_string(),
+                    key.clone(),
+                    self.window.as_millis().max(1).to_string(),
+                    "NX".to_string(),
+                ],
+                vec!["PTTL".to_string(), key],
+                vec!["EXEC".to_string()],
+            ])
+            .await?;
+        let Some(Reply::Array(results)) = replies.last() else {
+            anyhow::bail!("unexpected Redis reply {replies:?}");
+        };
+        let [count, _, reset] = results.as_slice() else {
+            anyhow::bail!("unexpected Redis reply {results:?}");
+        };
+        if count.integer()? <= i64::from(self.burst) {
+            return Ok(Ok(()));
+        }
+        let reset = u64::try_from(reset.integer()?).unwrap_or_default();
+        Ok(Err(Duration::from_millis(reset)))
+    }
+}
+
+/// Pacing of the requests sent to the matching hosts, shared by all clients.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HostLimit {
+    pub host: HostPattern,
+    /// Sustained requests per second.
+    pub rate: f64,
+    #[serde(default = "HostLimit::default_burst")]
+    pub burst: u32,
+    /// Upper bound of the random delay added to delayed requests, unless the pacing of the host
+    /// adds its own.
+    #[serde(default, with = "humantime_serde")]
+    pub jitter: Duration,
+}
+
+impl HostLimit {
+    fn default_burst() -> u32 {
+        1
+    }
+}
+
+/// Delays requests to configured hosts so that they don't exceed their rate.
+pub struct HostLimiter {
+    limits: Vec<(HostLimit, Mutex<TokenBucket>)>,
+}
+
+impl HostLimiter {
+    pub fn new(limits: Vec<HostLimit>) -> Result<Self> {
+        let limits = limits
+            .into_iter()
+            .map(|limit| {
+                ensure!(limit.rate > 0.0, "The rate of host limits must be positive");
+                let bucket = TokenBucket::new(f64::from(limit.burst.max(1)));
+                Ok((limit, Mutex::new(bucket)))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { limits })
+    }
+
+    /// Wait until a request to `host` is allowed by the first matching limit, if any, with its
+    /// jitter if `jitter` is set.
+    pub async fn wait(&self, host: &str, jitter: bool) {
+        let Some((limit, bucket)) = self
+            .limits
+            .iter()
+            .find(|(limit, _)| limit.host.matches(host))
+        else {
+            return;
+        };
+        let delay = bucket
+            .lock()
+            .unwrap()
+            .reserve(1.0, limit.rate, f64::from(limit.burst.max(1)));
+        if delay.is_zero() {
+            return;
+        }
+        let jitter = if jitter {
+            limit.jitter.mul_f64(rand::thread_rng().gen::<f64>())
+        } else {
+            Duration::ZERO
+        };
+        tracing::debug!(host, delay = ?(delay + jitter), "Delaying request to rate limited host");
+        tokio::time::sleep(delay + jitter).await;
+    }
+}
+
+/// Upper bound of the time a host can be paused for when honoring its `Retry-After` header.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(300);
+
+/// Pacing state of a host that asked us to slow down.
+struct Backoff {
+    rate: f64,
+    bucket: TokenBucket,
+    paused_until: Option<Instant>,
+}
+
+/// Slows down requests to hosts answering `429 Too Many Requests` or `503 Service Unavailable`.
+///
+/// The allowed rate is halved on each such response and increases additively on success, until it
+/// gets high enough for the host to go back to normal.
+pub struct AdaptiveLimiter {
+    hosts: Mutex<HashMap<String, Backoff>>,
+    /// Rate applied to a host after its first throttling response, in requests per second.
+    initial_rate: f64,
+    honor_retry_after: bool,
+}
+
+impl AdaptiveLimiter {
+    /// Rate increase on each successful response, relative to the initial rate.
+    const INCREASE: f64 = 0.1;
+    /// Once the rate reaches this multiple of the initial rate, the host goes back to normal.
+    const RECOVERED: f64 = 10.0;
+    /// Lowest rate a host can be slowed down to, in requests per second.
+    const MIN_RATE: f64 = 0.01;
+
+    pub fn new(initial_rate: f64, honor_retry_after: bool) -> Result<Self> {
+        ensure!(
+            initial_rate > 0.0,
+            "The adaptive backoff rate must be positive"
+        );
+        Ok(Self {
+            hosts: Mutex::new(HashMap::new()),
+            initial_rate,
+            honor_retry_after,
+        })
+    }
+
+    /// Wait until a request to `host` is allowed, if it asked us to slow down.
+    pub async fn wait(&self, host: &str) {
+        let delay = {
+            let mut hosts = self.hosts.lock().unwrap();
+            let Some(backoff) = hosts.get_mut(host) else {
+                return;
+            };
+            let paused = backoff
+                .paused_until
+                .map(|until| until.saturating_duration_since(Instant::now()))
+                .unwrap_or_default();
+            paused.max(backoff.bucket.reserve(1.0, backoff.rate, 1.0))
+        };
+        if !delay.is_zero() {
+            tracing::debug!(host, ?delay, "Backing off from throttling host");
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Adjust the rate of `host` according to the status of its response.
+    pub fn record(&self, host: &str, status: StatusCode, headers: &HeaderMap) {
+        let mut hosts = self.hosts.lock().unwrap();
+        if status == StatusCode::TOO_MANY_REQUESTS || status == StatusCode::SERVICE_UNAVAILABLE {
+            let backoff = hosts.entry(host.to_string()).or_insert_with(|| Backoff {
+                // halved right below
+                rate: self.initial_rate * 2.0,
+                bucket: TokenBucket::new(1.0),
+                paused_until: None,
+            });
+            backoff.rate = (backoff.rate / 2.0).max(Self::MIN_RATE);
+            if let Some(retry_after) = headers
+                .get(header::RETRY_AFTER)
+                .filter(|_| self.honor_retry_after)
+                .and_then(parse_retry_after)
+            {
+                backoff.paused_until = Some(Instant::now() + retry_after.min(MAX_RETRY_AFTER));
+            }
+            tracing::warn!(
+                host,
+                rate = backoff.rate,
+                "Host is throttling us, slowing down"
+            );
+        } else if let Some(backoff) = hosts.get_mut(host) {
+            backoff.rate += self.initial_rate * Self::INCREASE;
+            if backoff.rate >= self.initial_rate * Self::RECOVERED {
+                tracing::info!(host, "Host recovered from throttling");
+                hosts.remove(host);
+            }
+        }
+    }
+}
+
+/// Parse a `Retry-After` header, in either its delay in seconds or HTTP date form.
+pub fn parse_retry_after(value: &HeaderValue) -> Option<Duration> {
+    let value = value.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(
+        date.duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+/// Middleware rejecting clients that exceed their request rate with `429 Too Many Requests`.
+pub async fn limit_by_ip(
+    State(limiter): State<Arc<ClientLimiter>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if let Err(wait) = limiter.check(addr.ip()).await {
+        tracing::warn!(peer = anonymize::peer(addr), "Client rate limit exceeded");
+        return too_many_requests("client-ip", wait);
+    }
+    next.run(request).await
+}
+
+/// `429 Too Many Requests` response telling the client which limit it hit and when to retry.
+pub fn too_many_requests(rule: &'static str, wait: Duration) -> Response {
+    METRICS.record_rate_limited(rule);
+    // round up, so that retrying at the announced time succeeds
+    let retry_after = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+    (
+        [
+            (header::RETRY_AFTER, retry_after.max(1).to_string()),
+            (HeaderName::from_static(RULE_HEADER), rule.to_string()),
+        ],
+        errors::response(
+            StatusCode::TOO_MANY_REQUESTS,
+            "rate_limited",
+            "Too many requests",
+        ),
+    )
+        .into_response()
+}
@@ -0,0 +1,127 @@
+//! Redaction of sensitive headers from the log and trace output, of the query strings from the
+//! access log, and of the secrets of the settings from their debug output.
+use std::{borrow::Cow, fmt, ops::Deref, str::FromStr, sync::OnceLock};
+
+use anyhow::{Context, Result};
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue};
+use serde::Deserialize;
+
+/// Headers carrying credentials, which are always redacted.
+const SENSITIVE_HEADERS: [HeaderName; 4] = [
+    header::AUTHORIZATION,
+    header::PROXY_AUTHORIZATION,
+    header::COOKIE,
+    header::SET_COOKIE,
+];
+
+/// Value replacing the redacted ones.
+const REDACTED: &str = "[redacted]";
+
+/// Additional headers from the config file.
+static EXTRA_HEADERS: OnceLock<Vec<HeaderName>> = OnceLock::new();
+
+/// Also redact the headers named `extra`, from now on.
+pub fn init(extra: &[String]) -> Result<()> {
+    let extra = extra
+        .iter()
+        .map(|name| {
+            HeaderName::try_from(name.as_str())
+                .with_context(|| format!("invalid header name to redact: {name}"))
+        })
+        .collect::<Result<_>>()?;
+    let _ = EXTRA_HEADERS.set(extra);
+    Ok(())
+}
+
+pub fn is_sensitive(name: &HeaderName) -> bool {
+    SENSITIVE_HEADERS.contains(name)
+        || EXTRA_HEADERS
+            .get()
+            .is_some_and(|extra| extra.contains(name))
+}
+
+/// Copy of `headers` with the values of the sensitive ones replaced, for logging.
+pub fn headers(headers: &HeaderMap) -> HeaderMap {
+    let mut redacted = headers.clone();
+    for (name, value) in redacted.iter_mut() {
+        if is_sensitive(name) {
+            *value = HeaderValue::from_static(REDACTED);
+        }
+    }
+    redacted
+}
+
+/// The value to log for the header `name`.
+pub fn value<'a>(name: &HeaderName, value: &'a HeaderValue) -> Cow<'a, str> {
+    if is_sensitive(name) {
+        REDACTED.into()
+    } else {
+        String::from_utf8_lossy(value.as_bytes())
+    }
+}
+
+/// `uri` with the values of its query parameters replaced, as they may hold credentials such as
+/// the signatures of the pre-signed URLs, for logging.
+pub fn query(uri: &str) -> Cow<'_, str> {
+    let Some((path, query)) = uri.split_once('?') else {
+        return uri.into();
+    };
+    // the fragment isn't sent to the servers anyway
+    let query = query.split_once('#').map_or(query, |(query, _)| query);
+    let params = query
+        .split('&')
+        .map(|param| match param.split_once('=') {
+            Some((name, _)) => format!("{name}={REDACTED}"),
+            None => param.to_string(),
+        })
+        .collect::<Vec<_>>();
+    format!("{path}?{}", params.join("&")).into()
+}
+
+/// A setting whose debug output is redacted, like the one of the settings served by the admin API.
+#[derive(Clone, Default, Deserialize)]
+#[serde(transparent)]
+pub struct Secret<T>(pub T);
+
+impl<T> Deref for Secret<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+impl<T: FromStr> FromStr for Secret<T> {
+    type Err = T::Err;
+
+    fn from_str(value: &str) -> Result<Self, T::Err> {
+        value.parse().map(Self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query_values() {
+        assert_eq!(
+            query("https://example.com/path"),
+            "https://example.com/path"
+        );
+        assert_eq!(
+            query("/?url=https%3A%2F%2Fexample.com&expires=1700000000&signature=abc#top"),
+            "/?url=[redacted]&expires=[redacted]&signature=[redacted]"
+        );
+        assert_eq!(
+            query("https://example.com/?token=secret&flag"),
+            "https://example.com/?token=[redacted]&flag"
+        );
+    }
+}
@@ -0,0 +1,117 @@
+//! Following of the redirects of the origins by the HTTP client, the last response being relayed to
+//! the client. The redirects that aren't followed are relayed as is.
+//!
+//! The proxy only sends `GET` requests, which no redirect changes the method of. The HTTP client
+//! always removes `Authorization`, the cookies and `Proxy-Authorization` from the requests
+//! redirected to other origins, and the redirect policy can't see the headers of the requests, so
+//! whether the current one has sensitive headers is passed in a task-local.
+use std::future::Future;
+
+use anyhow::{Context, Result};
+use axum::http::{HeaderMap, HeaderName};
+use reqwest::{redirect, Url};
+use serde::Deserialize;
+
+tokio::task_local! {
+    /// Whether the upstream request being sent has sensitive headers.
+    static SENSITIVE: bool;
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RedirectConfig {
+    /// Redirects followed per request, beyond which the request fails. Redirects are relayed to
+    /// the clients if 0.
+    pub max: usize,
+    /// Follow the redirects to other hosts.
+    pub cross_host: bool,
+    /// Follow the redirects from `https` to `http`.
+    pub downgrade: bool,
+    /// Headers, e.g. of API keys, never sent to another origin than the requested one: the
+    /// redirects of the requests with them to other origins are relayed to the clients.
+    pub sensitive_headers: Vec<String>,
+}
+
+impl Default for RedirectConfig {
+    fn default() -> Self {
+        Self {
+            max: 10,
+            cross_host: true,
+            downgrade: true,
+            sensitive_headers: Vec::new(),
+        }
+    }
+}
+
+pub struct Redirects {
+    max: usize,
+    cross_host: bool,
+    downgrade: bool,
+    sensitive_headers: Vec<HeaderName>,
+}
+
+impl Redirects {
+    pub fn new(config: RedirectConfig) -> Result<Self> {
+        let sensitive_headers = config
+            .sensitive_headers
+            .iter()
+            .map(|name| {
+                name.parse()
+                    .with_context(|| format!("invalid sensitive header {name}"))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            max: config.max,
+            cross_host: config.cross_host,
+            downgrade: config.downgrade,
+            sensitive_headers,
+        })
+    }
+
+    /// Policy of the HTTP clients, which follows the redirects allowed by the settings.
+    pub fn policy(&self) -> redirect::Policy {
+        if self.max == 0 {
+            return redirect::Policy::none();
+        }
+        let (max, cross_host, downgrade) = (self.max, self.cross_host, self.downgrade);
+        redirect::Policy::custom(move |attempt| {
+            if attempt.previous().len() > max {
+                return attempt.error("too many redirects");
+            }
+            let Some(previous) = attempt.previous().last() else {
+                return attempt.follow();
+            };
+            let next = attempt.url();
+            let other_host = next.host_str() != previous.host_str();
+            let downgraded = previous.scheme() == "https" && next.scheme() == "http";
+            let sensitive = SENSITIVE.try_with(|sensitive| *sensitive).unwrap_or(false);
+            if (other_host && !cross_host)
+                || (downgraded && !downgrade)
+                || (sensitive && !same_origin(previous, next))
+            {
+                tracing::debug!(from = %previous, to = %next, "Relaying redirect");
+                return attempt.stop();
+            }
+            attempt.follow()
+        })
+    }
+
+    /// Whether an upstream request with `headers` has sensitive headers.
+    pub fn sensitive(&self, headers: &HeaderMap) -> bool {
+        self.sensitive_headers
+            .iter()
+            .any(|name| headers.contains_key(name))
+    }
+}
+
+/// Run `send`, sending an upstream request, whose redirects are followed according to whether it
+/// has `sensitive` headers.
+pub fn scoped<F: Future>(sensitive: bool, send: F) -> impl Future<Output = F::Output> {
+    SENSITIVE.scope(sensitive, send)
+}
+
+fn same_origin(a: &Url, b: &Url) -> bool {
+    a.scheme() == b.scheme()
+        && a.host_str() == b.host_str()
+        && a.port_or_known_default() == b.port_or_known_default()
+}
@@ -0,0 +1,216 @@
+//! Recording of the upstream exchanges to a directory, and replay of the recording instead of
+//! reaching the origins, for deterministic tests of the apps behind the proxy.
+//!
+//! Each response is saved to a JSON file named after its URL, with its full headers and body. The
+//! request headers are saved for reference, with their credentials redacted. On replay, the
+//! responses of a URL are answered in the order they were recorded, the last one being repeated.
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Context, Result};
+use axum::{
+    body::Bytes,
+    http::{HeaderMap, HeaderName, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures_util::{Stream, StreamExt};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{cache::CachedResponse, errors, redact};
+
+#[derive(Serialize, Deserialize)]
+struct Recorded {
+    url: String,
+    request_headers: Vec<(String, String)>,
+    status: u16,
+    headers: Vec<(String, String)>,
+    /// Base64 of the body.
+    body: String,
+}
+
+/// Name of the files of the responses of `url`, before their sequence number.
+fn file_prefix(url: &str) -> String {
+    let hash = Sha256::digest(url.as_bytes());
+    hash[..8].iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn pairs(headers: &HeaderMap, redact: bool) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = if redact {
+                redact::value(name, value).into_owned()
+            } else {
+                String::from_utf8_lossy(value.as_bytes()).into_owned()
+            };
+            (name.to_string(), value)
+        })
+        .collect()
+}
+
+/// Saves the upstream exchanges.
+pub struct Recording {
+    dir: PathBuf,
+    /// Number of responses recorded for each URL.
+    counts: Mutex<HashMap<String, usize>>,
+}
+
+impl Recording {
+    pub fn new(dir: PathBuf) -> Result<Self> {
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("could not create recording directory {}", dir.display()))?;
+        Ok(Self {
+            dir,
+            counts: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Save the response to a request of `url`, in the background.
+    pub fn record(
+        &self,
+        url: &Url,
+        request_headers: &HeaderMap,
+        status: StatusCode,
+        headers: &HeaderMap,
+        body: &[u8],
+    ) {
+        let count = {
+            let mut counts = self.counts.lock().unwrap();
+            let count = counts.entry(url.to_string()).or_default();
+            *count += 1;
+            *count
+        };
+        let path = self
+            .dir
+            .join(format!("{}-{count}.json", file_prefix(url.as_str())));
+        let recorded = Recorded {
+            url: url.to_string(),
+            request_headers: pairs(request_headers, true),
+            status: status.as_u16(),
+            headers: pairs(headers, false),
+            body: STANDARD.encode(body),
+        };
+        tokio::task::spawn_blocking(move || {
+            let json = serde_json::to_vec_pretty(&recorded).expect("recordings are serializable");
+            if let Err(err) = fs::write(&path, json) {
+                tracing::error!(error = %err, path = %path.display(), "Could not save recording");
+            }
+        });
+    }
+
+    /// `body` of a streamed response, saved once it is complete.
+    pub fn tee<S, E>(
+        self: Arc<Self>,
+        url: Url,
+        request_headers: HeaderMap,
+        status: StatusCode,
+        headers: HeaderMap,
+        body: S,
+    ) -> impl Stream<Item = Result<Bytes, E>>
+    where
+        S: Stream<Item = Result<Bytes, E>>,
+    {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let collected = received.clone();
+        let done = futures_util::stream::once(async move {
+            let body = received.lock().unwrap();
+            self.record(&url, &request_headers, status, &headers, &body);
+        });
+        body.inspect(move |chunk| {
+            if let Ok(chunk) = chunk {
+                collected.lock().unwrap().extend_from_slice(chunk);
+            }
+        })
+        // only reached once the body ended without error
+        .chain(done.filter_map(|()| async { None }))
+    }
+}
+
+/// Answers the requests from a recording.
+pub struct Replay {
+    /// Recorded responses of each URL, and the number already answered.
+    responses: Mutex<HashMap<String, (Vec<CachedResponse>, usize)>>,
+}
+
+impl Replay {
+    pub fn load(dir: &Path) -> Result<Self> {
+        let mut recorded = Vec::new();
+        let entries = fs::read_dir(dir)
+            .with_context(|| format!("could not read recording directory {}", dir.display()))?;
+        for entry in entries {
+            let path = entry?.path();
+            let Some((_, sequence)) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.rsplit_once('-'))
+                .and_then(|(prefix, sequence)| Some((prefix, sequence.parse::<usize>().ok()?)))
+            else {
+                continue;
+            };
+            let contents = fs::read(&path)?;
+            let exchange: Recorded = serde_json::from_slice(&contents)
+                .with_context(|| format!("invalid recording {}", path.display()))?;
+            recorded.push((sequence, exchange));
+        }
+        recorded.sort_by_key(|(sequence, _)| *sequence);
+        let mut responses: HashMap<String, (Vec<CachedResponse>, usize)> = HashMap::new();
+        for (_, exchange) in recorded {
+            let headers = exchange
+                .headers
+                .iter()
+                .map(|(name, value)| {
+                    Ok((
+                        HeaderName::try_from(name.as_str())?,
+                        HeaderValue::try_from(value.as_str())?,
+                    ))
+                })
+                .collect::<Result<HeaderMap>>()?;
+            let response = CachedResponse {
+                status: StatusCode::from_u16(exchange.status)?,
+                headers,
+                body: STANDARD.decode(&exchange.body)?.into(),
+            };
+            responses.entry(exchange.url).or_default().0.push(response);
+        }
+        tracing::info!(urls = responses.len(), "Loaded recording");
+        Ok(Self {
+            responses: Mutex::new(responses),
+        })
+    }
+
+    /// The next recorded response of `url`.
+    pub fn get(&self, url: &Url) -> Result<CachedResponse, NotRecorded> {
+        let mut responses = self.responses.lock().unwrap();
+        let (recorded, answered) = responses
+            .get_mut(url.as_str())
+            .ok_or_else(|| NotRecorded(url.to_string()))?;
+        let response = recorded[(*answered).min(recorded.len() - 1)].clone();
+        *answered += 1;
+        Ok(response)
+    }
+}
+
+/// Error of a replayed request whose URL isn't in the recording.
+#[derive(Clone, Debug)]
+pub struct NotRecorded(String);
+
+impl fmt::Display for NotRecorded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "no recorded response for {}", self.0)
+    }
+}
+
+impl std::error::Error for NotRecorded {}
+
+impl IntoResponse for NotRecorded {
+    fn into_response(self) -> Response {
+        errors::response(StatusCode::BAD_GATEWAY, "not_recorded", self.to_string())
+    }
+}
@@ -0,0 +1,117 @@
+//! Resumption of the upstream downloads interrupted mid-body, with ranged requests for the rest of
+//! the body, so that the client receives it unbroken.
+//!
+//! Only complete responses of a known length, whose origin accepts byte ranges and gives a strong
+//! validator, are resumed: the `If-Range` validator ensures that the rest is of the same version.
+use std::sync::atomic::Ordering;
+
+use axum::{
+    body::Bytes,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+};
+use futures_util::{stream::BoxStream, Stream, StreamExt};
+use reqwest::RequestBuilder;
+
+use crate::metrics::METRICS;
+
+/// The validator of a response that can be resumed.
+pub fn validator(status: StatusCode, headers: &HeaderMap) -> Option<HeaderValue> {
+    let ranges = headers
+        .get(header::ACCEPT_RANGES)
+        .is_some_and(|value| value.as_bytes().eq_ignore_ascii_case(b"bytes"));
+    if status != StatusCode::OK || !ranges || !headers.contains_key(header::CONTENT_LENGTH) {
+        return None;
+    }
+    headers
+        .get(header::ETAG)
+        // weak validators can't be used for ranges
+        .filter(|etag| !etag.as_bytes().starts_with(b"W/"))
+        .or_else(|| headers.get(header::LAST_MODIFIED))
+        .cloned()
+}
+
+struct Download {
+    body: BoxStream<'static, reqwest::Result<Bytes>>,
+    /// Request of the body, sent again with a range.
+    request: RequestBuilder,
+    validator: HeaderValue,
+    received: u64,
+    attempts: u32,
+}
+
+/// `body`, resumed up to `attempts` times with `request` when it fails.
+pub fn resumable(
+    body: impl Stream<Item = reqwest::Result<Bytes>> + Send + 'static,
+    request: RequestBuilder,
+    validator: HeaderValue,
+    attempts: u32,
+) -> impl Stream<Item = reqwest::Result<Bytes>> {
+    let download = Download {
+        body: body.boxed(),
+        request,
+        validator,
+        received: 0,
+        attempts,
+    };
+    futures_util::stream::unfold(Some(download), |download| async move {
+        let mut download = download?;
+        loop {
+            match download.body.next().await? {
+                Ok(chunk) => {
+                    download.received += chunk.len() as u64;
+                    return Some((Ok(chunk), Some(download)));
+                }
+                Err(err) if download.attempts == 0 => return Some((Err(err), None)),
+                Err(err) => {
+                    download.attempts -= 1;
+                    tracing::warn!(
+                        error = %err,
+                        received = download.received,
+                        "Resuming interrupted upstream download"
+                    );
+                    let rest = match download.request.try_clone() {
+                        Some(request) => {
+                            resume(request, download.validator.clone(), download.received).await
+                        }
+                        None => None,
+                    };
+                    match rest {
+                        Some(body) => download.body = body,
+                        None => return Some((Err(err), None)),
+                    }
+                }
+            }
+        }
+    })
+    .fuse()
+}
+
+/// The rest of the body requested by `request` after the `received` bytes, if the origin sends it.
+async fn resume(
+    request: RequestBuilder,
+    validator: HeaderValue,
+    received: u64,
+) -> Option<BoxStream<'static, reqwest::Result<Bytes>>> {
+    METRICS.upstream_requests.fetch_add(1, Ordering::Relaxed);
+    METRICS.upstream_retries.fetch_add(1, Ordering::Relaxed);
+    let response = request
+        .header(header::RANGE, format!("bytes={received}-"))
+        .header(header::IF_RANGE, validator)
+        .send()
+        .await
+        .ok()?;
+    let start = format!("bytes {received}-");
+    let continues = response.status() == StatusCode::PARTIAL_CONTENT
+        && response
+            .headers()
+            .get(header::CONTENT_RANGE)
+            .is_some_and(|value| value.as_bytes().starts_with(start.as_bytes()));
+    if !continues {
+        tracing::warn!(
+            status_code = response.status().as_u16(),
+            "Origin did not resume the download"
+        );
+        return None;
+    }
+    Some(response.bytes_stream().boxed())
+}
@@ -0,0 +1,111 @@
+//! Retries of failed upstream requests, with exponential backoff, or after the delay given by the
+//! `Retry-After` of the `429` and `503` responses.
+//!
+//! The proxy only sends `GET` requests to origins, which are idempotent and can always be retried.
+//! Requests failing because their pooled connection was closed by the origin while idle are sent
+//! again once on a new connection, whatever the policy.
+use std::{error::Error as _, io, time::Duration};
+
+use rand::Rng;
+use reqwest::{header, StatusCode};
+
+use crate::rate_limit::parse_retry_after;
+
+/// Upper bound of the delay between two attempts.
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+pub struct RetryPolicy {
+    /// Maximum number of retries of a request.
+    retries: u32,
+    /// Delay before the first retry, doubled for each further one.
+    backoff: Duration,
+    /// Response statuses that are retried, in addition to connection errors and timeouts.
+    statuses: Vec<StatusCode>,
+    /// Upper bound of the delays given by `Retry-After`.
+    max_retry_after: Duration,
+}
+
+impl RetryPolicy {
+    pub fn new(
+        retries: u32,
+        backoff: Duration,
+        statuses: Vec<StatusCode>,
+        max_retry_after: Duration,
+    ) -> Self {
+        Self {
+            retries,
+            backoff,
+            statuses,
+            max_retry_after,
+        }
+    }
+
+    /// Whether the outcome of the `attempt`-th retry (0 for the first request) warrants another.
+    pub fn should_retry(
+        &self,
+        attempt: u32,
+        result: &Result<reqwest::Response, reqwest::Error>,
+    ) -> bool {
+        attempt < self.retries
+            && match result {
+                Ok(response) => self.statuses.contains(&response.status()),
+                Err(err) => err.is_connect() || err.is_timeout() || err.is_request(),
+            }
+    }
+
+    /// Whether `err` is likely from a pooled connection the origin closed while it was idle, which
+    /// was reset or closed before any byte of the response, rather than from a failure of the
+    /// origin.
+    pub fn is_stale_connection(err: &reqwest::Error) -> bool {
+        if err.is_connect() || err.is_timeout() {
+            return false;
+        }
+        let mut source = err.source();
+        while let Some(err) = source {
+            if err
+                .downcast_ref::<hyper::Error>()
+                .is_some_and(hyper::Error::is_incomplete_message)
+            {
+                return true;
+            }
+            if let Some(err) = err.downcast_ref::<io::Error>() {
+                return matches!(
+                    err.kind(),
+                    io::ErrorKind::ConnectionReset
+                        | io::ErrorKind::ConnectionAborted
+                        | io::ErrorKind::BrokenPipe
+                );
+            }
+            source = err.source();
+        }
+        false
+    }
+
+    /// Delay before the `attempt`-th retry of a request that got `result`: the `Retry-After` of a
+    /// `429` or `503` response, else a backoff with full jitter so that clients don't retry in sync.
+    pub fn delay(
+        &self,
+        attempt: u32,
+        result: &Result<reqwest::Response, reqwest::Error>,
+    ) -> Duration {
+        let retry_after = result
+            .as_ref()
+            .ok()
+            .filter(|response| {
+                matches!(
+                    response.status(),
+                    StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE
+                )
+            })
+            .and_then(|response| response.headers().get(header::RETRY_AFTER))
+            .and_then(parse_retry_after);
+        if let Some(retry_after) = retry_after {
+            return retry_after.min(self.max_retry_after);
+        }
+        let max = self
+            .backoff
+            .saturating_mul(1 << attempt.saturating_sub(1).min(16))
+            .min(MAX_BACKOFF);
+        max.mul_f64(rand::thread_rng().gen())
+    }
+}
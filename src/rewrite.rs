@@ -0,0 +1,243 @@
+//! Find and replace rules applied to the textual response bodies of the matching hosts, e.g. to
+//! point their absolute URLs back through the proxy.
+//!
+//! The bodies are rewritten as they are streamed: the end of the received body is held back until
+//! a match can't start in it anymore, so matches are assumed to be shorter than the `max_match`
+//! of their rule.
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use axum::{
+    body::{Body, Bytes},
+    http::{header, HeaderMap},
+};
+use futures_util::{Stream, StreamExt};
+use regex::bytes::Regex;
+use reqwest::Url;
+use serde::Deserialize;
+
+use crate::config::HostPattern;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RewriteRule {
+    pub host: HostPattern,
+    /// Media types of the rewritten bodies, where `text/*` matches all the text types.
+    #[serde(default = "RewriteRule::default_content_types")]
+    pub content_types: Vec<String>,
+    /// Regular expression, see the syntax of the `regex` crate.
+    pub find: String,
+    /// Replacement of the matches, with `$1` or `${name}` for their capture groups.
+    pub replace: String,
+    /// Longest match, in bytes.
+    #[serde(default = "RewriteRule::default_max_match")]
+    pub max_match: usize,
+}
+
+impl RewriteRule {
+    fn default_content_types() -> Vec<String> {
+        [
+            "text/*",
+            "application/json",
+            "application/javascript",
+            "application/xml",
+        ]
+        .map(String::from)
+        .to_vec()
+    }
+
+    fn default_max_match() -> usize {
+        4096
+    }
+
+    fn applies(&self, host: &str, media_type: &str) -> bool {
+        self.host.matches(host)
+            && self
+                .content_types
+                .iter()
+                .any(|content_type| match content_type.strip_suffix("/*") {
+                    Some(kind) => media_type
+                        .split_once('/')
+                        .is_some_and(|(other, _)| other.eq_ignore_ascii_case(kind)),
+                    None => media_type.eq_ignore_ascii_case(content_type),
+                })
+    }
+}
+
+struct Compiled {
+    rule: RewriteRule,
+    regex: Regex,
+}
+
+pub struct Rewrites {
+    rules: Vec<Arc<Compiled>>,
+}
+
+impl Rewrites {
+    pub fn new(rules: Vec<RewriteRule>) -> Result<Self> {
+        let rules = rules
+            .into_iter()
+            .map(|rule| {
+                let regex = Regex::new(&rule.find)
+                    .with_context(|| format!("invalid rewrite of {}", rule.host))?;
+                Ok(Arc::new(Compiled { rule, regex }))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { rules })
+    }
+
+    /// Whether any rule rewrites the response to a request of `url`.
+    pub fn applies(&self, url: &Url, headers: &HeaderMap) -> bool {
+        let media_type = media_type(headers);
+        let host = url.host_str().unwrap_or_default();
+        self.rules
+            .iter()
+            .any(|compiled| compiled.rule.applies(host, media_type))
+    }
+
+    /// `body` of the response to a request of `url` rewritten by the matching rules, in order.
+    pub fn rewrite(&self, url: &Url, headers: &HeaderMap, mut body: Body) -> Body {
+        let media_type = media_type(headers);
+        let host = url.host_str().unwrap_or_default();
+        for compiled in &self.rules {
+            if compiled.rule.applies(host, media_type) {
+                body = Body::from_stream(rewritten(body.into_data_stream(), compiled.clone()));
+            }
+        }
+        body
+    }
+}
+
+fn media_type(headers: &HeaderMap) -> &str {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .unwrap_or_default()
+        .trim()
+}
+
+fn rewritten<S>(body: S, compiled: Arc<Compiled>) -> impl Stream<Item = Result<Bytes, axum::Error>>
+where
+    S: Stream<Item = Result<Bytes, axum::Error>> + Unpin,
+{
+    futures_util::stream::unfold(Some((body, Vec::new())), move |state| {
+        let compiled = compiled.clone();
+        async move {
+            let (mut body, mut pending) = state?;
+            match body.next().await {
+                Some(Ok(chunk)) => {
+                    pending.extend_from_slice(&chunk);
+                    let out = compiled.replace(&mut pending, false);
+                    Some((Ok(out.into()), Some((body, pending))))
+                }
+                Some(Err(err)) => Some((Err(err), None)),
+                None => Some((Ok(compiled.replace(&mut pending, true).into()), None)),
+            }
+        }
+    })
+    // the compression layer polls again after the end
+    .fuse()
+}
+
+impl Compiled {
+    /// The rewritten start of `pending`, leaving the bytes where a match could still start unless
+    /// the body is complete.
+    fn replace(&self, pending: &mut Vec<u8>, complete: bool) -> Vec<u8> {
+        let safe = if complete {
+            pending.len()
+        } else {
+            pending.len().saturating_sub(self.rule.max_match)
+        };
+        let mut out = Vec::with_capacity(pending.len());
+        let mut copied = 0;
+        for captures in self.regex.captures_iter(pending) {
+            let found = captures.get(0).expect("the whole match is always captured");
+            if found.start() >= safe {
+                break;
+            }
+            out.extend_from_slice(&pending[copied..found.start()]);
+            captures.expand(self.rule.replace.as_bytes(), &mut out);
+            copied = found.end();
+        }
+        let kept = copied.max(safe);
+        out.extend_from_slice(&pending[copied..kept]);
+        pending.drain(..kept);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rewrites(toml: &str) -> Rewrites {
+        #[derive(Deserialize)]
+        struct Rules {
+            rewrites: Vec<RewriteRule>,
+        }
+        Rewrites::new(toml::from_str::<Rules>(toml).unwrap().rewrites).unwrap()
+    }
+
+    async fn rewrite(rewrites: &Rewrites, chunks: &[&[u8]]) -> String {
+        let chunks: Vec<_> = chunks
+            .iter()
+            .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+            .collect();
+        let mut body = futures_util::stream::iter(chunks).boxed();
+        for compiled in &rewrites.rules {
+            body = rewritten(body, compiled.clone()).boxed();
+        }
+        let chunks: Vec<_> = body.map(Result::unwrap).collect().await;
+        String::from_utf8(chunks.concat()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn matches_across_chunks() {
+        let rewrites = rewrites(
+            r#"
+            [[rewrites]]
+            host = "example.com"
+            find = 'https://(\w+)\.example\.com'
+            replace = "https://gw.example/$1"
+            max_match = 64
+
+            [[rewrites]]
+            host = "example.com"
+            find = "gw"
+            replace = "gateway"
+            "#,
+        );
+        let body = b"<a href=\"https://api.example.com/v1\">https://www.example.com</a>";
+        let expected = "<a href=\"https://gateway.example/api/v1\">https://gateway.example/www</a>";
+        for first in 0..=body.len() {
+            for second in first..=body.len() {
+                let chunks = [&body[..first], &body[first..second], &body[second..]];
+                assert_eq!(rewrite(&rewrites, &chunks).await, expected, "{chunks:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn content_types() {
+        let rewrites = rewrites(
+            r#"
+            [[rewrites]]
+            host = "*.example.com"
+            find = "a"
+            replace = "b"
+            "#,
+        );
+        let url = Url::parse("https://www.example.com/").unwrap();
+        let headers = |content_type: &'static str| {
+            HeaderMap::from_iter([(header::CONTENT_TYPE, content_type.parse().unwrap())])
+        };
+        assert!(rewrites.applies(&url, &headers("text/html; charset=utf-8")));
+        assert!(rewrites.applies(&url, &headers("TEXT/CSS")));
+        assert!(rewrites.applies(&url, &headers("application/json")));
+        assert!(!rewrites.applies(&url, &headers("image/png")));
+        assert!(!rewrites.applies(&url, &HeaderMap::new()));
+        let other = Url::parse("https://example.org/").unwrap();
+        assert!(!rewrites.applies(&other, &headers("text/html")));
+    }
+}
@@ -0,0 +1,79 @@
+//! Routing of the upstream connections of some hosts to fixed addresses, like the Unix sockets of
+//! local daemons that don't listen on TCP, HTTP being spoken over them as usual.
+//!
+//! The routed connections go through a SOCKS5 bridge like the ones of the custom connectors, see
+//! [`connector`](crate::connector), while the others are opened as usual. The routed hosts aren't
+//! resolved, and the requests to them don't go through the upstream proxies.
+use std::{io, path::PathBuf};
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use tokio::net::{TcpStream, UnixStream};
+
+use crate::{
+    config::HostPattern,
+    connector::{Connector, Io},
+};
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteConfig {
+    pub host: HostPattern,
+    /// Where the connections to the host go, `unix:/run/backend.sock` or `host:port`.
+    pub to: String,
+}
+
+enum Target {
+    Unix(PathBuf),
+    Tcp(String),
+}
+
+pub struct Routes(Vec<(HostPattern, Target)>);
+
+impl Routes {
+    pub fn new(configs: Vec<RouteConfig>) -> Self {
+        let routes = configs
+            .into_iter()
+            .map(|config| {
+                let target = match config.to.strip_prefix("unix:") {
+                    Some(path) => Target::Unix(path.into()),
+                    None => Target::Tcp(config.to),
+                };
+                (config.host, target)
+            })
+            .collect();
+        Self(routes)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The target of the connections to `host`, the first matching route applies.
+    fn target(&self, host: &str) -> Option<&Target> {
+        self.0
+            .iter()
+            .find(|(pattern, _)| pattern.matches(host))
+            .map(|(_, target)| target)
+    }
+
+    pub fn routes(&self, host: &str) -> bool {
+        self.target(host).is_some()
+    }
+}
+
+// the futures boxed by `async_trait` are `must_use` already
+#[allow(clippy::double_must_use)]
+#[async_trait]
+impl Connector for Routes {
+    async fn connect(&self, host: &str, _port: u16) -> io::Result<Box<dyn Io>> {
+        match self.target(host) {
+            Some(Target::Unix(path)) => Ok(Box::new(UnixStream::connect(path).await?)),
+            Some(Target::Tcp(addr)) => Ok(Box::new(TcpStream::connect(addr).await?)),
+            None => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("no route to {host}"),
+            )),
+        }
+    }
+}
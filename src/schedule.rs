@@ -0,0 +1,155 @@
+//! Access rules allowing some requests only during time windows, e.g. the ones of scraping jobs
+//! off-peak, the requests outside of them being denied with a `403`.
+//!
+//! The windows are in local time unless set in UTC, and the ones spanning midnight, like
+//! `Mon-Fri 22:00-06:00`, belong to the day they start on.
+use std::str::FromStr;
+
+use axum::{http::StatusCode, response::Response};
+use chrono::{Datelike, Local, NaiveDateTime, NaiveTime, Utc, Weekday};
+use serde::Deserialize;
+
+use crate::{config::HostPattern, errors, quota::credential_id};
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScheduleRule {
+    /// Destination hosts of the rule, all of them if not set.
+    pub host: Option<HostPattern>,
+    /// Tenant of the rule, any if not set.
+    pub tenant: Option<String>,
+    /// Id of the credential of the rule, as in the access log, any if not set.
+    pub credential: Option<String>,
+    /// When the requests are allowed, e.g. `Mon-Fri 22:00-06:00`, `Sat,Sun` or `12:00-14:00`.
+    pub windows: Vec<Window>,
+    /// Whether the windows are in UTC instead of local time.
+    #[serde(default)]
+    pub utc: bool,
+}
+
+/// Days of the week and hours of the day.
+#[derive(Debug, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Window {
+    spec: String,
+    /// The days the window starts on, from Monday.
+    days: [bool; 7],
+    /// Start and end of the window, the whole day if not set.
+    hours: Option<(NaiveTime, NaiveTime)>,
+}
+
+impl Window {
+    fn contains(&self, time: NaiveDateTime) -> bool {
+        let (day, previous) = (time.weekday(), time.weekday().pred());
+        let starts = |day: Weekday| self.days[day.num_days_from_monday() as usize];
+        match self.hours {
+            None => starts(day),
+            Some((start, end)) if start < end => {
+                starts(day) && start <= time.time() && time.time() < end
+            }
+            Some((start, end)) => {
+                (starts(day) && start <= time.time()) || (starts(previous) && time.time() < end)
+            }
+        }
+    }
+}
+
+impl TryFrom<String> for Window {
+    type Error = String;
+
+    fn try_from(spec: String) -> Result<Self, String> {
+        let invalid = || format!("invalid time window `{spec}`");
+        let (mut days, mut hours) = (None, None);
+        for part in spec.split_whitespace() {
+            if part.contains(':') && hours.is_none() {
+                let (start, end) = part.split_once('-').ok_or_else(invalid)?;
+                let parse = |time| NaiveTime::parse_from_str(time, "%H:%M").map_err(|_| invalid());
+                let (start, end) = (parse(start)?, parse(end)?);
+                if start == end {
+                    return Err(format!("empty time window `{spec}`"));
+                }
+                hours = Some((start, end));
+            } else if days.is_none() {
+                days = Some(parse_days(part).ok_or_else(invalid)?);
+            } else {
+                return Err(invalid());
+            }
+        }
+        if days.is_none() && hours.is_none() {
+            return Err(invalid());
+        }
+        Ok(Self {
+            days: days.unwrap_or([true; 7]),
+            hours,
+            spec,
+        })
+    }
+}
+
+/// Parse a list of days or ranges of days, like `Sat,Sun` or `Mon-Fri`.
+fn parse_days(list: &str) -> Option<[bool; 7]> {
+    let mut days = [false; 7];
+    for range in list.split(',') {
+        let (first, last) = range.split_once('-').unwrap_or((range, range));
+        let (mut day, last) = (
+            Weekday::from_str(first).ok()?,
+            Weekday::from_str(last).ok()?,
+        );
+        days[day.num_days_from_monday() as usize] = true;
+        while day != last {
+            day = day.succ();
+            days[day.num_days_from_monday() as usize] = true;
+        }
+    }
+    Some(days)
+}
+
+pub struct Schedules(Vec<ScheduleRule>);
+
+impl Schedules {
+    pub fn new(rules: Vec<ScheduleRule>) -> Self {
+        Self(rules)
+    }
+
+    /// The response denying a request of `tenant` with `token` to `host`, if the first matching
+    /// rule doesn't allow it now.
+    pub fn check(&self, host: &str, tenant: Option<&str>, token: &str) -> Option<Response> {
+        let mut credential = None;
+        let rule = self.0.iter().find(|rule| {
+            rule.host
+                .as_ref()
+                .is_none_or(|pattern| pattern.matches(host))
+                && rule
+                    .tenant
+                    .as_ref()
+                    .is_none_or(|name| Some(name.as_str()) == tenant)
+                && rule
+                    .credential
+                    .as_ref()
+                    .is_none_or(|id| id == credential.get_or_insert_with(|| credential_id(token)))
+        })?;
+        let now = if rule.utc {
+            Utc::now().naive_utc()
+        } else {
+            Local::now().naive_local()
+        };
+        if rule.windows.iter().any(|window| window.contains(now)) {
+            return None;
+        }
+        let windows: Vec<_> = rule
+            .windows
+            .iter()
+            .map(|window| window.spec.as_str())
+            .collect();
+        let zone = if rule.utc { "UTC" } else { "local time" };
+        tracing::warn!(host, tenant, "Request outside of its schedule");
+        Some(errors::response(
+            StatusCode::FORBIDDEN,
+            "outside_schedule",
+            format!(
+                "Requests to {host} are only allowed {} ({zone})",
+                windows.join(", ")
+            ),
+        ))
+    }
+}
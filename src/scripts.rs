@@ -0,0 +1,290 @@
+//! Rhai scripts from the config file, run at request and response time for policy tweaks that
+//! don't justify a recompile. Requires the `scripting` feature.
+//!
+//! The request script can read and change `method`, `url` (the target of the request) and
+//! `headers` (a map of lowercase names to values, or to arrays of them for the repeated headers like
+//! `set-cookie`), and answer the request by setting
+//! `respond = #{ status: 403, body: "..." }`. The response script can read `url`, `error` (the code
+//! of the error responses of the proxy, `()` otherwise), and read and change `status` and
+//! `headers`.
+//!
+//! A script that fails or exceeds its limits makes the proxy answer `500 Internal Server Error`.
+use std::{sync::Arc, time::Duration};
+
+use anyhow::Result;
+use serde::Deserialize;
+
+use crate::hooks::Hook;
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+#[cfg_attr(not(feature = "scripting"), allow(dead_code))]
+pub struct ScriptConfig {
+    /// Script run before the request is handled.
+    pub request: Option<String>,
+    /// Script run with the response.
+    pub response: Option<String>,
+    /// Operations each run can make, e.g. expressions or function calls.
+    pub max_operations: u64,
+    /// Time each run can take.
+    #[serde(with = "humantime_serde")]
+    pub timeout: Duration,
+}
+
+impl Default for ScriptConfig {
+    fn default() -> Self {
+        Self {
+            request: None,
+            response: None,
+            max_operations: 100_000,
+            timeout: Duration::from_millis(50),
+        }
+    }
+}
+
+/// Hook running the scripts, if any.
+#[cfg(feature = "scripting")]
+pub fn load(config: &ScriptConfig) -> Result<Option<Arc<dyn Hook>>> {
+    if config.request.is_none() && config.response.is_none() {
+        return Ok(None);
+    }
+    Ok(Some(Arc::new(rhai_hook::Scripts::compile(config)?)))
+}
+
+#[cfg(not(feature = "scripting"))]
+pub fn load(config: &ScriptConfig) -> Result<Option<Arc<dyn Hook>>> {
+    anyhow::ensure!(
+        config.request.is_none() && config.response.is_none(),
+        "scripts require the proxy to be built with the `scripting` feature"
+    );
+    Ok(None)
+}
+
+#[cfg(feature = "scripting")]
+mod rhai_hook {
+    use std::{
+        cell::Cell,
+        collections::HashMap,
+        time::{Duration, Instant},
+    };
+
+    use anyhow::{anyhow, Context, Result};
+    use async_trait::async_trait;
+    use axum::{
+        body::Body,
+        extract::{Query, Request},
+        http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode, Uri},
+        response::Response,
+    };
+    use reqwest::Url;
+    use rhai::{Array, Dynamic, Engine, Map, Scope, AST};
+
+    use super::ScriptConfig;
+    use crate::{
+        errors,
+        hooks::{Flow, Hook, ProxyError, RequestHead},
+    };
+
+    thread_local! {
+        /// When the script running on this thread must stop.
+        static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+    }
+
+    pub struct Scripts {
+        engine: Engine,
+        request: Option<AST>,
+        response: Option<AST>,
+        timeout: Duration,
+    }
+
+    impl Scripts {
+        pub fn compile(config: &ScriptConfig) -> Result<Self> {
+            let mut engine = Engine::new();
+            engine.set_max_operations(config.max_operations);
+            engine.on_progress(|_| {
+                let expired = DEADLINE
+                    .get()
+                    .is_some_and(|deadline| Instant::now() > deadline);
+                expired.then(|| "script timed out".into())
+            });
+            let compile = |script: &Option<String>, name| {
+                script
+                    .as_deref()
+                    .map(|script| engine.compile(script))
+                    .transpose()
+                    .with_context(|| format!("invalid {name} script"))
+            };
+            Ok(Self {
+                request: compile(&config.request, "request")?,
+                response: compile(&config.response, "response")?,
+                engine,
+                timeout: config.timeout,
+            })
+        }
+
+        /// Run `ast` to completion, the scripts not being able to yield.
+        fn run(&self, ast: &AST, scope: &mut Scope) -> Result<()> {
+            DEADLINE.set(Some(Instant::now() + self.timeout));
+            let result = self.engine.run_ast_with_scope(scope, ast);
+            DEADLINE.set(None);
+            result.map_err(|err| anyhow!("{err}"))
+        }
+
+        fn failed(&self, err: anyhow::Error) -> Response {
+            tracing::error!(error = %err, "Script failed");
+            errors::response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "script_error",
+                "Something went wrong",
+            )
+        }
+
+        fn on_request(&self, ast: &AST, request: &mut Request) -> Result<Option<Response>> {
+            let mut scope = Scope::new();
+            scope.push("method", request.method().to_string());
+            scope.push("url", target(request.uri()).unwrap_or_default());
+            scope.push("headers", headers_map(request.headers()));
+            scope.push("respond", Dynamic::UNIT);
+            self.run(ast, &mut scope)?;
+
+            if let Some(respond) = scope.get_value::<Map>("respond") {
+                let status = respond
+                    .get("status")
+                    .and_then(|status| status.as_int().ok())
+                    .context("`respond.status` must be an integer")?;
+                let body = respond
+                    .get("body")
+                    .map(|body| body.to_string())
+                    .unwrap_or_default();
+                let mut response = Response::new(Body::from(body));
+                *response.status_mut() = StatusCode::from_u16(u16::try_from(status)?)?;
+                return Ok(Some(response));
+            }
+            let method = scope.get_value::<String>("method").unwrap_or_default();
+            *request.method_mut() = Method::from_bytes(method.as_bytes())?;
+            let url = scope.get_value::<String>("url").unwrap_or_default();
+            if Some(&url) != target(request.uri()).as_ref() {
+                set_target(request, &url)?;
+            }
+            *request.headers_mut() = header_map(scope.get_value("headers").unwrap_or_default())?;
+            Ok(None)
+        }
+
+        fn on_response(
+            &self,
+            request: &RequestHead,
+            error: Option<&'static str>,
+            mut response: Response,
+        ) -> Response {
+            let Some(ast) = &self.response else {
+                return response;
+            };
+            let mut scope = Scope::new();
+            scope.push("url", target(&request.uri).unwrap_or_default());
+            scope.push("error", error.map_or(Dynamic::UNIT, Dynamic::from));
+            scope.push("status", i64::from(response.status().as_u16()));
+            scope.push("headers", headers_map(response.headers()));
+            let result = self.run(ast, &mut scope).and_then(|()| {
+                let status = scope.get_value::<i64>("status").unwrap_or_default();
+                *response.status_mut() = StatusCode::from_u16(u16::try_from(status)?)?;
+                *response.headers_mut() =
+                    header_map(scope.get_value("headers").unwrap_or_default())?;
+                Ok(())
+            });
+            match result {
+                Ok(()) => response,
+                Err(err) => self.failed(err),
+            }
+        }
+    }
+
+    fn target(uri: &Uri) -> Option<String> {
+        Query::<HashMap<String, String>>::try_from_uri(uri)
+            .ok()
+            .and_then(|Query(mut params)| params.remove("url"))
+    }
+
+    /// Point the `url` param of the request to `target`.
+    fn set_target(request: &mut Request, target: &str) -> Result<()> {
+        let mut uri = Url::parse(&format!("http://proxy{}", request.uri()))?;
+        let pairs: Vec<(String, String)> = uri
+            .query_pairs()
+            .filter(|(name, _)| name != "url")
+            .map(|(name, value)| (name.into_owned(), value.into_owned()))
+            .collect();
+        uri.query_pairs_mut()
+            .clear()
+            .append_pair("url", target)
+            .extend_pairs(pairs);
+        *request.uri_mut() =
+            format!("{}?{}", uri.path(), uri.query().unwrap_or_default()).parse()?;
+        Ok(())
+    }
+
+    /// Headers as a map, the values of repeated ones being joined with commas.
+    /// The map of `headers`, the repeated ones being arrays of their values, since some of them
+    /// like `Set-Cookie` can't be joined.
+    fn headers_map(headers: &HeaderMap) -> Map {
+        let mut map = Map::new();
+        for name in headers.keys() {
+            let mut values: Array = headers
+                .get_all(name)
+                .iter()
+                .map(|value| {
+                    String::from_utf8_lossy(value.as_bytes())
+                        .into_owned()
+                        .into()
+                })
+                .collect();
+            let value = match values.len() {
+                1 => values.remove(0),
+                _ => values.into(),
+            };
+            map.insert(name.as_str().into(), value);
+        }
+        map
+    }
+
+    fn header_map(map: Map) -> Result<HeaderMap> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in map {
+            let name = HeaderName::try_from(name.as_str())?;
+            let values = if value.is_array() {
+                value.cast::<Array>()
+            } else {
+                vec![value]
+            };
+            for value in values {
+                headers.append(&name, HeaderValue::try_from(value.to_string())?);
+            }
+        }
+        Ok(headers)
+    }
+
+    #[async_trait]
+    impl Hook for Scripts {
+        async fn on_request(&self, mut request: Request) -> Flow {
+            let Some(ast) = &self.request else {
+                return Flow::Continue(request);
+            };
+            match Scripts::on_request(self, ast, &mut request) {
+                Ok(None) => Flow::Continue(request),
+                Ok(Some(response)) => Flow::Respond(response),
+                Err(err) => Flow::Respond(self.failed(err)),
+            }
+        }
+
+        async fn on_response(&self, request: &RequestHead, response: Response) -> Response {
+            Scripts::on_response(self, request, None, response)
+        }
+
+        async fn on_error(
+            &self,
+            request: &RequestHead,
+            error: &ProxyError,
+            response: Response,
+        ) -> Response {
+            Scripts::on_response(self, request, Some(error.code()), response)
+        }
+    }
+}
@@ -0,0 +1,190 @@
+//! HTTP/1 server of the proxy listeners, closing the client connections left idle.
+//!
+//! A connection is idle while it has no request in flight, responses being in flight until their
+//! body is sent. Idle connections are closed after the idle timeout, like the ones of clients that
+//! never send a request, so that leaked keep-alive connections don't accumulate.
+use std::{
+    convert::Infallible,
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::{body::Body, extract::ConnectInfo, Router};
+use hyper::{body::Incoming, server::conn::http1, service::service_fn};
+use hyper_util::rt::TokioIo;
+use tokio::net::{TcpListener, TcpStream};
+use tower::ServiceExt;
+
+use crate::{
+    body::Counted,
+    drain::Drain,
+    geoip::GeoIp,
+    metrics::{ConnectionGuard, METRICS},
+    profile,
+};
+
+/// Requests in flight on a connection, and since when it has been idle.
+struct Activity {
+    in_flight: AtomicUsize,
+    idle_since: Mutex<Instant>,
+}
+
+impl Activity {
+    fn new() -> Self {
+        METRICS.idle_connections.fetch_add(1, Ordering::Relaxed);
+        Self {
+            in_flight: AtomicUsize::new(0),
+            idle_since: Mutex::new(Instant::now()),
+        }
+    }
+
+    fn start(&self) {
+        if self.in_flight.fetch_add(1, Ordering::Relaxed) == 0 {
+            METRICS.idle_connections.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    fn end(&self) {
+        *self.idle_since.lock().unwrap() = Instant::now();
+        if self.in_flight.fetch_sub(1, Ordering::Relaxed) == 1 {
+            METRICS.idle_connections.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Wait until the connection has been idle for `timeout`.
+    async fn idle_for(&self, timeout: Duration) {
+        loop {
+            let idle = self.in_flight.load(Ordering::Relaxed) == 0;
+            let elapsed = self.idle_since.lock().unwrap().elapsed();
+            if idle && elapsed >= timeout {
+                return;
+            }
+            // requests in flight end at least the timeout before it expires
+            let wait = if idle { timeout - elapsed } else { timeout };
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+impl Drop for Activity {
+    fn drop(&mut self) {
+        if *self.in_flight.get_mut() == 0 {
+            METRICS.idle_connections.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Serve `app` on `listener` until the drain starts, then wait for the connections to complete.
+///
+/// The connections of the clients `geoip` denies are closed right away.
+pub async fn serve(
+    listener: TcpListener,
+    app: Router,
+    drain: Arc<Drain>,
+    idle_timeout: Duration,
+    geoip: Option<Arc<GeoIp>>,
+) -> io::Result<()> {
+    let mut connections = tokio::task::JoinSet::new();
+    loop {
+        let (stream, addr) = tokio::select! {
+            accepted = accept(&listener) => match accepted {
+                Some(accepted) => accepted,
+                None => continue,
+            },
+            () = drain.started() => break,
+        };
+        if geoip.as_ref().is_some_and(|geoip| !geoip.allows(addr)) {
+            continue;
+        }
+        connections.spawn(connection(
+            stream,
+            addr,
+            app.clone(),
+            drain.clone(),
+            idle_timeout,
+        ));
+        // reap the completed connections
+        while connections.try_join_next().is_some() {}
+    }
+    drop(listener);
+    while connections.join_next().await.is_some() {}
+    Ok(())
+}
+
+async fn connection(
+    stream: TcpStream,
+    addr: SocketAddr,
+    app: Router,
+    drain: Arc<Drain>,
+    idle_timeout: Duration,
+) {
+    let _guard = ConnectionGuard::new();
+    let activity = Arc::new(Activity::new());
+    let service = {
+        let activity = activity.clone();
+        service_fn(move |request: hyper::Request<Incoming>| {
+            profile::register_thread();
+            let mut request = request.map(Body::new);
+            request.extensions_mut().insert(ConnectInfo(addr));
+            activity.start();
+            let (app, activity) = (app.clone(), activity.clone());
+            async move {
+                let response = app.oneshot(request).await?;
+                Ok::<_, Infallible>(
+                    response.map(|body| Counted::wrap(body, move |_| activity.end())),
+                )
+            }
+        })
+    };
+    let connection = http1::Builder::new()
+        .serve_connection(TokioIo::new(stream), service)
+        .with_upgrades();
+    tokio::pin!(connection);
+    let mut closing = false;
+    loop {
+        tokio::select! {
+            result = connection.as_mut() => {
+                if let Err(err) = result {
+                    tracing::debug!(error = %err, "Client connection failed");
+                }
+                break;
+            }
+            () = drain.started(), if !closing => {
+                connection.as_mut().graceful_shutdown();
+                closing = true;
+            }
+            () = activity.idle_for(idle_timeout), if !closing => {
+                tracing::debug!("Closing idle client connection");
+                connection.as_mut().graceful_shutdown();
+                closing = true;
+            }
+        }
+    }
+}
+
+/// Accept a connection, backing off on errors like running out of file descriptors.
+pub async fn accept(listener: &TcpListener) -> Option<(TcpStream, SocketAddr)> {
+    match listener.accept().await {
+        Ok(accepted) => Some(accepted),
+        Err(err)
+            if matches!(
+                err.kind(),
+                io::ErrorKind::ConnectionRefused
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::ConnectionReset
+            ) =>
+        {
+            None
+        }
+        Err(err) => {
+            tracing::error!(error = %err, "Could not accept connection");
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            None
+        }
+    }
+}
@@ -0,0 +1,294 @@
+//! Sessions, so that a flow of requests looks like the one of a single device.
+//!
+//! The requests with the same [`SESSION_HEADER`] use the same persona and device, share the cookies
+//! set by the origins, follow the same pacing profile if any, and are sent over connections of
+//! their own, which a [`Connector`](crate::Connector) choosing an exit per connection keeps on the
+//! same exit. A session is recycled after its TTL, the next request with its ID starting a new one.
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{ensure, Context, Result};
+use axum::http::{header, HeaderMap, HeaderValue};
+use rand::seq::SliceRandom;
+use reqwest::Url;
+use serde::Deserialize;
+
+use crate::{
+    pacing::{Pace, PacingProfile},
+    persona::Personas,
+    upstream::Upstream,
+};
+
+/// Request header with the ID of the session.
+pub const SESSION_HEADER: &str = "x-proxy-session";
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConfig {
+    /// Lifetime of the sessions.
+    #[serde(with = "humantime_serde")]
+    pub ttl: Duration,
+    /// Maximum number of sessions, past which the ones expiring first are recycled.
+    pub max_sessions: usize,
+    /// Personas new sessions pick one of at random, the persona of their first request if empty.
+    pub personas: Vec<String>,
+    /// Pacing profiles new sessions pick one of at random, not paced if empty.
+    pub pacing_profiles: Vec<String>,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            ttl: Duration::from_secs(30 * 60),
+            max_sessions: 10_000,
+            personas: Vec::new(),
+            pacing_profiles: Vec::new(),
+        }
+    }
+}
+
+pub struct Session {
+    /// Name of the persona of the session.
+    pub persona: String,
+    /// Headers of the persona, with the device of the session.
+    pub headers: HeaderMap,
+    pub upstream: Upstream,
+    pub pace: Option<Pace>,
+    cookies: Mutex<Vec<Cookie>>,
+    expires: Instant,
+}
+
+impl Session {
+    /// `Cookie` header of a request to `url`, if the session has cookies for it.
+    pub fn cookie_header(&self, url: &Url) -> Option<HeaderValue> {
+        let now = Instant::now();
+        let mut cookies = self.cookies.lock().unwrap();
+        cookies.retain(|cookie| cookie.expires.is_none_or(|expires| expires > now));
+        let header = cookies
+            .iter()
+            .filter(|cookie| cookie.matches(url))
+            .map(|cookie| format!("{}={}", cookie.name, cookie.value))
+            .collect::<Vec<_>>()
+            .join("; ");
+        (!header.is_empty())
+            .then(|| HeaderValue::try_from(header).ok())
+            .flatten()
+    }
+
+    /// Store the cookies set by the response of a request to `url`.
+    pub fn store_cookies(&self, url: &Url, headers: &HeaderMap) {
+        let mut cookies = self.cookies.lock().unwrap();
+        for value in headers.get_all(header::SET_COOKIE) {
+            let Some(cookie) = value
+                .to_str()
+                .ok()
+                .and_then(|value| Cookie::parse(url, value))
+            else {
+                continue;
+            };
+            cookies.retain(|other| {
+                (&other.name, &other.domain, &other.path)
+                    != (&cookie.name, &cookie.domain, &cookie.path)
+            });
+            if cookie
+                .expires
+                .is_none_or(|expires| expires > Instant::now())
+            {
+                cookies.push(cookie);
+            }
+        }
+    }
+}
+
+/// A cookie, with the subset of RFC 6265 origins commonly rely on.
+struct Cookie {
+    name: String,
+    value: String,
+    /// Lowercase domain, without leading dot.
+    domain: String,
+    /// Whether the cookie is only sent to the domain, and not its subdomains.
+    host_only: bool,
+    path: String,
+    secure: bool,
+    /// Session cookies last as long as the session.
+    expires: Option<Instant>,
+}
+
+impl Cookie {
+    fn parse(url: &Url, value: &str) -> Option<Self> {
+        let host = url.host_str()?.to_ascii_lowercase();
+        let mut attributes = value.split(';');
+        let (name, value) = attributes.next()?.split_once('=')?;
+        let mut cookie = Self {
+            name: name.trim().to_string(),
+            value: value.trim().to_string(),
+            domain: host.clone(),
+            host_only: true,
+            // the directory of the request path
+            path: match url.path().rfind('/') {
+                Some(0) | None => "/".to_string(),
+                Some(end) => url.path()[..end].to_string(),
+            },
+            secure: false,
+            expires: None,
+        };
+        if cookie.name.is_empty() {
+            return None;
+        }
+        let mut max_age = None;
+        for attribute in attributes {
+            let (name, value) = attribute.split_once('=').unwrap_or((attribute, ""));
+            let value = value.trim();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "domain" if !value.is_empty() => {
+                    let domain = value.trim_start_matches('.').to_ascii_lowercase();
+                    // origins can't set cookies for other domains
+                    if host != domain && !host.ends_with(&format!(".{domain}")) {
+                        return None;
+                    }
+                    cookie.domain = domain;
+                    cookie.host_only = false;
+                }
+                "path" if value.starts_with('/') => cookie.path = value.to_string(),
+                "secure" => cookie.secure = true,
+                "max-age" => max_age = value.parse::<i64>().ok(),
+                "expires" if max_age.is_none() => {
+                    cookie.expires = httpdate::parse_http_date(value).ok().map(|date| {
+                        let remaining = date
+                            .duration_since(std::time::SystemTime::now())
+                            .unwrap_or_default();
+                        Instant::now() + remaining
+                    });
+                }
+                _ => {}
+            }
+        }
+        if let Some(max_age) = max_age {
+            let max_age = Duration::from_secs(max_age.max(0).unsigned_abs());
+            cookie.expires = Some(Instant::now() + max_age);
+        }
+        Some(cookie)
+    }
+
+    fn matches(&self, url: &Url) -> bool {
+        let host = url.host_str().unwrap_or_default().to_ascii_lowercase();
+        let domain = if self.host_only {
+            host == self.domain
+        } else {
+            host == self.domain || host.ends_with(&format!(".{}", self.domain))
+        };
+        let path = url.path();
+        let path = path == self.path
+            || (path.starts_with(&self.path)
+                && (self.path.ends_with('/') || path[self.path.len()..].starts_with('/')));
+        domain && path && (!self.secure || url.scheme() == "https")
+    }
+}
+
+pub struct Sessions {
+    sessions: Mutex<HashMap<String, Arc<Session>>>,
+    config: SessionConfig,
+    /// The pacing profiles the sessions pick from.
+    profiles: Vec<PacingProfile>,
+}
+
+impl Sessions {
+    pub fn new(
+        config: SessionConfig,
+        personas: &Personas,
+        profiles: &[PacingProfile],
+    ) -> Result<Self> {
+        ensure!(config.max_sessions > 0, "`max_sessions` must be positive");
+        for name in &config.personas {
+            ensure!(
+                personas.get(name).is_some(),
+                "unknown session persona {name}"
+            );
+        }
+        let profiles = config
+            .pacing_profiles
+            .iter()
+            .map(|name| {
+                let profile = profiles
+                    .iter()
+                    .find(|profile| profile.name == *name)
+                    .with_context(|| format!("unknown session pacing profile {name}"))?;
+                // check the profile
+                Pace::new(profile.clone())?;
+                Ok(profile.clone())
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            sessions: Mutex::new(HashMap::new()),
+            config,
+            profiles,
+        })
+    }
+
+    /// Number of sessions that have not expired.
+    pub fn active(&self) -> usize {
+        let now = Instant::now();
+        self.sessions
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|session| session.expires > now)
+            .count()
+    }
+
+    /// The session `id`, started with `persona` unless the sessions draw their persona from a pool.
+    pub fn get(
+        &self,
+        id: &str,
+        persona: &str,
+        personas: &Personas,
+        upstream: &Upstream,
+    ) -> Result<Arc<Session>> {
+        let now = Instant::now();
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some(session) = sessions.get(id).filter(|session| session.expires > now) {
+            return Ok(session.clone());
+        }
+        if sessions.len() >= self.config.max_sessions {
+            sessions.retain(|_, session| session.expires > now);
+        }
+        if sessions.len() >= self.config.max_sessions {
+            let first = sessions
+                .iter()
+                .min_by_key(|(_, session)| session.expires)
+                .map(|(id, _)| id.clone());
+            if let Some(first) = first {
+                sessions.remove(&first);
+            }
+        }
+        let persona = self
+            .config
+            .personas
+            .choose(&mut rand::thread_rng())
+            .map_or(persona, String::as_str);
+        let pace = self
+            .profiles
+            .choose(&mut rand::thread_rng())
+            .map(|profile| Pace::new(profile.clone()))
+            .transpose()?;
+        tracing::debug!(
+            session = id,
+            persona,
+            pacing = pace.as_ref().map(Pace::name),
+            "Starting session"
+        );
+        let session = Arc::new(Session {
+            pace,
+            persona: persona.to_string(),
+            headers: personas.get(persona).unwrap_or(personas.default()).draw(),
+            upstream: upstream.fork_session(id)?,
+            cookies: Mutex::new(Vec::new()),
+            expires: now + self.config.ttl,
+        });
+        sessions.insert(id.to_string(), session.clone());
+        Ok(session)
+    }
+}
@@ -0,0 +1,760 @@
+//! State shared by the handlers of the proxy, set up with the options by [`ProxyBuilder::build`].
+use std::{
+    env,
+    os::fd::AsRawFd,
+    sync::{atomic::AtomicBool, Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use anyhow::{ensure, Context, Result};
+use axum::{http::StatusCode, middleware, routing::get, Router};
+use reqwest::{header::HeaderValue, Client, Url};
+use tower::ServiceBuilder;
+use tower_http::{catch_panic::CatchPanicLayer, trace::TraceLayer};
+
+use crate::{
+    access_log::{self, AccessLog},
+    admin,
+    alerts::Alerter,
+    anonymize,
+    auth::{Authenticator, StaticToken},
+    cache::{Cache, FailureCache, Lifetimes},
+    capture::Capture,
+    challenge::Challenges,
+    chaos::Chaos,
+    circuit::CircuitBreaker,
+    clamav::ClamAv,
+    coalesce::Coalescer,
+    concurrency::{self, ConcurrencyLimiter},
+    config::Config,
+    connector::Bridge,
+    data_saver::DataSaver,
+    dns::{CacheLimits, Resolver},
+    drain::{self, Drain},
+    errors::{self, ErrorPages},
+    forwarded::Forwarded,
+    geoip::GeoIp,
+    har::Recorder,
+    hedge::HedgeRule,
+    history::History,
+    hooks::{self, Hook},
+    html::Html,
+    integrity::Integrity,
+    listener,
+    maintenance::{self, Maintenance},
+    memory::{self, MemoryBudget},
+    metrics,
+    mock::Mocks,
+    oauth::OAuth,
+    pacing::Pacer,
+    pcap::Pcap,
+    persona::Personas,
+    pipeline::{handler, handler_404, SharedFetch},
+    plugins,
+    prefetch::Prefetcher,
+    presign::UrlSigner,
+    quota::{Limits, Quotas},
+    rate_limit::{self, AdaptiveLimiter, ClientLimiter, HostLimiter},
+    redact,
+    redirect::Redirects,
+    redis::Redis,
+    replay::{Recording, Replay},
+    retry::RetryPolicy,
+    rewrite::Rewrites,
+    routes::Routes,
+    schedule::Schedules,
+    scripts,
+    session::Sessions,
+    sigv4::SigV4,
+    statsd, tail, telemetry,
+    tenant::{Tenant, Tenants},
+    throttle::Throttle,
+    timing, tls, upgrade,
+    upstream::{self, Upstream},
+    usage::{self, Usage},
+    validate::{self, Validation},
+    Proxy, ProxyBuilder,
+};
+
+/// How often per-credential usage is persisted.
+const QUOTA_SAVE_INTERVAL: Duration = Duration::from_secs(10);
+/// How often the usage by day is added to the statistics database.
+const HISTORY_FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often recorded exchanges are written to the HAR file.
+const HAR_SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Clone)]
+pub struct AppState {
+    /// When the proxy started, for the uptime.
+    pub started: Instant,
+    /// Replaced when the default persona is switched with the admin API.
+    pub personas: Arc<RwLock<Arc<Personas>>>,
+    pub challenges: Option<Arc<Challenges>>,
+    pub sessions: Option<Arc<Sessions>>,
+    /// Bearer token of the clients, unless they authenticate with a custom authenticator.
+    pub auth_token: Arc<RwLock<String>>,
+    pub authenticator: Arc<dyn Authenticator>,
+    /// Access control of the clients by country, if enabled.
+    pub geoip: Option<Arc<GeoIp>>,
+    /// Verification of the pre-signed URLs, if the `URL_SIGNING_KEY` is set.
+    pub url_signer: Option<Arc<UrlSigner>>,
+    pub tenants: Arc<Tenants>,
+    /// Tenant of the request, in the state the requests of a tenant are handled with.
+    pub tenant: Option<Arc<Tenant>>,
+    pub upstream: Arc<Upstream>,
+    pub redirects: Arc<Redirects>,
+    pub retry: Arc<RetryPolicy>,
+    /// Number of times an interrupted streamed download is resumed.
+    pub resume_downloads: u32,
+    pub circuit: Option<Arc<CircuitBreaker>>,
+    pub hedge_rules: Arc<Vec<HedgeRule>>,
+    pub mocks: Arc<Mocks>,
+    pub schedules: Arc<Schedules>,
+    pub chaos: Arc<Chaos>,
+    pub html: Arc<Html>,
+    pub rewrites: Arc<Rewrites>,
+    pub sigv4: Arc<SigV4>,
+    pub oauth: Arc<OAuth>,
+    pub dns: Arc<Resolver>,
+    pub cache: Option<Arc<Cache>>,
+    /// Verification of the response bodies against their digests.
+    pub integrity: Arc<Integrity>,
+    /// Antivirus scanning of the responses, if enabled.
+    pub clamav: Option<Arc<ClamAv>>,
+    /// Recompression of the images for the clients saving data, if enabled.
+    pub data_saver: Option<Arc<DataSaver>>,
+    /// Warming of the cache with the subresources of the pages, if enabled.
+    pub prefetcher: Option<Arc<Prefetcher>>,
+    /// Upstream fetches of cacheable requests, shared between concurrent clients.
+    pub inflight: Arc<Coalescer<SharedFetch>>,
+    pub failures: Option<Arc<FailureCache>>,
+    /// Replaced when the config file is reloaded.
+    pub host_limits: Arc<RwLock<Arc<HostLimiter>>>,
+    pub backoff: Option<Arc<AdaptiveLimiter>>,
+    pub pacing: Arc<Pacer>,
+    pub throttle: Option<Arc<Throttle>>,
+    pub quotas: Option<Arc<Quotas>>,
+    /// Whether the proxy listener is bound, and not draining.
+    pub ready: Arc<AtomicBool>,
+    pub drain: Arc<Drain>,
+    pub maintenance: Arc<Maintenance>,
+    pub readiness_canary: Option<Url>,
+    /// Summaries of the requests, for the live tail of the admin API.
+    pub tail: Arc<tail::Tail>,
+    pub har: Option<Arc<Recorder>>,
+    pub pcap: Option<Arc<Pcap>>,
+    pub recording: Option<Arc<Recording>>,
+    pub replay: Option<Arc<Replay>>,
+    pub capture: Option<Arc<Capture>>,
+    pub usage: Arc<Usage>,
+    /// Largest response body that is buffered, see [`fetch`](crate::pipeline::fetch).
+    pub max_buffered_body: usize,
+    /// Largest timeout clients can ask for with the [`TIMEOUT_HEADER`](crate::pipeline::TIMEOUT_HEADER).
+    pub max_upstream_timeout: Option<Duration>,
+    /// `Accept-Encoding` sent to origins, the one of the client if not set.
+    pub accept_encoding: Option<HeaderValue>,
+    /// `X-Forwarded-*` headers sent to origins, if enabled.
+    pub forwarded: Option<Arc<Forwarded>>,
+    /// Whether responses are decompressed for clients not accepting their encoding.
+    pub decompress: bool,
+    /// Whether responses tell the bytes and the time they took.
+    pub accounting_headers: bool,
+}
+
+impl AppState {
+    /// The current personas.
+    pub fn personas(&self) -> Arc<Personas> {
+        self.personas.read().unwrap().clone()
+    }
+
+    /// The state the requests of `tenant` are handled with, using its connections and quotas.
+    pub fn for_tenant(&self, tenant: Arc<Tenant>) -> Self {
+        Self {
+            upstream: tenant.upstream.clone(),
+            quotas: tenant.quotas.clone(),
+            tenant: Some(tenant),
+            ..self.clone()
+        }
+    }
+
+    /// Apply the settings of `config` that can change at runtime: the `host_limits` for now.
+    pub fn apply_config(&self, config: Config) -> Result<()> {
+        let limits = HostLimiter::new(config.host_limits)?;
+        *self.host_limits.write().unwrap() = Arc::new(limits);
+        Ok(())
+    }
+
+    /// The quotas of the proxy and of the tenants.
+    pub fn all_quotas(&self) -> impl Iterator<Item = &Arc<Quotas>> {
+        self.quotas.iter().chain(
+            self.tenants
+                .iter()
+                .filter_map(|tenant| tenant.quotas.as_ref()),
+        )
+    }
+}
+
+impl ProxyBuilder {
+    /// Set up the proxy and bind its listeners, without serving yet.
+    pub async fn build(self) -> Result<Proxy> {
+        let Self {
+            cli,
+            auth_token,
+            addr,
+            listener,
+            signals,
+            routes,
+            hooks,
+            connector,
+            authenticator,
+        } = self;
+        anonymize::init(cli.anonymize_ips);
+        let rotation = cli.rotation();
+
+        let config = match &cli.config {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
+        redact::init(&config.logging.redact_headers)?;
+        let settings = format!("{cli:#?}\n{config:#?}\n");
+        let personas = Personas::new(config.personas, &cli.persona, cli.user_agent.clone())?;
+        let user_agent = personas.default().user_agent().to_string();
+        let har = cli.har_file.clone().map(|path| {
+            Arc::new(Recorder::new(
+                path,
+                cli.har_max_entries,
+                cli.har_body_limit as usize,
+                user_agent.clone(),
+            ))
+        });
+        let pcap = config
+            .pcap
+            .map(|config| Pcap::new(config, user_agent.clone()).map(Arc::new))
+            .transpose()?;
+        let dns = Arc::new(Resolver::new(
+            &config.dns,
+            cli.dns_cache.then_some(CacheLimits {
+                max_entries: cli.dns_cache_size,
+                min_ttl: cli.dns_min_ttl,
+                max_ttl: cli.dns_max_ttl,
+                prefetch: cli.dns_prefetch,
+            }),
+        )?);
+        let warm_urls = config
+            .upstream
+            .warm
+            .iter()
+            .map(|url| {
+                url.parse::<Url>()
+                    .with_context(|| format!("invalid warm URL {url}"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let accept_encoding = config
+            .upstream
+            .accept_encoding
+            .as_deref()
+            .map(HeaderValue::from_str)
+            .transpose()
+            .context("invalid accept_encoding")?;
+        let (upstream_timeout, upstream_read_timeout, upstream_config) = (
+            cli.upstream_timeout,
+            cli.upstream_read_timeout,
+            config.upstream,
+        );
+        let (pool_max_idle_per_host, pool_idle_timeout, connect_timeout) = (
+            cli.pool_max_idle_per_host,
+            cli.pool_idle_timeout,
+            cli.connect_timeout,
+        );
+        let bridge = match connector {
+            Some(connector) => Some(Bridge::start(connector).await?.proxy_url()),
+            None => None,
+        };
+        let upstream_routes = Arc::new(Routes::new(upstream_config.routes));
+        let redirects = Arc::new(Redirects::new(upstream_config.redirects)?);
+        let redirect_policy = redirects.clone();
+        let routed = if upstream_routes.is_empty() {
+            None
+        } else {
+            Some(Bridge::start(upstream_routes.clone()).await?.proxy_url())
+        };
+        let proxies = upstream_config
+            .proxies
+            .iter()
+            .map(|proxy| {
+                let url = proxy
+                    .parse::<Url>()
+                    .context("invalid URL of upstream proxy")?;
+                ensure!(
+                    ["http", "https", "socks5", "socks5h"].contains(&url.scheme()),
+                    "unsupported scheme {} of upstream proxy {}",
+                    url.scheme(),
+                    url.host_str().unwrap_or_default()
+                );
+                Ok(url)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        ensure!(
+            proxies.is_empty() || bridge.is_none(),
+            "upstream proxies can't be used with a custom connector"
+        );
+        let tls = tls::client_configs(
+            &upstream_config.tls,
+            cli.tls_key_log.as_deref(),
+            &proxies,
+            personas.tls_profiles(),
+        )?;
+        let resolver = dns.clone();
+        let balance = upstream_config.balance;
+        let upstream = Arc::new(Upstream::new(tls, proxies, balance, move |tls, proxy| {
+            let mut client = Client::builder()
+                .user_agent(&user_agent)
+                .dns_resolver(resolver.clone())
+                .use_preconfigured_tls(tls)
+                .pool_max_idle_per_host(pool_max_idle_per_host)
+                .pool_idle_timeout(pool_idle_timeout)
+                .connect_timeout(connect_timeout)
+                .tcp_nodelay(upstream_config.nodelay)
+                .tcp_keepalive(upstream_config.keepalive)
+                .redirect(redirect_policy.policy());
+            if let Some(timeout) = upstream_timeout {
+                client = client.timeout(timeout);
+            }
+            if let Some(timeout) = upstream_read_timeout {
+                client = client.read_timeout(timeout);
+            }
+            if let Some(bridge) = &routed {
+                // before the other proxies, so that the routed hosts don't go through them
+                let (routes, bridge) = (upstream_routes.clone(), bridge.clone());
+                client = client.proxy(reqwest::Proxy::custom(move |url| {
+                    routes.routes(url.host_str()?).then(|| bridge.clone())
+                }));
+            }
+            if let Some(proxy) = proxy.or(bridge.as_ref()) {
+                client = client.proxy(reqwest::Proxy::all(proxy.clone())?);
+            }
+            client.build()
+        })?);
+        let cache = cli.cache.then(|| {
+            let lifetimes = Lifetimes {
+                ttl: cli.cache_ttl,
+                stale_while_revalidate: cli.cache_stale_while_revalidate,
+                stale_if_error: cli.cache_stale_if_error,
+            };
+            Arc::new(Cache::new(
+                lifetimes,
+                cli.cache_max_entries,
+                config.cache.keys,
+            ))
+        });
+        ensure!(
+            config
+                .hedge
+                .iter()
+                .all(|rule| rule.percentile > 0.0 && rule.percentile <= 1.0),
+            "The percentile of hedge rules must be between 0 and 1"
+        );
+        let retry_statuses = cli
+            .retry_on_status
+            .iter()
+            .map(|status| {
+                StatusCode::from_u16(*status).with_context(|| format!("invalid status {status}"))
+            })
+            .collect::<Result<_>>()?;
+        let limits = Limits {
+            daily_requests: cli.daily_request_quota,
+            daily_bytes: cli.daily_byte_quota,
+            monthly_requests: cli.monthly_request_quota,
+            monthly_bytes: cli.monthly_byte_quota,
+        };
+        let redis = cli
+            .redis_url
+            .as_ref()
+            .map(|url| Redis::new(url, cli.redis_prefix.clone()).map(Arc::new))
+            .transpose()?;
+        let tenants = Tenants::new(
+            config.tenants,
+            &personas,
+            &upstream,
+            limits,
+            cli.quota_file.as_deref(),
+            redis.as_ref(),
+        )?;
+        let auth_token = Arc::new(RwLock::new(auth_token));
+        let app_state = AppState {
+            started: Instant::now(),
+            challenges: config
+                .challenges
+                .map(|challenges| Challenges::new(challenges, &personas).map(Arc::new))
+                .transpose()?,
+            sessions: config
+                .sessions
+                .map(|sessions| {
+                    Sessions::new(sessions, &personas, &config.pacing_profiles).map(Arc::new)
+                })
+                .transpose()?,
+            personas: Arc::new(RwLock::new(Arc::new(personas))),
+            authenticator: authenticator
+                .unwrap_or_else(|| Arc::new(StaticToken(auth_token.clone()))),
+            geoip: config.geoip.map(GeoIp::open).transpose()?.map(Arc::new),
+            auth_token,
+            url_signer: env::var("URL_SIGNING_KEY")
+                .ok()
+                .map(|key| Arc::new(UrlSigner::new(key))),
+            tenants: Arc::new(tenants),
+            tenant: None,
+            upstream,
+            redirects,
+            retry: Arc::new(RetryPolicy::new(
+                cli.retries,
+                cli.retry_backoff,
+                retry_statuses,
+                cli.max_retry_after,
+            )),
+            resume_downloads: cli.resume_downloads,
+            circuit: cli
+                .circuit_breaker_failures
+                .map(|failures| Arc::new(CircuitBreaker::new(failures, cli.circuit_breaker_open))),
+            hedge_rules: Arc::new(config.hedge),
+            mocks: Arc::new(Mocks::new(config.mocks)?),
+            schedules: Arc::new(Schedules::new(config.schedules)),
+            chaos: Arc::new(Chaos::new(config.chaos)?),
+            html: Arc::new(Html::new(config.html)),
+            rewrites: Arc::new(Rewrites::new(config.rewrites)?),
+            sigv4: Arc::new(SigV4::new(config.sigv4)?),
+            oauth: Arc::new(OAuth::new(config.oauth)?),
+            dns,
+            max_buffered_body: cli.max_buffered_body as usize,
+            max_upstream_timeout: cli.max_upstream_timeout,
+            accept_encoding,
+            forwarded: config
+                .forwarded
+                .map(|config| Arc::new(Forwarded::new(config))),
+            decompress: cli.decompress,
+            accounting_headers: cli.accounting_headers,
+            cache,
+            integrity: Arc::new(Integrity::new(cli.verify_digests, config.integrity)?),
+            clamav: config.clamav.map(|config| Arc::new(ClamAv::new(config))),
+            data_saver: config
+                .data_saver
+                .map(DataSaver::new)
+                .transpose()?
+                .map(Arc::new),
+            prefetcher: cli
+                .prefetch_subresources
+                .map(|max_per_page| Arc::new(Prefetcher::new(max_per_page))),
+            inflight: Arc::new(Coalescer::new()),
+            failures: cli
+                .negative_cache_ttl
+                .map(|ttl| Arc::new(FailureCache::new(ttl))),
+            host_limits: Arc::new(RwLock::new(Arc::new(HostLimiter::new(config.host_limits)?))),
+            backoff: cli
+                .adaptive_backoff
+                .map(|rate| AdaptiveLimiter::new(rate, cli.honor_retry_after).map(Arc::new))
+                .transpose()?,
+            pacing: Arc::new(Pacer::new(config.pacing)?),
+            throttle: Throttle::new(
+                cli.bandwidth_limit,
+                cli.connection_bandwidth_limit,
+                cli.credential_bandwidth_limit,
+            )
+            .map(Arc::new),
+            quotas: cli
+                .quota_file
+                .map(|path| {
+                    let quotas = Quotas::load(limits, path)?;
+                    Ok::<_, anyhow::Error>(Arc::new(match &redis {
+                        Some(redis) => quotas.shared(redis.clone(), ""),
+                        None => quotas,
+                    }))
+                })
+                .transpose()?,
+            ready: Arc::new(AtomicBool::new(false)),
+            drain: Arc::new(Drain::new(cli.drain_timeout, cli.shutdown_delay)),
+            maintenance: Arc::new(Maintenance::new(config.maintenance)),
+            readiness_canary: cli.readiness_canary,
+            tail: Arc::new(tail::Tail::new()),
+            har,
+            pcap,
+            recording: cli
+                .record
+                .clone()
+                .map(|dir| Recording::new(dir).map(Arc::new))
+                .transpose()?,
+            replay: cli
+                .replay
+                .as_deref()
+                .map(|dir| Replay::load(dir).map(Arc::new))
+                .transpose()?,
+            capture: Capture::new(
+                config.capture,
+                cli.trust_debug_header,
+                cli.debug_body_limit as usize,
+            )
+            .map(Arc::new),
+            usage: Arc::new(Usage::new(
+                cli.usage_export
+                    .clone()
+                    .map(|path| (path, cli.usage_export_format)),
+                cli.stats_db
+                    .as_deref()
+                    .map(|path| History::open(path, cli.stats_db_retention))
+                    .transpose()?,
+            )),
+        };
+        tokio::spawn(app_state.dns.clone().prefetch_loop());
+        if !warm_urls.is_empty() {
+            tokio::spawn(upstream::keep_warm(
+                app_state.upstream.clone(),
+                warm_urls,
+                upstream_config.warm_interval,
+            ));
+        }
+        if let Some(lifetime) = cli.pool_max_lifetime {
+            tokio::spawn(upstream::recycle_loop(app_state.upstream.clone(), lifetime));
+            for tenant in app_state.tenants.iter() {
+                tokio::spawn(upstream::recycle_loop(tenant.upstream.clone(), lifetime));
+            }
+        }
+
+        if let Some(alerts) = config.alerts {
+            tokio::spawn(Alerter::new(alerts)?.watch());
+        }
+
+        if signals {
+            let state = app_state.clone();
+            tokio::spawn(async move {
+                if let Err(err) = metrics::log_on_sigusr1(state).await {
+                    tracing::error!(error = %err, "Could not listen for SIGUSR1");
+                }
+            });
+        }
+
+        let quotas: Vec<_> = app_state.all_quotas().cloned().collect();
+        if !quotas.is_empty() {
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(QUOTA_SAVE_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    for quotas in &quotas {
+                        if let Err(err) = quotas.save() {
+                            tracing::error!(error = %err, "Could not save usage");
+                        }
+                    }
+                }
+            });
+        }
+
+        if let Some(har) = app_state.har.clone() {
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(HAR_SAVE_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    if let Err(err) = har.save() {
+                        tracing::error!(error = %err, "Could not save HAR file");
+                    }
+                }
+            });
+        }
+
+        if cli.stats_db.is_some() {
+            let usage = app_state.usage.clone();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(HISTORY_FLUSH_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    if let Err(err) = usage.flush_history() {
+                        tracing::error!(error = %err, "Could not save usage statistics");
+                    }
+                }
+            });
+        }
+
+        if cli.usage_export.is_some() {
+            let usage = app_state.usage.clone();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(cli.usage_export_interval);
+                // the first tick completes right away
+                interval.tick().await;
+                loop {
+                    interval.tick().await;
+                    if let Err(err) = usage.export() {
+                        tracing::error!(error = %err, "Could not export usage");
+                    }
+                }
+            });
+        }
+
+        if let Some(addr) = &cli.statsd {
+            let statsd = statsd::Statsd::connect(addr, cli.statsd_prefix.clone(), cli.statsd_tags)?;
+            let _ = statsd::STATSD.set(statsd);
+            tokio::spawn(statsd::flush_loop(app_state.clone(), cli.statsd_interval));
+        }
+
+        let compression_service = ServiceBuilder::new().layer(config.compression.layer());
+        let builtin_hooks: [Arc<dyn Hook>; 2] = [
+            Arc::new(telemetry::AssignRequestId),
+            Arc::new(drain::CloseConnections(app_state.drain.clone())),
+        ];
+
+        let mut app = Router::new().route("/", get(handler));
+        for (path, handler) in routes {
+            app = app.route_service(&path, handler);
+        }
+        if let Some(max_concurrent) = cli.max_concurrent_requests {
+            let limiter = Arc::new(ConcurrencyLimiter::new(
+                max_concurrent,
+                cli.concurrency_wait,
+                cli.concurrency_queue_size,
+            ));
+            app = app.route_layer(middleware::from_fn_with_state(
+                limiter,
+                concurrency::limit_concurrency,
+            ));
+        }
+        if let Some(limit) = cli.memory_budget {
+            let budget = Arc::new(MemoryBudget::new(limit, app_state.cache.clone()));
+            app = app.route_layer(middleware::from_fn_with_state(budget, memory::shed));
+        }
+        app = app.route_layer(middleware::from_fn_with_state(
+            app_state.maintenance.clone(),
+            maintenance::reject,
+        ));
+        let mut app = app.fallback(handler_404);
+        if let Some(rate) = cli.rate_limit {
+            ensure!(rate > 0.0, "The rate limit must be positive");
+            let limiter = Arc::new(ClientLimiter::new(
+                rate,
+                cli.rate_limit_burst,
+                redis.clone(),
+            ));
+            app = app.layer(middleware::from_fn_with_state(
+                limiter,
+                rate_limit::limit_by_ip,
+            ));
+        }
+        app = app.layer(middleware::from_fn_with_state(
+            cli.server_timing,
+            timing::measure,
+        ));
+        if let Some(path) = &cli.access_log {
+            let log = Arc::new(AccessLog::open(
+                path,
+                cli.access_log_format,
+                rotation,
+                cli.access_log_sample,
+            )?);
+            app = app.layer(middleware::from_fn_with_state(log, access_log::log_access));
+        }
+        if cli.strict {
+            let validation = Arc::new(Validation {
+                max_header_size: cli.max_header_size as usize,
+            });
+            app = app.layer(middleware::from_fn_with_state(
+                validation,
+                validate::validate,
+            ));
+        }
+        let mut app = app
+            .layer(middleware::from_fn_with_state(
+                app_state.tail.clone(),
+                tail::publish,
+            ))
+            .layer(middleware::from_fn_with_state(
+                app_state.usage.clone(),
+                usage::account,
+            ))
+            .layer(middleware::from_fn(metrics::track_responses));
+        let hooks: Vec<_> = hooks
+            .into_iter()
+            .chain(plugins::load(&config.plugins)?)
+            .chain(scripts::load(&config.scripts)?)
+            .collect();
+        if !hooks.is_empty() {
+            app = app.layer(middleware::from_fn_with_state(
+                Arc::from(hooks),
+                hooks::intercept,
+            ));
+        }
+        let mut app = app.layer(CatchPanicLayer::custom(errors::panicked)).layer(
+            middleware::from_fn_with_state(
+                Arc::new(ErrorPages::new(
+                    cli.error_format,
+                    cli.error_template.as_deref(),
+                )?),
+                errors::render,
+            ),
+        );
+        if let Some(cors) = &config.cors {
+            // preflights are answered before the clients are authenticated, since they carry no
+            // credentials
+            app = app.layer(cors.layer()?);
+        }
+        let app = app
+            .layer(TraceLayer::new_for_http().make_span_with(telemetry::make_span))
+            .layer(middleware::from_fn_with_state(
+                Arc::from(builtin_hooks),
+                hooks::intercept,
+            ))
+            .layer(compression_service)
+            .with_state(app_state.clone());
+
+        let mut admin_fd = None;
+        if let Some(admin_addr) = cli.admin_addr {
+            let token = env::var("ADMIN_TOKEN").ok();
+            ensure!(
+                token.is_some() || admin_addr.ip().is_loopback(),
+                "ADMIN_TOKEN must be set when the admin API doesn't listen on a loopback address"
+            );
+            let inherited = if signals {
+                upgrade::inherited_admin_listener()?
+            } else {
+                None
+            };
+            let listener = match inherited {
+                Some(listener) => listener,
+                None => tokio::net::TcpListener::bind(admin_addr).await?,
+            };
+            admin_fd = Some(listener.as_raw_fd());
+            let app_state = app_state.clone();
+            tokio::spawn(async move {
+                if let Err(err) = admin::serve(listener, app_state, token, settings).await {
+                    tracing::error!(error = %err, "Admin API failed");
+                }
+            });
+        }
+
+        let inherited = if signals {
+            upgrade::inherited_listeners()?
+        } else {
+            None
+        };
+        let listeners = match (inherited, listener) {
+            // the listeners of the tenants are among the inherited ones
+            (Some(listeners), _) => listeners,
+            (None, listener) => {
+                let mut listeners = match listener {
+                    Some(listener) => {
+                        listener.set_nonblocking(true)?;
+                        vec![tokio::net::TcpListener::from_std(listener)?]
+                    }
+                    None => listener::bind(addr, cli.acceptors, &config.listener)?,
+                };
+                for addr in app_state.tenants.listeners() {
+                    listeners.extend(listener::bind(addr, 1, &config.listener)?);
+                }
+                listeners
+            }
+        };
+        Ok(Proxy {
+            state: app_state,
+            app,
+            listeners,
+            admin_fd,
+            signals,
+            config_path: cli.config,
+            client_idle_timeout: cli.client_idle_timeout,
+        })
+    }
+}
@@ -0,0 +1,178 @@
+//! StatsD metrics sink, pushing the process-wide metrics over UDP.
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    net::UdpSocket,
+    sync::{atomic::Ordering, Mutex, OnceLock},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+
+use crate::{metrics::METRICS, AppState};
+
+/// Lines are batched into datagrams of at most this size, which fits in common MTUs.
+const MAX_DATAGRAM: usize = 1432;
+
+/// Sink installed at startup, if configured.
+pub static STATSD: OnceLock<Statsd> = OnceLock::new();
+
+/// Name, tags and value of a counter.
+type Counter = (&'static str, Vec<(&'static str, String)>, u64);
+
+pub struct Statsd {
+    socket: UdpSocket,
+    prefix: String,
+    /// Whether to use the DogStatsD tags extension, instead of putting the tags in the names.
+    tags: bool,
+    /// Last values of the counters, which are sent as increments.
+    counters: Mutex<HashMap<String, u64>>,
+}
+
+impl Statsd {
+    pub fn connect(addr: &str, prefix: String, tags: bool) -> Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket
+            .connect(addr)
+            .with_context(|| format!("could not resolve StatsD address {addr}"))?;
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket,
+            prefix,
+            tags,
+            counters: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Format a metric line, `value` including its type (e.g. `12|c`).
+    fn line(&self, name: &str, tags: &[(&str, &str)], value: &str) -> String {
+        let mut line = format!("{}.{name}", self.prefix);
+        if self.tags && !tags.is_empty() {
+            let tags: Vec<_> = tags
+                .iter()
+                .map(|(key, tag)| format!("{key}:{tag}"))
+                .collect();
+            let _ = write!(line, ":{value}|#{}", tags.join(","));
+        } else {
+            for (_, tag) in tags {
+                let _ = write!(line, ".{}", tag.replace(['.', ':', '|', '#'], "_"));
+            }
+            let _ = write!(line, ":{value}");
+        }
+        line
+    }
+
+    fn send(&self, lines: &[String]) {
+        let mut datagram = String::new();
+        for line in lines {
+            if !datagram.is_empty() && datagram.len() + line.len() + 1 > MAX_DATAGRAM {
+                let _ = self.socket.send(datagram.as_bytes());
+                datagram.clear();
+            }
+            if !datagram.is_empty() {
+                datagram.push('\n');
+            }
+            datagram.push_str(line);
+        }
+        if !datagram.is_empty() {
+            // metrics are best effort, don't bother when the agent is unreachable
+            let _ = self.socket.send(datagram.as_bytes());
+        }
+    }
+
+    /// Send a timing sample.
+    ///
+    /// The tags are dropped without the DogStatsD extension, as they may take too many values to
+    /// be part of metric names.
+    pub fn timing(&self, name: &str, tags: &[(&str, &str)], duration: Duration) {
+        let value = format!("{:.3}|ms", duration.as_secs_f64() * 1e3);
+        let tags = if self.tags { tags } else { &[] };
+        self.send(&[self.line(name, tags, &value)]);
+    }
+
+    /// Send the increments of the counters since the last flush, and the current gauges.
+    fn flush(&self, state: &AppState) {
+        let load = |counter: &std::sync::atomic::AtomicU64| counter.load(Ordering::Relaxed);
+        let mut counters: Vec<Counter> = vec![
+            ("auth_failures", vec![], load(&METRICS.auth_failures)),
+            ("panics", vec![], load(&METRICS.panics)),
+            ("shed_requests", vec![], load(&METRICS.shed)),
+            (
+                "upstream_bytes_received",
+                vec![],
+                load(&METRICS.bytes_received),
+            ),
+            ("client_bytes_sent", vec![], load(&METRICS.bytes_sent)),
+            (
+                "upstream_requests",
+                vec![],
+                load(&METRICS.upstream_requests),
+            ),
+            ("upstream_errors", vec![], load(&METRICS.upstream_errors)),
+            ("upstream_retries", vec![], load(&METRICS.upstream_retries)),
+            ("upstream_hedges", vec![], load(&METRICS.upstream_hedges)),
+        ];
+        for (class, counter) in METRICS.responses.iter().enumerate() {
+            let class = format!("{}xx", class + 1);
+            counters.push(("responses", vec![("class", class)], load(counter)));
+        }
+        for (rule, count) in METRICS.rate_limited() {
+            counters.push(("rate_limited", vec![("rule", rule.to_string())], count));
+        }
+        let mut gauges = vec![
+            ("active_connections", load(&METRICS.active_connections)),
+            ("buffered_bytes", load(&METRICS.buffered_bytes)),
+        ];
+        if let Some(cache) = &state.cache {
+            let stats = cache.stats();
+            for (status, count) in [
+                ("hit", stats.hits),
+                ("miss", stats.misses),
+                ("stale", stats.stale),
+                ("bypass", stats.bypasses),
+            ] {
+                counters.push(("cache_lookups", vec![("status", status.to_string())], count));
+            }
+            counters.push(("cache_evictions", vec![], stats.evictions));
+            gauges.push(("cache_entries", stats.entries as u64));
+            gauges.push(("cache_stored_bytes", stats.stored_bytes as u64));
+        }
+        if let Some(stats) = state.dns.stats() {
+            for (status, count) in [("hit", stats.hits), ("miss", stats.misses)] {
+                counters.push((
+                    "dns_cache_lookups",
+                    vec![("status", status.to_string())],
+                    count,
+                ));
+            }
+            gauges.push(("dns_cache_entries", stats.entries as u64));
+        }
+
+        let mut last = self.counters.lock().unwrap();
+        let mut lines = Vec::new();
+        for (name, tags, value) in counters {
+            let tags: Vec<_> = tags.iter().map(|(key, tag)| (*key, tag.as_str())).collect();
+            let key = self.line(name, &tags, "");
+            let previous = last.insert(key, value).unwrap_or_default();
+            if value > previous {
+                lines.push(self.line(name, &tags, &format!("{}|c", value - previous)));
+            }
+        }
+        for (name, value) in gauges {
+            lines.push(self.line(name, &[], &format!("{value}|g")));
+        }
+        self.send(&lines);
+    }
+}
+
+/// Flush the metrics to the StatsD sink every `interval`.
+pub async fn flush_loop(state: AppState, interval: Duration) {
+    let Some(statsd) = STATSD.get() else {
+        return;
+    };
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        statsd.flush(&state);
+    }
+}
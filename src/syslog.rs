@@ -0,0 +1,250 @@
+//! Log shipping to a syslog server, as RFC 5424 messages over UDP, TCP or TLS.
+use std::{
+    fs,
+    io::{self, Write},
+    net::{TcpStream, UdpSocket},
+    os::unix::net::UnixDatagram,
+    sync::{
+        mpsc::{self, Receiver, SyncSender},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{SecondsFormat, Utc};
+use clap::ValueEnum;
+use reqwest::Url;
+use rustls::{pki_types::ServerName, ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use tracing::{Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// Number of messages buffered while the server is unreachable, past which they are dropped.
+const QUEUE_SIZE: usize = 1024;
+/// Delay before reconnecting to the server after a failure.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum Facility {
+    User = 1,
+    Daemon = 3,
+    Local0 = 16,
+    Local1 = 17,
+    Local2 = 18,
+    Local3 = 19,
+    Local4 = 20,
+    Local5 = 21,
+    Local6 = 22,
+    Local7 = 23,
+}
+
+enum Destination {
+    Udp(String),
+    Tcp(String),
+    Tls {
+        addr: String,
+        host: String,
+    },
+    /// Local syslog socket, usually `/dev/log`.
+    Unix(String),
+}
+
+impl Destination {
+    /// Parse a `udp://`, `tcp://`, `tls://` or `unix://` URL.
+    fn parse(url: &str) -> Result<Self> {
+        let url: Url = url.parse().context("invalid syslog URL")?;
+        if url.scheme() == "unix" {
+            return Ok(Self::Unix(url.path().to_string()));
+        }
+        let host = url
+            .host_str()
+            .ok_or_else(|| anyhow!("the syslog URL must have a host"))?
+            .to_string();
+        let addr = |default_port| format!("{host}:{}", url.port().unwrap_or(default_port));
+        Ok(match url.scheme() {
+            "udp" => Self::Udp(addr(514)),
+            "tcp" => Self::Tcp(addr(601)),
+            "tls" => Self::Tls {
+                addr: addr(6514),
+                host,
+            },
+            scheme => bail!("unsupported syslog scheme `{scheme}`"),
+        })
+    }
+}
+
+enum Connection {
+    Udp(UdpSocket),
+    Stream(Box<dyn Write + Send>),
+    Unix(UnixDatagram),
+}
+
+impl Connection {
+    fn open(destination: &Destination, tls: &Arc<ClientConfig>) -> io::Result<Self> {
+        Ok(match destination {
+            Destination::Udp(addr) => {
+                let socket = UdpSocket::bind("0.0.0.0:0")?;
+                socket.connect(addr)?;
+                Self::Udp(socket)
+            }
+            Destination::Tcp(addr) => Self::Stream(Box::new(TcpStream::connect(addr)?)),
+            Destination::Tls { addr, host } => {
+                let name = ServerName::try_from(host.clone())
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+                let connection =
+                    ClientConnection::new(tls.clone(), name).map_err(io::Error::other)?;
+                let stream = StreamOwned::new(connection, TcpStream::connect(addr)?);
+                Self::Stream(Box::new(stream))
+            }
+            Destination::Unix(path) => {
+                let socket = UnixDatagram::unbound()?;
+                socket.connect(path)?;
+                Self::Unix(socket)
+            }
+        })
+    }
+
+    fn send(&mut self, message: &[u8]) -> io::Result<()> {
+        match self {
+            Self::Udp(socket) => socket.send(message).map(drop),
+            Self::Unix(socket) => socket.send(message).map(drop),
+            // octet counting framing, see RFC 6587
+            Self::Stream(stream) => {
+                write!(stream, "{} ", message.len())?;
+                stream.write_all(message)?;
+                stream.flush()
+            }
+        }
+    }
+}
+
+/// Writer for the fmt layer, turning each event into a syslog message.
+///
+/// Messages are sent by a background thread, so that logging never blocks on the network.
+#[derive(Clone)]
+pub struct Syslog {
+    sender: SyncSender<Vec<u8>>,
+    facility: Facility,
+    hostname: Arc<str>,
+}
+
+impl Syslog {
+    pub fn connect(url: &str, facility: Facility) -> Result<Self> {
+        let destination = Destination::parse(url)?;
+        let roots = RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+        let tls = Arc::new(
+            ClientConfig::builder()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        );
+        // fail early on obvious misconfigurations
+        let connection = Connection::open(&destination, &tls)
+            .with_context(|| format!("could not connect to syslog at {url}"))?;
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+        thread::Builder::new()
+            .name("syslog".to_string())
+            .spawn(move || send_loop(destination, tls, connection, receiver))?;
+        let hostname = fs::read_to_string("/proc/sys/kernel/hostname")
+            .map(|hostname| hostname.trim().to_string())
+            .unwrap_or_else(|_| "-".to_string());
+        Ok(Self {
+            sender,
+            facility,
+            hostname: hostname.into(),
+        })
+    }
+
+    fn message(&self, level: Level) -> Message {
+        let severity = match level {
+            Level::ERROR => 3,
+            Level::WARN => 4,
+            Level::INFO => 6,
+            Level::DEBUG | Level::TRACE => 7,
+        };
+        let priority = self.facility as u8 * 8 + severity;
+        let header = format!(
+            "<{priority}>1 {} {} {} {} - - ",
+            Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true),
+            self.hostname,
+            env!("CARGO_PKG_NAME"),
+            std::process::id(),
+        );
+        Message {
+            buf: header.into_bytes(),
+            sender: self.sender.clone(),
+        }
+    }
+}
+
+fn send_loop(
+    destination: Destination,
+    tls: Arc<ClientConfig>,
+    connection: Connection,
+    receiver: Receiver<Vec<u8>>,
+) {
+    let mut connection = Some(connection);
+    for message in receiver {
+        loop {
+            let result = match &mut connection {
+                Some(connection) => connection.send(&message),
+                None => Connection::open(&destination, &tls)
+                    .and_then(|new| connection.insert(new).send(&message)),
+            };
+            match result {
+                Ok(()) => break,
+                // datagrams are lost anyway when the server is down, don't retry them
+                Err(_) if matches!(destination, Destination::Udp(_) | Destination::Unix(_)) => {
+                    break
+                }
+                Err(err) => {
+                    // can't log this through tracing, it would end up in the same queue
+                    eprintln!("Could not send log to syslog: {err}");
+                    connection = None;
+                    thread::sleep(RECONNECT_DELAY);
+                }
+            }
+        }
+    }
+}
+
+impl<'a> MakeWriter<'a> for Syslog {
+    type Writer = Message;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.message(Level::INFO)
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        self.message(*meta.level())
+    }
+}
+
+/// A syslog message, sent when dropped after the event has been written into it.
+pub struct Message {
+    buf: Vec<u8>,
+    sender: SyncSender<Vec<u8>>,
+}
+
+impl Write for Message {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Message {
+    fn drop(&mut self) {
+        if self.buf.last() == Some(&b'\n') {
+            self.buf.pop();
+        }
+        // drop the message rather than blocking when the queue is full
+        let _ = self.sender.try_send(std::mem::take(&mut self.buf));
+    }
+}
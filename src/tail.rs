@@ -0,0 +1,172 @@
+//! Live summary of the requests, streamed to admin API clients, and the recently denied ones.
+use std::{
+    collections::{HashMap, VecDeque},
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{ConnectInfo, Query, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{
+        sse::{Event, KeepAlive},
+        Response, Sse,
+    },
+};
+use chrono::{SecondsFormat, Utc};
+use futures_util::{stream, Stream};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::{
+    anonymize,
+    quota::{bearer_token, credential_id},
+};
+
+/// Number of summaries buffered for slow subscribers, past which they miss some.
+const CAPACITY: usize = 1024;
+/// Number of denied requests kept.
+const DENIED_CAPACITY: usize = 100;
+
+#[derive(Clone, Serialize)]
+pub struct Summary {
+    /// When the response was sent, in RFC 3339 format.
+    time: String,
+    method: String,
+    host: Option<String>,
+    status: u16,
+    duration_ms: f64,
+    client_ip: String,
+    /// Stable id of the credential, see [`credential_id`].
+    credential: Option<String>,
+}
+
+pub struct Tail {
+    sender: broadcast::Sender<Arc<Summary>>,
+    /// The last requests denied by the proxy, from the oldest.
+    denied: Mutex<VecDeque<Arc<Summary>>>,
+}
+
+impl Tail {
+    pub fn new() -> Self {
+        Self {
+            sender: broadcast::channel(CAPACITY).0,
+            denied: Mutex::new(VecDeque::with_capacity(DENIED_CAPACITY)),
+        }
+    }
+
+    /// The requests recently denied for lack of credentials, access or rate limit, from the most
+    /// recent.
+    pub fn denied(&self) -> Vec<Summary> {
+        let denied = self.denied.lock().unwrap();
+        denied
+            .iter()
+            .rev()
+            .map(|summary| (**summary).clone())
+            .collect()
+    }
+}
+
+fn is_denied(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::UNAUTHORIZED
+            | StatusCode::FORBIDDEN
+            | StatusCode::PROXY_AUTHENTICATION_REQUIRED
+            | StatusCode::TOO_MANY_REQUESTS
+    )
+}
+
+/// Middleware publishing a summary of each request if anyone is listening, and keeping the ones of
+/// the denied requests.
+pub async fn publish(
+    State(tail): State<Arc<Tail>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let start = Instant::now();
+    let method = request.method().clone();
+    let uri = request.uri().clone();
+    let token = bearer_token(request.headers()).map(str::to_string);
+    let response = next.run(request).await;
+    let denied = is_denied(response.status());
+    if !denied && tail.sender.receiver_count() == 0 {
+        return response;
+    }
+    let host = Query::<HashMap<String, String>>::try_from_uri(&uri)
+        .ok()
+        .and_then(|Query(params)| params.get("url")?.parse::<Url>().ok())
+        .and_then(|url| url.host_str().map(str::to_string));
+    let summary = Arc::new(Summary {
+        time: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        method: method.to_string(),
+        host,
+        status: response.status().as_u16(),
+        duration_ms: start.elapsed().as_secs_f64() * 1e3,
+        client_ip: anonymize::client_ip(addr.ip()),
+        credential: token.as_deref().map(credential_id),
+    });
+    if denied {
+        let mut recent = tail.denied.lock().unwrap();
+        if recent.len() == DENIED_CAPACITY {
+            recent.pop_front();
+        }
+        recent.push_back(summary.clone());
+    }
+    let _ = tail.sender.send(summary);
+    response
+}
+
+#[derive(Deserialize)]
+pub struct Filter {
+    host: Option<String>,
+    /// Either a credential or its id.
+    credential: Option<String>,
+}
+
+impl Filter {
+    fn matches(&self, summary: &Summary) -> bool {
+        let host = self
+            .host
+            .as_ref()
+            .is_none_or(|host| summary.host.as_ref() == Some(host));
+        let credential = self.credential.as_ref().is_none_or(|credential| {
+            summary
+                .credential
+                .as_ref()
+                .is_some_and(|id| id == credential || *id == credential_id(credential))
+        });
+        host && credential
+    }
+}
+
+/// Stream the summaries of the requests matching `filter` as server-sent events.
+pub fn subscribe(
+    tail: &Tail,
+    filter: Filter,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let stream = stream::unfold(
+        (tail.sender.subscribe(), filter),
+        |(mut receiver, filter)| async move {
+            loop {
+                let event = match receiver.recv().await {
+                    Ok(summary) if filter.matches(&summary) => {
+                        Event::default().json_data(&*summary).ok()?
+                    }
+                    Ok(_) => continue,
+                    Err(RecvError::Lagged(missed)) => {
+                        Event::default().event("lagged").data(missed.to_string())
+                    }
+                    Err(RecvError::Closed) => return None,
+                };
+                return Some((Ok(event), (receiver, filter)));
+            }
+        },
+    );
+    Sse::new(stream).keep_alive(KeepAlive::new().interval(Duration::from_secs(15)))
+}
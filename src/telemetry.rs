@@ -0,0 +1,297 @@
+//! Logging setup and OpenTelemetry trace export.
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Mutex, OnceLock},
+};
+
+use anyhow::{anyhow, ensure, Result};
+use async_trait::async_trait;
+use axum::{
+    extract::{ConnectInfo, Query},
+    http::{HeaderMap, HeaderName, HeaderValue, Request},
+};
+use clap::ValueEnum;
+use opentelemetry::{
+    global,
+    propagation::{Extractor, Injector},
+    trace::{TraceContextExt, TracerProvider as _},
+    KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{
+    propagation::TraceContextPropagator,
+    runtime,
+    trace::{Sampler, TracerProvider},
+    Resource,
+};
+use rand::Rng;
+use reqwest::Url;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{
+    field::RecordFields,
+    fmt::{
+        format::{DefaultFields, Writer},
+        FormatFields, MakeWriter,
+    },
+    layer::SubscriberExt,
+    registry::LookupSpan,
+    reload,
+    util::SubscriberInitExt,
+    EnvFilter, Layer, Registry,
+};
+
+use crate::{
+    anonymize,
+    hooks::{Flow, Hook},
+    log_file::RotatingFile,
+    syslog::Syslog,
+};
+
+const DEFAULT_FILTER: &str = "simple_proxy=debug,tower_http=debug,axum::rejection=trace";
+
+/// Handle to change the log filter at runtime.
+static FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+/// Log filter the proxy started with, restored by [`reset_log_filter`].
+static STARTUP_FILTER: OnceLock<String> = OnceLock::new();
+
+/// Request header carrying the id of a request, generated by the proxy if the client sent none.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum LogFormat {
+    /// Human-readable lines
+    Text,
+    /// One JSON object per line, including the fields of the current request
+    Json,
+}
+
+/// Install the global tracing subscriber, logging to `log_file` or stdout, as well as to `syslog`
+/// and exporting spans to `otlp_endpoint` if set.
+///
+/// The returned provider must be shut down before exiting, so that the pending spans are flushed.
+pub fn init(
+    format: LogFormat,
+    log_file: Option<RotatingFile>,
+    syslog: Option<Syslog>,
+    otlp_endpoint: Option<&str>,
+    sample_ratio: f64,
+) -> Result<Option<TracerProvider>> {
+    ensure!(
+        (0.0..=1.0).contains(&sample_ratio),
+        "The trace sample ratio must be between 0 and 1"
+    );
+    let provider = otlp_endpoint
+        .map(|endpoint| {
+            let exporter = opentelemetry_otlp::SpanExporter::builder()
+                .with_http()
+                .with_endpoint(endpoint)
+                .build()?;
+            // follow the sampling decision of the client when it sent a `traceparent`
+            let sampler = Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(sample_ratio)));
+            anyhow::Ok(
+                TracerProvider::builder()
+                    .with_batch_exporter(exporter, runtime::TokioCurrentThread)
+                    .with_sampler(sampler)
+                    .with_resource(Resource::new([KeyValue::new(
+                        "service.name",
+                        env!("CARGO_PKG_NAME"),
+                    )]))
+                    .build(),
+            )
+        })
+        .transpose()?;
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| DEFAULT_FILTER.into());
+    let _ = STARTUP_FILTER.set(filter.to_string());
+    let (filter, handle) = reload::Layer::new(filter);
+    let _ = FILTER.set(handle);
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(match log_file {
+            Some(file) => fmt_layer(format, Mutex::new(file), false),
+            None => fmt_layer(format, std::io::stdout, true),
+        })
+        .with(syslog.map(|syslog| {
+            // syslog messages have their own timestamp
+            tracing_subscriber::fmt::layer()
+                .with_writer(syslog)
+                .with_ansi(false)
+                .fmt_fields(PlainFields::default())
+                .without_time()
+        }))
+        .with(provider.as_ref().map(|provider| {
+            tracing_opentelemetry::layer().with_tracer(provider.tracer(env!("CARGO_PKG_NAME")))
+        }))
+        .init();
+    Ok(provider)
+}
+
+/// Current log filter, in the `RUST_LOG` syntax.
+pub fn log_filter() -> Option<String> {
+    FILTER.get()?.with_current(|filter| filter.to_string()).ok()
+}
+
+/// Replace the log filter with `directives`, in the `RUST_LOG` syntax.
+pub fn set_log_filter(directives: &str) -> Result<()> {
+    let filter = EnvFilter::try_new(directives)?;
+    let handle = FILTER
+        .get()
+        .ok_or_else(|| anyhow!("logging is not initialized"))?;
+    handle.reload(filter)?;
+    Ok(())
+}
+
+/// Restore the log filter the proxy started with.
+pub fn reset_log_filter() -> Result<()> {
+    set_log_filter(STARTUP_FILTER.get().map_or(DEFAULT_FILTER, String::as_str))
+}
+
+/// Switch between the startup log filter and `verbose` on each `SIGUSR2`, for hosts where the
+/// admin API is not reachable.
+pub async fn toggle_on_sigusr2(verbose: String) -> Result<()> {
+    let mut signals = signal(SignalKind::user_defined2())?;
+    let mut is_verbose = false;
+    while signals.recv().await.is_some() {
+        let result = if is_verbose {
+            reset_log_filter()
+        } else {
+            set_log_filter(&verbose)
+        };
+        match result {
+            Ok(()) => {
+                is_verbose = !is_verbose;
+                tracing::info!(filter = log_filter(), "Changed log filter");
+            }
+            Err(err) => tracing::error!(error = %err, "Could not change log filter"),
+        }
+    }
+    Ok(())
+}
+
+fn fmt_layer<S, W>(format: LogFormat, writer: W, ansi: bool) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: tracing::Subscriber + for<'span> LookupSpan<'span>,
+    W: for<'writer> MakeWriter<'writer> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(ansi);
+    match format {
+        LogFormat::Text => layer.boxed(),
+        LogFormat::Json => layer
+            .json()
+            .with_current_span(true)
+            .with_span_list(false)
+            .boxed(),
+    }
+}
+
+/// Span fields formatter of the syslog layer.
+///
+/// Formatted span fields are cached in the span by formatter type, so this has to be a different
+/// type than the one of the stdout layer, which may include ANSI colors.
+#[derive(Default)]
+struct PlainFields(DefaultFields);
+
+impl<'writer> FormatFields<'writer> for PlainFields {
+    fn format_fields<R: RecordFields>(
+        &self,
+        writer: Writer<'writer>,
+        fields: R,
+    ) -> std::fmt::Result {
+        self.0.format_fields(writer, fields)
+    }
+}
+
+/// Hook giving an id to the requests without one, so that the logs and the error responses tell
+/// the same.
+pub struct AssignRequestId;
+
+#[async_trait]
+impl Hook for AssignRequestId {
+    async fn on_request(&self, mut request: axum::extract::Request) -> Flow {
+        if !request.headers().contains_key(REQUEST_ID_HEADER) {
+            let id = format!("{:016x}", rand::thread_rng().gen::<u64>());
+            request.headers_mut().insert(
+                REQUEST_ID_HEADER,
+                HeaderValue::from_str(&id).expect("hexadecimal ids are valid header values"),
+            );
+        }
+        Flow::Continue(request)
+    }
+}
+
+/// Span of an incoming request, continuing the trace of the client if it sent a `traceparent`.
+pub fn make_span<B>(request: &Request<B>) -> Span {
+    let request_id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("{:016x}", rand::thread_rng().gen::<u64>()));
+    let client_ip = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| anonymize::client_ip(addr.ip()));
+    let target_host = Query::<HashMap<String, String>>::try_from_uri(request.uri())
+        .ok()
+        .and_then(|Query(params)| params.get("url")?.parse::<Url>().ok())
+        .and_then(|url| url.host_str().map(str::to_string));
+    let span = tracing::debug_span!(
+        "request",
+        method = %request.method(),
+        uri = %request.uri(),
+        version = ?request.version(),
+        request_id,
+        client_ip,
+        target_host,
+    );
+    let parent = global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderAccess(request.headers()))
+    });
+    span.set_parent(parent);
+    span
+}
+
+/// Id of the trace of the current span if it is sampled, for the exemplars of the metrics.
+pub fn trace_id() -> Option<String> {
+    let context = Span::current().context();
+    let span = context.span();
+    let span = span.span_context();
+    (span.is_valid() && span.is_sampled()).then(|| span.trace_id().to_string())
+}
+
+/// Add the trace context of `span` to the headers of an upstream request.
+pub fn inject(span: &Span, headers: &mut HeaderMap) {
+    let context = span.context();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut HeaderAccess(&mut *headers))
+    });
+}
+
+struct HeaderAccess<H>(H);
+
+impl Extractor for HeaderAccess<&HeaderMap> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(HeaderName::as_str).collect()
+    }
+}
+
+impl Injector for HeaderAccess<&mut HeaderMap> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
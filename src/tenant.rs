@@ -0,0 +1,207 @@
+//! Tenants served by the same process, each with its own credentials, headers, allowed hosts,
+//! quotas and upstream connections.
+//!
+//! A request belongs to the tenant whose listener accepted it, or else to the tenant owning its
+//! bearer token. The requests of a tenant don't share cache entries, sessions or connections with
+//! the ones of the other tenants.
+use std::{
+    collections::{BTreeMap, HashSet},
+    net::SocketAddr,
+    path::Path,
+    sync::Arc,
+};
+
+use anyhow::{ensure, Context, Result};
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use serde::Deserialize;
+
+use crate::{
+    config::HostPattern,
+    persona::Personas,
+    presign::constant_time_eq,
+    quota::{Limits, Quotas},
+    redact::Secret,
+    redis::Redis,
+    upstream::Upstream,
+};
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TenantConfig {
+    pub name: String,
+    /// Bearer tokens of the clients of the tenant, the ones of the proxy on its listener if empty.
+    #[serde(default)]
+    pub tokens: Vec<Secret<String>>,
+    /// Address of a listener of its own, whose requests all belong to the tenant.
+    pub listen: Option<SocketAddr>,
+    /// Persona of the requests not asking for one.
+    pub persona: Option<String>,
+    /// Headers of the upstream requests, replacing the ones of the persona.
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Hosts the tenant may reach, all of them if empty.
+    #[serde(default)]
+    pub allowed_hosts: Vec<HostPattern>,
+    /// Hosts the tenant may not reach, even if allowed.
+    #[serde(default)]
+    pub denied_hosts: Vec<HostPattern>,
+    /// Quotas of each credential of the tenant, the ones of the command line if not set.
+    pub daily_request_quota: Option<u64>,
+    pub daily_byte_quota: Option<u64>,
+    pub monthly_request_quota: Option<u64>,
+    pub monthly_byte_quota: Option<u64>,
+}
+
+pub struct Tenant {
+    pub name: String,
+    tokens: Vec<String>,
+    listen: Option<SocketAddr>,
+    pub persona: Option<String>,
+    pub headers: HeaderMap,
+    allowed_hosts: Vec<HostPattern>,
+    denied_hosts: Vec<HostPattern>,
+    pub upstream: Arc<Upstream>,
+    pub quotas: Option<Arc<Quotas>>,
+}
+
+impl Tenant {
+    /// Whether the clients of the tenant authenticate with tokens of their own.
+    pub fn has_tokens(&self) -> bool {
+        !self.tokens.is_empty()
+    }
+
+    pub fn owns(&self, token: &str) -> bool {
+        self.tokens
+            .iter()
+            .any(|owned| constant_time_eq(owned, token))
+    }
+
+    pub fn allows(&self, host: &str) -> bool {
+        (self.allowed_hosts.is_empty()
+            || self
+                .allowed_hosts
+                .iter()
+                .any(|pattern| pattern.matches(host)))
+            && !self
+                .denied_hosts
+                .iter()
+                .any(|pattern| pattern.matches(host))
+    }
+}
+
+/// Tenant of the requests accepted by its listener, as a request extension.
+#[derive(Clone)]
+pub struct ListenerTenant(pub Arc<Tenant>);
+
+pub struct Tenants {
+    tenants: Vec<Arc<Tenant>>,
+}
+
+impl Tenants {
+    /// The tenants of the `configs`, whose usage is persisted next to the `quota_file` if set, and
+    /// shared through `redis` if set.
+    pub fn new(
+        configs: Vec<TenantConfig>,
+        personas: &Personas,
+        upstream: &Upstream,
+        limits: Limits,
+        quota_file: Option<&Path>,
+        redis: Option<&Arc<Redis>>,
+    ) -> Result<Self> {
+        let mut names = HashSet::new();
+        let mut tokens = HashSet::new();
+        let tenants = configs
+            .into_iter()
+            .map(|config| {
+                let name = config.name;
+                ensure!(names.insert(name.clone()), "duplicate tenant {name}");
+                ensure!(
+                    config.listen.is_some() || !config.tokens.is_empty(),
+                    "tenant {name} has neither tokens nor a listener"
+                );
+                for token in &config.tokens {
+                    ensure!(
+                        tokens.insert(token.to_string()),
+                        "a token of tenant {name} belongs to another tenant"
+                    );
+                }
+                if let Some(persona) = &config.persona {
+                    ensure!(
+                        personas.get(persona).is_some(),
+                        "unknown persona {persona} of tenant {name}"
+                    );
+                }
+                let headers = config
+                    .headers
+                    .iter()
+                    .map(|(name, value)| {
+                        Ok((
+                            HeaderName::try_from(name.as_str())?,
+                            HeaderValue::try_from(value.as_str())?,
+                        ))
+                    })
+                    .collect::<Result<_>>()
+                    .with_context(|| format!("invalid headers of tenant {name}"))?;
+                let limits = Limits {
+                    daily_requests: config.daily_request_quota.or(limits.daily_requests),
+                    daily_bytes: config.daily_byte_quota.or(limits.daily_bytes),
+                    monthly_requests: config.monthly_request_quota.or(limits.monthly_requests),
+                    monthly_bytes: config.monthly_byte_quota.or(limits.monthly_bytes),
+                };
+                let quotas = match quota_file {
+                    Some(path) => {
+                        let path = path.with_extension(format!("{name}.json"));
+                        let quotas = Quotas::load(limits, path)?;
+                        Some(Arc::new(match redis {
+                            Some(redis) => quotas.shared(redis.clone(), &format!("{name}/")),
+                            None => quotas,
+                        }))
+                    }
+                    None => {
+                        ensure!(
+                            config.daily_request_quota.is_none()
+                                && config.daily_byte_quota.is_none()
+                                && config.monthly_request_quota.is_none()
+                                && config.monthly_byte_quota.is_none(),
+                            "the quotas of tenant {name} require --quota-file"
+                        );
+                        None
+                    }
+                };
+                Ok(Arc::new(Tenant {
+                    tokens: config.tokens.into_iter().map(|token| token.0).collect(),
+                    listen: config.listen,
+                    persona: config.persona,
+                    headers,
+                    allowed_hosts: config.allowed_hosts,
+                    denied_hosts: config.denied_hosts,
+                    upstream: Arc::new(upstream.fork()?),
+                    quotas,
+                    name,
+                }))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { tenants })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<Tenant>> {
+        self.tenants.iter()
+    }
+
+    /// Addresses of the listeners of the tenants.
+    pub fn listeners(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        self.tenants.iter().filter_map(|tenant| tenant.listen)
+    }
+
+    /// The tenant listening on `addr`.
+    pub fn by_listener(&self, addr: SocketAddr) -> Option<&Arc<Tenant>> {
+        self.tenants
+            .iter()
+            .find(|tenant| tenant.listen == Some(addr))
+    }
+
+    /// The tenant owning the bearer `token`.
+    pub fn by_token(&self, token: &str) -> Option<&Arc<Tenant>> {
+        self.tenants.iter().find(|tenant| tenant.owns(token))
+    }
+}
@@ -0,0 +1,100 @@
+//! Bandwidth throttling of proxied bodies.
+use std::{hash::Hash, net::SocketAddr, sync::Arc, time::Duration};
+
+use axum::body::{Body, Bytes};
+use futures_util::{Stream, StreamExt};
+
+use crate::rate_limit::KeyedLimiter;
+
+/// Bodies are sent in chunks of at most this size, so that the pacing stays smooth.
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// Byte rate limits, each one being a number of bytes per second.
+pub struct Throttle {
+    global: Option<KeyedLimiter<()>>,
+    per_connection: Option<KeyedLimiter<SocketAddr>>,
+    per_credential: Option<KeyedLimiter<String>>,
+}
+
+impl Throttle {
+    /// Build the throttle, or `None` if no limit is set.
+    pub fn new(
+        global: Option<u64>,
+        per_connection: Option<u64>,
+        per_credential: Option<u64>,
+    ) -> Option<Self> {
+        let throttle = Self {
+            global: global.map(byte_limiter),
+            per_connection: per_connection.map(byte_limiter),
+            per_credential: per_credential.map(byte_limiter),
+        };
+        (throttle.global.is_some()
+            || throttle.per_connection.is_some()
+            || throttle.per_credential.is_some())
+        .then_some(throttle)
+    }
+
+    /// How long to wait before transferring `len` bytes, accounting them against all the limits.
+    fn delay(&self, peer: SocketAddr, credential: &str, len: usize) -> Duration {
+        let len = len as f64;
+        [
+            self.global.as_ref().map(|limiter| limiter.reserve((), len)),
+            self.per_connection
+                .as_ref()
+                .map(|limiter| limiter.reserve(peer, len)),
+            self.per_credential
+                .as_ref()
+                .map(|limiter| limiter.reserve(credential.to_string(), len)),
+        ]
+        .into_iter()
+        .flatten()
+        .max()
+        .unwrap_or_default()
+    }
+
+    /// Pace a stream of chunks so that it doesn't exceed the limits.
+    pub fn stream<S, E>(
+        self: &Arc<Self>,
+        peer: SocketAddr,
+        credential: String,
+        stream: S,
+    ) -> impl Stream<Item = Result<Bytes, E>>
+    where
+        S: Stream<Item = Result<Bytes, E>>,
+    {
+        let throttle = self.clone();
+        stream
+            .flat_map(|chunk| {
+                let chunks: Vec<_> = match chunk {
+                    Ok(chunk) => (0..chunk.len())
+                        .step_by(CHUNK_SIZE)
+                        .map(|start| Ok(chunk.slice(start..(start + CHUNK_SIZE).min(chunk.len()))))
+                        .collect(),
+                    Err(err) => vec![Err(err)],
+                };
+                futures_util::stream::iter(chunks)
+            })
+            .then(move |chunk| {
+                let delay = match &chunk {
+                    Ok(chunk) => throttle.delay(peer, &credential, chunk.len()),
+                    Err(_) => Duration::ZERO,
+                };
+                async move {
+                    if !delay.is_zero() {
+                        tokio::time::sleep(delay).await;
+                    }
+                    chunk
+                }
+            })
+    }
+
+    /// Pace a response body.
+    pub fn body(self: &Arc<Self>, peer: SocketAddr, credential: String, body: Body) -> Body {
+        Body::from_stream(self.stream(peer, credential, body.into_data_stream()))
+    }
+}
+
+/// Limiter allowing one second worth of data in a burst.
+fn byte_limiter<K: Eq + Hash>(rate: u64) -> KeyedLimiter<K> {
+    KeyedLimiter::new(rate as f64, u32::try_from(rate).unwrap_or(u32::MAX))
+}
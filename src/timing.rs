@@ -0,0 +1,137 @@
+//! Breakdown of the time spent on each phase of the upstream requests.
+//!
+//! The phases of opening a connection are marked by the resolver and by the hooks of the TLS
+//! client, and also count towards the time to first byte. The HTTP client doesn't report when the
+//! plain TCP connections are established, so only the TCP handshakes followed by a TLS one, and
+//! preceded by a name resolution, are measured.
+use std::{
+    cell::RefCell,
+    fmt::Write as _,
+    sync::{Arc, OnceLock},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{Request, State},
+    http::HeaderValue,
+    middleware::Next,
+    response::Response,
+};
+
+/// Response header with the timings, when enabled.
+const SERVER_TIMING_HEADER: &str = "server-timing";
+
+tokio::task_local! {
+    /// Timings of the upstream request made while handling the current request.
+    static TIMINGS: RefCell<Option<Timings>>;
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Timings {
+    /// Name resolution, only when a new connection was opened.
+    pub dns: Option<Duration>,
+    /// TCP handshake of a new connection, from the end of the name resolution.
+    pub tcp: Option<Duration>,
+    /// TLS handshake of a new connection.
+    pub tls: Option<Duration>,
+    /// From sending the request to receiving the response headers.
+    pub ttfb: Duration,
+    /// Transfer of the response body, only known once it is over for the streamed ones.
+    pub body: Arc<OnceLock<Duration>>,
+    /// When the name resolution ended and the TLS handshake started.
+    resolved: Option<Instant>,
+    handshake: Option<Instant>,
+}
+
+impl Timings {
+    fn server_timing(&self) -> String {
+        let mut value = String::new();
+        for (name, duration) in [("dns", self.dns), ("tcp", self.tcp), ("tls", self.tls)] {
+            if let Some(duration) = duration {
+                let _ = write!(value, "{name};dur={:.3}, ", duration.as_secs_f64() * 1e3);
+            }
+        }
+        let _ = write!(value, "ttfb;dur={:.3}", self.ttfb.as_secs_f64() * 1e3);
+        // the header is sent before the streamed bodies are
+        if let Some(body) = self.body.get() {
+            let _ = write!(value, ", body;dur={:.3}", body.as_secs_f64() * 1e3);
+        }
+        value
+    }
+}
+
+fn update(f: impl FnOnce(&mut Timings)) -> Option<Timings> {
+    TIMINGS
+        .try_with(|timings| {
+            let mut timings = timings.borrow_mut();
+            let updated = timings.get_or_insert_with(Timings::default);
+            f(updated);
+            updated.clone()
+        })
+        .ok()
+}
+
+/// Record the phases of an upstream request, returning all the timings of the current request.
+///
+/// The transfer of a streamed `body` is recorded into the returned timings once it is over.
+pub fn record(ttfb: Duration, body: Option<Duration>) -> Timings {
+    let recorded = Arc::new(OnceLock::new());
+    if let Some(body) = body {
+        let _ = recorded.set(body);
+    }
+    update(|timings| {
+        timings.ttfb = ttfb;
+        timings.body = recorded.clone();
+    })
+    .unwrap_or(Timings {
+        ttfb,
+        body: recorded,
+        ..Timings::default()
+    })
+}
+
+/// Record the name resolution of a new upstream connection.
+pub fn record_dns(duration: Duration) {
+    update(|timings| {
+        timings.dns = Some(duration);
+        timings.resolved = Some(Instant::now());
+    });
+}
+
+/// Record the start of the TLS handshake of a new upstream connection, ending its TCP handshake.
+pub fn record_tls_start() {
+    update(|timings| {
+        timings.tcp = timings.resolved.take().map(|resolved| resolved.elapsed());
+        timings.handshake = Some(Instant::now());
+    });
+}
+
+/// Record the end of the TLS handshake of a new upstream connection.
+pub fn record_tls_end() {
+    update(|timings| {
+        if let Some(handshake) = timings.handshake.take() {
+            timings.tls = Some(handshake.elapsed());
+        }
+    });
+}
+
+/// Middleware collecting the timings of the upstream request into the response extensions, and
+/// into a `Server-Timing` header when `header` is set.
+pub async fn measure(State(header): State<bool>, request: Request, next: Next) -> Response {
+    let (mut response, timings) = TIMINGS
+        .scope(RefCell::new(None), async {
+            let response = next.run(request).await;
+            (response, TIMINGS.with(RefCell::take))
+        })
+        .await;
+    let Some(timings) = timings else {
+        return response;
+    };
+    if header {
+        if let Ok(value) = HeaderValue::try_from(timings.server_timing()) {
+            response.headers_mut().insert(SERVER_TIMING_HEADER, value);
+        }
+    }
+    response.extensions_mut().insert(timings);
+    response
+}
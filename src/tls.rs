@@ -0,0 +1,850 @@
+//! TLS of the upstream connections.
+//!
+//! The ClientHello is the one of rustls, whose cipher suites, key exchange groups and ALPN
+//! protocols and their order are configurable, for all the requests or for the ones of a persona
+//! with its own [`TlsProfile`]. The extensions and their order are the ones of rustls, which sends
+//! no GREASE values (RFC 8701), and they can't be changed, so the ClientHello can't match the
+//! fingerprint (JA3/JA4) of a browser or app. That would require a TLS library able to shape it,
+//! like BoringSSL.
+use std::{
+    fmt::Write as _,
+    fs::{File, OpenOptions},
+    io::{BufReader, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, ensure, Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use reqwest::Url;
+use rustls::{
+    client::{
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        ClientSessionMemoryCache, ClientSessionStore, Resumption, Tls12ClientSessionValue,
+        Tls13ClientSessionValue, WebPkiServerVerifier,
+    },
+    crypto::{ring, CryptoProvider},
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+    version, CertificateError, ClientConfig, DigitallySignedStruct, KeyLog, NamedGroup,
+    RootCertStore, SignatureScheme, SupportedProtocolVersion,
+};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::{config::HostPattern, ocsp, timing};
+
+/// The only ALPN protocol of the origins, as the HTTP client only speaks HTTP/1.
+const HTTP_1_1: &str = "http/1.1";
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM bundle of root certificates trusted in addition to the public ones, e.g. of a corporate
+    /// CA.
+    pub ca_bundle: Option<PathBuf>,
+    /// PEM bundle of root certificates of the upstream proxies with `https://` URLs, which are
+    /// then verified with these and the public ones instead of the `ca_bundle`.
+    pub proxy_ca_bundle: Option<PathBuf>,
+    /// Hosts whose certificates aren't verified, e.g. self-signed ones in a lab. Their
+    /// connections can be intercepted by anyone on the path.
+    pub insecure_skip_verify: Vec<HostPattern>,
+    /// Certificate presented to the origins asking for one, unless the host has its own.
+    pub client_cert: Option<ClientCert>,
+    /// Certificates of specific hosts, the first matching one applies.
+    pub host_client_certs: Vec<HostClientCert>,
+    /// Lowest TLS version negotiated with the origins.
+    pub min_version: Option<TlsVersion>,
+    /// Highest TLS version negotiated with the origins.
+    pub max_version: Option<TlsVersion>,
+    /// Cipher suites offered to the origins, in order of preference, e.g.
+    /// `TLS13_AES_256_GCM_SHA384` or `TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256`. All the supported
+    /// ones if empty.
+    pub cipher_suites: Vec<String>,
+    /// Key exchange groups offered to the origins, in order of preference, among `X25519`,
+    /// `secp256r1` and `secp384r1`. All of them if empty.
+    pub key_exchange_groups: Vec<String>,
+    /// Protocols offered to the origins with ALPN, only `http/1.1` being spoken to them, or none
+    /// to send the ClientHello without the ALPN extension. `http/1.1` if unset.
+    pub alpn_protocols: Option<Vec<String>>,
+    /// Public keys the certificate chains of specific hosts must include, the first matching
+    /// host applies.
+    pub pins: Vec<Pin>,
+    /// Server names sent instead of the ones of specific hosts, the first matching host applies.
+    pub sni: Vec<SniRule>,
+    pub revocation: RevocationConfig,
+}
+
+/// ClientHello of the requests of a persona, with the settings of `[upstream.tls]` for the ones
+/// that are unset or empty.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsProfile {
+    /// Cipher suites offered to the origins, in order of preference.
+    pub cipher_suites: Vec<String>,
+    /// Key exchange groups offered to the origins, in order of preference.
+    pub key_exchange_groups: Vec<String>,
+    /// Protocols offered to the origins with ALPN, among `http/1.1`, or none.
+    pub alpn_protocols: Option<Vec<String>>,
+}
+
+/// Revocation checks of the certificates of the origins against certificate revocation lists, and
+/// against the OCSP responses stapled by the origins.
+///
+/// The OCSP responders themselves aren't queried.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RevocationConfig {
+    /// PEM files with the CRLs of the CAs, disabled if empty.
+    pub crls: Vec<PathBuf>,
+    /// Check the OCSP responses stapled by the origins about their certificates. A valid response
+    /// telling a certificate is good stands in for the CRL of its issuer, the unknown statuses of
+    /// the intermediates then being accepted too.
+    pub stapled_ocsp: bool,
+    pub mode: RevocationMode,
+    /// Only check the certificates of the origins, not their intermediates.
+    pub end_entity_only: bool,
+}
+
+/// What happens to certificates whose revocation status is unknown, as their issuer has no CRL and,
+/// for the ones of the origins, no valid OCSP response is stapled when they are checked.
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RevocationMode {
+    /// Accept them.
+    #[default]
+    SoftFail,
+    /// Reject them.
+    HardFail,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SniRule {
+    pub host: HostPattern,
+    /// Name sent and verified instead of the host, which is then only sent in the `Host` header,
+    /// e.g. a CDN domain for domain fronting. No name is sent if empty.
+    pub sni: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Pin {
+    pub host: HostPattern,
+    /// Base64 SHA-256 hashes of the `SubjectPublicKeyInfo` of the keys, the certificate or one of
+    /// the intermediates presented by the host having to match one of them.
+    pub sha256: Vec<String>,
+}
+
+/// TLS versions supported by the client, older ones being insecure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+pub enum TlsVersion {
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+impl TlsVersion {
+    fn supported(self) -> &'static SupportedProtocolVersion {
+        match self {
+            Self::Tls12 => &version::TLS12,
+            Self::Tls13 => &version::TLS13,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientCert {
+    /// PEM file with the certificate and its chain.
+    pub cert: PathBuf,
+    /// PEM file with the private key.
+    pub key: PathBuf,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HostClientCert {
+    pub host: HostPattern,
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// TLS settings of the HTTP clients.
+///
+/// The ClientHello can't depend on the request, so the personas with their own TLS profile get
+/// their own clients.
+pub struct ClientConfigs {
+    pub default: HostConfigs,
+    /// Settings of the personas with a TLS profile, by name.
+    pub personas: Vec<(String, HostConfigs)>,
+    /// Server names sent instead of the ones of the hosts.
+    pub fronts: Vec<(HostPattern, String)>,
+}
+
+/// TLS settings of the HTTP clients with the same ClientHello.
+///
+/// The client certificate and whether the server name is sent can't depend on the server, so the
+/// hosts with their own certificate or without server name get their own client.
+pub struct HostConfigs {
+    pub default: ClientConfig,
+    pub hosts: Vec<(HostPattern, ClientConfig)>,
+}
+
+impl ClientConfigs {
+    /// Server name sent instead of `host`, if any.
+    pub fn front(&self, host: &str) -> Option<&str> {
+        self.fronts
+            .iter()
+            .find(|(pattern, _)| pattern.matches(host))
+            .map(|(_, front)| front.as_str())
+    }
+}
+
+/// Writes the TLS secrets of the upstream connections in the `SSLKEYLOGFILE` format, so that captures
+/// of their traffic can be decrypted.
+#[derive(Debug)]
+struct KeyLogFile(Mutex<File>);
+
+impl KeyLogFile {
+    fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .mode(0o600)
+            .open(path)
+            .with_context(|| format!("could not open TLS key log {}", path.display()))?;
+        Ok(Self(Mutex::new(file)))
+    }
+}
+
+impl KeyLog for KeyLogFile {
+    fn log(&self, label: &str, client_random: &[u8], secret: &[u8]) {
+        let mut line = label.to_string();
+        for bytes in [client_random, secret] {
+            line.push(' ');
+            for byte in bytes {
+                let _ = write!(line, "{byte:02x}");
+            }
+        }
+        line.push('\n');
+        if let Err(err) = self.0.lock().unwrap().write_all(line.as_bytes()) {
+            tracing::error!(error = %err, "Could not write TLS key log");
+        }
+    }
+}
+
+/// Number of sessions kept for resumption, like the default one of rustls.
+const RESUMED_SESSIONS: usize = 256;
+
+/// Session store marking the start of the TLS handshakes for the timings, as the session to resume
+/// is taken from it when the ClientHello is built, right after the TCP handshake.
+#[derive(Debug)]
+struct HandshakeStart(ClientSessionMemoryCache);
+
+impl ClientSessionStore for HandshakeStart {
+    fn set_kx_hint(&self, server_name: ServerName<'static>, group: NamedGroup) {
+        self.0.set_kx_hint(server_name, group);
+    }
+
+    fn kx_hint(&self, server_name: &ServerName<'_>) -> Option<NamedGroup> {
+        self.0.kx_hint(server_name)
+    }
+
+    fn set_tls12_session(&self, server_name: ServerName<'static>, value: Tls12ClientSessionValue) {
+        self.0.set_tls12_session(server_name, value);
+    }
+
+    fn tls12_session(&self, server_name: &ServerName<'_>) -> Option<Tls12ClientSessionValue> {
+        self.0.tls12_session(server_name)
+    }
+
+    fn remove_tls12_session(&self, server_name: &ServerName<'static>) {
+        self.0.remove_tls12_session(server_name);
+    }
+
+    fn insert_tls13_ticket(
+        &self,
+        server_name: ServerName<'static>,
+        value: Tls13ClientSessionValue,
+    ) {
+        self.0.insert_tls13_ticket(server_name, value);
+    }
+
+    fn take_tls13_ticket(
+        &self,
+        server_name: &ServerName<'static>,
+    ) -> Option<Tls13ClientSessionValue> {
+        timing::record_tls_start();
+        self.0.take_tls13_ticket(server_name)
+    }
+}
+
+/// Key log marking the end of the TLS handshakes for the timings, the secrets being written to
+/// the key log file, if any.
+#[derive(Debug)]
+struct HandshakeEnd(Option<Arc<KeyLogFile>>);
+
+impl KeyLog for HandshakeEnd {
+    fn log(&self, label: &str, client_random: &[u8], secret: &[u8]) {
+        // the application secret of TLS 1.3 is derived once the server finished its handshake,
+        // the master secret of TLS 1.2 once the keys are exchanged, a round trip earlier
+        if matches!(label, "CLIENT_TRAFFIC_SECRET_0" | "CLIENT_RANDOM") {
+            timing::record_tls_end();
+        }
+        if let Some(file) = &self.0 {
+            file.log(label, client_random, secret);
+        }
+    }
+}
+
+/// Client settings of `config` for origins and the upstream `proxies`, and of the personas with
+/// their own TLS `profiles`, logging the TLS secrets to `key_log` if set.
+///
+/// The HTTP client connects to the proxies with the settings of the origins, so the verifier tells
+/// them apart by name.
+pub fn client_configs<'a>(
+    config: &TlsConfig,
+    key_log: Option<&Path>,
+    proxies: &[Url],
+    profiles: impl IntoIterator<Item = (&'a str, &'a TlsProfile)>,
+) -> Result<ClientConfigs> {
+    let roots = |bundle: Option<&Path>| {
+        let mut roots = RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+        if let Some(path) = bundle {
+            let (added, ignored) = roots.add_parsable_certificates(certificates(path)?);
+            ensure!(added > 0, "no valid certificate in {}", path.display());
+            if ignored > 0 {
+                tracing::warn!(
+                    path = %path.display(),
+                    ignored,
+                    "Ignored invalid certificates of the CA bundle"
+                );
+            }
+        }
+        anyhow::Ok(roots)
+    };
+    let proxies = match &config.proxy_ca_bundle {
+        Some(path) => {
+            let hosts: Vec<_> = proxies
+                .iter()
+                .filter(|proxy| proxy.scheme() == "https")
+                .filter_map(|proxy| proxy.host_str())
+                .map(|host| host.trim_matches(['[', ']']).to_string())
+                .collect();
+            ensure!(
+                !hosts.is_empty(),
+                "proxy_ca_bundle is set without upstream proxies with https:// URLs"
+            );
+            let webpki = webpki_verifier(roots(Some(path))?, &config.revocation)?;
+            Some((hosts, webpki))
+        }
+        None => None,
+    };
+    for host in &config.insecure_skip_verify {
+        tracing::warn!(
+            %host,
+            "TLS certificates are NOT verified for this host, its connections may be intercepted"
+        );
+    }
+    let pins = config
+        .pins
+        .iter()
+        .map(|pin| {
+            let hashes = pin
+                .sha256
+                .iter()
+                .map(|hash| {
+                    STANDARD
+                        .decode(hash)
+                        .ok()
+                        .and_then(|hash| <[u8; 32]>::try_from(hash).ok())
+                        .ok_or_else(|| anyhow!("invalid SHA-256 pin {hash} of {}", pin.host))
+                })
+                .collect::<Result<_>>()?;
+            Ok((pin.host.clone(), hashes))
+        })
+        .collect::<Result<_>>()?;
+    let roots = roots(config.ca_bundle.as_deref())?;
+    let stapled = config.revocation.stapled_ocsp.then(|| {
+        // the same checks, accepting the unknown statuses
+        let revocation = RevocationConfig {
+            crls: config.revocation.crls.clone(),
+            stapled_ocsp: true,
+            mode: RevocationMode::SoftFail,
+            end_entity_only: config.revocation.end_entity_only,
+        };
+        anyhow::Ok(Stapled {
+            webpki: webpki_verifier(roots.clone(), &revocation)?,
+            hard_fail: matches!(config.revocation.mode, RevocationMode::HardFail)
+                && config.revocation.crls.is_empty(),
+        })
+    });
+    let verifier = Arc::new(Verifier {
+        webpki: webpki_verifier(roots, &config.revocation)?,
+        stapled: stapled.transpose()?,
+        proxies,
+        insecure: config.insecure_skip_verify.clone(),
+        pins,
+    });
+    let versions: Vec<_> = [TlsVersion::Tls12, TlsVersion::Tls13]
+        .into_iter()
+        .filter(|version| config.min_version.is_none_or(|min| *version >= min))
+        .filter(|version| config.max_version.is_none_or(|max| *version <= max))
+        .map(TlsVersion::supported)
+        .collect();
+    ensure!(
+        !versions.is_empty(),
+        "no TLS version between min_version and max_version"
+    );
+    let key_log = key_log
+        .map(|path| {
+            tracing::warn!(
+                path = %path.display(),
+                "TLS secrets of the upstream connections are LOGGED, anyone reading them can \
+                 decrypt the traffic"
+            );
+            KeyLogFile::open(path).map(Arc::new)
+        })
+        .transpose()?;
+    let client_config = |profile: &Profile, cert: Option<(&Path, &Path)>, sni: bool| {
+        let builder = ClientConfig::builder_with_provider(profile.provider.clone())
+            .with_protocol_versions(&versions)
+            .context("no cipher suite of the allowed TLS versions")?
+            .dangerous()
+            .with_custom_certificate_verifier(verifier.clone());
+        let mut tls = match cert {
+            Some((cert, key)) => builder
+                .with_client_auth_cert(certificates(cert)?, private_key(key)?)
+                .with_context(|| format!("invalid client certificate {}", cert.display()))?,
+            None => builder.with_no_client_auth(),
+        };
+        tls.alpn_protocols.clone_from(&profile.alpn_protocols);
+        tls.enable_sni = sni;
+        tls.resumption = Resumption::store(Arc::new(HandshakeStart(
+            ClientSessionMemoryCache::new(RESUMED_SESSIONS),
+        )));
+        tls.key_log = Arc::new(HandshakeEnd(key_log.clone()));
+        anyhow::Ok(tls)
+    };
+    let cert = |host: &str| {
+        config
+            .host_client_certs
+            .iter()
+            .find(|cert| cert.host.matches(host))
+            .map(|cert| (cert.cert.as_path(), cert.key.as_path()))
+            .or(config
+                .client_cert
+                .as_ref()
+                .map(|cert| (cert.cert.as_path(), cert.key.as_path())))
+    };
+    let host_configs = |profile: &TlsProfile| {
+        let profile = Profile::new(config, profile)?;
+        let default = client_config(
+            &profile,
+            config
+                .client_cert
+                .as_ref()
+                .map(|cert| (cert.cert.as_path(), cert.key.as_path())),
+            true,
+        )?;
+        // the hosts without server name first, as they may have a certificate of their own
+        let mut hosts = Vec::new();
+        for rule in config.sni.iter().filter(|rule| rule.sni.is_empty()) {
+            let tls = client_config(&profile, cert(&rule.host.to_string()), false)?;
+            hosts.push((rule.host.clone(), tls));
+        }
+        for cert in &config.host_client_certs {
+            let tls = client_config(&profile, Some((&cert.cert, &cert.key)), true)?;
+            hosts.push((cert.host.clone(), tls));
+        }
+        anyhow::Ok(HostConfigs { default, hosts })
+    };
+    let personas = profiles
+        .into_iter()
+        .map(|(name, profile)| {
+            let configs = host_configs(profile)
+                .with_context(|| format!("invalid TLS profile of persona {name}"))?;
+            Ok((name.to_string(), configs))
+        })
+        .collect::<Result<_>>()?;
+    let fronts = config
+        .sni
+        .iter()
+        .filter(|rule| !rule.sni.is_empty())
+        .map(|rule| (rule.host.clone(), rule.sni.clone()))
+        .collect();
+    Ok(ClientConfigs {
+        default: host_configs(&TlsProfile::default())?,
+        personas,
+        fronts,
+    })
+}
+
+/// The ClientHello of a TLS profile.
+struct Profile {
+    provider: Arc<CryptoProvider>,
+    alpn_protocols: Vec<Vec<u8>>,
+}
+
+impl Profile {
+    /// The `profile`, with the settings of `config` for the ones it doesn't set.
+    fn new(config: &TlsConfig, profile: &TlsProfile) -> Result<Self> {
+        let cipher_suites = if profile.cipher_suites.is_empty() {
+            &config.cipher_suites
+        } else {
+            &profile.cipher_suites
+        };
+        let key_exchange_groups = if profile.key_exchange_groups.is_empty() {
+            &config.key_exchange_groups
+        } else {
+            &profile.key_exchange_groups
+        };
+        let provider = crypto_provider(cipher_suites, key_exchange_groups)?;
+        let alpn_protocols = match profile
+            .alpn_protocols
+            .as_ref()
+            .or(config.alpn_protocols.as_ref())
+        {
+            Some(protocols) => protocols
+                .iter()
+                .map(|protocol| {
+                    ensure!(
+                        protocol == HTTP_1_1,
+                        "unsupported ALPN protocol {protocol}, the origins are only spoken to in \
+                         HTTP/1.1"
+                    );
+                    Ok(protocol.as_bytes().to_vec())
+                })
+                .collect::<Result<_>>()?,
+            None => vec![HTTP_1_1.as_bytes().to_vec()],
+        };
+        Ok(Self {
+            provider: Arc::new(provider),
+            alpn_protocols,
+        })
+    }
+}
+
+fn webpki_verifier(
+    roots: RootCertStore,
+    revocation: &RevocationConfig,
+) -> Result<Arc<WebPkiServerVerifier>> {
+    let mut builder = WebPkiServerVerifier::builder(Arc::new(roots));
+    if !revocation.crls.is_empty() {
+        let mut crls = Vec::new();
+        for path in &revocation.crls {
+            let file =
+                File::open(path).with_context(|| format!("could not open {}", path.display()))?;
+            for crl in rustls_pemfile::crls(&mut BufReader::new(file)) {
+                crls.push(crl.with_context(|| format!("invalid PEM file {}", path.display()))?);
+            }
+        }
+        builder = builder.with_crls(crls);
+        if revocation.end_entity_only {
+            builder = builder.only_check_end_entity_revocation();
+        }
+        if let RevocationMode::SoftFail = revocation.mode {
+            builder = builder.allow_unknown_revocation_status();
+        }
+    }
+    builder
+        .build()
+        .context("invalid certificate revocation lists")
+}
+
+/// The default cryptography, offering only the `cipher_suites` and `key_exchange_groups`, in their
+/// order.
+fn crypto_provider(
+    cipher_suites: &[String],
+    key_exchange_groups: &[String],
+) -> Result<CryptoProvider> {
+    let mut provider = ring::default_provider();
+    provider.cipher_suites = select(
+        &provider.cipher_suites,
+        cipher_suites,
+        |suite| format!("{:?}", suite.suite()),
+        "cipher suite",
+    )?;
+    provider.kx_groups = select(
+        &provider.kx_groups,
+        key_exchange_groups,
+        |group| format!("{:?}", group.name()),
+        "key exchange group",
+    )?;
+    Ok(provider)
+}
+
+/// The `available` items with the `names`, in their order, or all of them if there are no names.
+fn select<T: Copy>(
+    available: &[T],
+    names: &[String],
+    name: impl Fn(&T) -> String,
+    kind: &str,
+) -> Result<Vec<T>> {
+    if names.is_empty() {
+        return Ok(available.to_vec());
+    }
+    names
+        .iter()
+        .map(|wanted| {
+            available
+                .iter()
+                .find(|item| name(item).eq_ignore_ascii_case(wanted))
+                .copied()
+                .ok_or_else(|| anyhow!("unsupported {kind} {wanted}"))
+        })
+        .collect()
+}
+
+/// Certificates of a PEM file.
+fn certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let file = File::open(path).with_context(|| format!("could not open {}", path.display()))?;
+    rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<_, _>>()
+        .with_context(|| format!("invalid PEM file {}", path.display()))
+}
+
+/// First private key of a PEM file.
+fn private_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    let file = File::open(path).with_context(|| format!("could not open {}", path.display()))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .with_context(|| format!("invalid PEM file {}", path.display()))?
+        .with_context(|| format!("no private key in {}", path.display()))
+}
+
+/// Verifies the certificates of the origins and of the upstream proxies, except for the insecure
+/// hosts.
+#[derive(Debug)]
+struct Verifier {
+    webpki: Arc<WebPkiServerVerifier>,
+    stapled: Option<Stapled>,
+    /// Hosts of the upstream proxies and their verifier, if they have roots of their own.
+    proxies: Option<(Vec<String>, Arc<WebPkiServerVerifier>)>,
+    insecure: Vec<HostPattern>,
+    /// Hashes of the public keys pinned for the hosts.
+    pins: Vec<(HostPattern, Vec<[u8; 32]>)>,
+}
+
+/// Checks of the OCSP responses stapled by the origins.
+#[derive(Debug)]
+struct Stapled {
+    /// Verifier of the certificates stapling a good response.
+    webpki: Arc<WebPkiServerVerifier>,
+    /// Whether the other certificates are rejected, when there are no CRLs to tell their status.
+    hard_fail: bool,
+}
+
+impl Verifier {
+    /// The verifier of the certificates of `host`, if it is an upstream proxy with roots of its
+    /// own.
+    fn proxy(&self, host: &str) -> Option<&WebPkiServerVerifier> {
+        self.proxies
+            .as_ref()
+            .filter(|(hosts, _)| hosts.iter().any(|proxy| proxy.eq_ignore_ascii_case(host)))
+            .map(|(_, webpki)| &**webpki)
+    }
+
+    /// The verifier of the certificate of an origin, given its stapled OCSP `response`.
+    fn origin(
+        &self,
+        host: &str,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        response: &[u8],
+        now: UnixTime,
+    ) -> Result<&WebPkiServerVerifier, rustls::Error> {
+        let Some(stapled) = &self.stapled else {
+            return Ok(&self.webpki);
+        };
+        match ocsp::status(end_entity, intermediates, response, now.as_secs()) {
+            Some(ocsp::Status::Good) => return Ok(&stapled.webpki),
+            Some(ocsp::Status::Revoked) => {
+                tracing::error!(%host, "Certificate revoked by its stapled OCSP response");
+                return Err(CertificateError::Revoked.into());
+            }
+            Some(ocsp::Status::Unknown) => {}
+            None if response.is_empty() => {}
+            None => tracing::warn!(%host, "Invalid stapled OCSP response"),
+        }
+        if stapled.hard_fail {
+            tracing::error!(%host, "Certificate without a good stapled OCSP response");
+            return Err(CertificateError::UnknownRevocationStatus.into());
+        }
+        Ok(&self.webpki)
+    }
+
+    /// Whether the chain includes a pinned key of `host`, if it has pins.
+    fn pinned(&self, host: &str, chain: &[&CertificateDer<'_>]) -> bool {
+        let Some((_, pins)) = self.pins.iter().find(|(pattern, _)| pattern.matches(host)) else {
+            return true;
+        };
+        chain.iter().any(|cert| {
+            subject_public_key_info(cert)
+                .is_some_and(|key| pins.contains(&Sha256::digest(key).into()))
+        })
+    }
+}
+
+impl ServerCertVerifier for Verifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let host = server_name.to_str();
+        if !self.insecure.iter().any(|pattern| pattern.matches(&host)) {
+            let webpki = match self.proxy(&host) {
+                Some(webpki) => webpki,
+                None => self.origin(&host, end_entity, intermediates, ocsp_response, now)?,
+            };
+            webpki.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                ocsp_response,
+                now,
+            )?;
+        }
+        let chain: Vec<_> = std::iter::once(end_entity).chain(intermediates).collect();
+        if !self.pinned(&host, &chain) {
+            tracing::error!(%host, "Certificate chain without a pinned key");
+            return Err(CertificateError::ApplicationVerificationFailure.into());
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    // the handshake is still signed by the key of the presented certificate, even if the
+    // certificate itself isn't trusted
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.webpki.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.webpki.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.webpki.supported_verify_schemes()
+    }
+}
+
+/// The DER `SubjectPublicKeyInfo` of a certificate (RFC 5280, section 4.1).
+fn subject_public_key_info<'a>(cert: &'a CertificateDer<'_>) -> Option<&'a [u8]> {
+    let (certificate, _) = der_element(cert)?;
+    let (tbs_certificate, _) = der_element(certificate.content)?;
+    let mut fields = tbs_certificate.content;
+    // explicitly tagged version, absent for v1 certificates
+    if fields.first() == Some(&0xa0) {
+        fields = der_element(fields)?.1;
+    }
+    // serial number, signature algorithm, issuer, validity and subject
+    for _ in 0..5 {
+        fields = der_element(fields)?.1;
+    }
+    Some(der_element(fields)?.0.raw)
+}
+
+pub struct DerElement<'a> {
+    /// The whole element, with its tag and length.
+    pub raw: &'a [u8],
+    pub content: &'a [u8],
+}
+
+/// The first element of `input`, and the rest of it.
+pub fn der_element(input: &[u8]) -> Option<(DerElement<'_>, &[u8])> {
+    let first = *input.get(1)?;
+    let (len, header) = if first < 0x80 {
+        (usize::from(first), 2)
+    } else {
+        let bytes = usize::from(first & 0x7f);
+        if bytes == 0 || bytes > 4 {
+            return None;
+        }
+        let len = input
+            .get(2..2 + bytes)?
+            .iter()
+            .fold(0, |len, byte| len << 8 | usize::from(*byte));
+        (len, 2 + bytes)
+    };
+    let end = header.checked_add(len)?;
+    let raw = input.get(..end)?;
+    Some((
+        DerElement {
+            raw,
+            content: &raw[header..],
+        },
+        &input[end..],
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Leaf certificate of `example.com` with a P-256 key, issued by a test CA.
+    const LEAF: &str = "MIIBkTCCATegAwIBAgIUPrJit6ie0RgHbMB6GP+o34uGfF8wCgYIKoZIzj0EAwIwEjEQMA4GA1UEAwwHVGVzdCBDQTAgFw0yNjEwMTQxMDExMzlaGA8yMTI2MDkyMDEwMTEzOVowFjEUMBIGA1UEAwwLZXhhbXBsZS5jb20wWTATBgcqhkjOPQIBBggqhkjOPQMBBwNCAAQbdCoOx8suz2VGTw8mNB1ue0bbq/tKtT7EvyDadIIRrGRwU5KBpMGUpHEJQoI2PRTFepnuWHyz1FgwivnLCR2Co2UwYzAWBgNVHREEDzANggtleGFtcGxlLmNvbTAJBgNVHRMEAjAAMB0GA1UdDgQWBBQbQHWR45jaHPtPDtxuVrSWz/C2zDAfBgNVHSMEGDAWgBRwXfOfn0HyWJOyR+LSBhieYmtEZTAKBggqhkjOPQQDAgNIADBFAiEAsAqbHgG4pWwzxKNNhWlcZIQ46LT7wuz1/DAFdRHvIVMCIH3GJaaq72uXmMTTFFqQ8c+FGK8QzYTBF1P1zoMP46Jv";
+
+    #[test]
+    fn leaf_public_key_hash() {
+        let cert = CertificateDer::from(STANDARD.decode(LEAF).unwrap());
+        let spki = subject_public_key_info(&cert).unwrap();
+        // openssl x509 -noout -pubkey | openssl pkey -pubin -outform der | openssl dgst -sha256 -binary | base64
+        assert_eq!(
+            STANDARD.encode(Sha256::digest(spki)),
+            "jpasBsf14dZNOXVcfvCe7guMv1gs7aoEItroEbujxrY="
+        );
+    }
+
+    #[test]
+    fn long_form_lengths() {
+        let mut input = vec![0x04, 0x81, 0x80];
+        input.extend([0xaa; 0x80]);
+        input.extend([0x05, 0x00]);
+        let (element, rest) = der_element(&input).unwrap();
+        assert_eq!(element.raw.len(), 3 + 0x80);
+        assert_eq!(element.content, [0xaa; 0x80]);
+        assert_eq!(rest, [0x05, 0x00]);
+
+        let mut input = vec![0x04, 0x82, 0x01, 0x00];
+        input.extend([0xbb; 0x100]);
+        let (element, rest) = der_element(&input).unwrap();
+        assert_eq!(element.content.len(), 0x100);
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn truncated() {
+        assert!(der_element(&[]).is_none());
+        assert!(der_element(&[0x04]).is_none());
+        assert!(der_element(&[0x04, 0x03, 0x01, 0x02]).is_none());
+        assert!(der_element(&[0x04, 0x82, 0x01]).is_none());
+        assert!(der_element(&[0x04, 0x82, 0x01, 0x00, 0x01]).is_none());
+
+        let leaf = STANDARD.decode(LEAF).unwrap();
+        let cert = CertificateDer::from(&leaf[..leaf.len() - 1]);
+        assert!(subject_public_key_info(&cert).is_none());
+    }
+
+    #[test]
+    fn indefinite_length() {
+        // allowed in BER, but not in DER
+        assert!(der_element(&[0x30, 0x80, 0x04, 0x00, 0x00, 0x00]).is_none());
+    }
+}
@@ -0,0 +1,119 @@
+//! Zero-downtime upgrades, handing the listening sockets over to a new process.
+//!
+//! On `SIGHUP`, the proxy starts its binary again with the same arguments, passing it the
+//! listening sockets. Once the new process serves, it sends `SIGTERM` to the old one, which drains
+//! its connections. The sockets are never closed, so connections are queued rather than refused
+//! while the new process starts, and the old one keeps serving if it fails to.
+use std::{
+    env,
+    os::{
+        fd::{AsRawFd, FromRawFd, RawFd},
+        unix::process::CommandExt,
+    },
+    process::Command,
+};
+
+use anyhow::{Context, Result};
+use tokio::{
+    net::TcpListener,
+    signal::unix::{signal, SignalKind},
+};
+
+/// Environment variable with the file descriptors of the inherited proxy listeners.
+const LISTEN_FDS_VAR: &str = "SIMPLE_PROXY_LISTEN_FDS";
+/// Environment variable with the file descriptor of the inherited admin API listener.
+const ADMIN_FD_VAR: &str = "SIMPLE_PROXY_ADMIN_FD";
+
+fn listener(fd: &str) -> Result<TcpListener> {
+    let fd: RawFd = fd
+        .parse()
+        .with_context(|| format!("invalid inherited file descriptor {fd}"))?;
+    // SAFETY: the descriptor is a listening socket passed by the previous process, and isn't used
+    // anywhere else
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+    listener.set_nonblocking(true)?;
+    Ok(TcpListener::from_std(listener)?)
+}
+
+/// The proxy listeners passed by the previous process, if any.
+pub fn inherited_listeners() -> Result<Option<Vec<TcpListener>>> {
+    let Ok(fds) = env::var(LISTEN_FDS_VAR) else {
+        return Ok(None);
+    };
+    fds.split(',')
+        .map(listener)
+        .collect::<Result<_>>()
+        .map(Some)
+}
+
+/// The admin API listener passed by the previous process, if any.
+pub fn inherited_admin_listener() -> Result<Option<TcpListener>> {
+    env::var(ADMIN_FD_VAR)
+        .ok()
+        .as_deref()
+        .map(listener)
+        .transpose()
+}
+
+/// Tell the previous process, if any, to drain now that this one serves.
+pub fn take_over() {
+    if env::var_os(LISTEN_FDS_VAR).is_none() {
+        return;
+    }
+    let parent = std::os::unix::process::parent_id();
+    tracing::info!(
+        parent,
+        "Took over the listeners, draining the previous process"
+    );
+    // SAFETY: sending a signal has no memory safety implications
+    if unsafe { libc::kill(parent as libc::pid_t, libc::SIGTERM) } != 0 {
+        tracing::error!(
+            error = %std::io::Error::last_os_error(),
+            "Could not signal the previous process"
+        );
+    }
+}
+
+/// Start a new process with the listeners on each `SIGHUP`.
+pub async fn upgrade_on_sighup(listeners: Vec<RawFd>, admin: Option<RawFd>) -> Result<()> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    loop {
+        hangup.recv().await;
+        tracing::info!("Received SIGHUP, starting a new process");
+        if let Err(err) = spawn(&listeners, admin) {
+            tracing::error!(error = %err, "Could not start a new process");
+        }
+    }
+}
+
+fn spawn(listeners: &[RawFd], admin: Option<RawFd>) -> Result<()> {
+    let fds: Vec<_> = listeners.iter().map(ToString::to_string).collect();
+    let mut command = Command::new(env::current_exe()?);
+    command
+        .args(env::args_os().skip(1))
+        .env(LISTEN_FDS_VAR, fds.join(","));
+    match admin {
+        Some(fd) => command.env(ADMIN_FD_VAR, fd.to_string()),
+        None => command.env_remove(ADMIN_FD_VAR),
+    };
+    let inherited: Vec<RawFd> = listeners.iter().copied().chain(admin).collect();
+    // SAFETY: only `fcntl` is called between fork and exec, which is async-signal-safe
+    unsafe {
+        command.pre_exec(move || {
+            for fd in &inherited {
+                if libc::fcntl(*fd, libc::F_SETFD, 0) == -1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+    let child = command.spawn()?;
+    tracing::info!(pid = child.id(), "Started a new process");
+    Ok(())
+}
+
+/// File descriptors of listeners, to pass them on upgrades.
+pub fn raw_fds(listeners: &[TcpListener]) -> Vec<RawFd> {
+    listeners.iter().map(AsRawFd::as_raw_fd).collect()
+}
@@ -0,0 +1,277 @@
+//! HTTP client of the upstream requests.
+//!
+//! The requests may go through a pool of upstream proxies, picked by consistent hashing of the
+//! destination host or of the session: adding or removing a proxy only moves the hosts or sessions
+//! it serves, the others keep their exit IP.
+use std::{
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use anyhow::{ensure, Result};
+use reqwest::{header, Client, RequestBuilder, Url};
+use rustls::ClientConfig;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    config::HostPattern,
+    tls::{ClientConfigs, HostConfigs},
+};
+
+/// Builds a client with the TLS settings, going through the upstream proxy if any.
+type Build = dyn Fn(ClientConfig, Option<&Url>) -> reqwest::Result<Client> + Send + Sync;
+
+/// Points of each upstream proxy on the hash ring, so that they get even shares of the keys.
+const RING_POINTS: usize = 160;
+
+/// What picks the upstream proxy of a request.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Balance {
+    /// The destination host, so that each site sees the same exit IP.
+    #[default]
+    Host,
+    /// The session, so that each session keeps its exit IP across sites, or the destination host
+    /// for the requests without a session.
+    Session,
+}
+
+/// Consistent hash ring of the upstream proxies.
+struct Ring {
+    /// Hashes of the points, sorted, and the index of their proxy.
+    points: Vec<(u64, usize)>,
+}
+
+impl Ring {
+    fn new(proxies: &[Url]) -> Self {
+        let mut points: Vec<_> = proxies
+            .iter()
+            .enumerate()
+            .flat_map(|(index, proxy)| {
+                (0..RING_POINTS).map(move |point| (hash(&format!("{proxy}#{point}")), index))
+            })
+            .collect();
+        points.sort_unstable();
+        Self { points }
+    }
+
+    /// Index of the proxy of the `key` hash, the first one at or after it on the ring.
+    fn get(&self, key: u64) -> usize {
+        if self.points.is_empty() {
+            return 0;
+        }
+        let point = self.points.partition_point(|(hash, _)| *hash < key);
+        self.points[point % self.points.len()].1
+    }
+}
+
+fn hash(key: &str) -> u64 {
+    let digest = Sha256::digest(key.as_bytes());
+    u64::from_be_bytes(digest[..8].try_into().expect("the digest is long enough"))
+}
+
+/// Clients of the hosts with their own TLS settings, and of the other hosts.
+struct Clients {
+    default: Client,
+    hosts: Vec<(HostPattern, Client)>,
+}
+
+impl Clients {
+    fn new(tls: &HostConfigs, proxy: Option<&Url>, build: &Build) -> reqwest::Result<Self> {
+        Ok(Self {
+            default: build(tls.default.clone(), proxy)?,
+            hosts: tls
+                .hosts
+                .iter()
+                .map(|(host, tls)| Ok((host.clone(), build(tls.clone(), proxy)?)))
+                .collect::<reqwest::Result<_>>()?,
+        })
+    }
+}
+
+/// Clients of the personas with their own TLS profile, and of the other personas.
+struct Profiles {
+    default: Clients,
+    personas: Vec<(String, Clients)>,
+}
+
+/// Upstream client, which can be replaced to close all its pooled connections.
+pub struct Upstream {
+    /// Clients of each upstream proxy, or the single direct one.
+    clients: RwLock<Vec<Profiles>>,
+    tls: Arc<ClientConfigs>,
+    proxies: Arc<Vec<Url>>,
+    ring: Arc<Ring>,
+    balance: Balance,
+    /// Hash of the session whose requests all go through the same upstream proxy.
+    session: Option<u64>,
+    build: Arc<Build>,
+}
+
+impl Upstream {
+    pub fn new(
+        tls: ClientConfigs,
+        proxies: Vec<Url>,
+        balance: Balance,
+        build: impl Fn(ClientConfig, Option<&Url>) -> reqwest::Result<Client> + Send + Sync + 'static,
+    ) -> Result<Self> {
+        for (index, proxy) in proxies.iter().enumerate() {
+            ensure!(
+                !proxies[..index].contains(proxy),
+                "duplicate upstream proxy {proxy}"
+            );
+        }
+        let clients = Self::build(&tls, &proxies, &build)?;
+        Ok(Self {
+            clients: RwLock::new(clients),
+            tls: Arc::new(tls),
+            ring: Arc::new(Ring::new(&proxies)),
+            proxies: Arc::new(proxies),
+            balance,
+            session: None,
+            build: Arc::new(build),
+        })
+    }
+
+    /// An upstream with the same settings, and connections of its own.
+    pub fn fork(&self) -> Result<Self> {
+        Ok(Self {
+            clients: RwLock::new(Self::build(&self.tls, &self.proxies, &*self.build)?),
+            tls: self.tls.clone(),
+            proxies: self.proxies.clone(),
+            ring: self.ring.clone(),
+            balance: self.balance,
+            session: self.session,
+            build: self.build.clone(),
+        })
+    }
+
+    /// An upstream with connections of its own for the session `id`, whose requests all go through
+    /// the same upstream proxy when balancing by session.
+    pub fn fork_session(&self, id: &str) -> Result<Self> {
+        let mut upstream = self.fork()?;
+        if self.balance == Balance::Session {
+            upstream.session = Some(hash(id));
+        }
+        Ok(upstream)
+    }
+
+    fn build(
+        tls: &ClientConfigs,
+        proxies: &[Url],
+        build: &Build,
+    ) -> reqwest::Result<Vec<Profiles>> {
+        let build_clients = |proxy: Option<&Url>| {
+            Ok(Profiles {
+                default: Clients::new(&tls.default, proxy, build)?,
+                personas: tls
+                    .personas
+                    .iter()
+                    .map(|(name, tls)| Ok((name.clone(), Clients::new(tls, proxy, build)?)))
+                    .collect::<reqwest::Result<_>>()?,
+            })
+        };
+        if proxies.is_empty() {
+            return Ok(vec![build_clients(None)?]);
+        }
+        proxies
+            .iter()
+            .map(|proxy| build_clients(Some(proxy)))
+            .collect()
+    }
+
+    /// The upstream proxy of the requests to `url`, if any.
+    pub fn proxy(&self, url: &Url) -> Option<&Url> {
+        self.proxies.get(self.pick(url))
+    }
+
+    /// Index of the clients of the requests to `url`.
+    fn pick(&self, url: &Url) -> usize {
+        if self.proxies.len() < 2 {
+            return 0;
+        }
+        let key = self
+            .session
+            .unwrap_or_else(|| hash(url.host_str().unwrap_or_default()));
+        self.ring.get(key)
+    }
+
+    /// The current client of the host of `url` with the TLS profile of `persona`, if it has one,
+    /// cheap to clone.
+    pub fn client(&self, url: &Url, persona: Option<&str>) -> Client {
+        let clients = self.clients.read().unwrap();
+        let profiles = &clients[self.pick(url)];
+        let clients = persona
+            .and_then(|persona| profiles.personas.iter().find(|(name, _)| name == persona))
+            .map_or(&profiles.default, |(_, clients)| clients);
+        let host = url.host_str().unwrap_or_default();
+        clients
+            .hosts
+            .iter()
+            .find(|(pattern, _)| pattern.matches(host))
+            .map_or(&clients.default, |(_, client)| client)
+            .clone()
+    }
+
+    /// A `GET` request of `url` with the TLS profile of `persona`, sent to the front of its host if
+    /// it has one.
+    pub fn get(&self, url: &Url, persona: Option<&str>) -> RequestBuilder {
+        let client = self.client(url, persona);
+        let host = url.host_str().unwrap_or_default();
+        let Some(front) = self.tls.front(host).filter(|_| url.scheme() == "https") else {
+            return client.get(url.clone());
+        };
+        let mut fronted = url.clone();
+        if fronted.set_host(Some(front)).is_err() {
+            return client.get(url.clone());
+        }
+        let host = match url.port() {
+            Some(port) => format!("{host}:{port}"),
+            None => host.to_string(),
+        };
+        client.get(fronted).header(header::HOST, host)
+    }
+
+    /// Replace the clients, so that new requests use new connections.
+    ///
+    /// The connections of the previous clients are closed once the requests using them complete.
+    pub fn recycle(&self) -> Result<()> {
+        let clients = Self::build(&self.tls, &self.proxies, &*self.build)?;
+        *self.clients.write().unwrap() = clients;
+        Ok(())
+    }
+}
+
+/// Request the `urls` now and every `interval`, so that the pool keeps connections to their hosts.
+///
+/// This also warms up the connections of a recycled client, on the next tick.
+pub async fn keep_warm(upstream: Arc<Upstream>, urls: Vec<Url>, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        let requests = urls.iter().map(|url| {
+            let request = upstream.client(url, None).head(url.clone()).send();
+            async move {
+                if let Err(err) = request.await {
+                    tracing::warn!(%url, error = %err, "Could not warm up connection");
+                }
+            }
+        });
+        futures_util::future::join_all(requests).await;
+    }
+}
+
+/// Recycle the client every `lifetime`, which bounds the lifetime of the upstream connections.
+pub async fn recycle_loop(upstream: Arc<Upstream>, lifetime: Duration) {
+    let mut interval = tokio::time::interval(lifetime);
+    // the first tick completes right away
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        match upstream.recycle() {
+            Ok(()) => tracing::debug!("Recycled upstream connections"),
+            Err(err) => tracing::error!(error = %err, "Could not recycle upstream connections"),
+        }
+    }
+}
@@ -0,0 +1,207 @@
+//! Per-credential usage accounting, for chargeback.
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+use axum::{
+    extract::{Query, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::Response,
+};
+use clap::ValueEnum;
+use reqwest::Url;
+use serde::Serialize;
+
+use crate::{
+    body::Counted,
+    history::History,
+    quota::{bearer_token, credential_id},
+};
+
+/// Number of destinations tracked per credential, the others are grouped together.
+const MAX_DESTINATIONS: usize = 1000;
+/// Number of destinations reported per credential.
+const TOP_DESTINATIONS: usize = 10;
+/// Destination grouping the ones that are not tracked.
+const OTHER_DESTINATIONS: &str = "other";
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum ExportFormat {
+    Csv,
+    Json,
+}
+
+#[derive(Default)]
+struct Counters {
+    requests: u64,
+    bytes: u64,
+    /// Requests answered with a 4xx or 5xx status.
+    errors: u64,
+    /// Requests by destination host.
+    destinations: HashMap<String, u64>,
+}
+
+#[derive(Serialize)]
+pub struct Report {
+    credential: String,
+    requests: u64,
+    bytes: u64,
+    errors: u64,
+    error_rate: f64,
+    top_destinations: Vec<(String, u64)>,
+}
+
+/// Usage of each credential since the proxy started.
+pub struct Usage {
+    credentials: Mutex<HashMap<String, Counters>>,
+    export: Option<(PathBuf, ExportFormat)>,
+    /// Usage by day persisted in the statistics database, if any.
+    history: Option<History>,
+}
+
+impl Usage {
+    pub fn new(export: Option<(PathBuf, ExportFormat)>, history: Option<History>) -> Self {
+        Self {
+            credentials: Mutex::new(HashMap::new()),
+            export,
+            history,
+        }
+    }
+
+    fn record(&self, credential: String, host: Option<String>, status: StatusCode) {
+        if let Some(history) = &self.history {
+            history.record(&credential, host.as_deref().unwrap_or_default(), status);
+        }
+        let mut credentials = self.credentials.lock().unwrap();
+        let counters = credentials.entry(credential).or_default();
+        counters.requests += 1;
+        if status.is_client_error() || status.is_server_error() {
+            counters.errors += 1;
+        }
+        if let Some(host) = host {
+            let tracked = counters.destinations.len() < MAX_DESTINATIONS
+                || counters.destinations.contains_key(&host);
+            let host = if tracked {
+                host
+            } else {
+                OTHER_DESTINATIONS.to_string()
+            };
+            *counters.destinations.entry(host).or_default() += 1;
+        }
+    }
+
+    fn record_bytes(&self, credential: &str, host: Option<&str>, bytes: u64) {
+        if let Some(history) = &self.history {
+            history.record_bytes(credential, host.unwrap_or_default(), bytes);
+        }
+        if let Some(counters) = self.credentials.lock().unwrap().get_mut(credential) {
+            counters.bytes += bytes;
+        }
+    }
+
+    /// Usage of each credential, by credential id.
+    pub fn report(&self) -> Vec<Report> {
+        let credentials = self.credentials.lock().unwrap();
+        let mut reports: Vec<_> = credentials
+            .iter()
+            .map(|(credential, counters)| {
+                let mut top_destinations: Vec<_> = counters
+                    .destinations
+                    .iter()
+                    .map(|(host, count)| (host.clone(), *count))
+                    .collect();
+                top_destinations.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+                top_destinations.truncate(TOP_DESTINATIONS);
+                Report {
+                    credential: credential.clone(),
+                    requests: counters.requests,
+                    bytes: counters.bytes,
+                    errors: counters.errors,
+                    error_rate: counters.errors as f64 / counters.requests.max(1) as f64,
+                    top_destinations,
+                }
+            })
+            .collect();
+        reports.sort_unstable_by(|a, b| a.credential.cmp(&b.credential));
+        reports
+    }
+
+    /// The usage report as CSV, with the top destinations as `host=count` separated by spaces.
+    pub fn to_csv(&self) -> String {
+        let mut out =
+            String::from("credential,requests,bytes,errors,error_rate,top_destinations\n");
+        for report in self.report() {
+            let destinations: Vec<_> = report
+                .top_destinations
+                .iter()
+                .map(|(host, count)| format!("{host}={count}"))
+                .collect();
+            let _ = writeln!(
+                out,
+                "{},{},{},{},{:.4},{}",
+                report.credential,
+                report.requests,
+                report.bytes,
+                report.errors,
+                report.error_rate,
+                destinations.join(" ")
+            );
+        }
+        out
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&self.report()).expect("usage reports are serializable")
+    }
+
+    /// Add the usage by day to the statistics database, if any.
+    pub fn flush_history(&self) -> Result<()> {
+        match &self.history {
+            Some(history) => history.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Write the usage report to the export file, if any, replacing it atomically.
+    pub fn export(&self) -> Result<()> {
+        let Some((path, format)) = &self.export else {
+            return Ok(());
+        };
+        let contents = match format {
+            ExportFormat::Csv => self.to_csv(),
+            ExportFormat::Json => self.to_json(),
+        };
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, contents)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+/// Middleware accounting for the requests of each credential, once their response is sent.
+pub async fn account(State(usage): State<Arc<Usage>>, request: Request, next: Next) -> Response {
+    let Some(credential) = bearer_token(request.headers()).map(credential_id) else {
+        return next.run(request).await;
+    };
+    let host = Query::<HashMap<String, String>>::try_from_uri(request.uri())
+        .ok()
+        .and_then(|Query(params)| params.get("url")?.parse::<Url>().ok())
+        .and_then(|url| url.host_str().map(str::to_string));
+    let response = next.run(request).await;
+    // don't let unknown credentials pollute the report
+    if response.status() == StatusCode::UNAUTHORIZED {
+        return response;
+    }
+    usage.record(credential.clone(), host.clone(), response.status());
+    response.map(|body| {
+        Counted::wrap(body, move |bytes| {
+            usage.record_bytes(&credential, host.as_deref(), bytes)
+        })
+    })
+}
@@ -0,0 +1,95 @@
+//! Strict validation of the requests, rejecting the malformed ones before they are handled instead
+//! of relying on the leniency of the HTTP parser.
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap, StatusCode, Uri},
+    middleware::Next,
+    response::Response,
+};
+
+use crate::{errors, metrics::METRICS};
+
+pub struct Validation {
+    /// Maximum size of the request header block, names and values included.
+    pub max_header_size: usize,
+}
+
+/// Why a request was rejected.
+struct Rejection {
+    status: StatusCode,
+    code: &'static str,
+    message: &'static str,
+}
+
+impl Validation {
+    fn check(&self, uri: &Uri, headers: &HeaderMap) -> Result<(), Rejection> {
+        let size: usize = headers
+            .iter()
+            // the separators `: ` and `\r\n`
+            .map(|(name, value)| name.as_str().len() + value.len() + 4)
+            .sum();
+        if size > self.max_header_size {
+            return Err(Rejection {
+                status: StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+                code: "headers_too_large",
+                message: "Request headers too large",
+            });
+        }
+        // the parser lets through control characters other than CR and LF, and non-ASCII bytes
+        let printable = |byte: &u8| matches!(byte, b'\t' | b' '..=b'~');
+        if headers
+            .values()
+            .any(|value| !value.as_bytes().iter().all(printable))
+        {
+            return Err(Rejection {
+                status: StatusCode::BAD_REQUEST,
+                code: "invalid_header",
+                message: "Invalid characters in request header",
+            });
+        }
+        // an absolute-form target must be consistent with the `Host`
+        if let Some(authority) = uri.authority() {
+            let host = headers.get(header::HOST).map(|host| host.as_bytes());
+            if host.is_some_and(|host| !host.eq_ignore_ascii_case(authority.as_str().as_bytes())) {
+                return Err(Rejection {
+                    status: StatusCode::BAD_REQUEST,
+                    code: "target_mismatch",
+                    message: "Request target does not match the Host header",
+                });
+            }
+        }
+        let codings = headers
+            .get_all(header::TRANSFER_ENCODING)
+            .iter()
+            .flat_map(|value| value.as_bytes().split(|&byte| byte == b','))
+            .map(|coding| coding.trim_ascii());
+        for coding in codings {
+            if !coding.eq_ignore_ascii_case(b"chunked") {
+                return Err(Rejection {
+                    status: StatusCode::NOT_IMPLEMENTED,
+                    code: "unsupported_transfer_coding",
+                    message: "Unsupported transfer coding",
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Middleware rejecting the malformed requests.
+pub async fn validate(
+    State(validation): State<Arc<Validation>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    match validation.check(request.uri(), request.headers()) {
+        Ok(()) => next.run(request).await,
+        Err(rejection) => {
+            tracing::warn!(reason = rejection.code, "Rejected malformed request");
+            METRICS.record_invalid(rejection.code);
+            errors::response(rejection.status, rejection.code, rejection.message)
+        }
+    }
+}